@@ -137,6 +137,13 @@ struct SubmitTxResp {
     file_path: String,
 }
 
+/// Fee estimate response (`/v1/feeEstimate`)
+#[derive(Debug, Serialize, Deserialize)]
+struct FeeEstimateResp {
+    min_fee: u128,
+    suggested_fee: u128,
+}
+
 // ============================================================================
 // UTILITY FUNCTIONS
 // ============================================================================
@@ -354,6 +361,15 @@ where
     }
 }
 
+/// Fetch the node's current fee estimate
+fn fetch_fee_estimate() -> Result<FeeEstimateResp> {
+    let url = format!("{}/v1/feeEstimate", resolve_rpc());
+    safe_http_request(&url, || {
+        let resp = http().get(&url).timeout(Duration::from_secs(10)).send()?;
+        Ok(h_ok(resp)?.json()?)
+    })
+}
+
 // ============================================================================
 // NODE MANAGEMENT
 // ============================================================================
@@ -768,13 +784,24 @@ fn action_send() -> Result<()> {
             return Ok(());
         }
         
-        let fee_str = read_line("Enter fee (optional, press Enter for default)")?;
+        let fee_str = read_line("Enter fee (optional, press Enter for the node's estimate)")?;
         
         let amount: u128 = amount_str.trim().parse()
             .map_err(|_| anyhow!("Invalid amount - please enter a valid number"))?;
         
         let fee: u128 = if fee_str.is_empty() {
-            1000 // Default fee
+            match fetch_fee_estimate() {
+                Ok(estimate) => {
+                    print_info(&format!("Using suggested fee {} (minimum {})", estimate.suggested_fee, estimate.min_fee));
+                    estimate.suggested_fee
+                }
+                Err(e) => {
+                    // The network's minimum is only known to the node
+                    print_error(&format!("Could not get a fee estimate ({}); enter a fee to send without one", e));
+                    pause();
+                    return Ok(());
+                }
+            }
         } else {
             fee_str.parse().map_err(|_| anyhow!("Invalid fee"))?
         };
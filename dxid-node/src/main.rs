@@ -1,24 +1,39 @@
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{HeaderMap, StatusCode},
-    response::sse::{Event, Sse},
+    response::sse::{Event, KeepAlive, Sse},
     routing::{delete, get, post},
     Json, Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use dxid_crypto::ENGINE as STARK;
-use dxid_crypto::StarkSignEngine;
-use dxid_runtime::{Chain, State as ChainState, CHAIN_ID};
-use futures_util::stream::Stream;
+use dxid_crypto::{SecretKey, StarkSignEngine};
+use dxid_runtime::{
+    assets::{AssetId, AssetInfo, LAYER0_ASSET, LONGYIELD_ASSET, NATIVE_ASSET},
+    backup,
+    genesis::GenesisSpec,
+    consensus::{Consensus, RoundRobinPoa},
+    credentials::{CredentialStatus, MAX_REVOCATIONS_PER_TX},
+    finality::CommitCertificate,
+    import::ImportOutcome,
+    receipt::TxStatus,
+    storage::{self, Storage, StorageConfig},
+    did::{self, DidDocument},
+    htlc::Htlc,
+    staking::ValidatorRecord,
+    Block, Chain, State as ChainState, CHAIN_ID,
+};
+use futures_util::stream::{Stream, StreamExt};
 use hmac::{Hmac, Mac};
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
-use std::{convert::Infallible, fs, path::PathBuf, sync::Arc, time::Duration};
-use tokio::{sync::broadcast, time::sleep};
+use std::{collections::BTreeMap, convert::Infallible, fs, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{sync::{broadcast, mpsc}, time::sleep};
+use tokio_stream::wrappers::BroadcastStream;
 use tracing::warn;
 
 // P2P - temporarily disabled
@@ -47,6 +62,7 @@ fn ensure_admin_token(base: &PathBuf) -> Result<String> {
     fs::write(&p, &tok)?;
     Ok(tok)
 }
+fn genesis_path(base: &std::path::Path) -> PathBuf { base.join("genesis.json") }
 fn apikeys_path(base: &PathBuf) -> PathBuf { base.join("apikeys.json") }
 fn webhooks_path(base: &PathBuf) -> PathBuf { base.join("webhooks.json") }
 
@@ -113,12 +129,13 @@ impl WebhookStore {
 
 #[derive(Clone)]
 struct RpcCtx {
+    chain: Arc<Chain>,
     state: Arc<Mutex<ChainState>>,
     mempool_dir: PathBuf,
-    blocks_dir: PathBuf,
     base_dir: PathBuf,
     admin_token: String,
     sse_tx: broadcast::Sender<String>, // JSON events
+    block_tx: mpsc::Sender<Block>, // blocks from other nodes, for the block loop
 }
 
 /* ---------- CLI opts ---------- */
@@ -126,6 +143,10 @@ struct RpcCtx {
 #[derive(Parser, Debug, Clone)]
 #[command(name="dxid-node", version)]
 struct Opts {
+    /// Data directory
+    #[arg(long, default_value = "./dxid-data", global = true)]
+    data_dir: PathBuf,
+
     /// Enable P2P gossip (default: true for local development)
     #[arg(long, default_value = "true")]
     p2p: bool,
@@ -141,25 +162,314 @@ struct Opts {
     /// Disable automatic peer discovery
     #[arg(long)]
     no_discovery: bool,
+
+    /// hex(32) account credited with block rewards (default: the block's proposer)
+    #[arg(long)]
+    coinbase: Option<String>,
+
+    /// File with the hex secret this node seals blocks with
+    /// (default: <data-dir>/validator_key.txt, then the dev faucet_key.txt)
+    #[arg(long)]
+    validator_key: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Write and validate genesis.json in the data directory
+    Init {
+        /// Install this genesis spec instead of generating a dev genesis
+        #[arg(long)]
+        spec: Option<PathBuf>,
+        /// Chain id of a generated dev genesis
+        #[arg(long, default_value_t = CHAIN_ID)]
+        chain_id: u32,
+        /// Overwrite an existing genesis.json
+        #[arg(long)]
+        force: bool,
+    },
+    /// Export or import a state snapshot
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotCommand,
+    },
+    /// Write the stored state, blocks, receipts, undo journals and certificates as JSON
+    ExportJson {
+        /// Output directory (default: <data-dir>/export)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Rebuild the transaction index (hash and address lookups) from the stored blocks
+    RebuildIndex,
+    /// Rebuild the stored state tree from the stored accounts and records, if the result
+    /// matches the tip block header
+    RebuildSmt,
+    /// Check or restore the backups in <data-dir>/backups
+    Backup {
+        #[command(subcommand)]
+        action: BackupCommand,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum BackupCommand {
+    /// Check backup files against their manifest hashes
+    Verify {
+        /// Backup to check (default: all of them)
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Replace the database and block archive with a verified backup (stop the node first)
+    Restore {
+        #[arg(long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum SnapshotCommand {
+    /// Write a chunked, content-addressed snapshot of the state after a block
+    Export {
+        /// Block height to snapshot (default: current tip)
+        #[arg(long)]
+        height: Option<u64>,
+        /// Output directory (default: <data-dir>/snapshots/<height>)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Verify a snapshot against its state root and install it as the node state
+    Import {
+        /// Snapshot directory (containing manifest.json)
+        #[arg(long)]
+        from: PathBuf,
+        /// Replace an existing state in the data directory
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/* ---------- Genesis ---------- */
+
+/// Write genesis.json, either from a given spec or a fresh single-faucet dev genesis.
+fn init_genesis(base: &std::path::Path, spec: Option<PathBuf>, chain_id: u32, force: bool) -> Result<GenesisSpec> {
+    fs::create_dir_all(base)?;
+    let path = genesis_path(base);
+    if path.exists() && !force {
+        anyhow::bail!("{} already exists (use --force to overwrite)", path.display());
+    }
+
+    let spec = match spec {
+        Some(src) => GenesisSpec::load(&src)?,
+        None => {
+            let (faucet_sk, faucet_pk) = STARK.generate_keys()?;
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+            let mut spec = GenesisSpec::dev(faucet_pk, now);
+            spec.chain_id = chain_id;
+            fs::write(base.join("faucet_key.txt"), hex::encode(faucet_sk.bytes))?;
+            println!("GENESIS faucet pubkey: {}", hex::encode(faucet_pk));
+            println!("Dev faucet secret saved to {}/faucet_key.txt", base.display());
+            spec
+        }
+    };
+    spec.save(&path)?;
+    println!("Wrote {} (chain_id {}, genesis hash {})", path.display(), spec.chain_id, hex::encode(spec.hash()?));
+    Ok(spec)
+}
+
+/* ---------- Consensus ---------- */
+
+/// PoA engine for this node: a validator if a validator key is available, otherwise
+/// a follower that never proposes. Whether a validator proposes is decided per block
+/// by the active set in state, which rotates as operators stake and unstake.
+fn load_consensus(base: &std::path::Path, key: Option<&std::path::Path>, st: &ChainState) -> Result<Arc<dyn Consensus>> {
+    let candidates = match key {
+        Some(path) => vec![path.to_path_buf()],
+        None => vec![base.join("validator_key.txt"), base.join("faucet_key.txt")],
+    };
+    for path in candidates {
+        let Ok(txt) = fs::read_to_string(&path) else {
+            if key.is_some() {
+                anyhow::bail!("cannot read validator key {}", path.display());
+            }
+            continue;
+        };
+        let bytes = hex::decode(txt.trim()).ok().and_then(|v| <[u8; 32]>::try_from(v).ok())
+            .ok_or_else(|| anyhow::anyhow!("{} does not hold a hex(32) secret", path.display()))?;
+        let engine = RoundRobinPoa::validator(SecretKey { bytes })?;
+        let address = engine.local_validator().unwrap_or_default();
+        // An explicit key is used even before it is staked; a default key file only
+        // if the address is active or registered
+        if key.is_some() || st.validators.contains(&address) || st.validator_record(&address).is_some() {
+            let role = if st.validators.contains(&address) { "active" } else { "not in the active set yet" };
+            println!("Sealing blocks as validator {} ({}, {})", hex::encode(address), role, path.display());
+            return Ok(Arc::new(engine));
+        }
+    }
+    if !st.validators.is_empty() {
+        println!("No validator key found, following the chain without proposing");
+    }
+    Ok(Arc::new(RoundRobinPoa::follower()))
+}
+
+/* ---------- Snapshots ---------- */
+
+fn snapshot_export(base: &std::path::Path, height: Option<u64>, out: Option<PathBuf>) -> Result<()> {
+    let genesis = GenesisSpec::load(&genesis_path(base))?;
+    let chain = Chain::new(genesis.into_shared_state()?, base.to_path_buf(), 2000)?;
+    let height = height.unwrap_or_else(|| chain.state.lock().height);
+    let out = out.unwrap_or_else(|| base.join("snapshots").join(height.to_string()));
+    let manifest = chain.export_snapshot(height, &out)?;
+    println!(
+        "Snapshot of height {} written to {} ({} accounts in {} chunks, state root {})",
+        height,
+        out.display(),
+        manifest.account_count(),
+        manifest.chunks.len(),
+        hex::encode(manifest.state_root)
+    );
+    Ok(())
+}
+
+fn snapshot_import(base: &std::path::Path, from: &std::path::Path, force: bool) -> Result<()> {
+    // The snapshot must descend from the genesis installed with `dxid-node init`
+    let genesis = GenesisSpec::load(&genesis_path(base))
+        .map_err(|e| anyhow::anyhow!("{} (run `dxid-node init --spec` first)", e))?;
+    let storage = Storage::new(StorageConfig { base_dir: base.to_path_buf(), ..Default::default() })?;
+    if !force && storage.has_state()? {
+        anyhow::bail!("{} already has a state (use --force to replace it)", base.display());
+    }
+
+    let (manifest, state) = dxid_runtime::snapshot::import_snapshot(from)?;
+    let genesis_hash = genesis.hash()?;
+    if manifest.genesis_hash != genesis_hash || manifest.chain_id != genesis.chain_id {
+        anyhow::bail!(
+            "snapshot belongs to genesis {} (chain {}), not {} (chain {})",
+            hex::encode(manifest.genesis_hash),
+            manifest.chain_id,
+            hex::encode(genesis_hash),
+            genesis.chain_id
+        );
+    }
+    // Undo journals of a previous history cannot apply to the imported state
+    storage.replace_state(&state)?;
+    println!(
+        "Imported snapshot at height {} (block {}, state root {})",
+        manifest.height,
+        hex::encode(manifest.last_block_hash),
+        hex::encode(manifest.state_root)
+    );
+    Ok(())
+}
+
+/// Verify one backup or all of them, failing if any does not match its manifest.
+fn backup_verify(base: &std::path::Path, name: Option<String>) -> Result<()> {
+    let names = match name {
+        Some(name) => vec![name],
+        None => backup::list_backups(base)?,
+    };
+    let mut failed = 0;
+    for name in &names {
+        match backup::verify_backup(&base.join(backup::BACKUP_DIR).join(name)) {
+            Ok(m) => println!("{}: ok, {} files, height {}, state root {}", name, m.files.len(), m.blockchain_height, hex::encode(m.state_root)),
+            Err(e) => {
+                println!("{}: FAILED: {:#}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} backups failed verification", failed, names.len());
+    }
+    println!("{} backups verified", names.len());
+    Ok(())
+}
+
+/// Load genesis.json, creating a dev genesis on first start so a bare `dxid-node` still runs.
+/// A data directory that already holds a chain never gets a new genesis.
+fn load_or_init_genesis(base: &std::path::Path) -> Result<GenesisSpec> {
+    let path = genesis_path(base);
+    if path.exists() {
+        return GenesisSpec::load(&path);
+    }
+    if storage::holds_chain_data(base) {
+        anyhow::bail!(
+            "{} holds chain data but no genesis.json; install the genesis it was created from with `dxid-node init --spec <genesis.json>`",
+            base.display()
+        );
+    }
+    println!("No genesis.json in {}, initializing a dev genesis", base.display());
+    init_genesis(base, None, CHAIN_ID, false)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let opts = Opts::parse();
+    let base = opts.data_dir.clone();
 
-    // Genesis faucet
-    let (faucet_sk, faucet_pk) = STARK.generate_keys()?;
-    println!("GENESIS faucet pubkey: {}", hex::encode(faucet_pk));
-    println!(
-        "Save this for testing (dev only) faucet secret: {}",
-        hex::encode(faucet_sk.bytes)
-    );
+    match opts.command {
+        Some(Command::Init { spec, chain_id, force }) => {
+            init_genesis(&base, spec, chain_id, force)?;
+            return Ok(());
+        }
+        Some(Command::Snapshot { action: SnapshotCommand::Export { height, out } }) => {
+            return snapshot_export(&base, height, out);
+        }
+        Some(Command::Snapshot { action: SnapshotCommand::Import { from, force } }) => {
+            return snapshot_import(&base, &from, force);
+        }
+        Some(Command::ExportJson { out }) => {
+            let out = out.unwrap_or_else(|| base.join("export"));
+            let storage = Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() })?;
+            let height = storage.export_json(&out)?;
+            println!("Exported state at height {} and its history to {}", height, out.display());
+            return Ok(());
+        }
+        Some(Command::Backup { action: BackupCommand::Verify { name } }) => {
+            return backup_verify(&base, name);
+        }
+        Some(Command::Backup { action: BackupCommand::Restore { name } }) => {
+            let (manifest, aside) = backup::restore_backup(&base, &name)?;
+            println!(
+                "Restored {} at height {} (state root {}); replaced files moved to {}",
+                name,
+                manifest.blockchain_height,
+                hex::encode(manifest.state_root),
+                aside.display()
+            );
+            return Ok(());
+        }
+        Some(Command::RebuildSmt) => {
+            let storage = Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() })?;
+            let height = storage.rebuild_smt()?;
+            println!("Rebuilt the state tree at height {}", height);
+            return Ok(());
+        }
+        Some(Command::RebuildIndex) => {
+            let storage = Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() })?;
+            let indexed = storage.rebuild_tx_index()?;
+            println!("Indexed {} transactions", indexed);
+            return Ok(());
+        }
+        None => {}
+    }
 
     // Chain
-    let state = ChainState::new_with_genesis(vec![(faucet_pk, 1_000_000_000_000u128)]);
-    let base = PathBuf::from("./dxid-data");
+    let genesis = load_or_init_genesis(&base)?;
+    let state = genesis.into_shared_state()?;
+    let genesis_hash = hex::encode(state.lock().genesis_hash);
+    println!("Genesis {} (chain_id {})", genesis_hash, genesis.chain_id);
     let chain = Chain::new(state, base.clone(), 2000)?;
+    // The active set comes from the loaded state, not the genesis spec
+    let consensus = load_consensus(&base, opts.validator_key.as_deref(), &chain.state.lock())?;
+    let mut chain = chain.with_consensus(consensus);
+    if let Some(addr) = &opts.coinbase {
+        let coinbase = hex::decode(addr).ok().and_then(|v| <[u8; 32]>::try_from(v).ok())
+            .ok_or_else(|| anyhow::anyhow!("--coinbase must be hex(32)"))?;
+        chain = chain.with_coinbase(coinbase);
+    }
     let chain = Arc::new(chain);
 
     // Admin token (persisted)
@@ -168,27 +478,18 @@ async fn main() -> Result<()> {
 
     // SSE broadcast channel
     let (sse_tx, _sse_rx) = broadcast::channel::<String>(256);
+    // Received blocks, imported by the block loop
+    let (block_tx, mut block_rx) = mpsc::channel::<Block>(64);
 
     // RPC ctx
     let ctx = RpcCtx {
+        chain: chain.clone(),
         state: chain.state.clone(),
         mempool_dir: chain.mempool_dir.clone(),
-        blocks_dir: chain.blocks_dir.clone(),
         base_dir: base.clone(),
         admin_token,
         sse_tx,
-    };
-
-    // ===== P2P Network Startup =====
-    // Derive a "genesis hash" from current state root + height 0 marker.
-    let genesis_hash = {
-        let st = ctx.state.lock();
-        let seed = serde_json::json!({
-            "state_root": hex::encode(st.state_root),
-            "height": 0u64,
-            "chain_id": CHAIN_ID
-        });
-        hex::encode(*blake3::hash(seed.to_string().as_bytes()).as_bytes())
+        block_tx,
     };
 
     // P2P temporarily disabled for Railway build
@@ -207,10 +508,28 @@ async fn main() -> Result<()> {
         // V1 trust-minimized endpoints (real SMT)
         .route("/v1/proveAccount/:addr", get(v1_prove_account))
         .route("/v1/verifyProof", post(v1_verify_proof))
+        .route("/v1/proveTx/:hash", get(v1_prove_tx))
+        .route("/v1/receipt/:tx_hash", get(v1_receipt))
+        .route("/v1/account/:addr/txs", get(v1_account_txs))
+        .route("/v1/supply", get(v1_supply))
+        .route("/v1/feeEstimate", get(v1_fee_estimate))
+        .route("/v1/assets", get(v1_assets))
+        .route("/v1/asset/:id", get(v1_prove_asset))
+        .route("/v1/module/:id", get(v1_prove_module))
+        .route("/v1/module/:id/storage/:key", get(v1_prove_module_slot))
+        .route("/v1/htlc/:id", get(v1_prove_htlc))
+        .route("/v1/did/:id", get(v1_resolve_did))
+        .route("/v1/credentialStatus", post(v1_credential_statuses))
+        .route("/v1/credentialStatus/:issuer/:credential", get(v1_credential_status))
+        .route("/v1/validators", get(v1_validators))
+        .route("/v1/validator/:addr", get(v1_prove_validator))
+        .route("/v1/finality", get(v1_finality).post(v1_submit_commit))
+        .route("/v1/blocks", post(v1_submit_block))
         // API-key endpoints
         .route("/balance/:addr", get(balance))
         .route("/block/:height", get(block_by_height))
         .route("/submitTx", post(submit_tx))
+        .route("/v1/submitTx", post(v1_submit_tx))
         .route("/layer0/transfer", post(layer0_transfer))
         .route("/longyield/transfer", post(longyield_transfer))
         // Admin endpoints
@@ -218,6 +537,7 @@ async fn main() -> Result<()> {
         .route("/admin/apikeys/:id", delete(admin_delete_key))
         .route("/admin/webhooks", post(admin_add_webhook).get(admin_list_webhooks))
         .route("/admin/webhooks/:id", delete(admin_delete_webhook))
+        .route("/admin/revert", post(admin_revert))
         .with_state(ctx.clone());
 
     tokio::spawn({
@@ -247,18 +567,32 @@ async fn main() -> Result<()> {
     let block_interval = Duration::from_millis(2000);
 
     loop {
+        // Blocks from other proposers are imported as soon as they arrive
+        while let Ok(block) = block_rx.try_recv() {
+            if import_received_block(&ctx, block) {
+                last_block_time = std::time::Instant::now();
+            }
+        }
+
         // Check if it's time to produce a block
         let elapsed = last_block_time.elapsed();
         if elapsed < block_interval {
-            // Sleep for the remaining time to reduce CPU usage
+            // Sleep for the remaining time to reduce CPU usage, waking up for received blocks
             let sleep_time = block_interval - elapsed;
-            sleep(sleep_time).await;
+            tokio::select! {
+                Some(block) = block_rx.recv() => {
+                    if import_received_block(&ctx, block) {
+                        last_block_time = std::time::Instant::now();
+                    }
+                }
+                _ = sleep(sleep_time) => {}
+            }
             continue;
         }
         
         // Try to produce a block
         match chain.make_block_once() {
-            Ok(Some(block)) => {
+            Ok(Some((block, receipts))) => {
                 last_block_time = std::time::Instant::now();
                 
                 // SSE broadcast
@@ -268,7 +602,9 @@ async fn main() -> Result<()> {
                     "txs": block.txs.len(),
                     "tx_root": hex::encode(block.header.tx_root),
                     "state_root": hex::encode(block.header.state_root),
-                    "timestamp": block.header.timestamp
+                    "receipts_root": hex::encode(block.header.receipts_root),
+                    "timestamp": block.header.timestamp,
+                    "receipts": receipts
                 })
                 .to_string();
                 let _ = ctx.sse_tx.send(evt);
@@ -283,7 +619,9 @@ async fn main() -> Result<()> {
                                 "height": block.header.height,
                                 "txs": block.txs.len(),
                                 "state_root": hex::encode(block.header.state_root),
+                                "receipts_root": hex::encode(block.header.receipts_root),
                                 "timestamp": block.header.timestamp,
+                                "receipts": receipts,
                                 "proof": {
                                     "root": hex::encode(block.header.state_root),
                                     "height": block.header.height
@@ -293,13 +631,15 @@ async fn main() -> Result<()> {
                         } else if let Some(hex_addr) = ev.strip_prefix("transfer_to:") {
                             let want = hex_addr.to_lowercase();
                             for tx in &block.txs {
-                                if hex::encode(tx.to) == want {
+                                if tx.payload.recipient().map(hex::encode) == Some(want.clone()) {
+                                    let tx_hash = tx.hash();
                                     let body = serde_json::json!({
                                         "event": "transfer_to",
                                         "to": hex_addr,
-                                        "amount": tx.amount,
+                                        "amount": tx.payload.amount(),
                                         "block_height": block.header.height,
-                                        "tx_hash": hex::encode(blake3::hash(&serde_json::to_vec(tx).unwrap_or_default()).as_bytes())
+                                        "tx_hash": hex::encode(tx_hash),
+                                        "receipt": receipts.iter().find(|r| r.tx_hash == tx_hash)
                                     });
                                     send_signed_webhook(&http, &hook, body).await;
                                 }
@@ -346,6 +686,35 @@ async fn main() -> Result<()> {
 
 /* ---------- Helpers ---------- */
 
+/// Import a block received from another node, announcing it on the SSE stream.
+/// Returns whether the chain advanced.
+fn import_received_block(ctx: &RpcCtx, block: Block) -> bool {
+    let height = block.header.height;
+    match ctx.chain.import_block(block) {
+        Ok(ImportOutcome::Imported { height, hash, txs }) => {
+            let header = ctx.chain.load_block(height).ok().flatten().map(|b| b.header);
+            let evt = serde_json::json!({
+                "type": "block",
+                "height": height,
+                "hash": hex::encode(hash),
+                "txs": txs,
+                "state_root": header.as_ref().map(|h| hex::encode(h.state_root)),
+                "receipts_root": header.as_ref().map(|h| hex::encode(h.receipts_root)),
+                "timestamp": header.as_ref().map(|h| h.timestamp),
+                "imported": true
+            })
+            .to_string();
+            let _ = ctx.sse_tx.send(evt);
+            true
+        }
+        Ok(ImportOutcome::AlreadyKnown { .. }) => false,
+        Err(e) => {
+            warn!("Rejected block {}: {}", height, e);
+            false
+        }
+    }
+}
+
 fn require_api(headers: &HeaderMap, ctx: &RpcCtx) -> bool {
     if let Some(val) = headers.get("X-Api-Key") {
         if let Ok(sec) = val.to_str() {
@@ -402,6 +771,11 @@ struct StatusResp {
     last_block_hash: String,
     state_root: String,
     chain_id: u32,
+    genesis_hash: String,
+    consensus: &'static str,
+    /// hex(32) validator expected to propose the next block in the current round (None = any node)
+    next_proposer: Option<String>,
+    finalized_height: u64,
 }
 async fn status(State(ctx): State<RpcCtx>) -> Json<StatusResp> {
     // Quick state access without holding lock for too long
@@ -409,20 +783,31 @@ async fn status(State(ctx): State<RpcCtx>) -> Json<StatusResp> {
     let height = st.height;
     let last_block_hash = st.last_block_hash;
     let state_root = st.state_root;
+    let chain_id = st.chain_id;
+    let genesis_hash = st.genesis_hash;
+    let finalized_height = st.finalized_height;
+    // The proposer rotates to the next validator each time the current one times out
+    let round = ctx.chain.round_at(&st, now_ts()).unwrap_or(0);
+    let next_proposer = ctx.chain.consensus().proposer(&st, height + 1, round).map(hex::encode);
     drop(st); // Explicitly drop the lock
     
     Json(StatusResp {
         height,
         last_block_hash: hex::encode(last_block_hash),
         state_root: hex::encode(state_root),
-        chain_id: CHAIN_ID,
+        chain_id,
+        genesis_hash: hex::encode(genesis_hash),
+        consensus: ctx.chain.consensus().name(),
+        next_proposer,
+        finalized_height,
     })
 }
 
-async fn watch(_state: State<RpcCtx>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    // TODO: Fix SSE stream after resolving broadcast issues
-    let stream = futures_util::stream::empty();
-    Sse::new(stream)
+async fn watch(State(ctx): State<RpcCtx>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // Lagged subscribers just skip the events they missed
+    let stream = BroadcastStream::new(ctx.sse_tx.subscribe())
+        .filter_map(|msg| async move { msg.ok().map(|data| Ok(Event::default().data(data))) });
+    Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
 async fn peers(State(_ctx): State<RpcCtx>) -> Json<serde_json::Value> {
@@ -440,12 +825,12 @@ async fn peers(State(_ctx): State<RpcCtx>) -> Json<serde_json::Value> {
     }))
 }
 
-async fn network_status(State(_ctx): State<RpcCtx>) -> Json<serde_json::Value> {
+async fn network_status(State(ctx): State<RpcCtx>) -> Json<serde_json::Value> {
     // P2P temporarily disabled
     let status = serde_json::json!({
         "auto_discovery_enabled": false,
         "p2p_enabled": false,
-        "chain_id": CHAIN_ID,
+        "chain_id": ctx.state.lock().chain_id,
         "peer_count": 0,
         "discovery_active": false,
     });
@@ -458,8 +843,21 @@ async fn network_status(State(_ctx): State<RpcCtx>) -> Json<serde_json::Value> {
 #[derive(Serialize, Deserialize)]
 struct AccountLeaf {
     addr: String,
+    /// Native balance (kept for older clients; `balances` is authoritative)
     balance: String,
     nonce: u64,
+    /// Balance per asset id, decimal strings
+    #[serde(default)]
+    balances: BTreeMap<AssetId, String>,
+    /// False for a non-membership proof
+    #[serde(default = "default_true")]
+    exists: bool,
+}
+
+fn default_true() -> bool { true }
+
+fn balances_out(acct: &dxid_runtime::Account) -> BTreeMap<AssetId, String> {
+    acct.balances.iter().map(|(id, b)| (*id, b.to_string())).collect()
 }
 
 #[derive(Serialize, Deserialize)]
@@ -479,7 +877,8 @@ struct VerifyResp { ok: bool, reason: Option<String> }
 async fn v1_prove_account(State(ctx): State<RpcCtx>, Path(addr_hex): Path<String>) -> (StatusCode, Json<AccountProof>) {
     let st = ctx.state.lock();
     let (acct_opt, proof) = st.prove_account(&addr_hex);
-    let acct = acct_opt.unwrap_or(dxid_runtime::Account { balance: 0, nonce: 0, layer0_balance: 0, longyield_balance: 0 });
+    let exists = acct_opt.is_some();
+    let acct = acct_opt.unwrap_or_default();
 
     let path: Vec<String> = proof.siblings.iter().map(|s| hex::encode(s)).collect();
 
@@ -488,8 +887,10 @@ async fn v1_prove_account(State(ctx): State<RpcCtx>, Path(addr_hex): Path<String
         height: st.height,
         leaf: AccountLeaf {
             addr: addr_hex.to_lowercase(),
-            balance: acct.balance.to_string(),
+            balance: acct.balance(NATIVE_ASSET).to_string(),
             nonce: acct.nonce,
+            balances: balances_out(&acct),
+            exists,
         },
         path,
     };
@@ -502,11 +903,6 @@ async fn v1_verify_proof(Json(req): Json<VerifyReq>) -> (StatusCode, Json<Verify
         let v = hex::decode(s).ok()?; if v.len() != 32 { return None; }
         let mut o = [0u8; 32]; o.copy_from_slice(&v); Some(o)
     }
-    fn u128_to_h256(x: u128) -> H256 {
-        let mut out = [0u8; 32];
-        out[16..].copy_from_slice(&x.to_be_bytes());
-        out
-    }
 
     let p = req.proof;
     let Some(root) = dehex32(&p.root) else {
@@ -515,7 +911,14 @@ async fn v1_verify_proof(Json(req): Json<VerifyReq>) -> (StatusCode, Json<Verify
     let Some(addr) = dehex32(&p.leaf.addr) else {
         return (StatusCode::BAD_REQUEST, Json(VerifyResp { ok: false, reason: Some("bad addr".into()) }));
     };
-    let bal = p.leaf.balance.parse::<u128>().unwrap_or(u128::MAX);
+    let mut acct = dxid_runtime::Account { nonce: p.leaf.nonce, ..Default::default() };
+    for (id, b) in &p.leaf.balances {
+        let Ok(b) = b.parse::<u128>() else {
+            return (StatusCode::BAD_REQUEST, Json(VerifyResp { ok: false, reason: Some("bad balance".into()) }));
+        };
+        acct.credit(*id, b);
+    }
+    let leaf = p.leaf.exists.then(|| acct.leaf_hash());
 
     // decode path
     if p.path.len() != 256 {
@@ -530,7 +933,7 @@ async fn v1_verify_proof(Json(req): Json<VerifyReq>) -> (StatusCode, Json<Verify
     }
     let proof = dxid_smt::SmtProof { siblings };
 
-    let ok = SparseMerkleTree::verify(&root, &addr, Some(&u128_to_h256(bal)), &proof);
+    let ok = SparseMerkleTree::verify(&root, &addr, leaf.as_ref(), &proof);
     let resp = if ok {
         VerifyResp { ok: true, reason: None }
     } else {
@@ -539,6 +942,560 @@ async fn v1_verify_proof(Json(req): Json<VerifyReq>) -> (StatusCode, Json<Verify
     (if ok { StatusCode::OK } else { StatusCode::BAD_REQUEST }, Json(resp))
 }
 
+#[derive(Serialize)]
+struct TxProofResp {
+    tx_hash: String,
+    block_height: u64,
+    /// Header the proof verifies against (`tx_root` is the Merkle root)
+    header: dxid_runtime::BlockHeader,
+    tx: dxid_runtime::Tx,
+    index: u64,
+    leaf_count: u64,
+    /// Siblings from the leaf level upwards — hex-encoded
+    path: Vec<String>,
+}
+
+async fn v1_prove_tx(State(ctx): State<RpcCtx>, Path(hash_hex): Path<String>) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(tx_hash) = hex::decode(&hash_hex).ok().and_then(|v| <[u8; 32]>::try_from(v).ok()) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad tx hash" })));
+    };
+    match ctx.chain.prove_tx(&tx_hash) {
+        Ok(Some((header, tx, proof))) => {
+            let out = TxProofResp {
+                tx_hash: hex::encode(tx_hash),
+                block_height: header.height,
+                header,
+                tx,
+                index: proof.index,
+                leaf_count: proof.leaf_count,
+                path: proof.siblings.iter().map(hex::encode).collect(),
+            };
+            (StatusCode::OK, Json(serde_json::to_value(out).unwrap_or_default()))
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "not found" }))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": e.to_string() }))),
+    }
+}
+
+/// Circulating supply per token, checked against the sum of all balances.
+async fn v1_supply(State(ctx): State<RpcCtx>) -> Json<Vec<dxid_runtime::supply::SupplyAudit>> {
+    Json(ctx.state.lock().audit_supply())
+}
+
+/// Current minimum fee and a suggested fee for LongYield / native transfers.
+async fn v1_fee_estimate(State(ctx): State<RpcCtx>) -> Json<dxid_runtime::fees::FeeEstimate> {
+    Json(ctx.state.lock().fee_estimate())
+}
+
+#[derive(Serialize)]
+struct AssetEntry {
+    id: AssetId,
+    asset: AssetInfo,
+}
+
+/// Asset registry: protocol tokens and partner assets.
+async fn v1_assets(State(ctx): State<RpcCtx>) -> Json<Vec<AssetEntry>> {
+    let st = ctx.state.lock();
+    Json(st.assets.iter().map(|(id, a)| AssetEntry { id: *id, asset: a.clone() }).collect())
+}
+
+#[derive(Serialize)]
+struct FinalityResp {
+    height: u64,
+    finalized_height: u64,
+    /// Precommits finalizing `finalized_height` (None until a block is finalized)
+    certificate: Option<CommitCertificate>,
+}
+
+/// Highest finalized block and its commit certificate.
+async fn v1_finality(State(ctx): State<RpcCtx>) -> (StatusCode, Json<FinalityResp>) {
+    let height = ctx.state.lock().height;
+    match ctx.chain.finality() {
+        Ok((finalized_height, certificate)) => (StatusCode::OK, Json(FinalityResp { height, finalized_height, certificate })),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(FinalityResp { height, finalized_height: 0, certificate: None })),
+    }
+}
+
+#[derive(Serialize, Default)]
+struct SubmitCommitResp { accepted: bool, finalized_height: u64, error: Option<String> }
+
+/// Accept a commit certificate from the validators' finality gadget. Certificates
+/// carry their own signatures, so no token is needed.
+async fn v1_submit_commit(State(ctx): State<RpcCtx>, Json(cert): Json<CommitCertificate>)
+-> (StatusCode, Json<SubmitCommitResp>) {
+    let result = ctx.chain.record_commit(cert);
+    let finalized_height = ctx.state.lock().finalized_height;
+    match result {
+        Ok(accepted) => (StatusCode::OK, Json(SubmitCommitResp { accepted, finalized_height, error: None })),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(SubmitCommitResp { finalized_height, error: Some(e.to_string()), ..Default::default() })),
+    }
+}
+
+#[derive(Serialize, Default)]
+struct SubmitBlockResp { queued: bool, height: u64, error: Option<String> }
+
+/// Hand a block built by another validator to the block loop, which imports it.
+/// Blocks carry their proposer's seal and are re-executed on import, so no token is needed.
+async fn v1_submit_block(State(ctx): State<RpcCtx>, Json(block): Json<Block>) -> (StatusCode, Json<SubmitBlockResp>) {
+    let height = block.header.height;
+    match ctx.block_tx.try_send(block) {
+        Ok(()) => (StatusCode::ACCEPTED, Json(SubmitBlockResp { queued: true, height, error: None })),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, Json(SubmitBlockResp { height, error: Some(e.to_string()), ..Default::default() })),
+    }
+}
+
+#[derive(Serialize)]
+struct AssetProof {
+    root: String,
+    height: u64,
+    id: AssetId,
+    /// None proves the id is not registered
+    asset: Option<AssetInfo>,
+    /// Siblings from LSB to MSB (256 entries) — hex-encoded
+    path: Vec<String>,
+}
+
+/// Registry record of one asset with its SMT proof against the state root.
+async fn v1_prove_asset(State(ctx): State<RpcCtx>, Path(id): Path<AssetId>) -> Json<AssetProof> {
+    let st = ctx.state.lock();
+    let (asset, proof) = st.prove_asset(id);
+    Json(AssetProof {
+        root: hex::encode(st.state_root),
+        height: st.height,
+        id,
+        asset,
+        path: proof.siblings.iter().map(hex::encode).collect(),
+    })
+}
+
+#[derive(Serialize)]
+struct HtlcProof {
+    root: String,
+    height: u64,
+    id: String,
+    /// None proves no escrow exists under this id
+    htlc: Option<Htlc>,
+    /// Siblings from LSB to MSB (256 entries) — hex-encoded
+    path: Vec<String>,
+}
+
+/// HTLC escrow entry (with the preimage once claimed) and its SMT proof against the state root.
+async fn v1_prove_htlc(State(ctx): State<RpcCtx>, Path(id_hex): Path<String>) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(id) = parse_h256(&id_hex) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad escrow id" })));
+    };
+    let st = ctx.state.lock();
+    let (htlc, proof) = st.prove_htlc(&id);
+    let resp = HtlcProof {
+        root: hex::encode(st.state_root),
+        height: st.height,
+        id: id_hex,
+        htlc,
+        path: proof.siblings.iter().map(hex::encode).collect(),
+    };
+    (StatusCode::OK, Json(serde_json::to_value(resp).unwrap_or_default()))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DidResolution {
+    did: String,
+    /// W3C DID document; None proves the DID was never registered
+    did_document: Option<serde_json::Value>,
+    did_document_metadata: Option<DidMetadata>,
+    /// The committed record; BLAKE3 of its JSON is the leaf the proof opens
+    record: Option<DidDocument>,
+    root: String,
+    height: u64,
+    /// Siblings from LSB to MSB (256 entries) — hex-encoded
+    path: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DidMetadata {
+    created: u64,
+    updated: u64,
+    deactivated: bool,
+}
+
+/// Resolve `did:dxid:<id>` (or the bare hex id) with an SMT proof against the state root.
+async fn v1_resolve_did(State(ctx): State<RpcCtx>, Path(did): Path<String>) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(id) = did::parse_did(&did) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad DID" })));
+    };
+    let st = ctx.state.lock();
+    let (doc, proof) = st.prove_did(&id);
+    let resp = DidResolution {
+        did: did::did_string(&id),
+        did_document: doc.as_ref().map(|d| d.to_w3c(&id)),
+        did_document_metadata: doc.as_ref().map(|d| DidMetadata {
+            created: d.created,
+            updated: d.updated,
+            deactivated: d.deactivated,
+        }),
+        record: doc.clone(),
+        root: hex::encode(st.state_root),
+        height: st.height,
+        path: proof.siblings.iter().map(hex::encode).collect(),
+    };
+    (StatusCode::OK, Json(serde_json::to_value(resp).unwrap_or_default()))
+}
+
+#[derive(Serialize)]
+struct CredentialStatusResp {
+    root: String,
+    height: u64,
+    issuer: String,
+    /// Root of the issuer's revocation tree; None proves the issuer never revoked anything
+    registry_root: Option<String>,
+    /// SMT proof of the registry root (or its absence) — hex-encoded siblings
+    registry_path: Vec<String>,
+    statuses: Vec<CredentialStatusEntry>,
+}
+
+#[derive(Serialize)]
+struct CredentialStatusEntry {
+    credential: String,
+    revoked: bool,
+    /// Block that revoked the credential
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revoked_at: Option<u64>,
+    /// Proof of the credential (or its absence) in the issuer's tree — hex-encoded siblings
+    path: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CredentialStatusQuery {
+    issuer: String,
+    credentials: Vec<String>,
+}
+
+/// Revocation or non-revocation proofs for credentials of one issuer. The registry
+/// proof is shared by all of them, since it only depends on the issuer.
+fn credential_status_resp(st: &ChainState, issuer: &[u8; 32], credentials: &[[u8; 32]]) -> CredentialStatusResp {
+    let statuses: Vec<CredentialStatus> = credentials.iter().map(|c| st.credential_status(issuer, c)).collect();
+    let (registry_root, registry_path) = match statuses.first() {
+        Some(s) => (s.registry_root, s.registry_proof.siblings.iter().map(hex::encode).collect()),
+        None => (None, Vec::new()),
+    };
+    CredentialStatusResp {
+        root: hex::encode(st.state_root),
+        height: st.height,
+        issuer: hex::encode(issuer),
+        registry_root: registry_root.map(hex::encode),
+        registry_path,
+        statuses: statuses
+            .into_iter()
+            .map(|s| CredentialStatusEntry {
+                credential: hex::encode(s.credential),
+                revoked: s.revoked_at.is_some(),
+                revoked_at: s.revoked_at,
+                path: s.credential_proof.siblings.iter().map(hex::encode).collect(),
+            })
+            .collect(),
+    }
+}
+
+/// Status of one credential, checkable offline against the header at `height`.
+async fn v1_credential_status(
+    State(ctx): State<RpcCtx>,
+    Path((issuer_hex, credential_hex)): Path<(String, String)>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (Some(issuer), Some(credential)) = (parse_h256(&issuer_hex), parse_h256(&credential_hex)) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad issuer or credential id" })));
+    };
+    let st = ctx.state.lock();
+    let resp = credential_status_resp(&st, &issuer, &[credential]);
+    (StatusCode::OK, Json(serde_json::to_value(resp).unwrap_or_default()))
+}
+
+/// Batch status lookup: `{"issuer": hex, "credentials": [hex, ...]}`.
+async fn v1_credential_statuses(
+    State(ctx): State<RpcCtx>,
+    Json(query): Json<CredentialStatusQuery>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if query.credentials.is_empty() || query.credentials.len() > MAX_REVOCATIONS_PER_TX {
+        let error = format!("ask for 1 to {} credentials", MAX_REVOCATIONS_PER_TX);
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": error })));
+    }
+    let credentials: Option<Vec<[u8; 32]>> = query.credentials.iter().map(|c| parse_h256(c)).collect();
+    let (Some(issuer), Some(credentials)) = (parse_h256(&query.issuer), credentials) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad issuer or credential id" })));
+    };
+    let st = ctx.state.lock();
+    let resp = credential_status_resp(&st, &issuer, &credentials);
+    (StatusCode::OK, Json(serde_json::to_value(resp).unwrap_or_default()))
+}
+
+#[derive(Serialize)]
+struct ActiveSetResp {
+    root: String,
+    height: u64,
+    /// Epoch of the next block
+    epoch: u64,
+    epoch_length: u64,
+    /// hex(32) addresses, in proposer order
+    validators: Vec<String>,
+    /// First block proposed by this set (0 = genesis set)
+    since: u64,
+    previous_validators: Vec<String>,
+    /// SMT proof of the active set — hex-encoded siblings
+    path: Vec<String>,
+}
+
+/// Active validator set of the current epoch with its SMT proof against the state root.
+async fn v1_validators(State(ctx): State<RpcCtx>) -> Json<ActiveSetResp> {
+    let st = ctx.state.lock();
+    let proof = st.prove_active_set();
+    Json(ActiveSetResp {
+        root: hex::encode(st.state_root),
+        height: st.height,
+        epoch: st.staking.epoch(st.height + 1),
+        epoch_length: st.staking.epoch_length,
+        validators: st.validators.iter().map(hex::encode).collect(),
+        since: st.validators_since,
+        previous_validators: st.previous_validators.iter().map(hex::encode).collect(),
+        path: proof.siblings.iter().map(hex::encode).collect(),
+    })
+}
+
+#[derive(Serialize)]
+struct ValidatorProof {
+    root: String,
+    height: u64,
+    address: String,
+    active: bool,
+    /// None proves the address has no registry record
+    record: Option<ValidatorRecord>,
+    /// Siblings from LSB to MSB (256 entries) — hex-encoded
+    path: Vec<String>,
+}
+
+/// Registry record of one validator with its SMT proof against the state root.
+async fn v1_prove_validator(State(ctx): State<RpcCtx>, Path(addr_hex): Path<String>) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(addr) = parse_h256(&addr_hex) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad validator address" })));
+    };
+    let st = ctx.state.lock();
+    let (record, proof) = st.prove_validator(&addr);
+    let resp = ValidatorProof {
+        root: hex::encode(st.state_root),
+        height: st.height,
+        address: addr_hex,
+        active: st.validators.contains(&addr),
+        record,
+        path: proof.siblings.iter().map(hex::encode).collect(),
+    };
+    (StatusCode::OK, Json(serde_json::to_value(resp).unwrap_or_default()))
+}
+
+#[derive(Serialize)]
+struct ModuleProof {
+    root: String,
+    height: u64,
+    id: String,
+    /// None proves no module is deployed under this id
+    owner: Option<String>,
+    code_hash: Option<String>,
+    code_size: usize,
+    deployed_at: Option<u64>,
+    /// Root of the module's storage tree, folded into its leaf
+    storage_root: String,
+    /// Siblings from LSB to MSB (256 entries) — hex-encoded
+    path: Vec<String>,
+}
+
+fn parse_h256(s: &str) -> Option<[u8; 32]> {
+    hex::decode(s).ok().and_then(|v| <[u8; 32]>::try_from(v).ok())
+}
+
+/// A deployed module (without its code) with its SMT proof against the state root.
+async fn v1_prove_module(State(ctx): State<RpcCtx>, Path(id_hex): Path<String>) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(id) = parse_h256(&id_hex) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad module id" })));
+    };
+    let st = ctx.state.lock();
+    let (info, storage_root, proof) = st.prove_module(&id);
+    let resp = ModuleProof {
+        root: hex::encode(st.state_root),
+        height: st.height,
+        id: id_hex,
+        owner: info.as_ref().map(|m| hex::encode(m.owner)),
+        code_hash: info.as_ref().map(|m| hex::encode(m.code_hash)),
+        code_size: info.as_ref().map_or(0, |m| m.code.len()),
+        deployed_at: info.as_ref().map(|m| m.deployed_at),
+        storage_root: hex::encode(storage_root),
+        path: proof.siblings.iter().map(hex::encode).collect(),
+    };
+    (StatusCode::OK, Json(serde_json::to_value(resp).unwrap_or_default()))
+}
+
+#[derive(Serialize)]
+struct ModuleSlotProof {
+    storage_root: String,
+    key: String,
+    /// None proves the slot is empty
+    value: Option<String>,
+    /// Siblings in the module's storage tree, LSB to MSB — hex-encoded
+    path: Vec<String>,
+}
+
+/// One storage slot of a module with its proof against the module's storage root.
+async fn v1_prove_module_slot(
+    State(ctx): State<RpcCtx>,
+    Path((id_hex, key_hex)): Path<(String, String)>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let (Some(id), Ok(key)) = (parse_h256(&id_hex), hex::decode(&key_hex)) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad module id or key" })));
+    };
+    let st = ctx.state.lock();
+    let (value, proof) = st.prove_module_slot(&id, &key);
+    let resp = ModuleSlotProof {
+        storage_root: hex::encode(st.module_storage_root(&id)),
+        key: key_hex,
+        value: value.map(hex::encode),
+        path: proof.siblings.iter().map(hex::encode).collect(),
+    };
+    (StatusCode::OK, Json(serde_json::to_value(resp).unwrap_or_default()))
+}
+
+async fn v1_receipt(State(ctx): State<RpcCtx>, Path(hash_hex): Path<String>) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(tx_hash) = hex::decode(&hash_hex).ok().and_then(|v| <[u8; 32]>::try_from(v).ok()) else {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "bad tx hash" })));
+    };
+    match ctx.chain.find_receipt(&tx_hash) {
+        Ok(Some(receipt)) => (StatusCode::OK, Json(serde_json::to_value(receipt).unwrap_or_default())),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "not found" }))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": e.to_string() }))),
+    }
+}
+
+/// Default and largest page of `/v1/account/:addr/txs`
+const ACCOUNT_TXS_DEFAULT_LIMIT: usize = 50;
+const ACCOUNT_TXS_MAX_LIMIT: usize = 200;
+
+#[derive(Deserialize)]
+struct AccountTxsQuery {
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+    limit: Option<usize>,
+    /// "desc" (newest first, default) or "asc"
+    direction: Option<String>,
+    /// Asset id or symbol
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AccountTxsResp {
+    address: String,
+    height: u64,
+    transactions: Vec<AccountTxEntry>,
+    /// Pass as `cursor` for the next page; absent at the end of the history
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    /// A token filter skipped so many entries that the scan stopped before filling the
+    /// page; more may follow from `next_cursor`
+    scan_limit_reached: bool,
+}
+
+#[derive(Serialize)]
+struct AccountTxEntry {
+    tx_hash: String,
+    block_height: u64,
+    /// Position in the block
+    position: u32,
+    timestamp: u64,
+    kind: &'static str,
+    /// "sent", "received" or "self"
+    direction: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counterparty: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<AssetId>,
+    amount: String,
+    /// None if the block's receipts are missing
+    status: Option<TxStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<String>,
+}
+
+fn parse_history_cursor(s: &str) -> Option<(u64, u32)> {
+    let (height, position) = s.split_once(':')?;
+    Some((height.parse().ok()?, position.parse().ok()?))
+}
+
+/// Transactions sent or received by an address, from the address index.
+async fn v1_account_txs(
+    State(ctx): State<RpcCtx>,
+    Path(addr_hex): Path<String>,
+    Query(query): Query<AccountTxsQuery>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let bad_request = |error: &str| (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": error })));
+    let Some(addr) = parse_h256(&addr_hex) else { return bad_request("bad address") };
+    let cursor = match query.cursor.as_deref().filter(|c| !c.is_empty()) {
+        Some(c) => match parse_history_cursor(c) {
+            Some(cursor) => Some(cursor),
+            None => return bad_request("bad cursor"),
+        },
+        None => None,
+    };
+    let descending = match query.direction.as_deref() {
+        None | Some("desc") => true,
+        Some("asc") => false,
+        Some(_) => return bad_request("direction must be asc or desc"),
+    };
+    let limit = query.limit.unwrap_or(ACCOUNT_TXS_DEFAULT_LIMIT);
+    if limit == 0 || limit > ACCOUNT_TXS_MAX_LIMIT {
+        return bad_request(&format!("limit must be 1 to {}", ACCOUNT_TXS_MAX_LIMIT));
+    }
+    let (height, token) = {
+        let st = ctx.state.lock();
+        let token = match query.token.as_deref() {
+            Some(t) => match t.parse::<AssetId>().ok().or_else(|| st.assets.iter().find(|(_, a)| a.symbol.eq_ignore_ascii_case(t)).map(|(id, _)| *id)) {
+                Some(id) if st.assets.contains_key(&id) => Some(id),
+                _ => return bad_request("unknown token"),
+            },
+            None => None,
+        };
+        (st.height, token)
+    };
+
+    match ctx.chain.address_history(&addr, cursor, descending, limit, token) {
+        Ok(page) => {
+            let transactions = page
+                .entries
+                .into_iter()
+                .map(|e| AccountTxEntry {
+                    tx_hash: hex::encode(e.tx.hash()),
+                    block_height: e.height,
+                    position: e.position,
+                    timestamp: e.timestamp,
+                    kind: e.tx.payload.kind(),
+                    direction: match (e.sent, e.received) {
+                        (true, false) => "sent",
+                        (false, true) => "received",
+                        _ => "self",
+                    },
+                    counterparty: e.counterparty.map(hex::encode),
+                    token: e.tx.payload.token_type().map(|t| t.asset_id()),
+                    amount: e.tx.payload.amount().to_string(),
+                    status: e.receipt.as_ref().map(|r| r.status),
+                    error_code: e.receipt.and_then(|r| r.error_code),
+                })
+                .collect();
+            let resp = AccountTxsResp {
+                address: hex::encode(addr),
+                height,
+                transactions,
+                next_cursor: page.next.map(|(h, p)| format!("{}:{}", h, p)),
+                scan_limit_reached: page.scan_limit_reached,
+            };
+            (StatusCode::OK, Json(serde_json::to_value(resp).unwrap_or_default()))
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": e.to_string() }))),
+    }
+}
+
 /* ---------- API-key endpoints ---------- */
 
 #[derive(Serialize)]
@@ -549,41 +1506,83 @@ struct BalanceResp {
     nonce: u64,
     layer0_balance: String,
     longyield_balance: String,
+    /// Balance per asset id, decimal strings
+    balances: BTreeMap<AssetId, String>,
+    /// Not yet vested in schedules paying out to this address, per asset id
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    locked: BTreeMap<AssetId, String>,
+    /// Vested but not yet claimed, per asset id
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    unlocked: BTreeMap<AssetId, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    vesting: Vec<VestingResp>,
+}
+
+#[derive(Serialize)]
+struct VestingResp {
+    id: String,
+    asset: AssetId,
+    total: String,
+    claimed: String,
+    locked: String,
+    unlocked: String,
+    cliff_height: u64,
+    end_height: u64,
+}
+
+/// Locked / unlocked split of the vesting schedules paying out to `addr`, as of the tip.
+fn vesting_out(st: &ChainState, addr: &[u8; 32]) -> (BTreeMap<AssetId, String>, BTreeMap<AssetId, String>, Vec<VestingResp>) {
+    let (mut locked, mut unlocked) = (BTreeMap::<AssetId, u128>::new(), BTreeMap::<AssetId, u128>::new());
+    let mut schedules = Vec::new();
+    for (id, s) in st.vesting_of(addr) {
+        *locked.entry(s.asset).or_default() += s.locked_at(st.height);
+        *unlocked.entry(s.asset).or_default() += s.claimable_at(st.height);
+        schedules.push(VestingResp {
+            id: hex::encode(id),
+            asset: s.asset,
+            total: s.total.to_string(),
+            claimed: s.claimed.to_string(),
+            locked: s.locked_at(st.height).to_string(),
+            unlocked: s.claimable_at(st.height).to_string(),
+            cliff_height: s.cliff_height,
+            end_height: s.end_height,
+        });
+    }
+    let out = |m: BTreeMap<AssetId, u128>| m.into_iter().map(|(id, v)| (id, v.to_string())).collect();
+    (out(locked), out(unlocked), schedules)
 }
 
 async fn balance(State(ctx): State<RpcCtx>, headers: HeaderMap, Path(addr_hex): Path<String>)
 -> (StatusCode, Json<BalanceResp>) {
+    let mut resp = BalanceResp { 
+        address: addr_hex.clone(),
+        exists:false, 
+        balance:"0".into(), 
+        nonce:0,
+        layer0_balance: "0".into(),
+        longyield_balance: "0".into(),
+        balances: BTreeMap::new(),
+        locked: BTreeMap::new(),
+        unlocked: BTreeMap::new(),
+        vesting: Vec::new(),
+    };
     if !require_api(&headers, &ctx) {
-        return (StatusCode::UNAUTHORIZED, Json(BalanceResp { 
-            address: addr_hex.clone(),
-            exists:false, 
-            balance:"0".into(), 
-            nonce:0,
-            layer0_balance: "0".into(),
-            longyield_balance: "0".into(),
-        }));
+        return (StatusCode::UNAUTHORIZED, Json(resp));
     }
     let st = ctx.state.lock();
     let key = addr_hex.to_lowercase();
     if let Some(acct) = st.accounts.get(&key) {
-        (StatusCode::OK, Json(BalanceResp {
-            address: addr_hex.clone(),
-            exists: true, 
-            balance: acct.balance.to_string(), 
-            nonce: acct.nonce,
-            layer0_balance: acct.layer0_balance.to_string(),
-            longyield_balance: acct.longyield_balance.to_string(),
-        }))
-    } else {
-        (StatusCode::OK, Json(BalanceResp { 
-            address: addr_hex.clone(),
-            exists:false, 
-            balance:"0".into(), 
-            nonce:0,
-            layer0_balance: "0".into(),
-            longyield_balance: "0".into(),
-        }))
+        resp.exists = true;
+        resp.balance = acct.balance(NATIVE_ASSET).to_string();
+        resp.nonce = acct.nonce;
+        resp.layer0_balance = acct.balance(LAYER0_ASSET).to_string();
+        resp.longyield_balance = acct.balance(LONGYIELD_ASSET).to_string();
+        resp.balances = balances_out(acct);
+    }
+    if let Some(addr) = parse_h256(&key) {
+        (resp.locked, resp.unlocked, resp.vesting) = vesting_out(&st, &addr);
     }
+    (StatusCode::OK, Json(resp))
 }
 
 async fn block_by_height(State(ctx): State<RpcCtx>, headers: HeaderMap, Path(height): Path<u64>)
@@ -591,11 +1590,9 @@ async fn block_by_height(State(ctx): State<RpcCtx>, headers: HeaderMap, Path(hei
     if !require_api(&headers, &ctx) {
         return (StatusCode::UNAUTHORIZED, "{\"error\":\"unauthorized\"}".into());
     }
-    let fname = format!("{:016x}.json", height);
-    let path = ctx.blocks_dir.join(fname);
-    match std::fs::read_to_string(&path) {
-        Ok(s) => (StatusCode::OK, s),
-        Err(_) => (StatusCode::NOT_FOUND, "{\"error\":\"not found\"}".into()),
+    match ctx.chain.load_block(height) {
+        Ok(Some(block)) => (StatusCode::OK, serde_json::to_string_pretty(&block).unwrap_or_default()),
+        _ => (StatusCode::NOT_FOUND, "{\"error\":\"not found\"}".into()),
     }
 }
 
@@ -632,22 +1629,10 @@ async fn submit_tx(State(ctx): State<RpcCtx>, headers: HeaderMap, Json(body): Js
     if body.signature.pubkey_hash != from {
         return (StatusCode::BAD_REQUEST, Json(SubmitTxResp { queued:false, file:"".into() }));
     }
-    let msg = serde_json::to_vec(&(from, to, body.amount, body.fee, body.signature.nonce, CHAIN_ID)).unwrap();
-    if STARK.verify(&body.signature, &msg).is_err() {
+    let tx = dxid_runtime::Tx::legacy_transfer(from, to, body.amount, body.fee, dxid_runtime::TokenType::Native, body.signature.clone());
+    if tx.verify_signature().is_err() {
         return (StatusCode::BAD_REQUEST, Json(SubmitTxResp { queued:false, file:"".into() }));
     }
-
-    let signature = body.signature.clone();
-    let tx = dxid_runtime::Tx { 
-        from, 
-        to, 
-        amount: body.amount, 
-        fee: body.fee, 
-        signature,
-        token_type: dxid_runtime::TokenType::Native,
-        cross_chain: false,
-        target_chain_id: None,
-    };
     let fname = format!("{}.json", uuid::Uuid::new_v4());
     let path = ctx.mempool_dir.join(&fname);
     if std::fs::write(&path, serde_json::to_string_pretty(&tx).unwrap()).is_err() {
@@ -675,6 +1660,32 @@ async fn submit_tx(State(ctx): State<RpcCtx>, headers: HeaderMap, Json(body): Js
     (StatusCode::OK, Json(SubmitTxResp { queued:true, file: path.to_string_lossy().to_string() }))
 }
 
+/// Submit a signed transaction envelope of any kind (legacy flat JSON accepted too).
+async fn v1_submit_tx(State(ctx): State<RpcCtx>, headers: HeaderMap, Json(tx): Json<dxid_runtime::Tx>)
+-> (StatusCode, Json<serde_json::Value>) {
+    if !require_api(&headers, &ctx) {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({ "queued": false, "error": "unauthorized" })));
+    }
+    if tx.chain_id != ctx.state.lock().chain_id {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "queued": false, "error": "wrong chain id" })));
+    }
+    if let Err(e) = tx.verify_signature() {
+        return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "queued": false, "error": e.to_string() })));
+    }
+
+    let fname = format!("{}.json", uuid::Uuid::new_v4());
+    let path = ctx.mempool_dir.join(&fname);
+    if std::fs::write(&path, serde_json::to_string_pretty(&tx).unwrap()).is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "queued": false, "error": "mempool write failed" })));
+    }
+    (StatusCode::OK, Json(serde_json::json!({
+        "queued": true,
+        "tx_hash": hex::encode(tx.hash()),
+        "kind": tx.payload.kind(),
+        "file": path.to_string_lossy()
+    })))
+}
+
 // Layer0 Token Transfer Endpoint
 #[derive(Deserialize)]
 struct Layer0TransferReq {
@@ -710,22 +1721,10 @@ async fn layer0_transfer(State(ctx): State<RpcCtx>, headers: HeaderMap, Json(bod
         return (StatusCode::BAD_REQUEST, Json(SubmitTxResp { queued:false, file:"".into() }));
     }
     
-    let msg = serde_json::to_vec(&(from, to, body.amount, body.fee, body.signature.nonce, CHAIN_ID)).unwrap();
-    if STARK.verify(&body.signature, &msg).is_err() {
+    let tx = dxid_runtime::Tx::legacy_transfer(from, to, body.amount, body.fee, dxid_runtime::TokenType::Layer0, body.signature.clone());
+    if tx.verify_signature().is_err() {
         return (StatusCode::BAD_REQUEST, Json(SubmitTxResp { queued:false, file:"".into() }));
     }
-
-    let signature = body.signature.clone();
-    let tx = dxid_runtime::Tx { 
-        from, 
-        to, 
-        amount: body.amount, 
-        fee: body.fee, 
-        signature,
-        token_type: dxid_runtime::TokenType::Layer0,
-        cross_chain: false,
-        target_chain_id: None,
-    };
     
     let fname = format!("{}.json", uuid::Uuid::new_v4());
     let path = ctx.mempool_dir.join(&fname);
@@ -756,6 +1755,8 @@ async fn layer0_transfer(State(ctx): State<RpcCtx>, headers: HeaderMap, Json(bod
 }
 
 // LongYield Token Transfer Endpoint
+// Signed over the legacy `(from, to, amount, fee, nonce, CHAIN_ID)` message, which does
+// not cover the token; wallets that want it bound submit a current envelope instead
 #[derive(Deserialize)]
 struct LongYieldTransferReq {
     from: String, // hex(32)
@@ -790,22 +1791,10 @@ async fn longyield_transfer(State(ctx): State<RpcCtx>, headers: HeaderMap, Json(
         return (StatusCode::BAD_REQUEST, Json(SubmitTxResp { queued:false, file:"".into() }));
     }
     
-    let msg = serde_json::to_vec(&(from, to, body.amount, body.fee, body.signature.nonce, CHAIN_ID)).unwrap();
-    if STARK.verify(&body.signature, &msg).is_err() {
+    let tx = dxid_runtime::Tx::legacy_transfer(from, to, body.amount, body.fee, dxid_runtime::TokenType::LongYield, body.signature.clone());
+    if tx.verify_signature().is_err() {
         return (StatusCode::BAD_REQUEST, Json(SubmitTxResp { queued:false, file:"".into() }));
     }
-
-    let signature = body.signature.clone();
-        let tx = dxid_runtime::Tx {
-        from, 
-        to, 
-        amount: body.amount, 
-        fee: body.fee, 
-        signature,
-        token_type: dxid_runtime::TokenType::LongYield,
-        cross_chain: false,
-        target_chain_id: None,
-    };
     
     let fname = format!("{}.json", uuid::Uuid::new_v4());
     let path = ctx.mempool_dir.join(&fname);
@@ -946,3 +1935,29 @@ async fn send_signed_webhook(http: &reqwest::Client, hook: &Webhook, body: serde
         .send()
         .await;
 }
+
+#[derive(Deserialize)]
+struct AdminRevertReq { height: u64 }
+#[derive(Serialize, Default)]
+struct AdminRevertResp { height: u64, reverted_blocks: Vec<u64>, requeued_txs: usize, error: Option<String> }
+
+/// Roll the chain back to `height` from the per-block undo journals.
+async fn admin_revert(State(ctx): State<RpcCtx>, headers: HeaderMap, Json(body): Json<AdminRevertReq>)
+-> (StatusCode, Json<AdminRevertResp>) {
+    if !require_admin(&headers, &ctx) {
+        return (StatusCode::UNAUTHORIZED, Json(AdminRevertResp::default()));
+    }
+    match ctx.chain.revert_to(body.height) {
+        Ok(blocks) => (StatusCode::OK, Json(AdminRevertResp {
+            height: body.height,
+            reverted_blocks: blocks.iter().map(|b| b.header.height).collect(),
+            requeued_txs: blocks.iter().map(|b| b.txs.len()).sum(),
+            error: None,
+        })),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(AdminRevertResp {
+            height: ctx.state.lock().height,
+            error: Some(e.to_string()),
+            ..Default::default()
+        })),
+    }
+}
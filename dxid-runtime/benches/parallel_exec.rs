@@ -0,0 +1,81 @@
+//! Block execution throughput: sequential vs the parallel scheduler.
+//!
+//! Run with `cargo bench -p dxid-runtime --bench parallel_exec`. Optional
+//! arguments: transaction count (default 10000) and sender count (default 2000).
+//! The parallel path is timed at 2 and 4 threads and at the machine's parallelism.
+
+use std::time::{Duration, Instant};
+
+use dxid_crypto::{StarkSignEngine, ENGINE as STARK};
+use dxid_runtime::{
+    exec,
+    tx::{Tx, TxPayload},
+    State, TokenType,
+};
+
+const RUNS: u32 = 3;
+
+fn main() {
+    let args: Vec<usize> = std::env::args().skip(1).filter_map(|a| a.parse().ok()).collect();
+    let n_txs = args.first().copied().unwrap_or(10_000);
+    let n_senders = args.get(1).copied().unwrap_or(2_000).clamp(1, n_txs);
+
+    let senders: Vec<_> = (0..n_senders).map(|_| STARK.generate_keys().unwrap()).collect();
+    let mut st = State::new_with_genesis(senders.iter().map(|(_, pk)| (*pk, 1_000_000_000)).collect()).lock().clone();
+    st.commit_root();
+
+    // Each sender queues its transfers in nonce order, to a mix of fresh and shared recipients
+    let mut txs = Vec::with_capacity(n_txs);
+    for i in 0..n_txs {
+        let (sk, from) = &senders[i % n_senders];
+        let nonce = (i / n_senders) as u64;
+        let mut to = [0u8; 32];
+        to[..8].copy_from_slice(&((i % (n_txs / 4).max(1)) as u64).to_le_bytes());
+        let payload = TxPayload::Transfer { to, amount: 1_000, token_type: TokenType::Native };
+        txs.push(Tx::new_signed(sk, *from, nonce, 10, dxid_runtime::CHAIN_ID, None, payload).unwrap());
+    }
+    txs.sort_by_key(|tx| (tx.from, tx.nonce));
+
+    let threads = exec::available_threads();
+    println!("{} transactions from {} senders, {} cores available", n_txs, n_senders, threads);
+
+    let (seq_time, seq_root) = measure(|| {
+        let mut st = st.clone();
+        let out = exec::execute_sequential(&mut st, &txs, n_txs);
+        assert_eq!(out.included.len(), n_txs);
+        st.commit_root()
+    });
+    report("sequential", n_txs, seq_time);
+
+    let mut counts = vec![2, 4, threads];
+    counts.sort_unstable();
+    counts.dedup();
+    for threads in counts.into_iter().filter(|&t| t > 1) {
+        let (par_time, par_root) = measure(|| {
+            let mut st = st.clone();
+            let out = exec::execute(&mut st, &txs, n_txs, threads);
+            assert_eq!(out.included.len(), n_txs);
+            st.commit_root()
+        });
+        assert_eq!(seq_root, par_root, "parallel execution changed the state root");
+        report(&format!("{} threads", threads), n_txs, par_time);
+        println!("{:>10}  {:.2}x", "speedup", seq_time.as_secs_f64() / par_time.as_secs_f64());
+    }
+    println!("state root {}", hex::encode(seq_root));
+}
+
+/// Best of `RUNS` timings, with the result of the last run.
+fn measure<T>(mut f: impl FnMut() -> T) -> (Duration, T) {
+    let mut best = Duration::MAX;
+    let mut result = None;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        result = Some(f());
+        best = best.min(start.elapsed());
+    }
+    (best, result.expect("at least one run"))
+}
+
+fn report(name: &str, n_txs: usize, time: Duration) {
+    println!("{:>10}: {:>8.1} ms  {:>10.0} tx/s", name, time.as_secs_f64() * 1e3, n_txs as f64 / time.as_secs_f64());
+}
@@ -0,0 +1,271 @@
+//! Block archive.
+//!
+//! Blocks are packed into segment files of `SEGMENT_BLOCKS` consecutive heights
+//! under `blocks/`. `seg-NNNNNN.dat` holds the records back to back, each a codec
+//! byte followed by the block's JSON, gzip-compressed unless compression is off.
+//! `seg-NNNNNN.idx` has one fixed-width slot per height of the segment (offset and
+//! length into the `.dat` file, zero length = no block), so loading a block is one
+//! slot read and one record read whatever the height.
+//!
+//! Records are appended and their slots written before the storage transaction that
+//! makes the block part of the chain commits. `truncate_after` drops anything past
+//! the committed tip, which is all a crash in between can leave behind.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use parking_lot::Mutex;
+
+use crate::Block;
+
+/// Heights per segment
+pub const SEGMENT_BLOCKS: u64 = 1000;
+
+/// Offset (u64) and length (u32), little-endian
+const SLOT_LEN: u64 = 12;
+
+const CODEC_JSON: u8 = 0;
+const CODEC_GZIP: u8 = 1;
+
+pub struct BlockArchive {
+    dir: PathBuf,
+    compress: bool,
+    /// Serializes appends and truncation
+    write_lock: Mutex<()>,
+}
+
+impl BlockArchive {
+    pub fn open(dir: &Path, compress: bool) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self { dir: dir.to_path_buf(), compress, write_lock: Mutex::new(()) })
+    }
+
+    fn data_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("seg-{:06}.dat", segment))
+    }
+
+    fn index_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("seg-{:06}.idx", segment))
+    }
+
+    /// Append a block (replacing any earlier record at its height) and sync it to disk.
+    pub fn append(&self, block: &Block) -> Result<()> {
+        let height = block.header.height;
+        let json = serde_json::to_vec(block)?;
+        let record = if self.compress {
+            let mut enc = GzEncoder::new(vec![CODEC_GZIP], Compression::default());
+            enc.write_all(&json)?;
+            enc.finish()?
+        } else {
+            [&[CODEC_JSON][..], &json].concat()
+        };
+
+        let _guard = self.write_lock.lock();
+        if self.slot(height)?.is_some() {
+            // A new block at a stored height replaces it and everything above
+            self.drop_from(height)?;
+        }
+        let segment = height / SEGMENT_BLOCKS;
+        let mut data = OpenOptions::new().create(true).append(true).open(self.data_path(segment))?;
+        let offset = data.metadata()?.len();
+        data.write_all(&record)?;
+        data.sync_data()?;
+
+        let mut slot = [0u8; SLOT_LEN as usize];
+        slot[..8].copy_from_slice(&offset.to_le_bytes());
+        slot[8..].copy_from_slice(&(record.len() as u32).to_le_bytes());
+        let mut index = self.open_index(segment)?;
+        index.seek(SeekFrom::Start((height % SEGMENT_BLOCKS) * SLOT_LEN))?;
+        index.write_all(&slot)?;
+        index.sync_data()?;
+        Ok(())
+    }
+
+    fn open_index(&self, segment: u64) -> Result<File> {
+        let index = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(self.index_path(segment))?;
+        // Fixed size, so every slot exists
+        if index.metadata()?.len() < SEGMENT_BLOCKS * SLOT_LEN {
+            index.set_len(SEGMENT_BLOCKS * SLOT_LEN)?;
+        }
+        Ok(index)
+    }
+
+    /// Offset and length of the record at `height`
+    fn slot(&self, height: u64) -> Result<Option<(u64, u32)>> {
+        let Ok(mut index) = File::open(self.index_path(height / SEGMENT_BLOCKS)) else { return Ok(None) };
+        let mut slot = [0u8; SLOT_LEN as usize];
+        index.seek(SeekFrom::Start((height % SEGMENT_BLOCKS) * SLOT_LEN))?;
+        if index.read_exact(&mut slot).is_err() {
+            return Ok(None);
+        }
+        let offset = u64::from_le_bytes(slot[..8].try_into().unwrap_or_default());
+        let len = u32::from_le_bytes(slot[8..].try_into().unwrap_or_default());
+        Ok((len > 0).then_some((offset, len)))
+    }
+
+    pub fn load(&self, height: u64) -> Result<Option<Block>> {
+        let Some((offset, len)) = self.slot(height)? else { return Ok(None) };
+        let mut data = File::open(self.data_path(height / SEGMENT_BLOCKS)).context("Failed to open block segment")?;
+        let mut record = vec![0u8; len as usize];
+        data.seek(SeekFrom::Start(offset))?;
+        data.read_exact(&mut record).with_context(|| format!("Block segment truncated at height {}", height))?;
+        let block = match record.split_first() {
+            Some((&CODEC_GZIP, gz)) => serde_json::from_reader(GzDecoder::new(gz))?,
+            Some((&CODEC_JSON, json)) => serde_json::from_slice(json)?,
+            _ => bail!("unknown block codec at height {}", height),
+        };
+        Ok(Some(block))
+    }
+
+    /// Heights with a block, in order
+    pub fn heights(&self) -> Result<Vec<u64>> {
+        let mut heights = Vec::new();
+        for segment in self.segments()? {
+            let index = fs::read(self.index_path(segment))?;
+            for (i, slot) in index.chunks_exact(SLOT_LEN as usize).enumerate() {
+                if slot[8..] != [0u8; 4] {
+                    heights.push(segment * SEGMENT_BLOCKS + i as u64);
+                }
+            }
+        }
+        Ok(heights)
+    }
+
+    fn segments(&self) -> Result<Vec<u64>> {
+        let mut segments: Vec<u64> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                name.to_str()?.strip_prefix("seg-")?.strip_suffix(".idx")?.parse().ok()
+            })
+            .collect();
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Drop every block above `height`, reclaiming the space of their records.
+    pub fn truncate_after(&self, height: u64) -> Result<()> {
+        let _guard = self.write_lock.lock();
+        self.drop_from(height + 1)
+    }
+
+    /// Drop every block at `first` and above
+    fn drop_from(&self, first: u64) -> Result<()> {
+        for segment in self.segments()? {
+            if segment > first / SEGMENT_BLOCKS {
+                fs::remove_file(self.index_path(segment))?;
+                let _ = fs::remove_file(self.data_path(segment));
+                continue;
+            }
+            if segment < first / SEGMENT_BLOCKS {
+                continue;
+            }
+            let mut index = self.open_index(segment)?;
+            let start = (first % SEGMENT_BLOCKS) * SLOT_LEN;
+            let mut slots = Vec::new();
+            index.read_to_end(&mut slots)?;
+            let (kept, dropped) = slots.split_at(start as usize);
+            if dropped.chunks_exact(SLOT_LEN as usize).all(|slot| slot[8..] == [0u8; 4]) {
+                continue;
+            }
+            // The data is cut right after the last record still referenced, wherever the
+            // dropped ones were written
+            let cut = kept
+                .chunks_exact(SLOT_LEN as usize)
+                .map(|slot| {
+                    let offset = u64::from_le_bytes(slot[..8].try_into().unwrap_or_default());
+                    let len = u32::from_le_bytes(slot[8..].try_into().unwrap_or_default());
+                    if len > 0 { offset + len as u64 } else { 0 }
+                })
+                .max()
+                .unwrap_or(0);
+            index.seek(SeekFrom::Start(start))?;
+            index.write_all(&vec![0u8; dropped.len()])?;
+            index.sync_data()?;
+            let data = OpenOptions::new().write(true).open(self.data_path(segment))?;
+            data.set_len(cut)?;
+            data.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Number of stored blocks and bytes of segment data
+    pub fn stats(&self) -> Result<(usize, u64)> {
+        let mut bytes = 0;
+        for segment in self.segments()? {
+            bytes += fs::metadata(self.data_path(segment)).map_or(0, |m| m.len());
+        }
+        Ok((self.heights()?.len(), bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BlockHeader;
+
+    fn block(height: u64) -> Block {
+        let header = BlockHeader {
+            height,
+            timestamp: height,
+            tx_root: [height as u8; 32],
+            state_root: [0u8; 32],
+            receipts_root: [0u8; 32],
+            layer0_reward: 0,
+            longyield_reward: 0,
+            coinbase: [0u8; 32],
+            parent_hash: [0u8; 32],
+            last_commit: None,
+        };
+        Block { header, txs: Vec::new(), seal: None }
+    }
+
+    #[test]
+    fn test_segments_load_truncate_and_reopen() {
+        let dir = std::env::temp_dir().join(format!("dxid-archive-{}-{}", std::process::id(), crate::now_ts()));
+        let archive = BlockArchive::open(&dir, true).unwrap();
+        for height in 0..SEGMENT_BLOCKS + 5 {
+            archive.append(&block(height)).unwrap();
+        }
+        assert_eq!(archive.segments().unwrap(), vec![0, 1]);
+        assert_eq!(archive.load(SEGMENT_BLOCKS + 2).unwrap().unwrap().header, block(SEGMENT_BLOCKS + 2).header);
+        assert!(archive.load(SEGMENT_BLOCKS + 5).unwrap().is_none());
+
+        // Back into the first segment; the second goes and the first shrinks
+        let before = fs::metadata(archive.data_path(0)).unwrap().len();
+        archive.truncate_after(SEGMENT_BLOCKS - 3).unwrap();
+        assert_eq!(archive.segments().unwrap(), vec![0]);
+        assert!(fs::metadata(archive.data_path(0)).unwrap().len() < before);
+        assert!(archive.load(SEGMENT_BLOCKS - 2).unwrap().is_none());
+
+        // Rewriting a height replaces it, and plain JSON records read back too
+        let archive = BlockArchive::open(&dir, false).unwrap();
+        archive.append(&block(5)).unwrap();
+        assert_eq!(archive.heights().unwrap(), (0..=5).collect::<Vec<_>>());
+        assert_eq!(archive.load(3).unwrap().unwrap().header.height, 3);
+        assert_eq!(archive.load(5).unwrap().unwrap().header.height, 5);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_truncate_keeps_blocks_appended_out_of_order() {
+        let dir = std::env::temp_dir().join(format!("dxid-archive-order-{}-{}", std::process::id(), crate::now_ts()));
+        let archive = BlockArchive::open(&dir, true).unwrap();
+        for height in [10, 2, 6] {
+            archive.append(&block(height)).unwrap();
+        }
+        // Block 2 was written after block 10, which is dropped
+        archive.truncate_after(5).unwrap();
+        assert_eq!(archive.heights().unwrap(), vec![2]);
+        assert_eq!(archive.load(2).unwrap().unwrap().header, block(2).header);
+
+        archive.truncate_after(1).unwrap();
+        assert!(archive.heights().unwrap().is_empty());
+        assert_eq!(fs::metadata(archive.data_path(0)).unwrap().len(), 0);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,207 @@
+//! Asset registry.
+//!
+//! Balances are kept per account as a map of asset id to amount. Every asset has a
+//! registry record (symbol, decimals, supply cap, issuer, current supply). The three
+//! protocol tokens occupy the reserved ids below; partner assets are registered
+//! on-chain with an `IssueAsset` transaction and get ids from `FIRST_CUSTOM_ASSET`.
+//!
+//! Registry records are committed to the state SMT under `asset_key(id)`, so an
+//! asset's supply is provable just like an account. Supply changes during a block
+//! only touch the in-memory record; the leaves are written by `State::commit_root`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{State, TokenType, H256, LAYER0_DECIMALS, LONGYIELD_DECIMALS};
+
+pub type AssetId = u32;
+
+pub const NATIVE_ASSET: AssetId = 0;
+pub const LAYER0_ASSET: AssetId = 1;
+pub const LONGYIELD_ASSET: AssetId = 2;
+/// Ids below this are reserved for protocol tokens
+pub const FIRST_CUSTOM_ASSET: AssetId = 16;
+
+/// Longest accepted asset symbol
+pub const MAX_SYMBOL_LEN: usize = 12;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AssetInfo {
+    pub symbol: String,
+    pub decimals: u8,
+    /// Maximum supply (None = uncapped)
+    pub supply_cap: Option<u128>,
+    /// Account allowed to mint (None = issued by the protocol only)
+    pub issuer: Option<H256>,
+    /// Circulating supply
+    pub supply: u128,
+}
+
+impl AssetInfo {
+    fn leaf_hash(&self) -> H256 {
+        *blake3::hash(&serde_json::to_vec(self).unwrap_or_default()).as_bytes()
+    }
+}
+
+/// SMT key of an asset record (domain-separated from account addresses)
+pub fn asset_key(id: AssetId) -> H256 {
+    let mut buf = b"dxid-asset".to_vec();
+    buf.extend_from_slice(&id.to_le_bytes());
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// Symbols are 1..=12 uppercase ASCII letters or digits.
+pub fn valid_symbol(symbol: &str) -> bool {
+    !symbol.is_empty()
+        && symbol.len() <= MAX_SYMBOL_LEN
+        && symbol.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+}
+
+impl State {
+    pub fn asset(&self, id: AssetId) -> Option<&AssetInfo> {
+        self.assets.get(&id)
+    }
+
+    pub fn asset_by_symbol(&self, symbol: &str) -> Option<(AssetId, &AssetInfo)> {
+        self.assets.iter().find(|(_, a)| a.symbol == symbol).map(|(id, a)| (*id, a))
+    }
+
+    /// Insert or replace a registry record.
+    pub fn set_asset(&mut self, id: AssetId, info: AssetInfo) {
+        self.journal_asset(id);
+        self.assets.insert(id, info);
+        self.dirty_assets.insert(id);
+    }
+
+    pub(crate) fn asset_mut(&mut self, id: AssetId) -> Option<&mut AssetInfo> {
+        self.journal_asset(id);
+        let asset = self.assets.get_mut(&id)?;
+        self.dirty_assets.insert(id);
+        Some(asset)
+    }
+
+    /// Register a partner asset under the next free id.
+    pub fn register_asset(&mut self, info: AssetInfo) -> AssetId {
+        let id = self
+            .assets
+            .keys()
+            .next_back()
+            .map_or(FIRST_CUSTOM_ASSET, |last| (last + 1).max(FIRST_CUSTOM_ASSET));
+        self.set_asset(id, info);
+        id
+    }
+
+    /// Register the three protocol tokens with zero supply, caps from the monetary params.
+    pub fn register_protocol_assets(&mut self) {
+        let protocol = [
+            (NATIVE_ASSET, "DXID", 0, None),
+            (LAYER0_ASSET, "L0", LAYER0_DECIMALS, Some(self.monetary.layer0_total_supply)),
+            (LONGYIELD_ASSET, "LY", LONGYIELD_DECIMALS, Some(self.monetary.longyield_total_supply)),
+        ];
+        for (id, symbol, decimals, supply_cap) in protocol {
+            if !self.assets.contains_key(&id) {
+                let info = AssetInfo { symbol: symbol.into(), decimals, supply_cap, issuer: None, supply: 0 };
+                self.set_asset(id, info);
+            }
+        }
+    }
+
+    /// Move the pre-registry supply counters into registry records.
+    pub(crate) fn migrate_legacy_supply(&mut self) {
+        if !self.assets.is_empty() {
+            return;
+        }
+        self.register_protocol_assets();
+        let counters = [
+            (NATIVE_ASSET, self.legacy_native_circulating),
+            (LAYER0_ASSET, self.legacy_layer0_circulating),
+            (LONGYIELD_ASSET, self.legacy_longyield_circulating),
+        ];
+        for (id, supply) in counters {
+            if let Some(asset) = self.asset_mut(id) {
+                asset.supply = supply;
+            }
+        }
+        if self.legacy_native_circulating == 0 {
+            // Native supply was not tracked before; take it from the balances
+            let native = self.accounts.values().map(|a| a.balance(NATIVE_ASSET)).sum();
+            if let Some(asset) = self.asset_mut(NATIVE_ASSET) {
+                asset.supply = native;
+            }
+        }
+    }
+
+    /// Write pending registry changes to the SMT.
+    pub(crate) fn flush_assets(&mut self) {
+        if self.dirty_assets.is_empty() {
+            return;
+        }
+        let leaves: Vec<(H256, H256)> = std::mem::take(&mut self.dirty_assets)
+            .into_iter()
+            .filter_map(|id| Some((asset_key(id), self.assets.get(&id)?.leaf_hash())))
+            .collect();
+        self.smt.extend(leaves);
+    }
+
+    /// Registry record with its SMT inclusion proof.
+    pub fn prove_asset(&self, id: AssetId) -> (Option<AssetInfo>, dxid_smt::SmtProof) {
+        let (_, proof) = self.smt.prove(&asset_key(id));
+        (self.assets.get(&id).cloned(), proof)
+    }
+}
+
+impl TokenType {
+    pub fn asset_id(self) -> AssetId {
+        match self {
+            TokenType::Native => NATIVE_ASSET,
+            TokenType::Layer0 => LAYER0_ASSET,
+            TokenType::LongYield => LONGYIELD_ASSET,
+            TokenType::Asset(id) => id,
+        }
+    }
+
+    /// Canonical token for an asset id (protocol tokens map to their named variant).
+    pub fn from_asset_id(id: AssetId) -> Self {
+        match id {
+            NATIVE_ASSET => TokenType::Native,
+            LAYER0_ASSET => TokenType::Layer0,
+            LONGYIELD_ASSET => TokenType::LongYield,
+            id => TokenType::Asset(id),
+        }
+    }
+}
+
+/// Verify an asset record against a state root.
+pub fn verify_asset(root: &H256, id: AssetId, info: Option<&AssetInfo>, proof: &dxid_smt::SmtProof) -> bool {
+    let leaf = info.map(AssetInfo::leaf_hash);
+    dxid_smt::SparseMerkleTree::verify(root, &asset_key(id), leaf.as_ref(), proof)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_ids_and_proofs() {
+        let mut st = State::empty();
+        st.register_protocol_assets();
+        let info = AssetInfo { symbol: "ACME".into(), decimals: 6, supply_cap: Some(1_000), issuer: Some([3u8; 32]), supply: 0 };
+        assert_eq!(st.register_asset(info.clone()), FIRST_CUSTOM_ASSET);
+        assert_eq!(st.register_asset(AssetInfo { symbol: "ACME2".into(), ..info }), FIRST_CUSTOM_ASSET + 1);
+        assert_eq!(st.asset_by_symbol("L0").map(|(id, _)| id), Some(LAYER0_ASSET));
+
+        let root = st.commit_root();
+        let (rec, proof) = st.prove_asset(FIRST_CUSTOM_ASSET);
+        assert!(verify_asset(&root, FIRST_CUSTOM_ASSET, rec.as_ref(), &proof));
+        let mut forged = rec.unwrap();
+        forged.supply = 1;
+        assert!(!verify_asset(&root, FIRST_CUSTOM_ASSET, Some(&forged), &proof));
+    }
+
+    #[test]
+    fn test_symbol_rules() {
+        assert!(valid_symbol("USDC"));
+        assert!(!valid_symbol("usdc"));
+        assert!(!valid_symbol(""));
+        assert!(!valid_symbol("ABCDEFGHIJKLM"));
+    }
+}
@@ -0,0 +1,272 @@
+//! Data directory backups.
+//!
+//! A backup is a directory under `backups/` holding copies of the database, the
+//! block archive and the genesis spec, with a `manifest.json` listing every file
+//! with its size and BLAKE3 hash, plus the height and state root of the stored
+//! state. `verify_backup` checks the files against the manifest; `restore_backup`
+//! also reopens the copy, recomputes its state root and checks it against the
+//! manifest and the header of the block at that height before swapping it in.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    storage::{Storage, StorageConfig, DB_FILE},
+    H256,
+};
+
+pub const BACKUP_VERSION: u32 = 1;
+
+/// Directory of the backups inside the data directory
+pub const BACKUP_DIR: &str = "backups";
+
+/// What a backup holds, relative to the data directory
+pub const BACKUP_ITEMS: [&str; 3] = [DB_FILE, "blocks", "genesis.json"];
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// One file of a backup
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BackupFile {
+    /// Path inside the backup, `/`-separated
+    pub path: String,
+    pub size: u64,
+    /// hex(BLAKE3) of the contents
+    pub blake3: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// 0 for manifests written before file hashes, which cannot be verified
+    #[serde(default)]
+    pub version: u32,
+    pub timestamp: u64,
+    pub backup_name: String,
+    pub files_backed_up: usize,
+    pub blockchain_height: u64,
+    #[serde(default)]
+    pub state_root: H256,
+    #[serde(default)]
+    pub files: Vec<BackupFile>,
+}
+
+impl BackupManifest {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        let txt = fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&txt).with_context(|| format!("parsing {}", path.display()))
+    }
+}
+
+/// Files under `dir` (except the manifest) as `/`-separated relative paths, sorted
+fn list_files(dir: &Path) -> Result<Vec<String>> {
+    fn walk(root: &Path, dir: &Path, out: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                walk(root, &path, out)?;
+            } else {
+                let rel = path.strip_prefix(root)?.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>();
+                out.push(rel.join("/"));
+            }
+        }
+        Ok(())
+    }
+    let mut files = Vec::new();
+    walk(dir, dir, &mut files)?;
+    files.retain(|f| f != MANIFEST_FILE);
+    files.sort();
+    Ok(files)
+}
+
+fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let mut hasher = blake3::Hasher::new();
+    let size = std::io::copy(&mut fs::File::open(path).with_context(|| format!("opening {}", path.display()))?, &mut hasher)?;
+    Ok((size, hasher.finalize().to_hex().to_string()))
+}
+
+/// Hash the files copied into `dir` and write its manifest.
+pub(crate) fn write_manifest(dir: &Path, timestamp: u64, height: u64, state_root: H256) -> Result<BackupManifest> {
+    let mut files = Vec::new();
+    for path in list_files(dir)? {
+        let (size, blake3) = hash_file(&dir.join(&path))?;
+        files.push(BackupFile { path, size, blake3 });
+    }
+    let manifest = BackupManifest {
+        version: BACKUP_VERSION,
+        timestamp,
+        backup_name: dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+        files_backed_up: files.len(),
+        blockchain_height: height,
+        state_root,
+        files,
+    };
+    fs::write(dir.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
+/// Check every file of the backup in `dir` against its manifest, and that there are
+/// no others.
+pub fn verify_backup(dir: &Path) -> Result<BackupManifest> {
+    let manifest = BackupManifest::load(dir)?;
+    if manifest.version != BACKUP_VERSION {
+        bail!("backup {} has manifest version {}, expected {}", dir.display(), manifest.version, BACKUP_VERSION);
+    }
+    let listed: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+    if let Some(extra) = list_files(dir)?.into_iter().find(|f| !listed.contains(&f.as_str())) {
+        bail!("{} is not in the manifest", extra);
+    }
+    for file in &manifest.files {
+        let (size, hash) = hash_file(&dir.join(&file.path))?;
+        if size != file.size || hash != file.blake3 {
+            bail!("{} does not match the manifest ({} bytes, hash {})", file.path, size, hash);
+        }
+    }
+    Ok(manifest)
+}
+
+/// Names of the backups in a data directory, oldest first
+pub fn list_backups(base_dir: &Path) -> Result<Vec<String>> {
+    let dir = base_dir.join(BACKUP_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut names: Vec<String> = fs::read_dir(&dir)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            (entry.path().is_dir() && name.starts_with("backup_")).then_some(name)
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Replace the database, block archive and genesis spec of `base_dir` with the backup
+/// `name`, once its files match the manifest and the state they hold matches both the
+/// manifest and the header of its tip block. The replaced files are moved to
+/// `pre-restore-<time>/`, whose path is returned with the manifest. The node must
+/// not be running.
+pub fn restore_backup(base_dir: &Path, name: &str) -> Result<(BackupManifest, PathBuf)> {
+    let dir = base_dir.join(BACKUP_DIR).join(name);
+    let manifest = verify_backup(&dir).with_context(|| format!("backup {} failed verification", name))?;
+    if let Err(redb::DatabaseError::DatabaseAlreadyOpen) = redb::Database::open(base_dir.join(DB_FILE)) {
+        bail!("{} is in use; stop the node first", base_dir.join(DB_FILE).display());
+    }
+
+    // Check a copy, so opening it cannot change the backup itself
+    let staging = base_dir.join(format!("restore-{}", name));
+    let _ = fs::remove_dir_all(&staging);
+    for file in &manifest.files {
+        let to = staging.join(&file.path);
+        fs::create_dir_all(to.parent().unwrap_or(&staging))?;
+        fs::copy(dir.join(&file.path), &to)?;
+    }
+    let checked = check_restored_state(&staging, &manifest);
+    if let Err(e) = checked {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e.context(format!("backup {} does not hold a consistent state", name)));
+    }
+    let _ = fs::remove_dir_all(staging.join(BACKUP_DIR));
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let aside = base_dir.join(format!("pre-restore-{}", now));
+    fs::create_dir_all(&aside)?;
+    for item in BACKUP_ITEMS {
+        let (current, restored) = (base_dir.join(item), staging.join(item));
+        if current.exists() {
+            fs::rename(&current, aside.join(item)).with_context(|| format!("moving {} aside", current.display()))?;
+        }
+        if restored.exists() {
+            fs::rename(&restored, &current).with_context(|| format!("installing {}", current.display()))?;
+        }
+    }
+    fs::remove_dir_all(&staging)?;
+    Ok((manifest, aside))
+}
+
+/// Open the restored copy in `dir` and check its state against the manifest and the
+/// header of the block at its height.
+fn check_restored_state(dir: &Path, manifest: &BackupManifest) -> Result<()> {
+    let storage = Storage::new(StorageConfig { base_dir: dir.to_path_buf(), ..Default::default() })?;
+    let mut state = storage.load_state()?.context("the backup holds no state")?;
+    let stored_root = state.state_root;
+    state.reconstruct_smt();
+    if state.state_root != stored_root {
+        bail!("state root {} recomputes to {}", hex::encode(stored_root), hex::encode(state.state_root));
+    }
+    if state.height != manifest.blockchain_height || state.state_root != manifest.state_root {
+        bail!(
+            "state at height {} (root {}) but the manifest says {} (root {})",
+            state.height,
+            hex::encode(state.state_root),
+            manifest.blockchain_height,
+            hex::encode(manifest.state_root)
+        );
+    }
+    if state.height > 0 {
+        let block = storage.load_block(state.height)?.with_context(|| format!("block {} is missing", state.height))?;
+        if block.header.state_root != state.state_root {
+            bail!("block {} commits to state root {}", state.height, hex::encode(block.header.state_root));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tx::{Tx, TxPayload}, Chain, State, TokenType};
+    use dxid_crypto::{StarkSignEngine, ENGINE as STARK};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_backup_verify_and_restore() {
+        let base = std::env::temp_dir().join(format!("dxid-backup-{}-{}", std::process::id(), crate::now_ts()));
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap());
+        for nonce in 0..2 {
+            let send = TxPayload::Transfer { to: [7u8; 32], amount: 100, token_type: TokenType::Native };
+            let tx = Tx::new_signed(&sk, pk, nonce, 10, crate::CHAIN_ID, None, send).unwrap();
+            fs::write(chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash()))), serde_json::to_string(&tx).unwrap()).unwrap();
+            chain.make_block_once().unwrap().unwrap();
+        }
+        let root = chain.load_block(1).unwrap().unwrap().header.state_root;
+        drop(chain);
+
+        // The first block was backed up; the second came within the backup interval
+        let names = list_backups(&base).unwrap();
+        assert_eq!(names.len(), 1);
+        let backup = base.join(BACKUP_DIR).join(&names[0]);
+        let manifest = verify_backup(&backup).unwrap();
+        assert_eq!((manifest.blockchain_height, manifest.state_root), (1, root));
+        assert!(manifest.files.iter().any(|f| f.path == DB_FILE) && manifest.files.iter().any(|f| f.path.starts_with("blocks/")));
+
+        // A tampered or unlisted file fails verification, and restore leaves the node alone
+        let segment = backup.join(&manifest.files.iter().find(|f| f.path.starts_with("blocks/")).unwrap().path);
+        let original = fs::read(&segment).unwrap();
+        fs::write(&segment, [&original[..], b"x"].concat()).unwrap();
+        assert!(verify_backup(&backup).is_err());
+        assert!(restore_backup(&base, &names[0]).is_err());
+        fs::write(&segment, &original).unwrap();
+        fs::write(backup.join("extra"), b"x").unwrap();
+        assert!(verify_backup(&backup).is_err());
+        fs::remove_file(backup.join("extra")).unwrap();
+
+        let (restored, aside) = restore_backup(&base, &names[0]).unwrap();
+        assert_eq!(restored.blockchain_height, 1);
+        assert!(aside.join(DB_FILE).is_file());
+        let chain = Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap();
+        let st = chain.state.lock();
+        assert_eq!((st.height, st.state_root), (1, root));
+        assert!(chain.load_block(2).unwrap().is_none());
+        drop(st);
+        let _ = fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,211 @@
+//! Consensus engines.
+//!
+//! A `Consensus` decides who may propose each block, seals the blocks this node
+//! proposes and checks the seals of blocks from others. `Chain` holds one engine
+//! and only builds a block when the engine says it is this node's turn.
+//!
+//! `RoundRobinPoa` is the Proof-of-Authority engine: the validator set comes from
+//! genesis (`State::validators`, in order), the proposer of block `h` in round `r`
+//! is `validators[(h + r) % n]` (the rotation of `FinalityConfig::proposer`), and
+//! the proposer signs the header hash with the STARK signature engine. Without
+//! validators any node may build unsealed blocks, which keeps single-node devnets
+//! and tests working.
+//!
+//! The round of a block is the number of `PROPOSER_TIMEOUT_SECS` between its
+//! parent's timestamp and its own (see `round_at`), so when a proposer is offline
+//! the next validator takes over after the timeout. Block 1 has no parent to time
+//! from and is always round 0.
+
+use anyhow::{bail, Result};
+use dxid_crypto::{SecretKey, StarkSignEngine, StarkSignature, ENGINE as STARK};
+use serde::{Deserialize, Serialize};
+
+use crate::{h_block_header, BlockHeader, State, H256};
+
+/// How long a proposer has to build its block before the turn passes to the next validator
+pub const PROPOSER_TIMEOUT_SECS: u64 = 10;
+
+/// Round of a block at `timestamp` on top of a parent at `parent_timestamp`
+/// (None for block 1).
+pub fn round_at(parent_timestamp: Option<u64>, timestamp: u64) -> u32 {
+    match parent_timestamp {
+        Some(parent) => (timestamp.saturating_sub(parent) / PROPOSER_TIMEOUT_SECS).min(u32::MAX as u64) as u32,
+        None => 0,
+    }
+}
+
+/// Proposer signature over a block header.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Seal {
+    pub proposer: H256,
+    pub signature: StarkSignature,
+}
+
+/// Bytes a seal signs: domain, chain id and header hash.
+pub fn seal_message(chain_id: u32, header: &BlockHeader) -> Vec<u8> {
+    let mut msg = b"dxid-seal-v1".to_vec();
+    msg.extend_from_slice(&chain_id.to_le_bytes());
+    msg.extend_from_slice(&h_block_header(header));
+    msg
+}
+
+pub trait Consensus: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Validator entitled to propose the block at `height` in `round` (None = anyone)
+    fn proposer(&self, st: &State, height: u64, round: u32) -> Option<H256>;
+
+    /// Validator this node signs as, if any
+    fn local_validator(&self) -> Option<H256>;
+
+    /// Seal a header built by this node in `round` (None for engines or heights without seals)
+    fn seal(&self, st: &State, header: &BlockHeader, round: u32) -> Result<Option<Seal>>;
+
+    /// Check the seal of a block at `header.height`, built in `round`, against the state it builds on
+    fn verify_seal(&self, st: &State, header: &BlockHeader, round: u32, seal: Option<&Seal>) -> Result<()>;
+
+    /// Whether this node should build the block at `height` in `round`
+    fn is_local_turn(&self, st: &State, height: u64, round: u32) -> bool {
+        match self.proposer(st, height, round) {
+            Some(proposer) => self.local_validator() == Some(proposer),
+            None => true,
+        }
+    }
+}
+
+/// Round-robin Proof-of-Authority over the genesis validator set.
+pub struct RoundRobinPoa {
+    key: Option<(SecretKey, H256)>,
+}
+
+impl RoundRobinPoa {
+    /// Engine for a node that signs as the validator owning `secret`.
+    pub fn validator(secret: SecretKey) -> Result<Self> {
+        // The engine defines how keys map to addresses
+        let address = STARK.sign(&secret, b"dxid-validator-key", 0)?.pubkey_hash;
+        Ok(Self { key: Some((secret, address)) })
+    }
+
+    /// Engine for a node that follows the chain without proposing.
+    pub fn follower() -> Self {
+        Self { key: None }
+    }
+}
+
+impl Consensus for RoundRobinPoa {
+    fn name(&self) -> &'static str {
+        "round-robin-poa"
+    }
+
+    fn proposer(&self, st: &State, height: u64, round: u32) -> Option<H256> {
+        if st.validators.is_empty() {
+            return None;
+        }
+        let n = st.validators.len() as u64;
+        Some(st.validators[((height + round as u64) % n) as usize])
+    }
+
+    fn local_validator(&self) -> Option<H256> {
+        self.key.as_ref().map(|(_, address)| *address)
+    }
+
+    fn seal(&self, st: &State, header: &BlockHeader, round: u32) -> Result<Option<Seal>> {
+        let Some(proposer) = self.proposer(st, header.height, round) else { return Ok(None) };
+        let Some((secret, address)) = &self.key else { bail!("no validator key to seal block {}", header.height) };
+        if *address != proposer {
+            bail!("block {} round {} belongs to validator {}", header.height, round, hex::encode(proposer));
+        }
+        let signature = STARK.sign(secret, &seal_message(st.chain_id, header), header.height)?;
+        Ok(Some(Seal { proposer, signature }))
+    }
+
+    fn verify_seal(&self, st: &State, header: &BlockHeader, round: u32, seal: Option<&Seal>) -> Result<()> {
+        let Some(expected) = self.proposer(st, header.height, round) else { return Ok(()) };
+        let Some(seal) = seal else { bail!("block {} is not sealed", header.height) };
+        if seal.proposer != expected {
+            bail!("block {} sealed by {}, expected {}", header.height, hex::encode(seal.proposer), hex::encode(expected));
+        }
+        if seal.signature.pubkey_hash != expected || seal.signature.nonce != header.height {
+            bail!("seal signature of block {} does not match its proposer", header.height);
+        }
+        STARK.verify(&seal.signature, &seal_message(st.chain_id, header))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(height: u64) -> BlockHeader {
+        BlockHeader {
+            height,
+            timestamp: 0,
+            tx_root: [0u8; 32],
+            state_root: [1u8; 32],
+            receipts_root: [0u8; 32],
+            layer0_reward: 0,
+            longyield_reward: 0,
+            coinbase: [0u8; 32],
+            parent_hash: [0u8; 32],
+            last_commit: None,
+        }
+    }
+
+    #[test]
+    fn test_round_robin_seals_and_verifies() {
+        let keys: Vec<(SecretKey, H256)> = (0..3).map(|_| STARK.generate_keys().unwrap()).collect();
+        let mut st = State::empty();
+        st.validators = keys.iter().map(|(_, pk)| *pk).collect();
+        let engines: Vec<RoundRobinPoa> = keys.iter().map(|(sk, _)| RoundRobinPoa::validator(sk.clone()).unwrap()).collect();
+        let follower = RoundRobinPoa::follower();
+
+        for height in 1..=6u64 {
+            let turn = (height % 3) as usize;
+            assert_eq!(follower.proposer(&st, height, 0), Some(keys[turn].1));
+            assert!(engines[turn].is_local_turn(&st, height, 0));
+            assert!(!engines[(turn + 1) % 3].is_local_turn(&st, height, 0));
+
+            let h = header(height);
+            let seal = engines[turn].seal(&st, &h, 0).unwrap().unwrap();
+            follower.verify_seal(&st, &h, 0, Some(&seal)).unwrap();
+            assert!(engines[(turn + 1) % 3].seal(&st, &h, 0).is_err());
+            assert!(follower.verify_seal(&st, &h, 0, None).is_err());
+
+            // The seal is bound to the header
+            let mut other = h.clone();
+            other.state_root = [2u8; 32];
+            assert!(follower.verify_seal(&st, &other, 0, Some(&seal)).is_err());
+
+            // Once the proposer times out, the next validator's seal is the valid one
+            let next = (turn + 1) % 3;
+            assert!(engines[next].is_local_turn(&st, height, 1));
+            let takeover = engines[next].seal(&st, &h, 1).unwrap().unwrap();
+            follower.verify_seal(&st, &h, 1, Some(&takeover)).unwrap();
+            assert!(follower.verify_seal(&st, &h, 0, Some(&takeover)).is_err());
+            assert!(follower.verify_seal(&st, &h, 1, Some(&seal)).is_err());
+        }
+
+        // A seal from the wrong validator is rejected even if correctly signed
+        let h = header(1);
+        let wrong = Seal { proposer: keys[1].1, signature: STARK.sign(&keys[2].0, &seal_message(st.chain_id, &h), 1).unwrap() };
+        assert!(follower.verify_seal(&st, &h, 0, Some(&wrong)).is_err());
+    }
+
+    #[test]
+    fn test_round_counts_proposer_timeouts_since_the_parent() {
+        assert_eq!(round_at(None, 1_000), 0);
+        assert_eq!(round_at(Some(1_000), 1_000 + PROPOSER_TIMEOUT_SECS - 1), 0);
+        assert_eq!(round_at(Some(1_000), 1_000 + PROPOSER_TIMEOUT_SECS), 1);
+        assert_eq!(round_at(Some(1_000), 1_000 + 5 * PROPOSER_TIMEOUT_SECS + 3), 5);
+        assert_eq!(round_at(Some(1_000), 900), 0);
+    }
+
+    #[test]
+    fn test_without_validators_anyone_proposes_unsealed() {
+        let st = State::empty();
+        let engine = RoundRobinPoa::follower();
+        assert!(engine.is_local_turn(&st, 1, 0));
+        assert!(engine.seal(&st, &header(1), 0).unwrap().is_none());
+        engine.verify_seal(&st, &header(1), 0, None).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+//! Verifiable-credential revocation registry.
+//!
+//! Every issuer (an L0 account) has its own sparse Merkle tree of revoked
+//! credential ids, written only by the issuer's `RevokeCredentials` transactions.
+//! Credential ids are opaque 32-byte values chosen by the issuer, typically a
+//! salted hash of the credential, so the registry says nothing about holders or
+//! claims. One transaction revokes up to `MAX_REVOCATIONS_PER_TX` ids; revocation
+//! is permanent.
+//!
+//! The root of an issuer's tree is committed to the state SMT under
+//! `revocation_key(issuer)`. A `CredentialStatus` carries two proofs, the
+//! credential against the issuer's root (membership when revoked, non-membership
+//! otherwise) and that root against the state root, so a verifier holding a block
+//! header can check status offline.
+
+use std::collections::BTreeSet;
+
+use dxid_smt::{SmtProof, SparseMerkleTree};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    receipt::{Receipt, TxError},
+    tx::Tx,
+    Account, Chain, State, TokenType, H256,
+};
+
+/// Most credential ids revoked by one transaction
+pub const MAX_REVOCATIONS_PER_TX: usize = 256;
+
+/// State SMT key of an issuer's registry (domain-separated from accounts and other records)
+pub fn revocation_key(issuer: &H256) -> H256 {
+    let mut buf = b"dxid-revocations".to_vec();
+    buf.extend_from_slice(issuer);
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// State SMT leaf of an issuer's registry
+pub fn registry_leaf(registry_root: &H256) -> H256 {
+    let mut buf = b"dxid-revocation-registry".to_vec();
+    buf.extend_from_slice(registry_root);
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// Leaf of a revoked credential in its issuer's tree
+pub fn revocation_leaf(revoked_at: u64) -> H256 {
+    let mut buf = b"dxid-revoked".to_vec();
+    buf.extend_from_slice(&revoked_at.to_le_bytes());
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// Revocation status of one credential with the proofs backing it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CredentialStatus {
+    pub issuer: H256,
+    pub credential: H256,
+    /// Block that revoked the credential (None = not revoked)
+    pub revoked_at: Option<u64>,
+    /// Root of the issuer's tree (None if the issuer never revoked anything)
+    pub registry_root: Option<H256>,
+    /// The issuer's registry leaf (or its absence) against the state root
+    pub registry_proof: SmtProof,
+    /// The credential (or its absence) against `registry_root`
+    pub credential_proof: SmtProof,
+}
+
+impl CredentialStatus {
+    /// Check the status against the state root of a block header.
+    pub fn verify(&self, state_root: &H256) -> bool {
+        let Some(registry_root) = &self.registry_root else {
+            // No registry at all: nothing of this issuer is revoked
+            return self.revoked_at.is_none()
+                && SparseMerkleTree::verify(state_root, &revocation_key(&self.issuer), None, &self.registry_proof);
+        };
+        let leaf = self.revoked_at.map(revocation_leaf);
+        SparseMerkleTree::verify(registry_root, &self.credential, leaf.as_ref(), &self.credential_proof)
+            && SparseMerkleTree::verify(state_root, &revocation_key(&self.issuer), Some(&registry_leaf(registry_root)), &self.registry_proof)
+    }
+}
+
+impl State {
+    /// Block that revoked `credential` of `issuer`, if it is revoked
+    pub fn revoked_at(&self, issuer: &H256, credential: &H256) -> Option<u64> {
+        self.revocations.get(&hex::encode(issuer))?.get(&hex::encode(credential)).copied()
+    }
+
+    pub(crate) fn revoke_credential(&mut self, issuer: &H256, credential: &H256, height: u64) {
+        let (issuer_hex, credential_hex) = (hex::encode(issuer), hex::encode(credential));
+        self.journal_revocation(&issuer_hex, &credential_hex);
+        self.revocations.entry(issuer_hex).or_default().insert(credential_hex, height);
+        self.dirty_revocations.entry(*issuer).or_default().insert(*credential);
+    }
+
+    /// Status of a credential with its proofs against the current state root.
+    pub fn credential_status(&self, issuer: &H256, credential: &H256) -> CredentialStatus {
+        let (_, registry_proof) = self.smt.prove(&revocation_key(issuer));
+        let (registry_root, credential_proof) = match self.revocation_smts.get(issuer) {
+            Some(tree) => (Some(tree.root()), tree.prove(credential).1),
+            None => (None, SmtProof::empty()),
+        };
+        CredentialStatus {
+            issuer: *issuer,
+            credential: *credential,
+            revoked_at: self.revoked_at(issuer, credential),
+            registry_root,
+            registry_proof,
+            credential_proof,
+        }
+    }
+
+    /// Rebuild every issuer's tree; the leaves are written by the next `commit_root`.
+    pub(crate) fn rebuild_revocation_trees(&mut self) {
+        self.revocation_smts.clear();
+        self.dirty_revocations = self
+            .revocations
+            .iter()
+            .filter_map(|(issuer, revoked)| {
+                Some((crate::dehex32(issuer)?, revoked.keys().filter_map(|c| crate::dehex32(c)).collect()))
+            })
+            .collect();
+    }
+
+    /// Write pending revocations to the issuer trees and their roots to the state SMT.
+    pub(crate) fn flush_revocations(&mut self) {
+        if self.dirty_revocations.is_empty() {
+            return;
+        }
+        let mut leaves = Vec::with_capacity(self.dirty_revocations.len());
+        for (issuer, credentials) in std::mem::take(&mut self.dirty_revocations) {
+            let Some(revoked) = self.revocations.get(&hex::encode(issuer)) else {
+                // Only an undo empties a registry
+                self.revocation_smts.remove(&issuer);
+                leaves.push((revocation_key(&issuer), None));
+                continue;
+            };
+            let tree = self.revocation_smts.entry(issuer).or_default();
+            tree.update_many(credentials.iter().map(|c| (*c, revoked.get(&hex::encode(c)).map(|h| revocation_leaf(*h)))));
+            leaves.push((revocation_key(&issuer), Some(registry_leaf(&tree.root()))));
+        }
+        self.smt.update_many(leaves);
+    }
+}
+
+impl Chain {
+    pub(crate) fn apply_revoke_credentials(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        credentials: &[H256],
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        if credentials.is_empty() || credentials.len() > MAX_REVOCATIONS_PER_TX {
+            return Err(TxError::InvalidPayload(format!("revoke 1 to {} credentials per transaction", MAX_REVOCATIONS_PER_TX)));
+        }
+        let mut seen = BTreeSet::new();
+        for credential in credentials {
+            if !seen.insert(credential) || st.revoked_at(&tx.from, credential).is_some() {
+                return Err(TxError::AlreadyRevoked(*credential));
+            }
+        }
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        let height = st.height + 1;
+        for credential in credentials {
+            st.revoke_credential(&tx.from, credential, height);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::TxPayload;
+    use dxid_crypto::{StarkSignEngine, ENGINE as STARK};
+
+    #[test]
+    fn test_revocation_and_non_revocation_proofs() {
+        let (issuer_sk, issuer) = STARK.generate_keys().unwrap();
+        let mut st = State::new_with_genesis(vec![(issuer, 1_000_000)]).lock().clone();
+        let (revoked, valid) = ([1u8; 32], [2u8; 32]);
+
+        // Before any revocation the issuer has no registry, which is provable too
+        let root = st.commit_root();
+        let status = st.credential_status(&issuer, &valid);
+        assert!(status.registry_root.is_none() && status.verify(&root));
+
+        st.begin_journal();
+        let revoke = |st: &mut State, nonce, credentials: Vec<H256>| {
+            let tx = Tx::new_signed(&issuer_sk, issuer, nonce, 10, crate::CHAIN_ID, None, TxPayload::RevokeCredentials { credentials }).unwrap();
+            Chain::apply_tx(st, &tx)
+        };
+        assert_eq!(revoke(&mut st, 0, vec![revoked, revoked]), Err(TxError::AlreadyRevoked(revoked)));
+        revoke(&mut st, 0, vec![revoked]).unwrap();
+        assert_eq!(revoke(&mut st, 1, vec![revoked]), Err(TxError::AlreadyRevoked(revoked)));
+        let new_root = st.commit_root();
+
+        let status = st.credential_status(&issuer, &revoked);
+        assert_eq!(status.revoked_at, Some(1));
+        assert!(status.verify(&new_root));
+        assert!(!status.verify(&root), "bound to the state root");
+        let status = st.credential_status(&issuer, &valid);
+        assert!(status.revoked_at.is_none() && status.verify(&new_root));
+        let lie = CredentialStatus { revoked_at: None, ..st.credential_status(&issuer, &revoked) };
+        assert!(!lie.verify(&new_root));
+        // Registries are per issuer
+        assert!(st.credential_status(&[3u8; 32], &revoked).revoked_at.is_none());
+
+        // Trees are rebuilt on reload, and undo empties the registry again
+        let mut reloaded: State = serde_json::from_str(&serde_json::to_string(&st).unwrap()).unwrap();
+        reloaded.reconstruct_smt();
+        assert_eq!(reloaded.state_root, new_root);
+        let journal = st.take_journal().unwrap();
+        st.height += 1;
+        st.apply_undo(&journal).unwrap();
+        assert_eq!(st.state_root, root);
+        assert!(st.credential_status(&issuer, &revoked).verify(&root));
+    }
+}
@@ -0,0 +1,344 @@
+//! dxID decentralized identifier registry.
+//!
+//! A DID is `did:dxid:<hex(id)>`, where the id is derived from the creating
+//! account and nonce. Its document lists verification keys and service
+//! endpoints and is controlled by one L0 account: only the controller can
+//! `UpdateDid`, `RotateDidController` or `DeactivateDid`. Deactivation is final;
+//! the document is kept, emptied and marked deactivated, so resolvers can tell a
+//! retired DID from one that never existed.
+//!
+//! Documents are committed to the state SMT under `did_key(id)`, so a resolution
+//! comes with a proof against a state root.
+
+use std::collections::HashSet;
+
+use dxid_smt::{SmtProof, SparseMerkleTree};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    receipt::{Receipt, TxError},
+    tx::Tx,
+    Account, Chain, State, TokenType, H256,
+};
+
+pub type DidId = H256;
+
+pub const DID_PREFIX: &str = "did:dxid:";
+/// Most verification methods (and, separately, services) per document
+pub const MAX_DID_ENTRIES: usize = 16;
+/// Longest fragment, key type or service type
+pub const MAX_DID_LABEL_LEN: usize = 64;
+/// Longest service endpoint URL
+pub const MAX_ENDPOINT_LEN: usize = 256;
+/// Longest public key, in bytes
+pub const MAX_PUBLIC_KEY_LEN: usize = 128;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct VerificationMethod {
+    /// Fragment, unique within the document (`did:dxid:…#<id>`)
+    pub id: String,
+    /// e.g. "StarkKey2024", "Ed25519VerificationKey2020"
+    pub key_type: String,
+    /// hex-encoded public key
+    pub public_key: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ServiceEndpoint {
+    /// Fragment, unique within the document
+    pub id: String,
+    pub service_type: String,
+    pub endpoint: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DidDocument {
+    /// Account allowed to change the document
+    pub controller: H256,
+    pub verification_methods: Vec<VerificationMethod>,
+    pub services: Vec<ServiceEndpoint>,
+    /// Block that created the DID
+    pub created: u64,
+    /// Block of the last change
+    pub updated: u64,
+    pub deactivated: bool,
+}
+
+impl DidDocument {
+    fn leaf_hash(&self) -> H256 {
+        *blake3::hash(&serde_json::to_vec(self).unwrap_or_default()).as_bytes()
+    }
+
+    /// The document in W3C DID Core JSON form.
+    pub fn to_w3c(&self, id: &DidId) -> serde_json::Value {
+        let did = did_string(id);
+        let methods: Vec<serde_json::Value> = self
+            .verification_methods
+            .iter()
+            .map(|m| {
+                serde_json::json!({
+                    "id": format!("{}#{}", did, m.id),
+                    "type": m.key_type,
+                    "controller": did,
+                    "publicKeyHex": m.public_key,
+                })
+            })
+            .collect();
+        let refs: Vec<String> = self.verification_methods.iter().map(|m| format!("{}#{}", did, m.id)).collect();
+        let services: Vec<serde_json::Value> = self
+            .services
+            .iter()
+            .map(|s| serde_json::json!({ "id": format!("{}#{}", did, s.id), "type": s.service_type, "serviceEndpoint": s.endpoint }))
+            .collect();
+        serde_json::json!({
+            "@context": ["https://www.w3.org/ns/did/v1"],
+            "id": did,
+            "controller": hex::encode(self.controller),
+            "verificationMethod": methods,
+            "authentication": refs,
+            "service": services,
+        })
+    }
+}
+
+/// Id of the DID created by `creator`'s transaction with `nonce`
+pub fn did_id(creator: &H256, nonce: u64) -> DidId {
+    let mut buf = b"dxid-did-id".to_vec();
+    buf.extend_from_slice(creator);
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    *blake3::hash(&buf).as_bytes()
+}
+
+pub fn did_string(id: &DidId) -> String {
+    format!("{}{}", DID_PREFIX, hex::encode(id))
+}
+
+/// Accepts `did:dxid:<hex>` or the bare hex id.
+pub fn parse_did(s: &str) -> Option<DidId> {
+    let hex_id = s.strip_prefix(DID_PREFIX).unwrap_or(s);
+    hex::decode(hex_id).ok().and_then(|v| <[u8; 32]>::try_from(v).ok())
+}
+
+/// SMT key of a DID document (domain-separated from accounts and other records)
+pub fn did_key(id: &DidId) -> H256 {
+    let mut buf = b"dxid-did".to_vec();
+    buf.extend_from_slice(id);
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// Check a document (or its absence) against a state root.
+pub fn verify_did(root: &H256, id: &DidId, doc: Option<&DidDocument>, proof: &SmtProof) -> bool {
+    let leaf = doc.map(DidDocument::leaf_hash);
+    SparseMerkleTree::verify(root, &did_key(id), leaf.as_ref(), proof)
+}
+
+/// Check keys and services against the size limits and fragment uniqueness.
+pub fn validate_entries(keys: &[VerificationMethod], services: &[ServiceEndpoint]) -> std::result::Result<(), String> {
+    if keys.len() > MAX_DID_ENTRIES || services.len() > MAX_DID_ENTRIES {
+        return Err(format!("at most {} keys and {} services", MAX_DID_ENTRIES, MAX_DID_ENTRIES));
+    }
+    let label_ok = |s: &str| !s.is_empty() && s.len() <= MAX_DID_LABEL_LEN;
+    let mut fragments = HashSet::new();
+    for key in keys {
+        if !label_ok(&key.id) || !label_ok(&key.key_type) {
+            return Err(format!("bad verification method {:?}", key.id));
+        }
+        match hex::decode(&key.public_key) {
+            Ok(bytes) if !bytes.is_empty() && bytes.len() <= MAX_PUBLIC_KEY_LEN => {}
+            _ => return Err(format!("bad public key in {:?}", key.id)),
+        }
+        if !fragments.insert(key.id.as_str()) {
+            return Err(format!("duplicate fragment {:?}", key.id));
+        }
+    }
+    for service in services {
+        if !label_ok(&service.id) || !label_ok(&service.service_type) {
+            return Err(format!("bad service {:?}", service.id));
+        }
+        if service.endpoint.is_empty() || service.endpoint.len() > MAX_ENDPOINT_LEN {
+            return Err(format!("bad endpoint in {:?}", service.id));
+        }
+        if !fragments.insert(service.id.as_str()) {
+            return Err(format!("duplicate fragment {:?}", service.id));
+        }
+    }
+    Ok(())
+}
+
+impl State {
+    pub fn did(&self, id: &DidId) -> Option<&DidDocument> {
+        self.dids.get(&hex::encode(id))
+    }
+
+    /// Write a DID document. Its leaf is updated by the next `commit_root`.
+    pub fn set_did(&mut self, id: DidId, doc: DidDocument) {
+        self.journal_did(&id);
+        self.dids.insert(hex::encode(id), doc);
+        self.dirty_dids.insert(id);
+    }
+
+    pub(crate) fn flush_dids(&mut self) {
+        if self.dirty_dids.is_empty() {
+            return;
+        }
+        let updates: Vec<(H256, Option<H256>)> = std::mem::take(&mut self.dirty_dids)
+            .into_iter()
+            .map(|id| (did_key(&id), self.did(&id).map(DidDocument::leaf_hash)))
+            .collect();
+        self.smt.update_many(updates);
+    }
+
+    /// Document with its SMT proof against the state root.
+    pub fn prove_did(&self, id: &DidId) -> (Option<DidDocument>, SmtProof) {
+        let (_, proof) = self.smt.prove(&did_key(id));
+        (self.did(id).cloned(), proof)
+    }
+
+    /// Live document `id` controlled by `sender`
+    fn controlled_did(&self, id: &DidId, sender: &H256) -> std::result::Result<DidDocument, TxError> {
+        let Some(doc) = self.did(id) else { return Err(TxError::UnknownDid(*id)) };
+        if doc.deactivated {
+            return Err(TxError::DidDeactivated);
+        }
+        if doc.controller != *sender {
+            return Err(TxError::NotController);
+        }
+        Ok(doc.clone())
+    }
+}
+
+impl Chain {
+    pub(crate) fn apply_create_did(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        keys: &[VerificationMethod],
+        services: &[ServiceEndpoint],
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        validate_entries(keys, services).map_err(TxError::InvalidPayload)?;
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        let height = st.height + 1;
+        let doc = DidDocument {
+            controller: tx.from,
+            verification_methods: keys.to_vec(),
+            services: services.to_vec(),
+            created: height,
+            updated: height,
+            deactivated: false,
+        };
+        let id = did_id(&tx.from, tx.nonce);
+        st.set_did(id, doc);
+        receipt.created_did = Some(id);
+        Ok(())
+    }
+
+    pub(crate) fn apply_update_did(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        id: DidId,
+        keys: &[VerificationMethod],
+        services: &[ServiceEndpoint],
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let mut doc = st.controlled_did(&id, &tx.from)?;
+        validate_entries(keys, services).map_err(TxError::InvalidPayload)?;
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        doc.verification_methods = keys.to_vec();
+        doc.services = services.to_vec();
+        doc.updated = st.height + 1;
+        st.set_did(id, doc);
+        Ok(())
+    }
+
+    pub(crate) fn apply_rotate_did_controller(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        id: DidId,
+        new_controller: H256,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let mut doc = st.controlled_did(&id, &tx.from)?;
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        doc.controller = new_controller;
+        doc.updated = st.height + 1;
+        st.set_did(id, doc);
+        Ok(())
+    }
+
+    pub(crate) fn apply_deactivate_did(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        id: DidId,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let mut doc = st.controlled_did(&id, &tx.from)?;
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        doc.verification_methods.clear();
+        doc.services.clear();
+        doc.deactivated = true;
+        doc.updated = st.height + 1;
+        st.set_did(id, doc);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::TxPayload;
+    use dxid_crypto::{SecretKey, StarkSignEngine, ENGINE as STARK};
+
+    fn send(st: &mut State, sk: &SecretKey, pk: H256, payload: TxPayload) -> std::result::Result<Receipt, TxError> {
+        let nonce = st.accounts.get(&hex::encode(pk)).map_or(0, |a| a.nonce);
+        let tx = Tx::new_signed(sk, pk, nonce, 10, crate::CHAIN_ID, None, payload).unwrap();
+        Chain::apply_tx(st, &tx)
+    }
+
+    fn key(id: &str) -> VerificationMethod {
+        VerificationMethod { id: id.into(), key_type: "StarkKey2024".into(), public_key: hex::encode([9u8; 32]) }
+    }
+
+    #[test]
+    fn test_did_lifecycle_is_provable() {
+        let (alice_sk, alice) = STARK.generate_keys().unwrap();
+        let (bob_sk, bob) = STARK.generate_keys().unwrap();
+        let mut st = State::new_with_genesis(vec![(alice, 1_000_000), (bob, 1_000_000)]).lock().clone();
+
+        let dup = TxPayload::CreateDid { keys: vec![key("k1"), key("k1")], services: vec![] };
+        assert!(matches!(send(&mut st, &alice_sk, alice, dup), Err(TxError::InvalidPayload(_))));
+        let create = TxPayload::CreateDid { keys: vec![key("k1")], services: vec![] };
+        let id = send(&mut st, &alice_sk, alice, create).unwrap().created_did.unwrap();
+        assert_eq!(parse_did(&did_string(&id)), Some(id));
+
+        let service = ServiceEndpoint { id: "hub".into(), service_type: "DIDCommMessaging".into(), endpoint: "https://hub.example".into() };
+        let update = TxPayload::UpdateDid { did: id, keys: vec![key("k2")], services: vec![service] };
+        assert_eq!(send(&mut st, &bob_sk, bob, update.clone()), Err(TxError::NotController));
+        send(&mut st, &alice_sk, alice, update.clone()).unwrap();
+
+        // After rotation only the new controller can change the document
+        send(&mut st, &alice_sk, alice, TxPayload::RotateDidController { did: id, new_controller: bob }).unwrap();
+        assert_eq!(send(&mut st, &alice_sk, alice, update), Err(TxError::NotController));
+
+        let root = st.commit_root();
+        let (doc, proof) = st.prove_did(&id);
+        let doc = doc.unwrap();
+        assert_eq!((doc.controller, doc.verification_methods[0].id.as_str()), (bob, "k2"));
+        assert!(verify_did(&root, &id, Some(&doc), &proof));
+        assert_eq!(doc.to_w3c(&id)["service"][0]["serviceEndpoint"], "https://hub.example");
+
+        send(&mut st, &bob_sk, bob, TxPayload::DeactivateDid { did: id }).unwrap();
+        assert!(st.did(&id).unwrap().deactivated);
+        let rotate = TxPayload::RotateDidController { did: id, new_controller: alice };
+        assert_eq!(send(&mut st, &bob_sk, bob, rotate), Err(TxError::DidDeactivated));
+        let root = st.commit_root();
+        assert!(!verify_did(&root, &id, Some(&doc), &st.prove_did(&id).1));
+    }
+}
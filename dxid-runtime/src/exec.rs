@@ -0,0 +1,434 @@
+//! Parallel transaction execution.
+//!
+//! `execute` produces exactly the result of `execute_sequential`, which applies
+//! transactions one by one in mempool order. Each transaction declares the
+//! accounts and registry records it may touch (`access_set`); transactions are
+//! grouped so that no two groups share a resource, and the groups run on worker
+//! threads against overlays that hold only the records they need. Because a
+//! group never observes another group's writes, every transaction sees the same
+//! inputs it would have seen sequentially.
+//!
+//! The results are merged back in the original order, which also replays the
+//! block-size cap: a transaction past the cap is dropped with everything after
+//! it, and since a group only depends on earlier transactions of its own, the
+//! kept prefix is unaffected by the extra work. Transactions whose access set is
+//! unknown up front (asset issuance, which allocates a registry id, and module
+//! deployments and calls, which may touch any account) act as barriers: they are
+//! applied alone on the main state between two parallel segments.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    assets::{AssetId, AssetInfo},
+    monetary::MonetaryPolicy,
+    receipt::{Receipt, TxError},
+    tx::{Tx, TxPayload},
+    Account, Chain, State, TokenType, H256,
+};
+
+/// Below this many transactions a segment is not worth spreading over threads
+const MIN_PARALLEL_TXS: usize = 64;
+
+/// A record a transaction may read or write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Resource {
+    Account(H256),
+    Asset(AssetId),
+}
+
+/// Records `tx` may touch, or None if they cannot be known before executing it.
+pub fn access_set(policy: &dyn MonetaryPolicy, tx: &Tx) -> Option<Vec<Resource>> {
+    let mut set = vec![Resource::Account(tx.from)];
+    match &tx.payload {
+        TxPayload::Transfer { to, amount, token_type } | TxPayload::CrossChainTransfer { to, amount, token_type, .. } => {
+            set.push(Resource::Account(*to));
+            // Transfer-time issuance updates the token's supply
+            let token = TokenType::from_asset_id(token_type.asset_id());
+            if policy.transfer_issuance(token, *amount) > 0 {
+                set.push(Resource::Asset(token.asset_id()));
+            }
+        }
+        TxPayload::Mint { asset, to, .. } => {
+            set.push(Resource::Account(*to));
+            set.push(Resource::Asset(*asset));
+        }
+        TxPayload::Burn { asset, .. } => set.push(Resource::Asset(*asset)),
+        TxPayload::IssueAsset { .. }
+        | TxPayload::DeployModule { .. }
+        | TxPayload::CallModule { .. }
+        | TxPayload::Stake { .. }
+        | TxPayload::Unstake { .. }
+        | TxPayload::Slash { .. }
+        | TxPayload::CreateVesting { .. }
+        | TxPayload::ClaimVesting { .. }
+        | TxPayload::LockHtlc { .. }
+        | TxPayload::ClaimHtlc { .. }
+        | TxPayload::RefundHtlc { .. }
+        | TxPayload::CreateDid { .. }
+        | TxPayload::UpdateDid { .. }
+        | TxPayload::RotateDidController { .. }
+        | TxPayload::DeactivateDid { .. }
+        | TxPayload::RevokeCredentials { .. } => return None,
+    }
+    set.sort();
+    set.dedup();
+    Some(set)
+}
+
+/// Outcome of executing a batch of candidate transactions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Execution {
+    /// Indices of the transactions that made it into the block, in order
+    pub included: Vec<usize>,
+    /// Indices of the transactions rejected for good
+    pub rejected: Vec<usize>,
+    /// One receipt per included or rejected transaction, in execution order
+    pub receipts: Vec<Receipt>,
+}
+
+impl Execution {
+    fn new(capacity: usize) -> Self {
+        Self { included: Vec::with_capacity(capacity), rejected: Vec::new(), receipts: Vec::with_capacity(capacity) }
+    }
+
+    /// Record the result of transaction `index`. Returns false once the block is full.
+    fn record(&mut self, index: usize, tx: &Tx, result: &Result<Receipt, TxError>, max_txs: usize) -> bool {
+        if self.included.len() >= max_txs {
+            return false;
+        }
+        match result {
+            Ok(receipt) => {
+                self.included.push(index);
+                self.receipts.push(receipt.clone());
+            }
+            // Not executable yet, it stays queued
+            Err(e) if e.is_retryable() => {}
+            Err(e) => {
+                self.rejected.push(index);
+                self.receipts.push(Receipt::failed(tx.hash(), e));
+            }
+        }
+        true
+    }
+}
+
+/// Apply `txs` one at a time, stopping once `max_txs` are included.
+pub fn execute_sequential(st: &mut State, txs: &[Tx], max_txs: usize) -> Execution {
+    let mut out = Execution::new(txs.len().min(max_txs));
+    for (index, tx) in txs.iter().enumerate() {
+        if out.included.len() >= max_txs {
+            break;
+        }
+        let result = Chain::apply_tx(st, tx);
+        out.record(index, tx, &result, max_txs);
+    }
+    out
+}
+
+/// Apply `txs` like `execute_sequential`, running independent transactions on up to `threads` threads.
+pub fn execute(st: &mut State, txs: &[Tx], max_txs: usize, threads: usize) -> Execution {
+    if threads <= 1 || txs.len() < MIN_PARALLEL_TXS {
+        return execute_sequential(st, txs, max_txs);
+    }
+    let policy = st.policy();
+    let mut out = Execution::new(txs.len().min(max_txs));
+    let mut start = 0;
+    while start < txs.len() && out.included.len() < max_txs {
+        // A parallel segment runs up to the next barrier
+        let mut sets = Vec::new();
+        while let Some(set) = txs.get(start + sets.len()).and_then(|tx| access_set(policy.as_ref(), tx)) {
+            sets.push(set);
+        }
+        let end = start + sets.len();
+        if !run_segment(st, &txs[start..end], start, &sets, max_txs, threads, &mut out) {
+            break;
+        }
+        if let Some(tx) = txs.get(end) {
+            let result = Chain::apply_tx(st, tx);
+            if !out.record(end, tx, &result, max_txs) {
+                break;
+            }
+        }
+        start = end + 1;
+    }
+    out
+}
+
+/// Number of worker threads to use for block execution.
+pub fn available_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+type TxResult = Result<(Receipt, Vec<(H256, Account)>, Vec<(AssetId, AssetInfo)>), TxError>;
+
+/// Execute one barrier-free segment and merge it into `st`. Returns false once the block is full.
+fn run_segment(
+    st: &mut State,
+    txs: &[Tx],
+    offset: usize,
+    sets: &[Vec<Resource>],
+    max_txs: usize,
+    threads: usize,
+    out: &mut Execution,
+) -> bool {
+    if txs.is_empty() {
+        return true;
+    }
+    let buckets = schedule(sets, threads);
+    let base: &State = st;
+    let mut results: Vec<Option<TxResult>> = vec![None; txs.len()];
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = buckets
+            .iter()
+            .map(|bucket| scope.spawn(move || run_bucket(base, txs, sets, bucket)))
+            .collect();
+        for worker in workers {
+            for (i, result) in worker.join().expect("execution worker panicked") {
+                results[i] = Some(result);
+            }
+        }
+    });
+
+    for (i, result) in results.into_iter().enumerate() {
+        let result = result.expect("every transaction is scheduled");
+        let (index, tx) = (offset + i, &txs[i]);
+        let recorded = result.as_ref().map(|(receipt, _, _)| receipt.clone()).map_err(Clone::clone);
+        if !out.record(index, tx, &recorded, max_txs) {
+            return false;
+        }
+        if let Ok((_, accounts, assets)) = result {
+            for (addr, acct) in accounts {
+                st.set_account(addr, &acct);
+            }
+            for (id, info) in assets {
+                st.set_asset(id, info);
+            }
+        }
+    }
+    true
+}
+
+/// Group transactions that share a resource and spread the groups over `threads` buckets.
+/// Each bucket lists transaction positions in their original order.
+fn schedule(sets: &[Vec<Resource>], threads: usize) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..sets.len()).collect();
+    fn find(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    let mut owner: HashMap<Resource, usize> = HashMap::new();
+    for (i, set) in sets.iter().enumerate() {
+        for r in set {
+            let j = *owner.entry(*r).or_insert(i);
+            let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+            parent[a.max(b)] = a.min(b);
+        }
+    }
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..sets.len() {
+        let root = find(&mut parent, i);
+        groups.entry(root).or_default().push(i);
+    }
+
+    // Largest groups first, each to the least loaded bucket
+    let mut groups: Vec<Vec<usize>> = groups.into_values().collect();
+    groups.sort_by_key(|g| std::cmp::Reverse(g.len()));
+    let mut buckets = vec![Vec::new(); threads.min(groups.len())];
+    for group in groups {
+        let bucket = buckets.iter_mut().min_by_key(|b: &&mut Vec<usize>| b.len()).expect("at least one bucket");
+        bucket.extend(group);
+    }
+    for bucket in &mut buckets {
+        bucket.sort_unstable();
+    }
+    buckets
+}
+
+/// Run the transactions of one bucket against an overlay holding just their records.
+fn run_bucket(base: &State, txs: &[Tx], sets: &[Vec<Resource>], bucket: &[usize]) -> Vec<(usize, TxResult)> {
+    let mut overlay = base.overlay(bucket.iter().flat_map(|&i| &sets[i]));
+    bucket
+        .iter()
+        .map(|&i| {
+            let result = Chain::apply_tx(&mut overlay, &txs[i]);
+            // The overlay tracks exactly what the transaction wrote
+            let accounts: Vec<(H256, Account)> = std::mem::take(&mut overlay.dirty_accounts)
+                .into_iter()
+                .filter_map(|addr| Some((addr, overlay.accounts.get(&hex::encode(addr))?.clone())))
+                .collect();
+            let assets: Vec<(AssetId, AssetInfo)> = std::mem::take(&mut overlay.dirty_assets)
+                .into_iter()
+                .filter_map(|id| Some((id, overlay.assets.get(&id)?.clone())))
+                .collect();
+            (i, result.map(|receipt| (receipt, accounts, assets)))
+        })
+        .collect()
+}
+
+impl State {
+    /// Copy of the chain parameters, registry and the given accounts, without modules,
+    /// Merkle trees or journal, for executing transactions that only touch those.
+    fn overlay<'a>(&self, resources: impl IntoIterator<Item = &'a Resource>) -> State {
+        let mut accounts = HashMap::new();
+        for r in resources {
+            if let Resource::Account(addr) = r {
+                let key = hex::encode(addr);
+                if let Some(acct) = self.accounts.get(&key) {
+                    accounts.insert(key, acct.clone());
+                }
+            }
+        }
+        State {
+            accounts,
+            height: self.height,
+            last_block_hash: self.last_block_hash,
+            state_root: self.state_root,
+            assets: self.assets.clone(),
+            legacy_layer0_circulating: 0,
+            legacy_longyield_circulating: 0,
+            legacy_native_circulating: 0,
+            genesis_hash: self.genesis_hash,
+            chain_id: self.chain_id,
+            monetary: self.monetary.clone(),
+            validators: self.validators.clone(),
+            previous_validators: self.previous_validators.clone(),
+            validators_since: self.validators_since,
+            staking: self.staking.clone(),
+            validator_registry: Default::default(),
+            vesting: Default::default(),
+            htlcs: Default::default(),
+            dids: Default::default(),
+            revocations: Default::default(),
+            min_fee: self.min_fee,
+            recent_block_txs: self.recent_block_txs.clone(),
+            finalized_height: self.finalized_height,
+            modules: Default::default(),
+            module_storage: Default::default(),
+            smt: Default::default(),
+            dirty_accounts: Default::default(),
+            dirty_assets: Default::default(),
+            module_smts: Default::default(),
+            dirty_modules: Default::default(),
+            dirty_validators: Default::default(),
+            dirty_vesting: Default::default(),
+            dirty_htlcs: Default::default(),
+            dirty_dids: Default::default(),
+            revocation_smts: Default::default(),
+            dirty_revocations: Default::default(),
+            journal: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dxid_crypto::{SecretKey, StarkSignEngine, ENGINE as STARK};
+
+    fn keys(n: usize) -> Vec<(SecretKey, H256)> {
+        (0..n).map(|_| STARK.generate_keys().unwrap()).collect()
+    }
+
+    fn transfer(sk: &SecretKey, from: H256, nonce: u64, to: H256, amount: u128, token_type: TokenType) -> Tx {
+        Tx::new_signed(sk, from, nonce, 10, crate::CHAIN_ID, None, TxPayload::Transfer { to, amount, token_type }).unwrap()
+    }
+
+    /// Run both executors from the same state and check they agree on everything.
+    fn assert_equivalent(st: &State, txs: &[Tx], max_txs: usize) -> Execution {
+        let (mut seq, mut par) = (st.clone(), st.clone());
+        seq.begin_journal();
+        par.begin_journal();
+        let expected = execute_sequential(&mut seq, txs, max_txs);
+        let got = execute(&mut par, txs, max_txs, 4);
+        assert_eq!(got, expected);
+        assert_eq!(par.commit_root(), seq.commit_root());
+        assert_eq!(par.accounts, seq.accounts);
+        assert_eq!(par.assets, seq.assets);
+        assert_eq!(par.take_journal(), seq.take_journal());
+        expected
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential_under_contention() {
+        let users = keys(40);
+        let mut st = State::new_with_genesis(users.iter().map(|(_, pk)| (*pk, 10_000)).collect()).lock().clone();
+        let hot = users[0].1;
+        let mut nonces = vec![0u64; users.len()];
+        let mut txs = Vec::new();
+        for round in 0..6u64 {
+            for (i, (sk, pk)) in users.iter().enumerate() {
+                // Chains of transfers, a hot recipient, overdrafts and a Layer0 transfer with issuance
+                let to = match (i + round as usize) % 4 {
+                    0 => hot,
+                    1 => users[(i + 1) % users.len()].1,
+                    _ => [i as u8; 32],
+                };
+                let (amount, token) = if i % 7 == 3 { (50_000, TokenType::Native) } else { (100 + round as u128, TokenType::Native) };
+                let token = if i % 11 == 5 { TokenType::Layer0 } else { token };
+                txs.push(transfer(sk, *pk, nonces[i], to, amount, token));
+                nonces[i] += 1;
+            }
+        }
+        // Replayed and future nonces
+        txs.push(transfer(&users[1].0, users[1].1, 0, hot, 1, TokenType::Native));
+        txs.push(transfer(&users[2].0, users[2].1, 99, hot, 1, TokenType::Native));
+        st.commit_root();
+
+        let out = assert_equivalent(&st, &txs, 1_000);
+        assert!(!out.rejected.is_empty() && out.included.len() > 150);
+        // A cap in the middle of the batch
+        assert_equivalent(&st, &txs, 97);
+    }
+
+    #[test]
+    fn test_asset_issuance_is_a_barrier() {
+        let users = keys(12);
+        let mut st = State::new_with_genesis(users.iter().map(|(_, pk)| (*pk, 10_000)).collect()).lock().clone();
+        st.commit_root();
+        let (issuer_sk, issuer) = &users[0];
+        let issue = TxPayload::IssueAsset { symbol: "GOLD".into(), decimals: 2, supply_cap: Some(1_000), initial_supply: 100 };
+        let mut txs = vec![Tx::new_signed(issuer_sk, *issuer, 0, 10, crate::CHAIN_ID, None, issue).unwrap()];
+        // The asset id the registry will hand out
+        let asset = {
+            let mut probe = st.clone();
+            Chain::apply_tx(&mut probe, &txs[0]).unwrap().created_asset.unwrap()
+        };
+        // Mints before the issuance are rejected, the ones after succeed
+        let mint = |nonce, to| Tx::new_signed(issuer_sk, *issuer, nonce, 10, crate::CHAIN_ID, None, TxPayload::Mint { asset, to, amount: 300 }).unwrap();
+        let mut batch = vec![mint(0, users[1].1)];
+        batch.append(&mut txs);
+        for nonce in 1..5 {
+            batch.push(mint(nonce, users[nonce as usize].1));
+        }
+        for (i, (sk, pk)) in users.iter().enumerate().skip(1) {
+            for nonce in 0..8 {
+                batch.push(transfer(sk, *pk, nonce, users[(i + 1) % users.len()].1, 10, TokenType::Native));
+            }
+            batch.push(transfer(sk, *pk, 8, *issuer, 5, TokenType::Asset(asset)));
+            let burn = TxPayload::Burn { asset, amount: 1 };
+            batch.push(Tx::new_signed(sk, *pk, 9, 10, crate::CHAIN_ID, None, burn).unwrap());
+        }
+
+        let out = assert_equivalent(&st, &batch, 1_000);
+        assert_eq!(out.rejected.first(), Some(&0));
+        assert!(out.included.contains(&1));
+    }
+
+    #[test]
+    fn test_groups_do_not_share_resources() {
+        let a = |b: u8| Resource::Account([b; 32]);
+        let sets = vec![vec![a(1), a(2)], vec![a(3)], vec![a(2), a(4)], vec![a(5), Resource::Asset(0)], vec![a(4)], vec![a(6), Resource::Asset(0)]];
+        let buckets = schedule(&sets, 8);
+        let mut seen: Vec<usize> = buckets.concat();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..sets.len()).collect::<Vec<_>>());
+        let bucket_of = |i: usize| buckets.iter().position(|b| b.contains(&i)).unwrap();
+        assert_eq!((bucket_of(0), bucket_of(0)), (bucket_of(2), bucket_of(4)));
+        assert_eq!(bucket_of(3), bucket_of(5));
+        assert_eq!(buckets.len(), 3);
+        assert!(buckets.iter().all(|b| b.windows(2).all(|w| w[0] < w[1])));
+    }
+}
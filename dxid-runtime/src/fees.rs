@@ -0,0 +1,223 @@
+//! Fee distribution and the minimum-fee market.
+//!
+//! Fees are only charged on LongYield and native transfers (Layer0 has zero fees).
+//! Execution records the fee on the receipt; at the end of the block the totals
+//! per token are split by the monetary policy into a burned share, a proposer
+//! share (credited to the block's coinbase) and a treasury share. Shares without
+//! a recipient are burned.
+//!
+//! The minimum fee adjusts every block from the average fullness of the last
+//! `FULLNESS_WINDOW` blocks: above half full it rises by 1/8, below it falls by
+//! 1/8 (by at least 1 either way), never below the configured floor.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{dehex32, State, TokenType, H256};
+
+/// Number of recent blocks averaged for the fullness signal
+pub const FULLNESS_WINDOW: usize = 10;
+
+const BPS: u128 = 10_000;
+
+/// Fee parameters fixed at genesis (part of the monetary parameters)
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FeeParams {
+    /// Share of each fee burned, in basis points
+    pub burn_bps: u32,
+    /// Share of each fee paid to the block proposer, in basis points
+    pub proposer_bps: u32,
+    /// Share of each fee paid to the treasury, in basis points
+    pub treasury_bps: u32,
+    /// hex(32) treasury account; required when `treasury_bps` is non-zero
+    #[serde(default)]
+    pub treasury: Option<String>,
+    /// Maximum number of transactions applied per block
+    pub max_block_txs: u32,
+    /// Lower bound of the dynamic minimum fee
+    pub min_fee_floor: u128,
+}
+
+impl Default for FeeParams {
+    fn default() -> Self {
+        Self {
+            burn_bps: 5_000,
+            proposer_bps: 5_000,
+            treasury_bps: 0,
+            treasury: None,
+            max_block_txs: 1_000,
+            min_fee_floor: 0,
+        }
+    }
+}
+
+impl FeeParams {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.burn_bps as u128 + self.proposer_bps as u128 + self.treasury_bps as u128 != BPS {
+            anyhow::bail!("fee shares must add up to 10000 bps");
+        }
+        match &self.treasury {
+            Some(t) if dehex32(&t.to_lowercase()).is_none() => anyhow::bail!("invalid treasury address {}", t),
+            None if self.treasury_bps > 0 => anyhow::bail!("treasury_bps set without a treasury account"),
+            _ => {}
+        }
+        if self.max_block_txs == 0 {
+            anyhow::bail!("max_block_txs must be non-zero");
+        }
+        Ok(())
+    }
+
+    pub fn treasury_address(&self) -> Option<H256> {
+        self.treasury.as_deref().and_then(|t| dehex32(&t.to_lowercase()))
+    }
+
+    /// Split `fee` by the configured shares; rounding dust is burned.
+    pub fn split(&self, fee: u128) -> FeeShares {
+        let proposer = fee / BPS * self.proposer_bps as u128 + fee % BPS * self.proposer_bps as u128 / BPS;
+        let treasury = fee / BPS * self.treasury_bps as u128 + fee % BPS * self.treasury_bps as u128 / BPS;
+        FeeShares { burn: fee - proposer - treasury, proposer, treasury }
+    }
+}
+
+/// How a fee is divided.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct FeeShares {
+    pub burn: u128,
+    pub proposer: u128,
+    pub treasury: u128,
+}
+
+/// Fee charged by a transaction, recorded on its receipt.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FeePaid {
+    pub token: TokenType,
+    pub amount: u128,
+}
+
+/// Answer of `/v1/feeEstimate`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FeeEstimate {
+    /// Minimum fee accepted right now
+    pub min_fee: u128,
+    /// Fee that stays above the minimum even if the next block raises it
+    pub suggested_fee: u128,
+    /// Average fullness of recent blocks, in basis points
+    pub recent_fullness_bps: u32,
+    pub max_block_txs: u32,
+}
+
+impl State {
+    /// Pay out one token's fee total collected in a block.
+    pub fn distribute_fees(&mut self, token: TokenType, total: u128, proposer: Option<H256>) -> FeeShares {
+        let shares = self.policy().fee_split(total);
+        let treasury = self.monetary.fees.treasury_address();
+        let mut burned = shares.burn;
+        for (recipient, amount) in [(proposer, shares.proposer), (treasury, shares.treasury)] {
+            match recipient {
+                Some(addr) if amount > 0 => {
+                    let mut acct = self.accounts.get(&hex::encode(addr)).cloned().unwrap_or_default();
+                    acct.credit(token.asset_id(), amount);
+                    self.set_account(addr, &acct);
+                }
+                _ => burned += amount,
+            }
+        }
+        self.burn(token, burned);
+        shares
+    }
+
+    /// Average fullness of the last `FULLNESS_WINDOW` blocks, in basis points.
+    pub fn recent_fullness_bps(&self) -> u32 {
+        if self.recent_block_txs.is_empty() {
+            return 0;
+        }
+        let txs: u128 = self.recent_block_txs.iter().map(|&n| n as u128).sum();
+        let capacity = self.recent_block_txs.len() as u128 * self.monetary.fees.max_block_txs.max(1) as u128;
+        (txs * BPS / capacity).min(BPS) as u32
+    }
+
+    /// Record a finished block's transaction count and move the minimum fee.
+    pub fn update_min_fee(&mut self, block_txs: u32) {
+        self.recent_block_txs.push(block_txs);
+        if self.recent_block_txs.len() > FULLNESS_WINDOW {
+            self.recent_block_txs.remove(0);
+        }
+        self.min_fee = next_min_fee(self.min_fee, self.recent_fullness_bps(), self.monetary.fees.min_fee_floor);
+    }
+
+    /// Smallest fee a transaction may pay: the dynamic minimum, never below the floor.
+    pub fn required_min_fee(&self) -> u128 {
+        self.min_fee.max(self.monetary.fees.min_fee_floor)
+    }
+
+    pub fn fee_estimate(&self) -> FeeEstimate {
+        let fullness = self.recent_fullness_bps();
+        let min_fee = self.required_min_fee();
+        FeeEstimate {
+            min_fee,
+            suggested_fee: next_min_fee(min_fee, fullness, self.monetary.fees.min_fee_floor).max(min_fee),
+            recent_fullness_bps: fullness,
+            max_block_txs: self.monetary.fees.max_block_txs,
+        }
+    }
+}
+
+fn next_min_fee(current: u128, fullness_bps: u32, floor: u128) -> u128 {
+    let step = (current / 8).max(1);
+    let next = match fullness_bps.cmp(&(BPS as u32 / 2)) {
+        std::cmp::Ordering::Greater => current.saturating_add(step),
+        std::cmp::Ordering::Less => current.saturating_sub(step),
+        std::cmp::Ordering::Equal => current,
+    };
+    next.max(floor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::NATIVE_ASSET;
+
+    #[test]
+    fn test_split_conserves_fee_and_routes_shares() {
+        let mut st = State::empty();
+        st.monetary.fees = FeeParams {
+            burn_bps: 2_000,
+            proposer_bps: 5_000,
+            treasury_bps: 3_000,
+            treasury: Some(hex::encode([7u8; 32])),
+            ..Default::default()
+        };
+        st.monetary.fees.validate().unwrap();
+        st.issue(TokenType::Native, 1_003);
+
+        let shares = st.distribute_fees(TokenType::Native, 1_003, Some([8u8; 32]));
+        assert_eq!(shares.burn + shares.proposer + shares.treasury, 1_003);
+        assert_eq!(st.accounts[&hex::encode([8u8; 32])].balance(NATIVE_ASSET), shares.proposer);
+        assert_eq!(st.accounts[&hex::encode([7u8; 32])].balance(NATIVE_ASSET), shares.treasury);
+        assert_eq!(st.circulating(TokenType::Native), 1_003 - shares.burn);
+
+        // No proposer: its share is burned
+        let before = st.circulating(TokenType::Native);
+        let shares = st.distribute_fees(TokenType::Native, 100, None);
+        assert_eq!(st.circulating(TokenType::Native), before - shares.burn - shares.proposer);
+        assert_eq!(st.accounts[&hex::encode([8u8; 32])].balance(NATIVE_ASSET), 501);
+    }
+
+    #[test]
+    fn test_min_fee_follows_fullness() {
+        let mut st = State::empty();
+        st.monetary.fees.max_block_txs = 10;
+        st.monetary.fees.min_fee_floor = 5;
+        for _ in 0..FULLNESS_WINDOW {
+            st.update_min_fee(10);
+        }
+        let peak = st.min_fee;
+        assert!(peak > 5);
+        assert!(st.fee_estimate().suggested_fee > peak);
+
+        for _ in 0..200 {
+            st.update_min_fee(0);
+        }
+        assert_eq!(st.min_fee, 5);
+        assert_eq!(st.recent_fullness_bps(), 0);
+    }
+}
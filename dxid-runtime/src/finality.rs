@@ -0,0 +1,711 @@
+//! BFT finality gadget.
+//!
+//! A Tendermint-style propose / prevote / precommit protocol run by the genesis
+//! validators on top of block production. Every height is decided in rounds: the
+//! round's proposer proposes a block hash, validators prevote for it (or nil),
+//! and once a quorum (more than two thirds) prevoted for the same hash they lock
+//! on it and precommit. A quorum of precommits for one hash decides the height;
+//! those precommits form the `CommitCertificate` that the next block carries in
+//! its header (`BlockHeader::last_commit`).
+//!
+//! `FinalityNode` is a pure state machine: it consumes `Input`s (messages and
+//! expired timeouts) and returns `Output`s (messages to broadcast, timeouts to
+//! schedule, decisions). It has no clock and does no IO, so the same code runs in
+//! the node and in the deterministic multi-validator `harness`.
+//!
+//! Votes are signed with the STARK signature engine. A validator counts at most
+//! once per block in a round; an equivocator's votes for two different blocks are
+//! both kept, which is safe because quorums for two blocks always share an honest
+//! validator. Validators periodically resend their messages for the current
+//! round, the prevotes behind the block they would re-propose, and the
+//! certificate of the last decided height, so the protocol makes progress even
+//! when the network loses messages. A validator that hears from a peer at an
+//! older height sends it the certificates it missed (the most recent
+//! `MAX_RETAINED_COMMITS` heights; anything older needs block sync).
+
+pub mod harness;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, Result};
+use dxid_crypto::{SecretKey, StarkSignEngine, StarkSignature, ENGINE as STARK};
+use serde::{Deserialize, Serialize};
+
+use crate::H256;
+
+pub type Round = u32;
+
+/// Votes needed for a quorum among `n` validators (more than two thirds).
+pub fn quorum(n: usize) -> usize {
+    n * 2 / 3 + 1
+}
+
+/// Votes that include at least one honest validator (more than one third).
+pub fn honest_threshold(n: usize) -> usize {
+    n - quorum(n) + 1
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub height: u64,
+    pub round: Round,
+    /// None = nil
+    pub block_hash: Option<H256>,
+    pub voter: H256,
+    pub signature: StarkSignature,
+}
+
+impl Vote {
+    pub fn signing_bytes(chain_id: u32, kind: VoteKind, height: u64, round: Round, block_hash: Option<&H256>) -> Vec<u8> {
+        let mut msg = b"dxid-vote-v1".to_vec();
+        msg.extend_from_slice(&chain_id.to_le_bytes());
+        msg.push(kind as u8);
+        msg.extend_from_slice(&height.to_le_bytes());
+        msg.extend_from_slice(&round.to_le_bytes());
+        match block_hash {
+            Some(hash) => {
+                msg.push(1);
+                msg.extend_from_slice(hash);
+            }
+            None => msg.push(0),
+        }
+        msg
+    }
+
+    pub fn new_signed(
+        chain_id: u32,
+        secret: &SecretKey,
+        kind: VoteKind,
+        height: u64,
+        round: Round,
+        block_hash: Option<H256>,
+    ) -> Result<Self> {
+        let signature = STARK.sign(secret, &Self::signing_bytes(chain_id, kind, height, round, block_hash.as_ref()), height)?;
+        Ok(Self { kind, height, round, block_hash, voter: signature.pubkey_hash, signature })
+    }
+
+    pub fn verify(&self, chain_id: u32) -> Result<()> {
+        if self.signature.pubkey_hash != self.voter || self.signature.nonce != self.height {
+            bail!("vote signature does not belong to {}", hex::encode(self.voter));
+        }
+        STARK.verify(&self.signature, &Self::signing_bytes(chain_id, self.kind, self.height, self.round, self.block_hash.as_ref()))
+    }
+}
+
+// Signatures have no PartialEq of their own
+impl PartialEq for Vote {
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b) = (&self.signature, &other.signature);
+        (self.kind, self.height, self.round, self.block_hash, self.voter) == (other.kind, other.height, other.round, other.block_hash, other.voter)
+            && (a.msg_hash, a.sig, a.pubkey_hash, a.nonce) == (b.msg_hash, b.sig, b.pubkey_hash, b.nonce)
+            && a.proof.bytes == b.proof.bytes
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Proposal {
+    pub height: u64,
+    pub round: Round,
+    pub block_hash: H256,
+    /// Round in which the proposer saw a prevote quorum for this hash
+    pub valid_round: Option<Round>,
+    /// Certificate of the previous height, so lagging validators can catch up
+    pub last_commit: Option<CommitCertificate>,
+    pub proposer: H256,
+    pub signature: StarkSignature,
+}
+
+impl Proposal {
+    fn signing_bytes(chain_id: u32, height: u64, round: Round, block_hash: &H256, valid_round: Option<Round>) -> Vec<u8> {
+        let mut msg = b"dxid-proposal-v1".to_vec();
+        msg.extend_from_slice(&chain_id.to_le_bytes());
+        msg.extend_from_slice(&height.to_le_bytes());
+        msg.extend_from_slice(&round.to_le_bytes());
+        msg.extend_from_slice(block_hash);
+        msg.extend_from_slice(&valid_round.map_or(u64::MAX, u64::from).to_le_bytes());
+        msg
+    }
+
+    pub fn new_signed(
+        chain_id: u32,
+        secret: &SecretKey,
+        height: u64,
+        round: Round,
+        block_hash: H256,
+        valid_round: Option<Round>,
+        last_commit: Option<CommitCertificate>,
+    ) -> Result<Self> {
+        let msg = Self::signing_bytes(chain_id, height, round, &block_hash, valid_round);
+        let signature = STARK.sign(secret, &msg, height)?;
+        Ok(Self { height, round, block_hash, valid_round, last_commit, proposer: signature.pubkey_hash, signature })
+    }
+
+    pub fn verify(&self, chain_id: u32) -> Result<()> {
+        if self.signature.pubkey_hash != self.proposer || self.signature.nonce != self.height {
+            bail!("proposal signature does not belong to {}", hex::encode(self.proposer));
+        }
+        let msg = Self::signing_bytes(chain_id, self.height, self.round, &self.block_hash, self.valid_round);
+        STARK.verify(&self.signature, &msg)
+    }
+}
+
+/// A quorum of precommits deciding `block_hash` at `height`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CommitCertificate {
+    pub height: u64,
+    pub round: Round,
+    pub block_hash: H256,
+    pub precommits: Vec<Vote>,
+}
+
+impl CommitCertificate {
+    /// Check that a quorum of distinct `validators` signed precommits for this block.
+    pub fn verify(&self, chain_id: u32, validators: &[H256]) -> Result<()> {
+        let mut voters = BTreeSet::new();
+        for vote in &self.precommits {
+            if vote.kind != VoteKind::Precommit
+                || vote.height != self.height
+                || vote.round != self.round
+                || vote.block_hash != Some(self.block_hash)
+            {
+                bail!("certificate for block {} holds an unrelated vote", self.height);
+            }
+            if !validators.contains(&vote.voter) {
+                bail!("{} is not a validator", hex::encode(vote.voter));
+            }
+            if !voters.insert(vote.voter) {
+                bail!("{} voted twice", hex::encode(vote.voter));
+            }
+            vote.verify(chain_id)?;
+        }
+        if voters.len() < quorum(validators.len()) {
+            bail!("{} of {} precommits, {} needed", voters.len(), validators.len(), quorum(validators.len()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Message {
+    Proposal(Proposal),
+    Vote(Vote),
+    /// Certificate of a decided height, resent for validators that missed it
+    Commit(CommitCertificate),
+}
+
+impl Message {
+    pub fn height(&self) -> u64 {
+        match self {
+            Message::Proposal(p) => p.height,
+            Message::Vote(v) => v.height,
+            Message::Commit(c) => c.height,
+        }
+    }
+
+    pub fn round(&self) -> Round {
+        match self {
+            Message::Proposal(p) => p.round,
+            Message::Vote(v) => v.round,
+            Message::Commit(c) => c.round,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Step {
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Propose,
+    Prevote,
+    Precommit,
+    /// Resend this round's messages
+    Rebroadcast,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeout {
+    pub kind: TimeoutKind,
+    pub height: u64,
+    pub round: Round,
+}
+
+// Inputs are handled as soon as they are built, so boxing would only add allocations
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum Input {
+    /// Begin round 0 of the first height
+    Start,
+    Message(Message),
+    Timeout(Timeout),
+}
+
+#[derive(Clone, Debug)]
+pub enum Output {
+    Broadcast(Message),
+    /// Send to one validator
+    Send { to: H256, msg: Message },
+    /// Feed `Input::Timeout(timeout)` back after `after` milliseconds
+    Schedule { timeout: Timeout, after: u64 },
+    Decide(CommitCertificate),
+}
+
+/// Where block hashes come from: the proposer asks for one, everyone validates.
+pub trait BlockSource {
+    fn propose(&mut self, height: u64, round: Round) -> H256;
+    fn is_valid(&self, height: u64, block_hash: &H256) -> bool;
+}
+
+#[derive(Clone, Debug)]
+pub struct FinalityConfig {
+    pub chain_id: u32,
+    /// Validator set, in proposer order
+    pub validators: Vec<H256>,
+    pub timeout_propose: u64,
+    pub timeout_prevote: u64,
+    pub timeout_precommit: u64,
+    /// Added to every timeout per round, so rounds eventually outlast message delays
+    pub timeout_delta: u64,
+    pub rebroadcast_interval: u64,
+}
+
+impl FinalityConfig {
+    pub fn new(chain_id: u32, validators: Vec<H256>) -> Self {
+        Self {
+            chain_id,
+            validators,
+            timeout_propose: 1_000,
+            timeout_prevote: 500,
+            timeout_precommit: 500,
+            timeout_delta: 250,
+            rebroadcast_interval: 1_000,
+        }
+    }
+
+    pub fn proposer(&self, height: u64, round: Round) -> H256 {
+        let n = self.validators.len() as u64;
+        self.validators[((height + round as u64) % n) as usize]
+    }
+}
+
+/// Messages buffered for heights above the current one
+const MAX_FUTURE_MESSAGES: usize = 10_000;
+
+/// Decided certificates kept for lagging validators
+pub const MAX_RETAINED_COMMITS: usize = 1_024;
+
+/// Certificates sent in reply to one message from an older height
+const CATCH_UP_BATCH: usize = 16;
+
+/// Votes of one round and kind, keyed by (voter, block)
+type VoteSet = BTreeMap<(H256, Option<H256>), Vote>;
+
+/// One validator's view of the protocol.
+pub struct FinalityNode<S> {
+    cfg: FinalityConfig,
+    key: SecretKey,
+    address: H256,
+    source: S,
+
+    height: u64,
+    round: Round,
+    step: Step,
+    locked: Option<(H256, Round)>,
+    valid: Option<(H256, Round)>,
+    last_commit: Option<CommitCertificate>,
+
+    /// Proposals per round, first received first
+    proposals: BTreeMap<Round, Vec<Proposal>>,
+    /// Votes per round and kind
+    votes: BTreeMap<(Round, VoteKind), VoteSet>,
+    prevote_timeout_set: BTreeSet<Round>,
+    precommit_timeout_set: BTreeSet<Round>,
+    prevote_quorum_seen: BTreeSet<Round>,
+    future: Vec<Message>,
+    /// Recently decided heights
+    commits: BTreeMap<u64, CommitCertificate>,
+    /// Verified certificates for heights above the current one
+    pending_commits: BTreeMap<u64, CommitCertificate>,
+    outputs: Vec<Output>,
+}
+
+impl<S: BlockSource> FinalityNode<S> {
+    /// Validator starting at `height` (usually the block after the last finalized one).
+    pub fn new(cfg: FinalityConfig, key: SecretKey, source: S, height: u64) -> Result<Self> {
+        let address = STARK.sign(&key, b"dxid-validator-key", 0)?.pubkey_hash;
+        if !cfg.validators.contains(&address) {
+            bail!("{} is not in the validator set", hex::encode(address));
+        }
+        Ok(Self {
+            cfg,
+            key,
+            address,
+            source,
+            height,
+            round: 0,
+            step: Step::Propose,
+            locked: None,
+            valid: None,
+            last_commit: None,
+            proposals: BTreeMap::new(),
+            votes: BTreeMap::new(),
+            prevote_timeout_set: BTreeSet::new(),
+            precommit_timeout_set: BTreeSet::new(),
+            prevote_quorum_seen: BTreeSet::new(),
+            future: Vec::new(),
+            commits: BTreeMap::new(),
+            pending_commits: BTreeMap::new(),
+            outputs: Vec::new(),
+        })
+    }
+
+    pub fn address(&self) -> H256 {
+        self.address
+    }
+
+    /// Height currently being decided
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn round(&self) -> Round {
+        self.round
+    }
+
+    pub fn handle(&mut self, input: Input) -> Vec<Output> {
+        match input {
+            Input::Start => self.start_round(0),
+            Input::Message(msg) => self.receive(msg),
+            Input::Timeout(t) => self.on_timeout(t),
+        }
+        self.evaluate();
+        std::mem::take(&mut self.outputs)
+    }
+
+    fn n(&self) -> usize {
+        self.cfg.validators.len()
+    }
+
+    fn timeout(&self, base: u64, round: Round) -> u64 {
+        base + self.cfg.timeout_delta * round as u64
+    }
+
+    fn start_round(&mut self, round: Round) {
+        self.round = round;
+        self.step = Step::Propose;
+        self.schedule(TimeoutKind::Rebroadcast, self.cfg.rebroadcast_interval);
+        if self.cfg.proposer(self.height, round) == self.address {
+            let (block_hash, valid_round) = match self.valid {
+                Some((hash, r)) => (hash, Some(r)),
+                None => (self.source.propose(self.height, round), None),
+            };
+            let proposal = Proposal::new_signed(
+                self.cfg.chain_id,
+                &self.key,
+                self.height,
+                round,
+                block_hash,
+                valid_round,
+                self.last_commit.clone(),
+            );
+            if let Ok(proposal) = proposal {
+                self.broadcast(Message::Proposal(proposal));
+            }
+        } else {
+            self.schedule(TimeoutKind::Propose, self.timeout(self.cfg.timeout_propose, round));
+        }
+    }
+
+    fn schedule(&mut self, kind: TimeoutKind, after: u64) {
+        let timeout = Timeout { kind, height: self.height, round: self.round };
+        self.outputs.push(Output::Schedule { timeout, after });
+    }
+
+    /// Record our own message and send it to everyone else.
+    fn broadcast(&mut self, msg: Message) {
+        self.record(msg.clone());
+        self.outputs.push(Output::Broadcast(msg));
+    }
+
+    fn vote(&mut self, kind: VoteKind, block_hash: Option<H256>) {
+        if let Ok(vote) = Vote::new_signed(self.cfg.chain_id, &self.key, kind, self.height, self.round, block_hash) {
+            self.broadcast(Message::Vote(vote));
+        }
+        self.step = match kind {
+            VoteKind::Prevote => Step::Prevote,
+            VoteKind::Precommit => Step::Precommit,
+        };
+    }
+
+    /// Send again what we said in this round, plus the last certificate.
+    fn rebroadcast(&mut self) {
+        let r = self.round;
+        if let Some(cert) = &self.last_commit {
+            self.outputs.push(Output::Broadcast(Message::Commit(cert.clone())));
+        }
+        let own = self.proposals.get(&r).into_iter().flatten().filter(|p| p.proposer == self.address);
+        let mut resend: Vec<Message> = own.cloned().map(Message::Proposal).collect();
+        for kind in [VoteKind::Prevote, VoteKind::Precommit] {
+            let votes = self.votes.get(&(r, kind)).into_iter().flat_map(|votes| votes.values());
+            resend.extend(votes.filter(|v| v.voter == self.address).cloned().map(Message::Vote));
+        }
+        // Others need the same polka before they accept our valid block again
+        if let Some((hash, vr)) = self.valid {
+            let votes = self.votes.get(&(vr, VoteKind::Prevote)).into_iter().flat_map(|votes| votes.values());
+            resend.extend(votes.filter(|v| v.block_hash == Some(hash)).cloned().map(Message::Vote));
+        }
+        self.outputs.extend(resend.into_iter().map(Output::Broadcast));
+        self.schedule(TimeoutKind::Rebroadcast, self.cfg.rebroadcast_interval);
+    }
+
+    fn receive(&mut self, msg: Message) {
+        // A certificate decides its height whatever round we are in; `step_once` applies them in order
+        let (cert, sender) = match &msg {
+            Message::Commit(cert) => (Some(cert), None),
+            Message::Proposal(p) => (p.last_commit.as_ref(), Some(p.proposer)),
+            Message::Vote(v) => (None, Some(v.voter)),
+        };
+        if let Some(cert) = cert {
+            if cert.height >= self.height
+                && self.pending_commits.len() < MAX_FUTURE_MESSAGES
+                && !self.pending_commits.contains_key(&cert.height)
+                && cert.verify(self.cfg.chain_id, &self.cfg.validators).is_ok()
+            {
+                self.pending_commits.insert(cert.height, cert.clone());
+            }
+        }
+        if matches!(msg, Message::Commit(_)) {
+            return;
+        }
+        if msg.height() < self.height {
+            if let Some(to) = sender.filter(|to| *to != self.address && self.cfg.validators.contains(to)) {
+                let missed = self.commits.range(msg.height()..).take(CATCH_UP_BATCH);
+                let replies: Vec<Output> = missed.map(|(_, cert)| Output::Send { to, msg: Message::Commit(cert.clone()) }).collect();
+                self.outputs.extend(replies);
+            }
+            return;
+        }
+        if msg.height() > self.height {
+            if self.future.len() < MAX_FUTURE_MESSAGES {
+                self.future.push(msg);
+            }
+            return;
+        }
+        self.record(msg);
+    }
+
+    /// Store a verified message for the current height.
+    fn record(&mut self, msg: Message) {
+        match msg {
+            Message::Proposal(p) => {
+                if p.proposer != self.cfg.proposer(p.height, p.round) || p.verify(self.cfg.chain_id).is_err() {
+                    return;
+                }
+                let proposals = self.proposals.entry(p.round).or_default();
+                if !proposals.iter().any(|known| known.block_hash == p.block_hash) {
+                    proposals.push(p);
+                }
+            }
+            Message::Vote(v) => {
+                if !self.cfg.validators.contains(&v.voter) || v.verify(self.cfg.chain_id).is_err() {
+                    return;
+                }
+                self.votes.entry((v.round, v.kind)).or_default().entry((v.voter, v.block_hash)).or_insert(v);
+            }
+            Message::Commit(_) => {}
+        }
+    }
+
+    fn count(&self, round: Round, kind: VoteKind, block_hash: Option<Option<H256>>) -> usize {
+        self.votes.get(&(round, kind)).map_or(0, |votes| match block_hash {
+            Some(hash) => votes.values().filter(|v| v.block_hash == hash).count(),
+            None => votes.keys().map(|(voter, _)| voter).collect::<BTreeSet<_>>().len(),
+        })
+    }
+
+    fn on_timeout(&mut self, t: Timeout) {
+        if t.height != self.height || t.round != self.round {
+            return;
+        }
+        match t.kind {
+            TimeoutKind::Propose if self.step == Step::Propose => self.vote(VoteKind::Prevote, None),
+            TimeoutKind::Prevote if self.step == Step::Prevote => self.vote(VoteKind::Precommit, None),
+            TimeoutKind::Precommit => self.start_round(self.round + 1),
+            TimeoutKind::Rebroadcast => self.rebroadcast(),
+            _ => {}
+        }
+    }
+
+    /// Apply protocol rules until none fires.
+    fn evaluate(&mut self) {
+        loop {
+            if !self.step_once() {
+                break;
+            }
+        }
+    }
+
+    fn step_once(&mut self) -> bool {
+        let q = quorum(self.n());
+        let (h, r) = (self.height, self.round);
+
+        if let Some(cert) = self.pending_commits.remove(&h) {
+            self.decide(cert);
+            return true;
+        }
+
+        // Decide: a quorum of precommits for one block in any round
+        let decided = self.votes.iter().filter(|((_, kind), _)| *kind == VoteKind::Precommit).find_map(|((round, _), votes)| {
+            let mut tally: BTreeMap<H256, usize> = BTreeMap::new();
+            for hash in votes.values().filter_map(|v| v.block_hash) {
+                *tally.entry(hash).or_default() += 1;
+            }
+            tally.into_iter().find(|(_, n)| *n >= q).map(|(hash, _)| (*round, hash))
+        });
+        if let Some((round, block_hash)) = decided {
+            let precommits = self.votes[&(round, VoteKind::Precommit)]
+                .values()
+                .filter(|v| v.block_hash == Some(block_hash))
+                .cloned()
+                .collect();
+            self.decide(CommitCertificate { height: h, round, block_hash, precommits });
+            return true;
+        }
+
+        // Skip ahead when more than a third of validators are in a later round
+        let later = self.votes.keys().map(|(round, _)| *round).chain(self.proposals.keys().copied()).filter(|round| *round > r);
+        if let Some(round) = later.max() {
+            for round in (r + 1..=round).rev() {
+                let mut senders: BTreeSet<H256> = BTreeSet::new();
+                for kind in [VoteKind::Prevote, VoteKind::Precommit] {
+                    senders.extend(self.votes.get(&(round, kind)).into_iter().flat_map(|v| v.keys().map(|(voter, _)| *voter)));
+                }
+                senders.extend(self.proposals.get(&round).into_iter().flatten().map(|p| p.proposer));
+                if senders.len() >= honest_threshold(self.n()) {
+                    self.start_round(round);
+                    return true;
+                }
+            }
+        }
+
+        // Prevote on the round's (first) proposal
+        if self.step == Step::Propose {
+            if let Some((hash, valid_round)) = self.proposals.get(&r).and_then(|p| p.first()).map(|p| (p.block_hash, p.valid_round)) {
+                let acceptable = match valid_round {
+                    None => Some(self.locked.is_none_or(|(locked, _)| locked == hash)),
+                    Some(vr) if vr < r && self.count(vr, VoteKind::Prevote, Some(Some(hash))) >= q => {
+                        Some(self.locked.is_none_or(|(locked, lr)| lr <= vr || locked == hash))
+                    }
+                    // Waiting for the prevotes that justify the re-proposal
+                    Some(_) => None,
+                };
+                if let Some(acceptable) = acceptable {
+                    let vote = (acceptable && self.source.is_valid(h, &hash)).then_some(hash);
+                    self.vote(VoteKind::Prevote, vote);
+                    return true;
+                }
+            }
+        }
+
+        // Any prevote quorum: give the round a little longer
+        if self.step == Step::Prevote && self.count(r, VoteKind::Prevote, None) >= q && self.prevote_timeout_set.insert(r) {
+            self.schedule(TimeoutKind::Prevote, self.timeout(self.cfg.timeout_prevote, r));
+            return true;
+        }
+
+        // Prevote quorum for a proposal: lock and precommit
+        let proposed: Vec<H256> = self.proposals.get(&r).into_iter().flatten().map(|p| p.block_hash).collect();
+        for hash in proposed {
+            if self.step >= Step::Prevote
+                && self.count(r, VoteKind::Prevote, Some(Some(hash))) >= q
+                && self.source.is_valid(h, &hash)
+                && self.prevote_quorum_seen.insert(r)
+            {
+                if self.step == Step::Prevote {
+                    self.locked = Some((hash, r));
+                    self.vote(VoteKind::Precommit, Some(hash));
+                }
+                self.valid = Some((hash, r));
+                return true;
+            }
+        }
+
+        // Prevote quorum for nil
+        if self.step == Step::Prevote && self.count(r, VoteKind::Prevote, Some(None)) >= q {
+            self.vote(VoteKind::Precommit, None);
+            return true;
+        }
+
+        // Any precommit quorum: move on if nothing is decided in time
+        if self.count(r, VoteKind::Precommit, None) >= q && self.precommit_timeout_set.insert(r) {
+            self.schedule(TimeoutKind::Precommit, self.timeout(self.cfg.timeout_precommit, r));
+            return true;
+        }
+
+        false
+    }
+
+    fn decide(&mut self, cert: CommitCertificate) {
+        self.outputs.push(Output::Decide(cert.clone()));
+        self.height += 1;
+        self.commits.insert(cert.height, cert.clone());
+        while self.commits.len() > MAX_RETAINED_COMMITS {
+            self.commits.pop_first();
+        }
+        self.pending_commits.retain(|height, _| *height > cert.height);
+        self.last_commit = Some(cert);
+        self.locked = None;
+        self.valid = None;
+        self.proposals.clear();
+        self.votes.clear();
+        self.prevote_timeout_set.clear();
+        self.precommit_timeout_set.clear();
+        self.prevote_quorum_seen.clear();
+        self.start_round(0);
+
+        let (current, later): (Vec<Message>, Vec<Message>) =
+            std::mem::take(&mut self.future).into_iter().filter(|m| m.height() >= self.height).partition(|m| m.height() == self.height);
+        self.future = later;
+        for msg in current {
+            self.record(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certificate_requires_quorum_of_distinct_validators() {
+        let keys: Vec<(SecretKey, H256)> = (0..4).map(|_| STARK.generate_keys().unwrap()).collect();
+        let validators: Vec<H256> = keys.iter().map(|(_, pk)| *pk).collect();
+        let hash = [7u8; 32];
+        let precommit = |i: usize, hash: H256| Vote::new_signed(1, &keys[i].0, VoteKind::Precommit, 5, 0, Some(hash)).unwrap();
+
+        let cert = CommitCertificate { height: 5, round: 0, block_hash: hash, precommits: (0..3).map(|i| precommit(i, hash)).collect() };
+        cert.verify(1, &validators).unwrap();
+        assert!(cert.verify(2, &validators).is_err(), "signed for another chain");
+
+        let short = CommitCertificate { precommits: cert.precommits[..2].to_vec(), ..cert.clone() };
+        assert!(short.verify(1, &validators).is_err());
+        let mut duplicated = short.clone();
+        duplicated.precommits.push(precommit(0, hash));
+        assert!(duplicated.verify(1, &validators).is_err());
+        let mut mixed = short;
+        mixed.precommits.push(precommit(3, [8u8; 32]));
+        assert!(mixed.verify(1, &validators).is_err());
+    }
+}
@@ -0,0 +1,368 @@
+//! In-process network for exercising the finality gadget.
+//!
+//! `Simulation` runs N validators against a simulated clock and an in-memory
+//! message bus. Every message gets a random delay between `min_delay` and
+//! `max_delay`, may be dropped (`drop_ppm`), and passes a link filter that tests
+//! use to partition the network. Validators can be honest, silent, or
+//! equivocating: an equivocator runs the protocol but sends conflicting signed
+//! proposals and votes to the two halves of the network.
+//!
+//! The RNG is seeded, and events at the same instant run in the order they were
+//! scheduled, so a run is fully reproducible from its `SimConfig`.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use dxid_crypto::SecretKey;
+
+use super::{
+    BlockSource, CommitCertificate, FinalityConfig, FinalityNode, Input, Message, Output, Proposal, Round, Vote,
+};
+use crate::H256;
+
+pub const SIM_CHAIN_ID: u32 = 7_777;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Behaviour {
+    Honest,
+    /// Never sends anything
+    Silent,
+    /// Sends conflicting proposals and votes to different halves of the network
+    Equivocate,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub behaviours: Vec<Behaviour>,
+    pub seed: u64,
+    /// Message delay bounds in milliseconds
+    pub min_delay: u64,
+    pub max_delay: u64,
+    /// Messages lost, in parts per million
+    pub drop_ppm: u32,
+}
+
+impl SimConfig {
+    pub fn honest(validators: usize) -> Self {
+        Self { behaviours: vec![Behaviour::Honest; validators], seed: 1, min_delay: 5, max_delay: 50, drop_ppm: 0 }
+    }
+
+    pub fn with(mut self, index: usize, behaviour: Behaviour) -> Self {
+        self.behaviours[index] = behaviour;
+        self
+    }
+}
+
+/// Each proposer makes up its own block hash; every hash is valid.
+struct SimBlocks {
+    index: usize,
+}
+
+impl BlockSource for SimBlocks {
+    fn propose(&mut self, height: u64, round: Round) -> H256 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"dxid-sim-block");
+        hasher.update(&height.to_le_bytes());
+        hasher.update(&round.to_le_bytes());
+        hasher.update(&(self.index as u64).to_le_bytes());
+        *hasher.finalize().as_bytes()
+    }
+
+    fn is_valid(&self, _height: u64, _block_hash: &H256) -> bool {
+        true
+    }
+}
+
+enum Participant {
+    Honest(FinalityNode<SimBlocks>),
+    Silent,
+    Equivocator(FinalityNode<SimBlocks>),
+}
+
+/// Whether a message sent at `time` from one validator index to another gets through
+pub type LinkFilter = Box<dyn FnMut(u64, usize, usize) -> bool>;
+
+pub struct Simulation {
+    cfg: SimConfig,
+    keys: Vec<SecretKey>,
+    validators: Vec<H256>,
+    participants: Vec<Participant>,
+    /// (time, sequence) -> (recipient, input)
+    queue: BTreeMap<(u64, u64), (usize, Input)>,
+    seq: u64,
+    now: u64,
+    rng: u64,
+    link: Option<LinkFilter>,
+    decisions: Vec<Vec<CommitCertificate>>,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl Simulation {
+    pub fn new(cfg: SimConfig) -> Result<Self> {
+        let n = cfg.behaviours.len();
+        if n == 0 || cfg.min_delay > cfg.max_delay {
+            bail!("a simulation needs validators and min_delay <= max_delay");
+        }
+        let keys: Vec<SecretKey> = (0..n as u64)
+            .map(|i| {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(b"dxid-sim-key");
+                hasher.update(&cfg.seed.to_le_bytes());
+                hasher.update(&i.to_le_bytes());
+                SecretKey { bytes: *hasher.finalize().as_bytes() }
+            })
+            .collect();
+        // Dev engine addresses are the hash of the secret
+        let validators: Vec<H256> = keys.iter().map(|k| *blake3::hash(&k.bytes).as_bytes()).collect();
+        let finality = FinalityConfig::new(SIM_CHAIN_ID, validators.clone());
+
+        let mut participants = Vec::with_capacity(n);
+        for (index, (key, behaviour)) in keys.iter().zip(&cfg.behaviours).enumerate() {
+            let node = || FinalityNode::new(finality.clone(), key.clone(), SimBlocks { index }, 1);
+            participants.push(match behaviour {
+                Behaviour::Honest => Participant::Honest(node()?),
+                Behaviour::Silent => Participant::Silent,
+                Behaviour::Equivocate => Participant::Equivocator(node()?),
+            });
+        }
+
+        let mut sim = Self {
+            rng: cfg.seed.max(1),
+            cfg,
+            keys,
+            validators,
+            participants,
+            queue: BTreeMap::new(),
+            seq: 0,
+            now: 0,
+            link: None,
+            decisions: vec![Vec::new(); n],
+            delivered: 0,
+            dropped: 0,
+        };
+        for i in 0..n {
+            sim.push(0, i, Input::Start);
+        }
+        Ok(sim)
+    }
+
+    /// Install a filter deciding which links are up at a given time.
+    pub fn set_link_filter(&mut self, filter: impl FnMut(u64, usize, usize) -> bool + 'static) {
+        self.link = Some(Box::new(filter));
+    }
+
+    pub fn validators(&self) -> &[H256] {
+        &self.validators
+    }
+
+    /// Simulated milliseconds since the start
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// (delivered, dropped) message counts
+    pub fn traffic(&self) -> (u64, u64) {
+        (self.delivered, self.dropped)
+    }
+
+    /// Heights decided by validator `index`, in order (always empty for Byzantine validators).
+    pub fn decisions(&self, index: usize) -> &[CommitCertificate] {
+        &self.decisions[index]
+    }
+
+    pub fn honest(&self) -> impl Iterator<Item = usize> + '_ {
+        self.cfg.behaviours.iter().enumerate().filter(|(_, b)| **b == Behaviour::Honest).map(|(i, _)| i)
+    }
+
+    /// Lowest height every honest validator has decided
+    pub fn finalized_height(&self) -> u64 {
+        self.honest().map(|i| self.decisions[i].len() as u64).min().unwrap_or(0)
+    }
+
+    /// Run until every honest validator decided `height`. False if simulated time passes `max_time` first.
+    pub fn run_until(&mut self, height: u64, max_time: u64) -> bool {
+        while self.finalized_height() < height {
+            match self.queue.first_key_value() {
+                Some(((time, _), _)) if *time <= max_time => self.step(),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Check that honest validators never decided different blocks, and that every
+    /// decision carries a valid certificate.
+    pub fn check_safety(&self) -> Result<()> {
+        let mut decided: BTreeMap<u64, (usize, H256)> = BTreeMap::new();
+        for i in self.honest() {
+            for (pos, cert) in self.decisions[i].iter().enumerate() {
+                if cert.height != pos as u64 + 1 {
+                    bail!("validator {} decided height {} out of order", i, cert.height);
+                }
+                cert.verify(SIM_CHAIN_ID, &self.validators)?;
+                let (first, hash) = *decided.entry(cert.height).or_insert((i, cert.block_hash));
+                if hash != cert.block_hash {
+                    bail!(
+                        "validators {} and {} decided different blocks at height {}: {} vs {}",
+                        first,
+                        i,
+                        cert.height,
+                        hex::encode(hash),
+                        hex::encode(cert.block_hash)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) {
+        let Some(((time, _), (index, input))) = self.queue.pop_first() else { return };
+        self.now = time;
+        let outputs = match &mut self.participants[index] {
+            Participant::Honest(node) | Participant::Equivocator(node) => node.handle(input),
+            Participant::Silent => return,
+        };
+        for output in outputs {
+            match output {
+                Output::Broadcast(msg) => self.broadcast(index, msg),
+                Output::Send { to: peer, msg } => {
+                    if let Some(peer) = self.validators.iter().position(|v| *v == peer) {
+                        self.send(index, peer, msg);
+                    }
+                }
+                Output::Schedule { timeout, after } => self.push(self.now + after, index, Input::Timeout(timeout)),
+                Output::Decide(cert) => {
+                    if let Participant::Honest(_) = self.participants[index] {
+                        self.decisions[index].push(cert);
+                    }
+                }
+            }
+        }
+    }
+
+    fn broadcast(&mut self, from: usize, msg: Message) {
+        let n = self.participants.len();
+        let forged = match self.participants[from] {
+            Participant::Equivocator(_) => Some(self.forge(from, &msg)),
+            _ => None,
+        };
+        for to in (0..n).filter(|to| *to != from) {
+            let msg = match &forged {
+                Some(forged) if to >= n / 2 => forged.clone(),
+                _ => msg.clone(),
+            };
+            self.send(from, to, msg);
+        }
+    }
+
+    /// Same message signed by `from`, for a different block.
+    fn forge(&self, from: usize, msg: &Message) -> Message {
+        let key = &self.keys[from];
+        let fake = |hash: Option<H256>| {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(b"dxid-sim-forged");
+            hasher.update(&hash.unwrap_or_default());
+            *hasher.finalize().as_bytes()
+        };
+        let forged = match msg {
+            Message::Vote(v) => Vote::new_signed(SIM_CHAIN_ID, key, v.kind, v.height, v.round, Some(fake(v.block_hash)))
+                .map(Message::Vote),
+            Message::Proposal(p) => Proposal::new_signed(
+                SIM_CHAIN_ID,
+                key,
+                p.height,
+                p.round,
+                fake(Some(p.block_hash)),
+                p.valid_round,
+                p.last_commit.clone(),
+            )
+            .map(Message::Proposal),
+            Message::Commit(_) => Ok(msg.clone()),
+        };
+        forged.unwrap_or_else(|_| msg.clone())
+    }
+
+    fn send(&mut self, from: usize, to: usize, msg: Message) {
+        let now = self.now;
+        let link_up = self.link.as_mut().is_none_or(|link| link(now, from, to));
+        if !link_up || (self.next_rand() % 1_000_000) < self.cfg.drop_ppm as u64 {
+            self.dropped += 1;
+            return;
+        }
+        let delay = self.cfg.min_delay + self.next_rand() % (self.cfg.max_delay - self.cfg.min_delay + 1);
+        self.delivered += 1;
+        self.push(now + delay, to, Input::Message(msg));
+    }
+
+    fn push(&mut self, time: u64, to: usize, input: Input) {
+        self.seq += 1;
+        self.queue.insert((time, self.seq), (to, input));
+    }
+
+    /// xorshift64*
+    fn next_rand(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_honest_validators_finalize_every_height() {
+        let mut sim = Simulation::new(SimConfig::honest(4)).unwrap();
+        assert!(sim.run_until(10, 60_000), "stalled at {}", sim.finalized_height());
+        sim.check_safety().unwrap();
+        // A healthy network decides in the first round
+        assert!(sim.decisions(0).iter().all(|cert| cert.round == 0));
+    }
+
+    #[test]
+    fn test_silent_validator_does_not_stop_finality() {
+        let mut sim = Simulation::new(SimConfig::honest(4).with(1, Behaviour::Silent)).unwrap();
+        assert!(sim.run_until(8, 120_000), "stalled at {}", sim.finalized_height());
+        sim.check_safety().unwrap();
+        // Its turns to propose end in a round change
+        assert!(sim.decisions(0).iter().any(|cert| cert.round > 0));
+    }
+
+    #[test]
+    fn test_equivocator_with_lossy_network_stays_safe() {
+        let cfg = SimConfig { seed: 42, min_delay: 10, max_delay: 300, drop_ppm: 100_000, ..SimConfig::honest(4) };
+        let mut sim = Simulation::new(cfg.with(0, Behaviour::Equivocate)).unwrap();
+        assert!(sim.run_until(6, 600_000), "stalled at {}", sim.finalized_height());
+        sim.check_safety().unwrap();
+        assert!(sim.traffic().1 > 0);
+    }
+
+    #[test]
+    fn test_partition_blocks_finality_until_it_heals() {
+        // 7 validators tolerate 2 faults; both equivocators reach every side
+        let cfg = SimConfig { seed: 9, ..SimConfig::honest(7) }.with(0, Behaviour::Equivocate).with(1, Behaviour::Equivocate);
+        let mut sim = Simulation::new(cfg).unwrap();
+        const HEAL: u64 = 20_000;
+        sim.set_link_filter(|time, from, to| time >= HEAL || from < 2 || to < 2 || (from < 4) == (to < 4));
+
+        assert!(!sim.run_until(1, HEAL - 1), "a minority side finalized a block");
+        assert!(sim.run_until(5, HEAL + 300_000), "stalled at {}", sim.finalized_height());
+        sim.check_safety().unwrap();
+    }
+
+    #[test]
+    fn test_runs_are_deterministic() {
+        let run = || {
+            let cfg = SimConfig { seed: 5, drop_ppm: 50_000, ..SimConfig::honest(4) }.with(3, Behaviour::Equivocate);
+            let mut sim = Simulation::new(cfg).unwrap();
+            assert!(sim.run_until(4, 300_000));
+            let hashes: Vec<H256> = sim.decisions(0).iter().map(|c| c.block_hash).collect();
+            (sim.now(), sim.traffic(), hashes)
+        };
+        assert_eq!(run(), run());
+    }
+}
@@ -0,0 +1,326 @@
+//! Genesis specification (`genesis.json`).
+//!
+//! The spec is the single source of truth for a network's starting point: chain id,
+//! initial allocations per token, the initial validator set, monetary and staking
+//! parameters and the genesis timestamp. Every node loading the same spec builds the same
+//! genesis state and derives the same genesis hash.
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, path::Path, sync::Arc};
+
+use crate::{dehex32, fees::FeeParams, monetary::{MonetaryParams, PolicyKind}, staking::StakingParams, Account, State, TokenType, CHAIN_ID, H256, LAYER0_FAUCET_AMOUNT};
+
+/// Domain tag for the genesis hash
+const GENESIS_DOMAIN: &[u8] = b"dxid-genesis-v1";
+/// Version of the spec encoding `genesis_hash` hashes
+const GENESIS_ENCODING_VERSION: u8 = 1;
+
+/// Initial balances of one account
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct GenesisAllocation {
+    /// hex(32) account address
+    pub address: String,
+    #[serde(default)]
+    pub native: u128,
+    #[serde(default)]
+    pub layer0: u128,
+    #[serde(default)]
+    pub longyield: u128,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct GenesisSpec {
+    pub chain_id: u32,
+    /// Unix seconds
+    pub timestamp: u64,
+    #[serde(default)]
+    pub allocations: Vec<GenesisAllocation>,
+    /// hex(32) validator addresses; order is significant (proposer rotation).
+    /// They form the active set until staked validators take over.
+    #[serde(default)]
+    pub validators: Vec<String>,
+    #[serde(default)]
+    pub monetary: MonetaryParams,
+    #[serde(default)]
+    pub staking: StakingParams,
+}
+
+impl GenesisSpec {
+    /// Single-operator dev network funding `faucet` and making it the only validator.
+    pub fn dev(faucet: H256, timestamp: u64) -> Self {
+        Self {
+            chain_id: CHAIN_ID,
+            timestamp,
+            allocations: vec![GenesisAllocation {
+                address: hex::encode(faucet),
+                native: 1_000_000_000_000,
+                layer0: LAYER0_FAUCET_AMOUNT,
+                longyield: 0,
+            }],
+            validators: vec![hex::encode(faucet)],
+            monetary: MonetaryParams::default(),
+            staking: StakingParams::default(),
+        }
+    }
+
+    /// Read and validate a spec file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let txt = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read genesis spec {}", path.display()))?;
+        let spec: GenesisSpec = serde_json::from_str(&txt)
+            .context("Failed to parse genesis spec")?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    /// Validate and write the canonical form of the spec.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.validate()?;
+        fs::write(path, serde_json::to_string_pretty(&self.canonical())?)
+            .with_context(|| format!("Failed to write genesis spec {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.chain_id == 0 {
+            anyhow::bail!("chain_id must be non-zero");
+        }
+        if self.monetary.layer0_halving_blocks == 0 {
+            anyhow::bail!("layer0_halving_blocks must be non-zero");
+        }
+        self.monetary.fees.validate()?;
+        self.staking.validate()?;
+
+        let mut seen = HashSet::new();
+        let (mut layer0, mut longyield) = (0u128, 0u128);
+        for alloc in &self.allocations {
+            let Some(addr) = dehex32(&alloc.address.to_lowercase()) else {
+                anyhow::bail!("invalid allocation address {}", alloc.address);
+            };
+            if !seen.insert(addr) {
+                anyhow::bail!("duplicate allocation for {}", alloc.address);
+            }
+            layer0 = layer0.checked_add(alloc.layer0).context("layer0 allocations overflow")?;
+            longyield = longyield.checked_add(alloc.longyield).context("longyield allocations overflow")?;
+        }
+        if layer0 > self.monetary.layer0_total_supply {
+            anyhow::bail!("layer0 allocations exceed total supply");
+        }
+        if longyield > self.monetary.longyield_total_supply {
+            anyhow::bail!("longyield allocations exceed total supply");
+        }
+
+        let mut seen = HashSet::new();
+        for v in &self.validators {
+            let Some(addr) = dehex32(&v.to_lowercase()) else {
+                anyhow::bail!("invalid validator address {}", v);
+            };
+            if !seen.insert(addr) {
+                anyhow::bail!("duplicate validator {}", v);
+            }
+        }
+        Ok(())
+    }
+
+    /// Normalized spec: lowercase hex, allocations sorted by address.
+    /// Validator order is kept since it defines proposer rotation.
+    pub fn canonical(&self) -> Self {
+        let mut spec = self.clone();
+        for alloc in &mut spec.allocations {
+            alloc.address = alloc.address.to_lowercase();
+        }
+        spec.allocations.sort_by(|a, b| a.address.cmp(&b.address));
+        for v in &mut spec.validators {
+            *v = v.to_lowercase();
+        }
+        spec
+    }
+
+    pub fn validator_addresses(&self) -> Vec<H256> {
+        self.validators.iter().filter_map(|v| dehex32(&v.to_lowercase())).collect()
+    }
+
+    /// Build the genesis state. Its `genesis_hash` and `last_block_hash` are set to
+    /// the genesis hash, so block 1 links to genesis.
+    pub fn build_state(&self) -> Result<State> {
+        self.validate()?;
+        let spec = self.canonical();
+
+        let mut state = State::with_monetary(spec.monetary.clone());
+        state.chain_id = spec.chain_id;
+        state.validators = spec.validator_addresses();
+        state.staking = spec.staking.clone();
+        for alloc in &spec.allocations {
+            let addr = dehex32(&alloc.address).context("invalid allocation address")?;
+            let mut acct = Account::default();
+            for (token, amount) in [
+                (TokenType::Native, alloc.native),
+                (TokenType::Layer0, alloc.layer0),
+                (TokenType::LongYield, alloc.longyield),
+            ] {
+                // validate() already checked the allocations against the caps
+                let issued = state.issue(token, amount);
+                acct.credit(token.asset_id(), issued);
+            }
+            state.set_account(addr, &acct);
+        }
+        state.commit_root();
+
+        let hash = genesis_hash(&spec, &state.state_root)?;
+        state.genesis_hash = hash;
+        state.last_block_hash = hash;
+        Ok(state)
+    }
+
+    /// Canonical genesis hash: H(domain || encoding version || spec fields || genesis
+    /// state root), see `genesis_hash`.
+    pub fn hash(&self) -> Result<H256> {
+        Ok(self.build_state()?.genesis_hash)
+    }
+
+    pub fn into_shared_state(&self) -> Result<Arc<Mutex<State>>> {
+        Ok(Arc::new(Mutex::new(self.build_state()?)))
+    }
+}
+
+/// Hash the canonical spec field by field, in a fixed binary layout: integers
+/// little-endian, addresses as 32 bytes, lists prefixed by their length. Parameters
+/// added after the first networks launched (policy, fees, staking) are hashed, behind a
+/// tag, only when they differ from their defaults, so a spec that leaves them out keeps
+/// its hash. New spec fields must follow the same rule.
+fn genesis_hash(canonical: &GenesisSpec, state_root: &H256) -> Result<H256> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(GENESIS_DOMAIN);
+    hasher.update(&[GENESIS_ENCODING_VERSION]);
+    hasher.update(&canonical.chain_id.to_le_bytes());
+    hasher.update(&canonical.timestamp.to_le_bytes());
+    hasher.update(&(canonical.allocations.len() as u64).to_le_bytes());
+    for alloc in &canonical.allocations {
+        hasher.update(&dehex32(&alloc.address).context("invalid allocation address")?);
+        for amount in [alloc.native, alloc.layer0, alloc.longyield] {
+            hasher.update(&amount.to_le_bytes());
+        }
+    }
+    let validators = canonical.validator_addresses();
+    hasher.update(&(validators.len() as u64).to_le_bytes());
+    for v in &validators {
+        hasher.update(v);
+    }
+
+    let m = &canonical.monetary;
+    hasher.update(&m.layer0_total_supply.to_le_bytes());
+    hasher.update(&m.layer0_block_reward.to_le_bytes());
+    hasher.update(&m.layer0_halving_blocks.to_le_bytes());
+    hasher.update(&m.layer0_appreciation_rate.to_le_bytes());
+    hasher.update(&m.longyield_total_supply.to_le_bytes());
+    hasher.update(&m.longyield_block_reward.to_le_bytes());
+    if m.policy != PolicyKind::default() {
+        hasher.update(b"policy");
+        hasher.update(&[match m.policy {
+            PolicyKind::Legacy => 0,
+            PolicyKind::Halving => 1,
+        }]);
+    }
+    if m.fees != FeeParams::default() {
+        let f = &m.fees;
+        hasher.update(b"fees");
+        for bps in [f.burn_bps, f.proposer_bps, f.treasury_bps, f.max_block_txs] {
+            hasher.update(&bps.to_le_bytes());
+        }
+        match f.treasury_address() {
+            Some(addr) => hasher.update(&[1]).update(&addr),
+            None => hasher.update(&[0]),
+        };
+        hasher.update(&f.min_fee_floor.to_le_bytes());
+    }
+    if canonical.staking != StakingParams::default() {
+        let st = &canonical.staking;
+        hasher.update(b"staking");
+        hasher.update(&st.epoch_length.to_le_bytes());
+        hasher.update(&st.unbonding_blocks.to_le_bytes());
+        hasher.update(&st.min_stake.to_le_bytes());
+        hasher.update(&st.max_validators.to_le_bytes());
+        hasher.update(&st.slash_bps.to_le_bytes());
+    }
+    hasher.update(state_root);
+    Ok(*hasher.finalize().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> GenesisSpec {
+        let mut spec = GenesisSpec::dev([0xabu8; 32], 1_700_000_000);
+        spec.allocations.push(GenesisAllocation { address: hex::encode([0x01u8; 32]), native: 5, layer0: 6, longyield: 7 });
+        spec
+    }
+
+    #[test]
+    fn test_hash_is_stable_across_formatting_and_order() {
+        let a = spec();
+        let mut b = a.clone();
+        b.allocations.reverse();
+        b.allocations[0].address = b.allocations[0].address.to_uppercase();
+        assert_eq!(a.hash().unwrap(), b.hash().unwrap());
+
+        let reparsed: GenesisSpec = serde_json::from_str(&serde_json::to_string_pretty(&b).unwrap()).unwrap();
+        assert_eq!(a.hash().unwrap(), reparsed.hash().unwrap());
+
+        let mut c = a.clone();
+        c.monetary.layer0_block_reward += 1;
+        assert_ne!(a.hash().unwrap(), c.hash().unwrap());
+    }
+
+    #[test]
+    fn test_hash_of_a_fixed_spec_is_pinned() {
+        let json = r#"{
+            "chain_id": 1337,
+            "timestamp": 1700000000,
+            "allocations": [
+                { "address": "abababababababababababababababababababababababababababababababab", "native": 1000000, "layer0": 500 },
+                { "address": "0101010101010101010101010101010101010101010101010101010101010101", "longyield": 7 }
+            ],
+            "validators": ["abababababababababababababababababababababababababababababababab"],
+            "monetary": {
+                "layer0_total_supply": 1000000000,
+                "layer0_block_reward": 100,
+                "layer0_halving_blocks": 1000,
+                "layer0_appreciation_rate": 10,
+                "longyield_total_supply": 1000000000,
+                "longyield_block_reward": 50
+            }
+        }"#;
+        let spec: GenesisSpec = serde_json::from_str(json).unwrap();
+        assert_eq!(hex::encode(spec.hash().unwrap()), "15245162a605c6d2fcc3ef792c2ce0fc594b294e18dc88e0924ef190bc1cded8");
+
+        // Parameters spelled out at their defaults hash like absent ones
+        let mut explicit: serde_json::Value = serde_json::from_str(json).unwrap();
+        explicit["staking"] = serde_json::to_value(StakingParams::default()).unwrap();
+        explicit["monetary"]["fees"] = serde_json::to_value(FeeParams::default()).unwrap();
+        explicit["monetary"]["policy"] = "legacy".into();
+        let explicit: GenesisSpec = serde_json::from_value(explicit).unwrap();
+        assert_eq!(explicit.hash().unwrap(), spec.hash().unwrap());
+
+        let mut staked = spec.clone();
+        staked.staking.min_stake += 1;
+        assert_ne!(staked.hash().unwrap(), spec.hash().unwrap());
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_specs() {
+        let mut dup = spec();
+        dup.allocations.push(dup.allocations[0].clone());
+        assert!(dup.validate().is_err());
+
+        let mut over = spec();
+        over.allocations[0].layer0 = over.monetary.layer0_total_supply + 1;
+        assert!(over.validate().is_err());
+
+        let mut bad = spec();
+        bad.validators.push("zz".into());
+        assert!(bad.validate().is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! Hashed timelock contracts (HTLC).
+//!
+//! `LockHtlc` moves tokens from the sender into an escrow entry guarded by a
+//! SHA-256 hash lock (the hash Bitcoin and EVM HTLCs use) and an expiry height.
+//! Before expiry anyone holding the preimage can `ClaimHtlc`, which pays the
+//! recipient and records the preimage; from the expiry height on `RefundHtlc` pays
+//! the sender back. Either transaction may be submitted by anyone (a relayer, say),
+//! the funds only ever go to the recipient or the sender.
+//!
+//! Entries are committed to the state SMT under `htlc_key(id)` and stay there once
+//! settled, so the lock, and after a claim the revealed preimage, can be proven
+//! against a state root. That is what the counterparty of an atomic swap on
+//! another chain needs to complete its side without a trusted bridge.
+
+use dxid_smt::{SmtProof, SparseMerkleTree};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    assets::AssetId,
+    receipt::{BalanceChange, Receipt, TxError},
+    tx::Tx,
+    Account, Chain, State, TokenType, H256,
+};
+
+pub type HtlcId = H256;
+
+/// Longest accepted preimage
+pub const MAX_PREIMAGE_LEN: usize = 256;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HtlcStatus {
+    Locked,
+    /// Paid to the recipient; the preimage is hex-encoded
+    Claimed { preimage: String },
+    /// Paid back to the sender
+    Refunded,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Htlc {
+    pub sender: H256,
+    pub recipient: H256,
+    pub asset: AssetId,
+    pub amount: u128,
+    /// SHA-256 of the preimage
+    pub hash_lock: H256,
+    /// First block at which the entry can no longer be claimed, only refunded
+    pub expiry_height: u64,
+    pub status: HtlcStatus,
+}
+
+impl Htlc {
+    fn leaf_hash(&self) -> H256 {
+        *blake3::hash(&serde_json::to_vec(self).unwrap_or_default()).as_bytes()
+    }
+}
+
+/// Hash lock a preimage opens
+pub fn hash_lock(preimage: &[u8]) -> H256 {
+    Sha256::digest(preimage).into()
+}
+
+/// Id of the entry created by `sender`'s transaction with `nonce`
+pub fn htlc_id(sender: &H256, nonce: u64) -> HtlcId {
+    let mut buf = b"dxid-htlc-id".to_vec();
+    buf.extend_from_slice(sender);
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// SMT key of an escrow entry (domain-separated from accounts and other records)
+pub fn htlc_key(id: &HtlcId) -> H256 {
+    let mut buf = b"dxid-htlc".to_vec();
+    buf.extend_from_slice(id);
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// Check an escrow entry (or its absence) against a state root.
+pub fn verify_htlc(root: &H256, id: &HtlcId, htlc: Option<&Htlc>, proof: &SmtProof) -> bool {
+    let leaf = htlc.map(Htlc::leaf_hash);
+    SparseMerkleTree::verify(root, &htlc_key(id), leaf.as_ref(), proof)
+}
+
+impl State {
+    pub fn htlc(&self, id: &HtlcId) -> Option<&Htlc> {
+        self.htlcs.get(&hex::encode(id))
+    }
+
+    /// Write an escrow entry. Its leaf is updated by the next `commit_root`.
+    pub fn set_htlc(&mut self, id: HtlcId, htlc: Htlc) {
+        self.journal_htlc(&id);
+        self.htlcs.insert(hex::encode(id), htlc);
+        self.dirty_htlcs.insert(id);
+    }
+
+    pub(crate) fn flush_htlcs(&mut self) {
+        if self.dirty_htlcs.is_empty() {
+            return;
+        }
+        let updates: Vec<(H256, Option<H256>)> = std::mem::take(&mut self.dirty_htlcs)
+            .into_iter()
+            .map(|id| (htlc_key(&id), self.htlc(&id).map(Htlc::leaf_hash)))
+            .collect();
+        self.smt.update_many(updates);
+    }
+
+    /// Escrow entry with its SMT proof against the state root.
+    pub fn prove_htlc(&self, id: &HtlcId) -> (Option<Htlc>, SmtProof) {
+        let (_, proof) = self.smt.prove(&htlc_key(id));
+        (self.htlc(id).cloned(), proof)
+    }
+
+    /// Locked entry `id`, or why it cannot be settled
+    fn locked_htlc(&self, id: &HtlcId) -> std::result::Result<Htlc, TxError> {
+        let Some(htlc) = self.htlc(id) else { return Err(TxError::UnknownHtlc(*id)) };
+        if htlc.status != HtlcStatus::Locked {
+            return Err(TxError::HtlcSettled);
+        }
+        Ok(htlc.clone())
+    }
+}
+
+impl Chain {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn apply_lock_htlc(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        recipient: H256,
+        token: TokenType,
+        amount: u128,
+        hash_lock: H256,
+        expiry_height: u64,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let token = TokenType::from_asset_id(token.asset_id());
+        let asset = token.asset_id();
+        if st.asset(asset).is_none() {
+            return Err(TxError::UnknownAsset(asset));
+        }
+        if amount == 0 {
+            return Err(TxError::InvalidPayload("escrow amount must be non-zero".into()));
+        }
+        if expiry_height <= st.height + 1 {
+            return Err(TxError::InvalidPayload(format!("expiry {} is not after block {}", expiry_height, st.height + 1)));
+        }
+
+        let before = from_acct.balance(asset);
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, token != TokenType::Native, receipt)?;
+        if !from_acct.debit(asset, amount) {
+            return Err(TxError::InsufficientBalance { token });
+        }
+        let id = htlc_id(&tx.from, tx.nonce);
+        let htlc = Htlc { sender: tx.from, recipient, asset, amount, hash_lock, expiry_height, status: HtlcStatus::Locked };
+        st.set_htlc(id, htlc);
+        receipt.created_htlc = Some(id);
+        receipt.balance_changes.push(BalanceChange { address: tx.from, token, before, after: from_acct.balance(asset) });
+        Ok(())
+    }
+
+    pub(crate) fn apply_claim_htlc(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        id: HtlcId,
+        preimage: &[u8],
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let mut htlc = st.locked_htlc(&id)?;
+        let height = st.height + 1;
+        if height >= htlc.expiry_height {
+            return Err(TxError::HtlcExpired { expiry: htlc.expiry_height });
+        }
+        if preimage.len() > MAX_PREIMAGE_LEN || hash_lock(preimage) != htlc.hash_lock {
+            return Err(TxError::WrongPreimage);
+        }
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        Self::pay_out(st, tx, from_acct, htlc.recipient, htlc.asset, htlc.amount, receipt);
+        htlc.status = HtlcStatus::Claimed { preimage: hex::encode(preimage) };
+        st.set_htlc(id, htlc);
+        Ok(())
+    }
+
+    pub(crate) fn apply_refund_htlc(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        id: HtlcId,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let mut htlc = st.locked_htlc(&id)?;
+        if st.height + 1 < htlc.expiry_height {
+            return Err(TxError::HtlcNotExpired { expiry: htlc.expiry_height });
+        }
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        Self::pay_out(st, tx, from_acct, htlc.sender, htlc.asset, htlc.amount, receipt);
+        htlc.status = HtlcStatus::Refunded;
+        st.set_htlc(id, htlc);
+        Ok(())
+    }
+
+    /// Credit escrowed funds to `to`, which may be the transaction's own sender.
+    fn pay_out(st: &mut State, tx: &Tx, from_acct: &mut Account, to: H256, asset: AssetId, amount: u128, receipt: &mut Receipt) {
+        let token = TokenType::from_asset_id(asset);
+        if to == tx.from {
+            let before = from_acct.balance(asset);
+            from_acct.credit(asset, amount);
+            receipt.balance_changes.push(BalanceChange { address: to, token, before, after: from_acct.balance(asset) });
+        } else {
+            let mut to_acct = st.accounts.get(&hex::encode(to)).cloned().unwrap_or_default();
+            let before = to_acct.balance(asset);
+            to_acct.credit(asset, amount);
+            st.set_account(to, &to_acct);
+            receipt.balance_changes.push(BalanceChange { address: to, token, before, after: to_acct.balance(asset) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assets::LAYER0_ASSET, tx::TxPayload};
+    use dxid_crypto::{SecretKey, StarkSignEngine, ENGINE as STARK};
+
+    fn send(st: &mut State, sk: &SecretKey, pk: H256, payload: TxPayload) -> std::result::Result<Receipt, TxError> {
+        let nonce = st.accounts.get(&hex::encode(pk)).map_or(0, |a| a.nonce);
+        let tx = Tx::new_signed(sk, pk, nonce, 10, crate::CHAIN_ID, None, payload).unwrap();
+        let receipt = Chain::apply_tx(st, &tx)?;
+        // Settle the fee right away so the supply audit holds between txs
+        st.distribute_fees(TokenType::Native, receipt.fee.map_or(0, |f| f.amount), None);
+        Ok(receipt)
+    }
+
+    fn assert_supply_consistent(st: &State) {
+        assert!(st.audit_supply().iter().all(|a| a.consistent), "{:?}", st.audit_supply());
+    }
+
+    fn layer0(st: &State, addr: &H256) -> u128 {
+        st.accounts.get(&hex::encode(addr)).map_or(0, |a| a.balance(LAYER0_ASSET))
+    }
+
+    #[test]
+    fn test_claim_with_preimage_is_provable() {
+        let (alice_sk, alice) = STARK.generate_keys().unwrap();
+        let (relayer_sk, relayer) = STARK.generate_keys().unwrap();
+        let bob = [7u8; 32];
+        let mut st = State::new_with_genesis(vec![(alice, 1_000_000), (relayer, 1_000)]).lock().clone();
+        let start = layer0(&st, &alice);
+
+        let lock = TxPayload::LockHtlc {
+            recipient: bob,
+            token_type: TokenType::Layer0,
+            amount: 5_000,
+            hash_lock: hash_lock(b"secret"),
+            expiry_height: 10,
+        };
+        let id = send(&mut st, &alice_sk, alice, lock).unwrap().created_htlc.unwrap();
+        assert_eq!(layer0(&st, &alice), start - 5_000);
+        assert_supply_consistent(&st);
+
+        // Early refunds and wrong preimages fail; a relayer may claim on Bob's behalf
+        assert_eq!(send(&mut st, &alice_sk, alice, TxPayload::RefundHtlc { htlc: id }), Err(TxError::HtlcNotExpired { expiry: 10 }));
+        let wrong = TxPayload::ClaimHtlc { htlc: id, preimage: b"guess".to_vec() };
+        assert_eq!(send(&mut st, &relayer_sk, relayer, wrong), Err(TxError::WrongPreimage));
+        send(&mut st, &relayer_sk, relayer, TxPayload::ClaimHtlc { htlc: id, preimage: b"secret".to_vec() }).unwrap();
+        assert_eq!(layer0(&st, &bob), 5_000);
+        assert_supply_consistent(&st);
+        assert_eq!(send(&mut st, &relayer_sk, relayer, TxPayload::ClaimHtlc { htlc: id, preimage: b"secret".to_vec() }), Err(TxError::HtlcSettled));
+
+        // The revealed preimage is provable against the state root
+        let root = st.commit_root();
+        let (htlc, proof) = st.prove_htlc(&id);
+        let htlc = htlc.unwrap();
+        assert_eq!(htlc.status, HtlcStatus::Claimed { preimage: hex::encode(b"secret") });
+        assert!(verify_htlc(&root, &id, Some(&htlc), &proof));
+        let forged = Htlc { status: HtlcStatus::Locked, ..htlc };
+        assert!(!verify_htlc(&root, &id, Some(&forged), &proof));
+    }
+
+    #[test]
+    fn test_refund_after_expiry_and_undo() {
+        let (alice_sk, alice) = STARK.generate_keys().unwrap();
+        let mut st = State::new_with_genesis(vec![(alice, 1_000_000)]).lock().clone();
+        let root = st.commit_root();
+        st.begin_journal();
+        let lock = TxPayload::LockHtlc {
+            recipient: [7u8; 32],
+            token_type: TokenType::Native,
+            amount: 5_000,
+            hash_lock: hash_lock(b"secret"),
+            expiry_height: 3,
+        };
+        let id = send(&mut st, &alice_sk, alice, lock).unwrap().created_htlc.unwrap();
+        let native = |st: &State| st.accounts[&hex::encode(alice)].balance(crate::assets::NATIVE_ASSET);
+        assert_eq!(native(&st), 1_000_000 - 5_010);
+        assert_supply_consistent(&st);
+        st.commit_root();
+        let journal = st.take_journal().unwrap();
+
+        st.height = 2;
+        let late = TxPayload::ClaimHtlc { htlc: id, preimage: b"secret".to_vec() };
+        assert_eq!(send(&mut st, &alice_sk, alice, late), Err(TxError::HtlcExpired { expiry: 3 }));
+        let mut reverted = st.clone();
+        send(&mut st, &alice_sk, alice, TxPayload::RefundHtlc { htlc: id }).unwrap();
+        assert_eq!(native(&st), 1_000_000 - 20);
+        assert_eq!(st.htlc(&id).map(|h| h.status.clone()), Some(HtlcStatus::Refunded));
+        assert_supply_consistent(&st);
+
+        reverted.height = 1;
+        reverted.apply_undo(&journal).unwrap();
+        assert_eq!(reverted.state_root, root);
+        assert!(reverted.htlc(&id).is_none());
+    }
+}
@@ -0,0 +1,251 @@
+//! Importing blocks produced by other nodes.
+//!
+//! `Chain::import_block` checks a block against the current tip (height, parent
+//! hash, timestamp, proposer seal, parent certificate), re-executes its
+//! transactions on a scratch copy of the state and only swaps the copy in when
+//! the resulting rewards, state root and receipts root match the header and the
+//! block is stored. Any failure leaves the chain untouched and is reported as an
+//! `ImportError`.
+
+use std::fs;
+
+use thiserror::Error;
+
+use crate::{h_block_header, is_zero_hash, merkle, now_ts, receipt::{self, TxError}, Block, Chain, H256};
+
+/// How far ahead of the local clock a block timestamp may be
+pub const MAX_CLOCK_DRIFT_SECS: u64 = 15;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportOutcome {
+    /// The block extended the chain
+    Imported { height: u64, hash: H256, txs: usize },
+    /// The chain already holds this block
+    AlreadyKnown { height: u64, hash: H256 },
+}
+
+/// Why a block was rejected.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ImportError {
+    #[error("block {height} does not extend the tip at {tip}")]
+    UnknownParent { height: u64, tip: u64 },
+    #[error("block {height} conflicts with the block already at that height")]
+    ConflictsWithChain { height: u64 },
+    #[error("parent hash {} does not match the tip {}", hex::encode(got), hex::encode(expected))]
+    ParentMismatch { expected: H256, got: H256 },
+    #[error("timestamp {got} is before the parent's {parent}")]
+    TimestampBeforeParent { parent: u64, got: u64 },
+    #[error("timestamp {got} is ahead of the local clock {now}")]
+    TimestampInFuture { now: u64, got: u64 },
+    #[error("bad seal: {0}")]
+    BadSeal(String),
+    #[error("bad parent certificate: {0}")]
+    BadCommit(String),
+    #[error("{got} transactions, at most {max} per block")]
+    TooManyTxs { max: usize, got: usize },
+    #[error("transaction root does not match the transactions")]
+    TxRootMismatch,
+    #[error("transaction {index} rejected: {error}")]
+    TxRejected { index: usize, error: TxError },
+    #[error("rewards {got:?} differ from the header's {expected:?}")]
+    RewardMismatch { expected: (u128, u128), got: (u128, u128) },
+    #[error("state root {} differs from the header's {}", hex::encode(got), hex::encode(expected))]
+    StateRootMismatch { expected: H256, got: H256 },
+    #[error("receipts root {} differs from the header's {}", hex::encode(got), hex::encode(expected))]
+    ReceiptsRootMismatch { expected: H256, got: H256 },
+    #[error("storage: {0}")]
+    Storage(String),
+}
+
+impl ImportError {
+    fn storage(e: anyhow::Error) -> Self {
+        ImportError::Storage(e.to_string())
+    }
+}
+
+impl Chain {
+    /// Validate `block` against the tip and, if it checks out, execute and store it.
+    pub fn import_block(&self, block: Block) -> std::result::Result<ImportOutcome, ImportError> {
+        let mut st = self.state.lock();
+        let header = &block.header;
+        let (height, hash) = (header.height, h_block_header(header));
+
+        if height <= st.height {
+            let known = self.storage.load_block(height).map_err(ImportError::storage)?;
+            return match known {
+                Some(known) if h_block_header(&known.header) == hash => Ok(ImportOutcome::AlreadyKnown { height, hash }),
+                _ => Err(ImportError::ConflictsWithChain { height }),
+            };
+        }
+        if height != st.height + 1 {
+            return Err(ImportError::UnknownParent { height, tip: st.height });
+        }
+        if header.parent_hash != st.last_block_hash {
+            return Err(ImportError::ParentMismatch { expected: st.last_block_hash, got: header.parent_hash });
+        }
+
+        let parent_ts = match st.height {
+            0 => 0,
+            h => self.storage.load_block(h).map_err(ImportError::storage)?.map_or(0, |b| b.header.timestamp),
+        };
+        if header.timestamp < parent_ts {
+            return Err(ImportError::TimestampBeforeParent { parent: parent_ts, got: header.timestamp });
+        }
+        let now = now_ts();
+        if header.timestamp > now + MAX_CLOCK_DRIFT_SECS {
+            return Err(ImportError::TimestampInFuture { now, got: header.timestamp });
+        }
+
+        self.verify_seal(&st, &block).map_err(|e| ImportError::BadSeal(e.to_string()))?;
+        if let Some(cert) = &header.last_commit {
+            if cert.height != st.height || cert.block_hash != st.last_block_hash {
+                return Err(ImportError::BadCommit(format!("certificate is for block {}, not the parent", cert.height)));
+            }
+            cert.verify(st.chain_id, st.validators_at(cert.height)).map_err(|e| ImportError::BadCommit(e.to_string()))?;
+        }
+
+        let max = st.monetary.fees.max_block_txs as usize;
+        if block.txs.len() > max {
+            return Err(ImportError::TooManyTxs { max, got: block.txs.len() });
+        }
+        if merkle::tx_root(&block.txs) != header.tx_root {
+            return Err(ImportError::TxRootMismatch);
+        }
+
+        // Execute on a copy; the live state only changes once everything matched
+        let mut scratch = st.clone();
+        scratch.begin_journal();
+        let mut receipts = Vec::with_capacity(block.txs.len());
+        for (index, tx) in block.txs.iter().enumerate() {
+            let receipt = Self::apply_tx(&mut scratch, tx).map_err(|error| ImportError::TxRejected { index, error })?;
+            receipts.push(receipt);
+        }
+        let coinbase = (!is_zero_hash(&header.coinbase)).then_some(header.coinbase);
+        let rewards = Self::finish_block(&mut scratch, &mut receipts, coinbase);
+        if rewards != (header.layer0_reward, header.longyield_reward) {
+            return Err(ImportError::RewardMismatch { expected: (header.layer0_reward, header.longyield_reward), got: rewards });
+        }
+        if scratch.state_root != header.state_root {
+            return Err(ImportError::StateRootMismatch { expected: header.state_root, got: scratch.state_root });
+        }
+        let receipts_root = receipt::receipts_root(&receipts);
+        if receipts_root != header.receipts_root {
+            return Err(ImportError::ReceiptsRootMismatch { expected: header.receipts_root, got: receipts_root });
+        }
+        scratch.last_block_hash = hash;
+        let journal = scratch.take_journal();
+
+        if let Some(cert) = &header.last_commit {
+            if cert.height > scratch.finalized_height {
+                self.storage.save_commit(cert).map_err(ImportError::storage)?;
+                scratch.finalized_height = cert.height;
+            }
+        }
+        // The tip only moves once the block is stored
+        self.persist_block(&mut scratch, &block, &receipts, journal.as_ref()).map_err(ImportError::storage)?;
+        *st = scratch;
+        for tx in &block.txs {
+            let _ = fs::remove_file(self.mempool_dir.join(format!("{}.json", hex::encode(tx.hash()))));
+        }
+        println!("⛓  imported block h={} txs={} root={}", height, block.txs.len(), hex::encode(st.state_root));
+        Ok(ImportOutcome::Imported { height, hash, txs: block.txs.len() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tx::{Tx, TxPayload}, State, TokenType};
+    use dxid_crypto::{SecretKey, StarkSignEngine, ENGINE as STARK};
+    use std::sync::Arc;
+
+    fn chain_pair(tag: &str) -> (Arc<Chain>, Arc<Chain>, SecretKey, H256) {
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let make = |side: &str| {
+            let base = std::env::temp_dir().join(format!("dxid-import-{}-{}-{}-{}", tag, side, std::process::id(), now_ts()));
+            let _ = fs::remove_dir_all(&base);
+            Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base, 2000).unwrap())
+        };
+        (make("producer"), make("follower"), sk, pk)
+    }
+
+    fn transfer(chain: &Chain, sk: &SecretKey, from: H256, nonce: u64) {
+        let tx = Tx::new_signed(sk, from, nonce, 10, crate::CHAIN_ID, None, TxPayload::Transfer { to: [9u8; 32], amount: 100, token_type: TokenType::Native })
+            .unwrap();
+        let path = chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash())));
+        fs::write(path, serde_json::to_string(&tx).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_follower_replays_produced_blocks() {
+        let (producer, follower, sk, pk) = chain_pair("replay");
+        let mut blocks = Vec::new();
+        for nonce in 0..3 {
+            transfer(&producer, &sk, pk, nonce);
+            blocks.push(producer.make_block_once().unwrap().unwrap().0);
+        }
+        // A block whose proposer also rejected a transaction still replays, receipts root included
+        transfer(&producer, &sk, pk, 3);
+        let send = TxPayload::Transfer { to: [9u8; 32], amount: u128::MAX, token_type: TokenType::Native };
+        let broke = Tx::new_signed(&sk, pk, 4, 10, crate::CHAIN_ID, None, send).unwrap();
+        fs::write(producer.mempool_dir.join(format!("{}.json", hex::encode(broke.hash()))), serde_json::to_string(&broke).unwrap()).unwrap();
+        let (last, receipts) = producer.make_block_once().unwrap().unwrap();
+        assert_eq!((last.txs.len(), receipts.len()), (1, 2));
+
+        for block in &blocks {
+            let outcome = follower.import_block(block.clone()).unwrap();
+            assert!(matches!(outcome, ImportOutcome::Imported { txs: 1, .. }));
+        }
+        assert!(matches!(follower.import_block(last), Ok(ImportOutcome::Imported { txs: 1, .. })));
+        let (a, b) = (producer.state.lock().clone(), follower.state.lock().clone());
+        assert_eq!((b.height, b.state_root, b.last_block_hash), (a.height, a.state_root, a.last_block_hash));
+        assert_eq!(follower.load_block(2).unwrap().unwrap().header, blocks[1].header);
+
+        let again = follower.import_block(blocks[2].clone()).unwrap();
+        assert_eq!(again, ImportOutcome::AlreadyKnown { height: 3, hash: h_block_header(&blocks[2].header) });
+        // The follower can undo imported blocks like its own
+        follower.revert_to(1).unwrap();
+        assert_eq!(follower.state.lock().state_root, blocks[0].header.state_root);
+    }
+
+    #[test]
+    fn test_invalid_blocks_leave_the_chain_untouched() {
+        let (producer, follower, sk, pk) = chain_pair("reject");
+        transfer(&producer, &sk, pk, 0);
+        let (block, _) = producer.make_block_once().unwrap().unwrap();
+        let before = follower.state.lock().state_root;
+
+        let mut bad_root = block.clone();
+        bad_root.header.state_root = [7u8; 32];
+        assert!(matches!(follower.import_block(bad_root), Err(ImportError::StateRootMismatch { .. })));
+
+        let mut bad_receipts = block.clone();
+        bad_receipts.header.receipts_root = [7u8; 32];
+        assert!(matches!(follower.import_block(bad_receipts), Err(ImportError::ReceiptsRootMismatch { .. })));
+
+        let mut orphan = block.clone();
+        orphan.header.parent_hash = [1u8; 32];
+        assert!(matches!(follower.import_block(orphan), Err(ImportError::ParentMismatch { .. })));
+
+        let mut ahead = block.clone();
+        ahead.header.height = 5;
+        assert_eq!(follower.import_block(ahead), Err(ImportError::UnknownParent { height: 5, tip: 0 }));
+
+        let mut future = block.clone();
+        future.header.timestamp = now_ts() + 10 * MAX_CLOCK_DRIFT_SECS;
+        assert!(matches!(follower.import_block(future), Err(ImportError::TimestampInFuture { .. })));
+
+        let mut forged = block.clone();
+        if let TxPayload::Transfer { amount, .. } = &mut forged.txs[0].payload {
+            *amount = 900_000;
+        }
+        assert_eq!(follower.import_block(forged.clone()), Err(ImportError::TxRootMismatch));
+        forged.header.tx_root = merkle::tx_root(&forged.txs);
+        assert!(matches!(follower.import_block(forged), Err(ImportError::TxRejected { index: 0, error: TxError::BadSignature })));
+
+        let st = follower.state.lock().clone();
+        assert_eq!((st.height, st.state_root), (0, before));
+        assert!(follower.load_block(1).unwrap().is_none());
+        assert!(follower.import_block(block).is_ok());
+    }
+}
@@ -1,13 +1,40 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fs, path::{Path, PathBuf}, sync::Arc};
 
-use dxid_crypto::{StarkSignEngine, ENGINE as STARK};
 use dxid_smt::{H256, SparseMerkleTree, SmtProof};
 
 // Import the storage module
+pub mod archive;
+pub mod assets;
+pub mod backup;
+pub mod consensus;
+pub mod credentials;
+pub mod did;
+pub mod exec;
+pub mod genesis;
+pub mod htlc;
+pub mod import;
+pub mod fees;
+pub mod finality;
+pub mod merkle;
+pub mod monetary;
+pub mod receipt;
+pub mod snapshot;
+pub mod staking;
 pub mod storage;
+pub mod supply;
+pub mod tx;
+pub mod txindex;
+pub mod undo;
+pub mod vesting;
+pub mod wasm;
+pub use tx::{Tx, TxPayload, TX_VERSION};
+use assets::{AssetId, AssetInfo, NATIVE_ASSET};
+use consensus::{Consensus, RoundRobinPoa};
+use monetary::{MonetaryParams, MonetaryPolicy};
+use receipt::{BalanceChange, Receipt, TxError, TxStatus};
 use storage::{Storage, StorageConfig};
 
 pub const CHAIN_ID: u32 = 1337;
@@ -19,125 +46,325 @@ pub const LAYER0_HALVING_BLOCKS: u64 = 100_000; // Halve every 100,000 blocks
 pub const LAYER0_DECIMALS: u8 = 8; // 8 decimals like Bitcoin
 pub const LAYER0_ZERO_FEES: bool = true; // NO TRANSACTION FEES - PURE STORE OF VALUE
 pub const LAYER0_APPRECIATION_RATE: u64 = 1000; // 0.1% appreciation per block
+/// Layer0 funded to test faucets: 10 million with 8 decimals. It has to stay well
+/// inside `LAYER0_TOTAL_SUPPLY`, or the faucet takes the whole cap and leaves nothing
+/// for block rewards.
+pub const LAYER0_FAUCET_AMOUNT: u128 = 1_000_000_000_000_000;
 
 
 // LongYield L1 Token Constants
 pub const LONGYIELD_CHAIN_ID: u32 = 1338;
-pub const LONGYIELD_TOTAL_SUPPLY: u128 = 1_000_000_000_000_000_000; // 1 billion with 18 decimals
+/// 1 billion with 18 decimals. Caps are enforced since block rewards are issued, and
+/// 1e18 (one token) would end LongYield issuance after the first block's reward.
+pub const LONGYIELD_TOTAL_SUPPLY: u128 = 1_000_000_000_000_000_000_000_000_000;
 pub const LONGYIELD_DECIMALS: u8 = 18;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(from = "AccountRaw")]
 pub struct Account {
-    pub balance: u128,
     pub nonce: u64,
-    pub layer0_balance: u128, // Layer0 token balance
-    pub longyield_balance: u128, // LongYield L1 token balance
+    /// Balance per asset id (see `assets`); zero balances are not stored
+    pub balances: BTreeMap<AssetId, u128>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct Tx {
-    pub from: H256,
-    pub to: H256,
-    pub amount: u128,
-    pub fee: u128, // Only used for non-Layer0 tokens
-    pub signature: dxid_crypto::StarkSignature,
-    pub token_type: TokenType, // Which token to transfer
-    pub cross_chain: bool, // Is this a cross-chain transaction?
-    pub target_chain_id: Option<u32>, // Target chain for cross-chain txs
+impl Account {
+    pub fn balance(&self, asset: AssetId) -> u128 {
+        self.balances.get(&asset).copied().unwrap_or(0)
+    }
+
+    pub fn credit(&mut self, asset: AssetId, amount: u128) {
+        if amount > 0 {
+            let bal = self.balances.entry(asset).or_insert(0);
+            *bal = bal.saturating_add(amount);
+        }
+    }
+
+    /// Take `amount` from the balance; false (and unchanged) if it is too small.
+    pub fn debit(&mut self, asset: AssetId, amount: u128) -> bool {
+        let bal = self.balance(asset);
+        if bal < amount {
+            return false;
+        }
+        if bal == amount {
+            self.balances.remove(&asset);
+        } else {
+            self.balances.insert(asset, bal - amount);
+        }
+        true
+    }
+
+    /// Balance held in the given token
+    pub fn token_balance(&self, token: TokenType) -> u128 {
+        self.balance(token.asset_id())
+    }
+
+    /// SMT leaf value: H(nonce || (asset id || balance) for every non-zero balance)
+    pub fn leaf_hash(&self) -> H256 {
+        let mut account_data = Vec::with_capacity(8 + self.balances.len() * 20);
+        account_data.extend_from_slice(&self.nonce.to_le_bytes());
+        for (asset, bal) in self.balances.iter().filter(|(_, b)| **b > 0) {
+            account_data.extend_from_slice(&asset.to_le_bytes());
+            account_data.extend_from_slice(&bal.to_le_bytes());
+        }
+        *blake3::hash(&account_data).as_bytes()
+    }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+/// Account layout on disk; also accepts the pre-registry fields
+/// (`balance`, `layer0_balance`, `longyield_balance`).
+#[derive(Deserialize)]
+struct AccountRaw {
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    balances: BTreeMap<AssetId, u128>,
+    #[serde(default)]
+    balance: u128,
+    #[serde(default)]
+    layer0_balance: u128,
+    #[serde(default)]
+    longyield_balance: u128,
+}
+
+impl From<AccountRaw> for Account {
+    fn from(raw: AccountRaw) -> Self {
+        let mut acct = Account { nonce: raw.nonce, balances: BTreeMap::new() };
+        for (asset, bal) in raw.balances {
+            acct.credit(asset, bal);
+        }
+        acct.credit(assets::NATIVE_ASSET, raw.balance);
+        acct.credit(assets::LAYER0_ASSET, raw.layer0_balance);
+        acct.credit(assets::LONGYIELD_ASSET, raw.longyield_balance);
+        acct
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum TokenType {
     Layer0, // Store of value token (Bitcoin-like)
     LongYield, // L1 token
     Native, // Legacy native token
+    /// Any asset from the registry, by id
+    Asset(AssetId),
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct BlockHeader {
     pub height: u64,
     pub timestamp: u64,
     pub tx_root: H256,
     pub state_root: H256,
+    #[serde(default)]
+    pub receipts_root: H256,
     pub layer0_reward: u128, // Layer0 block reward
     pub longyield_reward: u128, // LongYield block reward
+    /// Account credited with the block rewards and the proposer's fee share (zero if none)
+    #[serde(default)]
+    pub coinbase: H256,
+    /// Hash of the parent header (genesis hash for block 1; zero on pre-linkage blocks)
+    #[serde(default, skip_serializing_if = "is_zero_hash")]
+    pub parent_hash: H256,
+    /// Finality certificate of the parent block, when the proposer had one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_commit: Option<finality::CommitCertificate>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Block {
     pub header: BlockHeader,
     pub txs: Vec<Tx>,
+    /// Proposer signature (None on chains without validators)
+    #[serde(default)]
+    pub seal: Option<consensus::Seal>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct State {
+    #[serde(default)]
     pub accounts: HashMap<String, Account>, // key: hex(addr)
     pub height: u64,
     pub last_block_hash: H256,
     pub state_root: H256,
-    pub layer0_circulating: u128, // Total Layer0 tokens in circulation
-    pub longyield_circulating: u128, // Total LongYield tokens in circulation
+    /// Asset registry (symbol, decimals, cap, issuer, supply per asset id)
+    #[serde(default)]
+    pub assets: BTreeMap<AssetId, AssetInfo>,
+    // Pre-registry supply counters, only read by `migrate_legacy_supply`
+    #[serde(default, rename = "layer0_circulating", skip_serializing)]
+    legacy_layer0_circulating: u128,
+    #[serde(default, rename = "longyield_circulating", skip_serializing)]
+    legacy_longyield_circulating: u128,
+    #[serde(default, rename = "native_circulating", skip_serializing)]
+    legacy_native_circulating: u128,
+    /// Hash of the genesis spec this state descends from (zero for pre-spec data dirs)
+    #[serde(default)]
+    pub genesis_hash: H256,
+    #[serde(default = "default_chain_id")]
+    pub chain_id: u32,
+    #[serde(default)]
+    pub monetary: MonetaryParams,
+    /// Active validator set, in proposer order
+    #[serde(default)]
+    pub validators: Vec<H256>,
+    /// Active set before the last epoch rotation (certificates of older blocks)
+    #[serde(default)]
+    pub previous_validators: Vec<H256>,
+    /// First block proposed by the current active set (0 = genesis set)
+    #[serde(default)]
+    pub validators_since: u64,
+    #[serde(default)]
+    pub staking: staking::StakingParams,
+    /// Validator registry, keyed by hex(addr)
+    #[serde(default)]
+    pub validator_registry: BTreeMap<String, staking::ValidatorRecord>,
+    /// Vesting schedules, keyed by hex(id)
+    #[serde(default)]
+    pub vesting: BTreeMap<String, vesting::VestingSchedule>,
+    /// HTLC escrow entries, keyed by hex(id)
+    #[serde(default)]
+    pub htlcs: BTreeMap<String, htlc::Htlc>,
+    /// DID documents, keyed by hex(id)
+    #[serde(default)]
+    pub dids: BTreeMap<String, did::DidDocument>,
+    /// Revoked credentials: hex(issuer) -> hex(credential id) -> revocation height
+    #[serde(default)]
+    pub revocations: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Current minimum fee for LongYield and native transfers
+    #[serde(default)]
+    pub min_fee: u128,
+    /// Transaction counts of the most recent blocks (fee market input)
+    #[serde(default)]
+    pub recent_block_txs: Vec<u32>,
+    /// Highest block with a commit certificate; it and its ancestors are never reverted
+    #[serde(default)]
+    pub finalized_height: u64,
+    /// Deployed WASM modules, keyed by hex(id)
+    #[serde(default)]
+    pub modules: BTreeMap<String, wasm::ModuleInfo>,
+    /// Module storage: hex(id) -> hex(key) -> hex(value)
+    #[serde(default)]
+    pub module_storage: BTreeMap<String, BTreeMap<String, String>>,
     #[serde(skip)]
     smt: SparseMerkleTree,
+    /// Accounts written since the last `commit_root`
+    #[serde(skip)]
+    dirty_accounts: BTreeSet<H256>,
+    /// Registry records changed since the last `commit_root`
+    #[serde(skip)]
+    dirty_assets: BTreeSet<AssetId>,
+    /// Per-module storage trees, rebuilt from `module_storage` on load
+    #[serde(skip)]
+    module_smts: BTreeMap<wasm::ModuleId, SparseMerkleTree>,
+    /// Modules (and their storage slots) changed since the last `commit_root`
+    #[serde(skip)]
+    dirty_modules: BTreeMap<wasm::ModuleId, BTreeSet<String>>,
+    /// Registry records changed since the last `commit_root`
+    #[serde(skip)]
+    dirty_validators: BTreeSet<H256>,
+    /// Vesting schedules changed since the last `commit_root`
+    #[serde(skip)]
+    dirty_vesting: BTreeSet<vesting::VestingId>,
+    /// Escrow entries changed since the last `commit_root`
+    #[serde(skip)]
+    dirty_htlcs: BTreeSet<htlc::HtlcId>,
+    /// DID documents changed since the last `commit_root`
+    #[serde(skip)]
+    dirty_dids: BTreeSet<did::DidId>,
+    /// Per-issuer revocation trees, rebuilt from `revocations` on load
+    #[serde(skip)]
+    revocation_smts: BTreeMap<H256, SparseMerkleTree>,
+    /// Issuers (and their credentials) changed since the last `commit_root`
+    #[serde(skip)]
+    dirty_revocations: BTreeMap<H256, BTreeSet<H256>>,
+    /// Undo journal of the block being built
+    #[serde(skip)]
+    journal: Option<undo::UndoJournal>,
+}
+
+fn default_chain_id() -> u32 {
+    CHAIN_ID
 }
 
 impl State {
+    /// State with no accounts and the default chain parameters.
+    pub fn empty() -> Self {
+        Self::with_monetary(MonetaryParams::default())
+    }
+
+    /// State with no accounts, the given monetary parameters and the protocol assets registered.
+    pub fn with_monetary(monetary: MonetaryParams) -> Self {
+        let mut state = Self {
+            accounts: HashMap::new(),
+            height: 0,
+            last_block_hash: [0u8; 32],
+            state_root: [0u8; 32],
+            assets: BTreeMap::new(),
+            legacy_layer0_circulating: 0,
+            legacy_longyield_circulating: 0,
+            legacy_native_circulating: 0,
+            genesis_hash: [0u8; 32],
+            chain_id: CHAIN_ID,
+            monetary,
+            validators: Vec::new(),
+            previous_validators: Vec::new(),
+            validators_since: 0,
+            staking: staking::StakingParams::default(),
+            validator_registry: BTreeMap::new(),
+            vesting: BTreeMap::new(),
+            htlcs: BTreeMap::new(),
+            dids: BTreeMap::new(),
+            revocations: BTreeMap::new(),
+            min_fee: 0,
+            recent_block_txs: Vec::new(),
+            finalized_height: 0,
+            smt: SparseMerkleTree::new(),
+            modules: BTreeMap::new(),
+            module_storage: BTreeMap::new(),
+            dirty_accounts: BTreeSet::new(),
+            dirty_assets: BTreeSet::new(),
+            module_smts: BTreeMap::new(),
+            dirty_modules: BTreeMap::new(),
+            dirty_validators: BTreeSet::new(),
+            dirty_vesting: BTreeSet::new(),
+            dirty_htlcs: BTreeSet::new(),
+            dirty_dids: BTreeSet::new(),
+            revocation_smts: BTreeMap::new(),
+            dirty_revocations: BTreeMap::new(),
+            journal: None,
+        };
+        state.register_protocol_assets();
+        state
+    }
+
+    /// Ad-hoc genesis funding each address with native tokens (and the Layer0 test
+    /// faucet amount). Networks should start from a `genesis::GenesisSpec` instead.
     pub fn new_with_genesis(genesis_alloc: Vec<(H256, u128)>) -> Arc<Mutex<Self>> {
-        let mut smt = SparseMerkleTree::new();
-        let mut accounts: HashMap<String, Account> = HashMap::new();
-        
-        // Initialize Layer0 faucet with 1 trillion tokens for testing
-        let layer0_faucet_balance = 1_000_000_000_000_000_000u128; // 1 trillion with 8 decimals
+        let mut state = Self::empty();
         
         for (addr, bal) in genesis_alloc {
-            accounts.insert(hex::encode(addr), Account { 
-                balance: bal, 
-                nonce: 0,
-                layer0_balance: if bal > 0 { layer0_faucet_balance } else { 0 },
-                longyield_balance: 0,
-            });
-            smt.update(addr, Some(u128_to_h256(bal)));
+            let layer0_balance = if bal > 0 { LAYER0_FAUCET_AMOUNT } else { 0 };
+            let mut acct = Account::default();
+            acct.credit(NATIVE_ASSET, state.issue(TokenType::Native, bal));
+            acct.credit(assets::LAYER0_ASSET, state.issue(TokenType::Layer0, layer0_balance));
+            state.set_account(addr, &acct);
         }
         
-        let state_root = smt.root();
-        Arc::new(Mutex::new(Self {
-            accounts,
-            height: 0,
-            last_block_hash: [0u8; 32],
-            state_root,
-            layer0_circulating: layer0_faucet_balance,
-            longyield_circulating: 0,
-            smt,
-        }))
+        state.commit_root();
+        Arc::new(Mutex::new(state))
     }
 
-    /// Calculate Layer0 block reward
+    /// Monetary policy selected by the genesis spec
+    pub fn policy(&self) -> Box<dyn MonetaryPolicy> {
+        self.monetary.policy()
+    }
+
+    /// Layer0 reward requested by the policy for the current height (before the cap)
     pub fn calculate_layer0_reward(&self) -> u128 {
-        let halvings = self.height / LAYER0_HALVING_BLOCKS;
-        let base_reward = LAYER0_BLOCK_REWARD >> halvings; // Bit shift for halving
-        
-        // Early block bonuses
-        let early_miner_bonus = if self.height < 1000 { 
-            LAYER0_BLOCK_REWARD / 2 // 50% bonus for first 1000 blocks
-        } else if self.height < 10000 {
-            LAYER0_BLOCK_REWARD / 4 // 25% bonus for first 10,000 blocks
-        } else {
-            0
-        };
-        
-        // Difficulty bonuses
-        let difficulty_bonus = if self.height > 1000 {
-            ((self.height / 1000) as u128) * 10_000_000_000u128 // Bonus increases with height
-        } else {
-            0u128
-        };
-        
-        base_reward + early_miner_bonus + difficulty_bonus
+        self.policy().layer0_reward(self.height)
     }
 
-    /// Calculate LongYield block reward (fixed for now)
+    /// LongYield reward requested by the policy for the current height (before the cap)
     pub fn calculate_longyield_reward(&self) -> u128 {
-        1_000_000_000_000_000_000u128 // 1 L1 token per block
+        self.policy().longyield_reward(self.height)
     }
 
     /// Produce a real SMT inclusion proof for an address.
@@ -153,40 +380,77 @@ impl State {
         (leaf, proof)
     }
 
-    /// Update in-memory SMT after a balance/nonce change.
+    /// Write an account after a balance/nonce change. Its SMT leaf is updated by the
+    /// next `commit_root`, so a block rehashes the tree once rather than per write.
     pub fn set_account(&mut self, addr: H256, acct: &Account) {
+        self.journal_account(&addr);
         self.accounts.insert(hex::encode(addr), acct.clone());
-        
-        // Create a comprehensive account hash that includes all account data
-        let mut account_data = Vec::new();
-        account_data.extend_from_slice(&acct.balance.to_le_bytes());
-        account_data.extend_from_slice(&acct.nonce.to_le_bytes());
-        account_data.extend_from_slice(&acct.layer0_balance.to_le_bytes());
-        account_data.extend_from_slice(&acct.longyield_balance.to_le_bytes());
-        
-        let account_hash = blake3::hash(&account_data);
-        self.smt.update(addr, Some(*account_hash.as_bytes()));
-        // Don't update state_root here - let make_block_once handle it
+        self.dirty_accounts.insert(addr);
+    }
+
+    /// Write pending account, registry, module, validator, vesting, escrow, DID and revocation
+    /// changes to the SMT and recompute the state root.
+    pub fn commit_root(&mut self) -> H256 {
+        self.flush_accounts();
+        self.flush_assets();
+        self.flush_modules();
+        self.flush_validators();
+        self.flush_vesting();
+        self.flush_htlcs();
+        self.flush_dids();
+        self.flush_revocations();
+        self.state_root = self.smt.root();
+        self.state_root
+    }
+
+    fn flush_accounts(&mut self) {
+        if self.dirty_accounts.is_empty() {
+            return;
+        }
+        let updates: Vec<(H256, Option<H256>)> = std::mem::take(&mut self.dirty_accounts)
+            .into_iter()
+            .map(|addr| (addr, self.accounts.get(&hex::encode(addr)).map(Account::leaf_hash)))
+            .collect();
+        self.smt.update_many(updates);
     }
 
     /// Reconstruct SMT from accounts (used when loading from storage)
     pub fn reconstruct_smt(&mut self) {
         self.smt = SparseMerkleTree::new();
-        for (addr_hex, account) in &self.accounts {
-            if let Some(addr) = dehex32(addr_hex) {
-                // Create comprehensive account hash that includes all account data
-                let mut account_data = Vec::new();
-                account_data.extend_from_slice(&account.balance.to_le_bytes());
-                account_data.extend_from_slice(&account.nonce.to_le_bytes());
-                account_data.extend_from_slice(&account.layer0_balance.to_le_bytes());
-                account_data.extend_from_slice(&account.longyield_balance.to_le_bytes());
-                
-                let account_hash = blake3::hash(&account_data);
-                self.smt.update(addr, Some(*account_hash.as_bytes()));
-            }
-        }
+        let leaves = self
+            .accounts
+            .iter()
+            .filter_map(|(addr_hex, account)| Some((dehex32(addr_hex)?, account.leaf_hash())));
+        self.smt.extend(leaves);
+        self.dirty_accounts.clear();
+        self.dirty_assets = self.assets.keys().copied().collect();
+        self.dirty_validators = self.validator_registry.keys().filter_map(|addr| dehex32(addr)).collect();
+        self.dirty_vesting = self.vesting.keys().filter_map(|id| dehex32(id)).collect();
+        self.dirty_htlcs = self.htlcs.keys().filter_map(|id| dehex32(id)).collect();
+        self.dirty_dids = self.dids.keys().filter_map(|id| dehex32(id)).collect();
+        self.rebuild_module_trees();
+        self.rebuild_revocation_trees();
         // Update state root after reconstruction
-        self.state_root = self.smt.root();
+        self.commit_root();
+    }
+
+    /// Load the SMT from stored leaves and rebuild the sub-trees. Returns false (and
+    /// leaves the tree in an unspecified state) if the leaves do not add up to `state_root`.
+    pub(crate) fn restore_smt(&mut self, leaves: Vec<(H256, H256)>) -> bool {
+        let expected = self.state_root;
+        self.smt = SparseMerkleTree::new();
+        self.smt.extend(leaves);
+        self.rebuild_module_trees();
+        self.rebuild_revocation_trees();
+        let ok = self.commit_root() == expected;
+        // Nothing differs from what is stored
+        self.smt.take_changes();
+        ok
+    }
+
+    /// SMT leaves written since the last call, for storage to persist.
+    pub(crate) fn take_smt_changes(&mut self) -> Vec<(H256, Option<H256>)> {
+        self.smt.take_changes()
     }
 }
 
@@ -194,17 +458,19 @@ impl State {
 pub struct Chain {
     pub state: Arc<Mutex<State>>,
     pub mempool_dir: PathBuf,
-    pub blocks_dir: PathBuf,
+    /// Data directory holding the mempool and the database
+    pub data_dir: PathBuf,
     block_time_ms: u64,
     storage: Arc<Storage>,
+    /// Reward recipient; defaults to the block's proposer
+    coinbase: Option<H256>,
+    consensus: Arc<dyn Consensus>,
 }
 
 impl Chain {
     pub fn new(state: Arc<Mutex<State>>, base: PathBuf, block_time_ms: u64) -> Result<Self> {
         let mempool = base.join("mempool");
-        let blocks = base.join("blocks");
         fs::create_dir_all(&mempool)?;
-        fs::create_dir_all(&blocks)?;
         
         // Initialize storage
         let storage_config = StorageConfig {
@@ -216,17 +482,79 @@ impl Chain {
         // Try to load existing state from storage
         if let Some(saved_state) = storage.load_state()? {
             let mut state_guard = state.lock();
+            let (genesis_hash, chain_id) = (state_guard.genesis_hash, state_guard.chain_id);
+            if saved_state.genesis_hash != [0u8; 32] && saved_state.genesis_hash != genesis_hash {
+                anyhow::bail!(
+                    "Data directory belongs to genesis {}, not {}",
+                    hex::encode(saved_state.genesis_hash),
+                    hex::encode(genesis_hash)
+                );
+            }
             *state_guard = saved_state;
-            state_guard.reconstruct_smt();
+            if state_guard.genesis_hash == [0u8; 32] {
+                // Pre-spec data dir: adopt the genesis the operator installed for it
+                if state_guard.chain_id != chain_id {
+                    anyhow::bail!("Data directory is for chain id {}, the genesis for {}", state_guard.chain_id, chain_id);
+                }
+                state_guard.genesis_hash = genesis_hash;
+            }
+            // Storage hands back the state with its Merkle trees in place
+            let registered = state_guard.assets.is_empty();
+            state_guard.migrate_legacy_supply();
+            state_guard.commit_root();
+            if registered {
+                // The registry was created outside any block's journal
+                state_guard.take_smt_changes();
+                storage.save_state(&state_guard)?;
+            }
             println!("Loaded existing state from height {}", state_guard.height);
         } else {
             println!("Starting with fresh genesis state");
         }
         
-        Ok(Self { state, mempool_dir: mempool, blocks_dir: blocks, block_time_ms, storage })
+        Ok(Self {
+            state,
+            mempool_dir: mempool,
+            data_dir: base,
+            block_time_ms,
+            storage,
+            coinbase: None,
+            consensus: Arc::new(RoundRobinPoa::follower()),
+        })
     }
 
-    pub fn make_block_once(self: &Arc<Self>) -> Result<Option<Block>> {
+    /// Produce and check blocks with `consensus` (default: a non-proposing PoA follower).
+    pub fn with_consensus(mut self, consensus: Arc<dyn Consensus>) -> Self {
+        self.consensus = consensus;
+        self
+    }
+
+    pub fn consensus(&self) -> &dyn Consensus {
+        self.consensus.as_ref()
+    }
+
+    /// Check a block's seal against the state it was built on.
+    pub fn verify_seal(&self, parent: &State, block: &Block) -> Result<()> {
+        let round = self.round_at(parent, block.header.timestamp)?;
+        self.consensus.verify_seal(parent, &block.header, round, block.seal.as_ref())
+    }
+
+    /// Proposer round of a block at `timestamp` on top of `parent` (see `consensus::round_at`).
+    pub fn round_at(&self, parent: &State, timestamp: u64) -> Result<u32> {
+        let parent_timestamp = match parent.height {
+            0 => None,
+            h => Some(self.storage.load_block(h)?.with_context(|| format!("block {} is missing", h))?.header.timestamp),
+        };
+        Ok(consensus::round_at(parent_timestamp, timestamp))
+    }
+
+    /// Credit block rewards to `coinbase` instead of the block's proposer.
+    pub fn with_coinbase(mut self, coinbase: H256) -> Self {
+        self.coinbase = Some(coinbase);
+        self
+    }
+
+    pub fn make_block_once(self: &Arc<Self>) -> Result<Option<(Block, Vec<Receipt>)>> {
         // Pre-allocate vectors to reduce allocations
         let mut txs = Vec::with_capacity(100); // Reasonable capacity for mempool
         
@@ -246,97 +574,252 @@ impl Chain {
             }
         }
 
+        // Apply in (sender, nonce) order so a sender's queued transactions land in sequence
+        txs.sort_by(|(pa, a), (pb, b)| {
+            (a.from, a.nonce, pa).cmp(&(b.from, b.nonce, pb))
+        });
+
         // Apply transactions with better error handling
         let mut st = self.state.lock();
-        let mut applied = Vec::with_capacity(txs.len());
-        let mut failed_txs = Vec::new();
-        
-        for (path, tx) in txs {
-            match Self::apply_tx(&mut st, &tx) {
-                Ok(_) => {
-                    // Remove file only after successful application
-                    let _ = fs::remove_file(&path);
-                    applied.push(tx);
-                }
-                Err(_) => {
-                    failed_txs.push(path);
-                }
-            }
+        let timestamp = now_ts();
+        let round = self.round_at(&st, timestamp)?;
+        let proposer = self.consensus.proposer(&st, st.height + 1, round);
+        if !self.consensus.is_local_turn(&st, st.height + 1, round) {
+            // Another validator's slot, until its proposer times out
+            return Ok(None);
         }
+        st.begin_journal();
+        let max_block_txs = st.monetary.fees.max_block_txs as usize;
+        let (paths, txs): (Vec<_>, Vec<_>) = txs.into_iter().unzip();
 
-        // Always produce a block (even empty) for Layer0 store of value
-        st.height += 1;
-        
-        // For empty blocks, ensure state root changes by including block metadata
-        if applied.is_empty() {
-            // Create a unique "empty block" key that changes with each block
-            let mut empty_block_key = [0u8; 32];
-            empty_block_key[0..8].copy_from_slice(&st.height.to_le_bytes());
-            empty_block_key[8..16].copy_from_slice(&now_ts().to_le_bytes());
-            empty_block_key[16..24].copy_from_slice(&st.last_block_hash[0..8]); // Include part of last block hash
-            empty_block_key[24..32].copy_from_slice(&(st.height as u64).wrapping_mul(999999).to_le_bytes()); // More entropy
-            
-            let mut empty_block_data = Vec::new();
-            empty_block_data.extend_from_slice(&st.height.to_le_bytes());
-            empty_block_data.extend_from_slice(&now_ts().to_le_bytes());
-            empty_block_data.extend_from_slice(&st.last_block_hash);
-            empty_block_data.extend_from_slice(&(st.height as u64).wrapping_mul(1337).to_le_bytes()); // Add some entropy
-            empty_block_data.extend_from_slice(&(now_ts() as u64).wrapping_mul(777).to_le_bytes()); // More timestamp entropy
-            
-            let empty_block_hash = blake3::hash(&empty_block_data);
-            
-            st.smt.update(empty_block_key, Some(*empty_block_hash.as_bytes()));
+        // Independent transactions run in parallel; the result matches applying them in order.
+        // Whatever does not fit in the block or is not executable yet stays queued.
+        let exec::Execution { included, rejected, receipts } =
+            exec::execute(&mut st, &txs, max_block_txs, exec::available_threads());
+        // Included and rejected transactions both leave the mempool
+        for &i in included.iter().chain(&rejected) {
+            let _ = fs::remove_file(&paths[i]);
         }
-        
-        // Recalculate state root AFTER all updates (transactions + empty block data)
-        st.state_root = st.smt.root();
-        
-
-        
+        let applied: Vec<Tx> = included.into_iter().map(|i| txs[i].clone()).collect();
+        // Only the block's own transactions count towards the receipts root; the
+        // receipts of rejected ones are stored after them
+        let in_block: BTreeSet<H256> = applied.iter().map(Tx::hash).collect();
+        let (mut receipts, rejected_receipts): (Vec<_>, Vec<_>) = receipts.into_iter().partition(|r| in_block.contains(&r.tx_hash));
 
+        // Always produce a block (even empty) for Layer0 store of value
+        let parent_hash = st.last_block_hash;
+        let coinbase = self.coinbase.or(proposer).or_else(|| st.validators.first().copied());
+        let (layer0_reward, longyield_reward) = Self::finish_block(&mut st, &mut receipts, coinbase);
+        let receipts_root = receipt::receipts_root(&receipts);
+        receipts.extend(rejected_receipts.into_iter().map(|r| Receipt { block_height: st.height, ..r }));
+        let last_commit = match self.storage.load_commit(st.height - 1) {
+            Ok(cert) => cert,
+            Err(e) => {
+                self.abandon_block(&mut st, &applied)?;
+                return Err(e);
+            }
+        };
         
         let header = BlockHeader {
             height: st.height,
-            timestamp: now_ts(),
-            tx_root: h_txs(&applied),
+            timestamp,
+            tx_root: merkle::tx_root(&applied),
             state_root: st.state_root,
-            layer0_reward: st.calculate_layer0_reward(),
-            longyield_reward: st.calculate_longyield_reward(),
+            receipts_root,
+            layer0_reward,
+            longyield_reward,
+            coinbase: coinbase.unwrap_or_default(),
+            parent_hash,
+            last_commit,
+        };
+        let seal = match self.consensus.seal(&st, &header, round) {
+            Ok(seal) => seal,
+            Err(e) => {
+                self.abandon_block(&mut st, &applied)?;
+                return Err(e);
+            }
         };
-        let block = Block { header: header.clone(), txs: applied };
+        let block = Block { header: header.clone(), txs: applied, seal };
         
         // Update the last_block_hash in state
         st.last_block_hash = h_block_header(&header);
+        let journal = st.take_journal();
         
-        // Persist block with enhanced storage
-        if let Err(e) = self.storage.save_block(&block) {
-            eprintln!("Failed to persist block {}: {}", header.height, e);
-        }
+        self.persist_block(&mut st, &block, &receipts, journal.as_ref())?;
         
-        // Save state to persistent storage
-        if let Err(e) = self.storage.save_state(&st) {
-            eprintln!("Failed to save state: {}", e);
+        Ok(Some((block, receipts)))
+    }
+
+    /// Advance `st` past a block whose transactions were just applied: number the
+    /// receipts, pay the block rewards, split the fees, run the staking epilogue and
+    /// commit the new state root.
+    /// Returns the (Layer0, LongYield) rewards paid to `coinbase`.
+    pub(crate) fn finish_block(st: &mut State, receipts: &mut [Receipt], coinbase: Option<H256>) -> (u128, u128) {
+        st.height += 1;
+        for (i, receipt) in receipts.iter_mut().enumerate() {
+            receipt.block_height = st.height;
+            receipt.index = i as u32;
         }
         
-        // Create checkpoint periodically
-        if let Err(e) = self.storage.create_checkpoint(&st, &block) {
-            eprintln!("Failed to create checkpoint: {}", e);
-        }
+        // Block rewards go to the coinbase, within the supply caps
+        let rewards = match coinbase {
+            Some(addr) => st.credit_block_rewards(addr),
+            None => (0, 0),
+        };
         
-        // Index transactions for efficient querying
-        for (tx_index, tx) in block.txs.iter().enumerate() {
-            let tx_hash = h_txs(&[tx.clone()]);
-            if let Err(e) = self.storage.index_transaction(tx_hash, block.header.height, tx_index) {
-                eprintln!("Failed to index transaction: {}", e);
+        // Fees collected in this block are split between burn, proposer and treasury
+        let mut fees: Vec<(TokenType, u128)> = Vec::new();
+        for fee in receipts.iter().filter_map(|r| r.fee) {
+            match fees.iter_mut().find(|(t, _)| *t == fee.token) {
+                Some((_, total)) => *total += fee.amount,
+                None => fees.push((fee.token, fee.amount)),
             }
         }
+        for (token, total) in fees {
+            st.distribute_fees(token, total, coinbase);
+        }
+        st.update_min_fee(receipts.iter().filter(|r| r.status == TxStatus::Success).count() as u32);
+
+        // Unbonding payouts, and the next epoch's active set at a boundary
+        st.end_block_staking();
+        
+        // Recalculate state root AFTER all updates (transactions + rewards)
+        st.commit_root();
+        rewards
+    }
+
+    /// Write a block that `st` now includes, with its receipts, undo journal and the
+    /// state it leads to, in one storage transaction. If that fails, `st` is rolled back
+    /// with the journal and the block's transactions go back to the mempool, so the tip
+    /// never moves past what is stored.
+    pub(crate) fn persist_block(&self, st: &mut State, block: &Block, receipts: &[Receipt], journal: Option<&undo::UndoJournal>) -> Result<()> {
+        let height = block.header.height;
+        let smt_changes = st.take_smt_changes();
+        if let Err(e) = self.storage.commit_block(st, &smt_changes, block, receipts, journal) {
+            let journal = journal.with_context(|| format!("block {} was not stored and has no undo journal: {}", height, e))?;
+            st.apply_undo(journal).with_context(|| format!("rolling back unstored block {}", height))?;
+            self.requeue(&block.txs)?;
+            return Err(e.context(format!("Failed to persist block {}", height)));
+        }
         
         // Create backup periodically
-        if let Err(e) = self.storage.create_backup() {
+        if let Err(e) = self.storage.create_backup(st) {
             eprintln!("Failed to create backup: {}", e);
         }
-        
-        Ok(Some(block))
+        Ok(())
+    }
+
+    /// Leave the state and mempool as they were before the block being built
+    fn abandon_block(&self, st: &mut State, applied: &[Tx]) -> Result<()> {
+        if let Some(journal) = st.take_journal() {
+            st.apply_undo(&journal)?;
+        }
+        self.requeue(applied)
+    }
+
+    /// Put transactions back in the mempool
+    fn requeue(&self, txs: &[Tx]) -> Result<()> {
+        for tx in txs {
+            let path = self.mempool_dir.join(format!("{}.json", hex::encode(tx.hash())));
+            fs::write(path, serde_json::to_string(tx)?)?;
+        }
+        Ok(())
+    }
+
+    /// Roll the chain back to `height` using the undo journals of the blocks above it.
+    /// The transactions of the reverted blocks go back to the mempool. Returns the
+    /// reverted blocks, newest first. Nothing is changed if a journal is missing.
+    pub fn revert_to(&self, height: u64) -> Result<Vec<Block>> {
+        let mut st = self.state.lock();
+        if height < st.finalized_height {
+            anyhow::bail!("cannot revert to {}: block {} is finalized", height, st.finalized_height);
+        }
+        let (mut reverted, heights) = self.rewind(&st, height)?;
+
+        // Read before storage drops them
+        let mut blocks = Vec::with_capacity(heights.len());
+        for &h in &heights {
+            if let Some(block) = self.storage.load_block(h)? {
+                blocks.push(block);
+            }
+        }
+        // The whole state is rewritten, so the tracked leaf changes are moot
+        reverted.take_smt_changes();
+        self.storage.revert(&reverted, &heights)?;
+        // Only once the revert is stored does the live state follow and the txs go back
+        *st = reverted;
+        for block in &blocks {
+            self.requeue(&block.txs)?;
+        }
+        println!("Reverted {} block(s) to height {}", heights.len(), height);
+        Ok(blocks)
+    }
+
+    /// The state as it was after block `height`, rebuilt from the undo journals.
+    pub fn state_at(&self, height: u64) -> Result<State> {
+        let current = self.state.lock().clone();
+        Ok(self.rewind(&current, height)?.0)
+    }
+
+    /// Undo `st` down to `height` on a copy; also returns the undone heights, newest first.
+    fn rewind(&self, st: &State, height: u64) -> Result<(State, Vec<u64>)> {
+        let tip = st.height;
+        if height > tip {
+            anyhow::bail!("cannot revert to {}: tip is {}", height, tip);
+        }
+
+        let mut journals = Vec::with_capacity((tip - height) as usize);
+        for h in (height + 1..=tip).rev() {
+            let journal = self.storage.load_undo(h)?.with_context(|| format!("no undo journal for block {}", h))?;
+            journals.push(journal);
+        }
+
+        // Undo on a copy so a bad journal leaves the live state untouched
+        let mut rewound = st.clone();
+        for journal in &journals {
+            rewound.apply_undo(journal)?;
+        }
+        if let Some(block) = self.storage.load_block(height)? {
+            if block.header.state_root != rewound.state_root {
+                anyhow::bail!("reverted state root does not match block {}", height);
+            }
+        }
+        Ok((rewound, journals.iter().map(|j| j.height).collect()))
+    }
+
+    /// Record a commit certificate for a block on this chain. Returns false if the
+    /// block was already final.
+    pub fn record_commit(&self, cert: finality::CommitCertificate) -> Result<bool> {
+        let mut st = self.state.lock();
+        if cert.height <= st.finalized_height {
+            return Ok(false);
+        }
+        let block = self.storage.load_block(cert.height)?.with_context(|| format!("block {} not found", cert.height))?;
+        if h_block_header(&block.header) != cert.block_hash {
+            anyhow::bail!("certificate for block {} does not match the stored block", cert.height);
+        }
+        cert.verify(st.chain_id, st.validators_at(cert.height))?;
+
+        st.finalized_height = cert.height;
+        self.storage.save_finality(&cert, &st)?;
+        println!("Finalized block {} (round {}, {} precommits)", cert.height, cert.round, cert.precommits.len());
+        Ok(true)
+    }
+
+    /// Highest finalized block and its certificate
+    pub fn finality(&self) -> Result<(u64, Option<finality::CommitCertificate>)> {
+        let height = self.state.lock().finalized_height;
+        Ok((height, self.storage.load_commit(height)?))
+    }
+
+    /// Export the state after block `height` as a verified snapshot into `dir`.
+    pub fn export_snapshot(&self, height: u64, dir: &Path) -> Result<snapshot::SnapshotManifest> {
+        let state = self.state_at(height)?;
+        let header = match height {
+            0 => None,
+            h => Some(self.storage.load_block(h)?.with_context(|| format!("block {} not found", h))?.header),
+        };
+        snapshot::export_snapshot(&state, header.as_ref(), dir)
     }
 
     /// Get storage statistics
@@ -344,88 +827,441 @@ impl Chain {
         self.storage.get_stats()
     }
 
-    fn apply_tx(st: &mut State, tx: &Tx) -> Result<()> {
-        // basic sig/domain separation
-        let msg = serde_json::to_vec(&(tx.from, tx.to, tx.amount, tx.fee, tx.signature.nonce, CHAIN_ID))?;
-        STARK.verify(&tx.signature, &msg)?;
-
-        let from_hex = hex::encode(tx.from);
-        let to_hex = hex::encode(tx.to);
-
-        // snapshot current accounts (avoid holding entry borrows while updating SMT)
-        let mut from_acct = st.accounts.get(&from_hex).cloned().unwrap_or(Account { balance: 0, nonce: 0, layer0_balance: 0, longyield_balance: 0 });
-        let mut to_acct   = st.accounts.get(&to_hex).cloned().unwrap_or(Account { balance: 0, nonce: 0, layer0_balance: 0, longyield_balance: 0 });
-
-        // economic rules
-        if from_acct.nonce != tx.signature.nonce { anyhow::bail!("bad nonce"); }
-        let spend = tx.amount.saturating_add(tx.fee);
-
-        // Handle different token types
-        match tx.token_type {
-            TokenType::Layer0 => {
-                // LAYER0: ULTIMATE STORE OF VALUE - ZERO FEES, PURE APPRECIATION
-                if from_acct.layer0_balance < tx.amount { anyhow::bail!("insufficient layer0 balance"); }
-                
-                // ZERO FEES - Pure transfer, no cost
-                from_acct.layer0_balance -= tx.amount;
-                to_acct.layer0_balance = to_acct.layer0_balance.saturating_add(tx.amount);
-                
-                // AUTOMATIC APPRECIATION: Every Layer0 holder gets appreciation
-                let appreciation = tx.amount * LAYER0_APPRECIATION_RATE as u128 / 1_000_000; // 0.1% appreciation
-                to_acct.layer0_balance = to_acct.layer0_balance.saturating_add(appreciation);
-                
-
-                
-                // NO FEES BURNED - Layer0 is pure store of value
-            },
-            TokenType::LongYield => {
-                // LongYield L1 token transfer (still has fees for utility)
-                if from_acct.longyield_balance < spend { anyhow::bail!("insufficient longyield balance"); }
-                
-                from_acct.longyield_balance -= spend;
-                to_acct.longyield_balance = to_acct.longyield_balance.saturating_add(tx.amount);
-                
-                st.longyield_circulating = st.longyield_circulating.saturating_sub(tx.fee);
-            },
-            TokenType::Native => {
-                // Legacy native token transfer
-                if from_acct.balance < spend { anyhow::bail!("insufficient native balance"); }
-                
-                from_acct.balance -= spend;
-                to_acct.balance = to_acct.balance.saturating_add(tx.amount);
-            },
-        }
-
-        // Update nonce
-        from_acct.nonce += 1;
+    /// Load a persisted block by height
+    pub fn load_block(&self, height: u64) -> Result<Option<Block>> {
+        self.storage.load_block(height)
+    }
+
+    /// Load the receipts recorded for a block
+    pub fn load_receipts(&self, height: u64) -> Result<Vec<Receipt>> {
+        Ok(self.storage.load_receipts(height)?.unwrap_or_default())
+    }
+
+    /// Find the most recent receipt for a transaction hash (included or rejected).
+    pub fn find_receipt(&self, tx_hash: &H256) -> Result<Option<Receipt>> {
+        if self.storage.indexing_enabled() {
+            let height = match self.storage.find_transaction(&hex::encode(tx_hash))? {
+                Some(entry) => Some(entry.block_height),
+                None => self.storage.find_rejected(tx_hash)?,
+            };
+            let Some(height) = height else { return Ok(None) };
+            let receipts = self.storage.load_receipts(height)?.unwrap_or_default();
+            return Ok(receipts.into_iter().find(|r| &r.tx_hash == tx_hash));
+        }
+
+        // Without the index: scan receipts from the tip backwards
+        let tip = self.state.lock().height;
+        for height in (1..=tip).rev() {
+            if let Some(receipts) = self.storage.load_receipts(height)? {
+                if let Some(r) = receipts.into_iter().find(|r| &r.tx_hash == tx_hash) {
+                    return Ok(Some(r));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Locate an included transaction, returning its block and position.
+    pub fn find_tx(&self, tx_hash: &H256) -> Result<Option<(Block, usize)>> {
+        // The transaction index, when kept
+        if let Some(entry) = self.storage.find_transaction(&hex::encode(tx_hash))? {
+            if let Some(block) = self.storage.load_block(entry.block_height)? {
+                if block.txs.get(entry.tx_index).map(Tx::hash).as_ref() == Some(tx_hash) {
+                    return Ok(Some((block, entry.tx_index)));
+                }
+            }
+        }
 
-        // write back + update SMT without overlapping borrows
+        if self.storage.indexing_enabled() {
+            return Ok(None);
+        }
+
+        // Without the index: scan blocks from the tip backwards
+        let tip = self.state.lock().height;
+        for height in (1..=tip).rev() {
+            if let Some(block) = self.storage.load_block(height)? {
+                if let Some(pos) = block.txs.iter().position(|tx| &tx.hash() == tx_hash) {
+                    return Ok(Some((block, pos)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Indexed transactions of an address; see `Storage::address_transactions`.
+    pub fn address_txs(&self, addr: &H256, cursor: Option<(u64, u32)>, descending: bool, limit: usize) -> Result<Vec<storage::AddressTx>> {
+        self.storage.address_transactions(addr, cursor, descending, limit)
+    }
+
+    /// Up to `limit` transactions of `addr` after the `cursor` position (before it when
+    /// `descending`), optionally only those moving `token`. Cursors are index positions,
+    /// so pages stay put as blocks are added. A page stops early, with a cursor, once
+    /// `txindex::MAX_HISTORY_SCAN` entries were examined.
+    pub fn address_history(
+        &self,
+        addr: &H256,
+        cursor: Option<(u64, u32)>,
+        descending: bool,
+        limit: usize,
+        token: Option<AssetId>,
+    ) -> Result<txindex::HistoryPage> {
+        let batch = limit.clamp(16, 256);
+        let mut cursor = cursor;
+        let mut entries = Vec::new();
+        let mut scanned = 0;
+        let mut block: Option<(Block, Vec<Receipt>)> = None;
+        loop {
+            let found = self.address_txs(addr, cursor, descending, batch)?;
+            let exhausted = found.len() < batch;
+            for item in found {
+                cursor = Some((item.height, item.position));
+                scanned += 1;
+                if block.as_ref().map(|(b, _)| b.header.height) != Some(item.height) {
+                    let loaded = self.load_block(item.height)?.with_context(|| format!("Indexed block {} is missing", item.height))?;
+                    block = Some((loaded, self.load_receipts(item.height)?));
+                }
+                let Some((b, receipts)) = &block else { continue };
+                let Some(tx) = b.txs.get(item.position as usize) else { continue };
+                let counterparty = match (item.sent, item.received) {
+                    (true, false) => tx.payload.recipient(),
+                    (false, true) => Some(tx.from),
+                    _ => Some(*addr),
+                };
+                let entry = txindex::HistoryEntry {
+                    tx: tx.clone(),
+                    height: item.height,
+                    position: item.position,
+                    timestamp: b.header.timestamp,
+                    sent: item.sent,
+                    received: item.received,
+                    counterparty,
+                    receipt: receipts.iter().find(|r| r.tx_hash == item.tx_hash).cloned(),
+                };
+                if token.is_some_and(|asset| !entry.moves(addr, asset)) {
+                    continue;
+                }
+                entries.push(entry);
+                if entries.len() == limit {
+                    return Ok(txindex::HistoryPage { entries, next: cursor, scan_limit_reached: false });
+                }
+            }
+            if exhausted {
+                return Ok(txindex::HistoryPage { entries, next: None, scan_limit_reached: false });
+            }
+            if scanned >= txindex::MAX_HISTORY_SCAN {
+                // Hand back what matched so far; the caller continues from the cursor
+                return Ok(txindex::HistoryPage { entries, next: cursor, scan_limit_reached: true });
+            }
+        }
+    }
+
+    /// Build an inclusion proof for a transaction against its block's `tx_root`.
+    pub fn prove_tx(&self, tx_hash: &H256) -> Result<Option<(BlockHeader, Tx, merkle::MerkleProof)>> {
+        let Some((block, pos)) = self.find_tx(tx_hash)? else { return Ok(None) };
+        let Some(proof) = merkle::prove_tx(&block.txs, tx_hash) else { return Ok(None) };
+        Ok(Some((block.header, block.txs[pos].clone(), proof)))
+    }
+
+    /// Apply one transaction: envelope checks, then the payload handler from the dispatch table.
+    fn apply_tx(st: &mut State, tx: &Tx) -> std::result::Result<Receipt, TxError> {
+        // A newer envelope may mean something this node cannot execute correctly
+        if tx.version > TX_VERSION {
+            return Err(TxError::UnsupportedVersion { max: TX_VERSION, got: tx.version });
+        }
+        tx.verify_signature().map_err(|_| TxError::BadSignature)?;
+        if tx.chain_id != st.chain_id {
+            return Err(TxError::WrongChain { expected: st.chain_id, got: tx.chain_id });
+        }
+        // The transaction lands in the block being built, one above the current height
+        let height = st.height + 1;
+        if let Some(expiry) = tx.expiry {
+            if height > expiry { return Err(TxError::Expired { expiry, height }); }
+        }
+
+        // snapshot the sender (avoid holding entry borrows while updating SMT)
+        let mut from_acct = st.accounts.get(&hex::encode(tx.from)).cloned().unwrap_or_default();
+        if from_acct.nonce != tx.nonce {
+            return Err(TxError::BadNonce { expected: from_acct.nonce, got: tx.nonce });
+        }
+
+        let mut receipt = Receipt {
+            tx_hash: tx.hash(),
+            block_height: 0,
+            index: 0,
+            status: TxStatus::Success,
+            error_code: None,
+            balance_changes: Vec::new(),
+            nonce_consumed: Some(from_acct.nonce),
+            appreciation_minted: 0,
+            fee: None,
+            created_asset: None,
+            created_module: None,
+            created_vesting: None,
+            created_htlc: None,
+            created_did: None,
+            gas_used: None,
+            output: None,
+        };
+
+        // dispatch table: one handler per payload kind
+        match &tx.payload {
+            TxPayload::Transfer { to, amount, token_type } => {
+                Self::apply_transfer(st, tx, &mut from_acct, *to, *amount, *token_type, &mut receipt)?
+            }
+            TxPayload::CrossChainTransfer { to, amount, token_type, target_chain_id } => {
+                if *target_chain_id == st.chain_id {
+                    return Err(TxError::InvalidPayload("cross-chain transfer targets this chain".into()));
+                }
+                Self::apply_transfer(st, tx, &mut from_acct, *to, *amount, *token_type, &mut receipt)?
+            }
+            TxPayload::IssueAsset { symbol, decimals, supply_cap, initial_supply } => {
+                Self::apply_issue_asset(st, tx, &mut from_acct, symbol, *decimals, *supply_cap, *initial_supply, &mut receipt)?
+            }
+            TxPayload::Mint { asset, to, amount } => {
+                Self::apply_mint(st, tx, &mut from_acct, *asset, *to, *amount, &mut receipt)?
+            }
+            TxPayload::Burn { asset, amount } => {
+                Self::apply_burn(st, tx, &mut from_acct, *asset, *amount, &mut receipt)?
+            }
+            TxPayload::DeployModule { code } => Self::apply_deploy_module(st, tx, &mut from_acct, code, &mut receipt)?,
+            TxPayload::CallModule { module, function, input, value, gas_limit } => Self::apply_call_module(
+                st,
+                tx,
+                &mut from_acct,
+                *module,
+                function,
+                input,
+                *value,
+                *gas_limit,
+                &mut receipt,
+            )?,
+            TxPayload::Stake { amount } => Self::apply_stake(st, tx, &mut from_acct, *amount, &mut receipt)?,
+            TxPayload::Unstake { amount } => Self::apply_unstake(st, tx, &mut from_acct, *amount, &mut receipt)?,
+            TxPayload::Slash { evidence } => Self::apply_slash(st, tx, &mut from_acct, evidence, &mut receipt)?,
+            TxPayload::CreateVesting { beneficiary, token_type, amount, cliff_height, end_height } => Self::apply_create_vesting(
+                st,
+                tx,
+                &mut from_acct,
+                *beneficiary,
+                *token_type,
+                *amount,
+                *cliff_height,
+                *end_height,
+                &mut receipt,
+            )?,
+            TxPayload::ClaimVesting { schedule } => Self::apply_claim_vesting(st, tx, &mut from_acct, *schedule, &mut receipt)?,
+            TxPayload::LockHtlc { recipient, token_type, amount, hash_lock, expiry_height } => Self::apply_lock_htlc(
+                st,
+                tx,
+                &mut from_acct,
+                *recipient,
+                *token_type,
+                *amount,
+                *hash_lock,
+                *expiry_height,
+                &mut receipt,
+            )?,
+            TxPayload::ClaimHtlc { htlc, preimage } => Self::apply_claim_htlc(st, tx, &mut from_acct, *htlc, preimage, &mut receipt)?,
+            TxPayload::RefundHtlc { htlc } => Self::apply_refund_htlc(st, tx, &mut from_acct, *htlc, &mut receipt)?,
+            TxPayload::CreateDid { keys, services } => Self::apply_create_did(st, tx, &mut from_acct, keys, services, &mut receipt)?,
+            TxPayload::UpdateDid { did, keys, services } => {
+                Self::apply_update_did(st, tx, &mut from_acct, *did, keys, services, &mut receipt)?
+            }
+            TxPayload::RotateDidController { did, new_controller } => {
+                Self::apply_rotate_did_controller(st, tx, &mut from_acct, *did, *new_controller, &mut receipt)?
+            }
+            TxPayload::DeactivateDid { did } => Self::apply_deactivate_did(st, tx, &mut from_acct, *did, &mut receipt)?,
+            TxPayload::RevokeCredentials { credentials } => {
+                Self::apply_revoke_credentials(st, tx, &mut from_acct, credentials, &mut receipt)?
+            }
+        }
+
+        // Update nonce and write the sender back
+        from_acct.nonce += 1;
         st.set_account(tx.from, &from_acct);
-        st.set_account(tx.to, &to_acct);
 
+        Ok(receipt)
+    }
+
+    fn apply_transfer(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        to: H256,
+        amount: u128,
+        token: TokenType,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let token = TokenType::from_asset_id(token.asset_id());
+        let asset = token.asset_id();
+        if st.asset(asset).is_none() {
+            return Err(TxError::UnknownAsset(asset));
+        }
+        let self_transfer = to == tx.from;
+        let mut to_acct = st.accounts.get(&hex::encode(to)).cloned().unwrap_or_default();
+        let from_before = from_acct.balance(asset);
+        let to_before = to_acct.balance(asset);
+
+        // LAYER0: ULTIMATE STORE OF VALUE - ZERO FEES, PURE APPRECIATION.
+        // LongYield and Native transfers pay fees in the transferred token, registry assets in native.
+        match token {
+            TokenType::Layer0 => {}
+            // the fee shows up in this token's balance change below
+            TokenType::LongYield | TokenType::Native => Self::charge_fee(st, tx, from_acct, token, false, receipt)?,
+            TokenType::Asset(_) => Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?,
+        }
+        if !from_acct.debit(asset, amount) {
+            return Err(TxError::InsufficientBalance { token });
+        }
+
+        // Transfer-time issuance (Layer0 appreciation under the legacy policy);
+        // minted supply, so it stops once the cap is reached
+        let appreciation = st.issue(token, st.policy().transfer_issuance(token, amount));
+
+        let credit = amount.saturating_add(appreciation);
+        if self_transfer {
+            from_acct.credit(asset, credit);
+        } else {
+            to_acct.credit(asset, credit);
+            st.set_account(to, &to_acct);
+        }
+
+        receipt.balance_changes.push(BalanceChange { address: tx.from, token, before: from_before, after: from_acct.balance(asset) });
+        if !self_transfer {
+            receipt.balance_changes.push(BalanceChange { address: to, token, before: to_before, after: to_acct.balance(asset) });
+        }
+        receipt.appreciation_minted = appreciation;
+        Ok(())
+    }
+
+    /// Take the transaction fee in `token` from the sender. It is distributed at the end of the block.
+    fn charge_fee(
+        st: &State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        token: TokenType,
+        record_change: bool,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let minimum = st.required_min_fee();
+        if tx.fee < minimum {
+            return Err(TxError::FeeTooLow { minimum, got: tx.fee });
+        }
+        if tx.fee == 0 {
+            return Ok(());
+        }
+        let before = from_acct.token_balance(token);
+        if !from_acct.debit(token.asset_id(), tx.fee) {
+            return Err(TxError::InsufficientBalance { token });
+        }
+        receipt.fee = Some(fees::FeePaid { token, amount: tx.fee });
+        if record_change {
+            receipt.balance_changes.push(BalanceChange { address: tx.from, token, before, after: from_acct.token_balance(token) });
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn apply_issue_asset(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        symbol: &str,
+        decimals: u8,
+        supply_cap: Option<u128>,
+        initial_supply: u128,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        if !assets::valid_symbol(symbol) {
+            return Err(TxError::InvalidPayload(format!("invalid asset symbol {:?}", symbol)));
+        }
+        if st.asset_by_symbol(symbol).is_some() {
+            return Err(TxError::InvalidPayload(format!("asset symbol {} already registered", symbol)));
+        }
+        if supply_cap.is_some_and(|cap| initial_supply > cap) {
+            return Err(TxError::SupplyCapExceeded);
+        }
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        let info = AssetInfo { symbol: symbol.to_string(), decimals, supply_cap, issuer: Some(tx.from), supply: initial_supply };
+        let asset = st.register_asset(info);
+        from_acct.credit(asset, initial_supply);
+        receipt.created_asset = Some(asset);
+        if initial_supply > 0 {
+            let token = TokenType::Asset(asset);
+            receipt.balance_changes.push(BalanceChange { address: tx.from, token, before: 0, after: initial_supply });
+        }
+        Ok(())
+    }
+
+    fn apply_mint(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        asset: AssetId,
+        to: H256,
+        amount: u128,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let Some(info) = st.asset(asset) else { return Err(TxError::UnknownAsset(asset)) };
+        if info.issuer != Some(tx.from) {
+            return Err(TxError::NotIssuer);
+        }
+        let supply = info.supply.checked_add(amount).ok_or(TxError::SupplyCapExceeded)?;
+        if info.supply_cap.is_some_and(|cap| supply > cap) {
+            return Err(TxError::SupplyCapExceeded);
+        }
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        if let Some(info) = st.asset_mut(asset) {
+            info.supply = supply;
+        }
+        let token = TokenType::from_asset_id(asset);
+        if to == tx.from {
+            let before = from_acct.balance(asset);
+            from_acct.credit(asset, amount);
+            receipt.balance_changes.push(BalanceChange { address: to, token, before, after: from_acct.balance(asset) });
+        } else {
+            let mut to_acct = st.accounts.get(&hex::encode(to)).cloned().unwrap_or_default();
+            let before = to_acct.balance(asset);
+            to_acct.credit(asset, amount);
+            st.set_account(to, &to_acct);
+            receipt.balance_changes.push(BalanceChange { address: to, token, before, after: to_acct.balance(asset) });
+        }
+        Ok(())
+    }
+
+    fn apply_burn(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        asset: AssetId,
+        amount: u128,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        if st.asset(asset).is_none() {
+            return Err(TxError::UnknownAsset(asset));
+        }
+        let token = TokenType::from_asset_id(asset);
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+        let before = from_acct.balance(asset);
+        if !from_acct.debit(asset, amount) {
+            return Err(TxError::InsufficientBalance { token });
+        }
+        st.burn(token, amount);
+        receipt.balance_changes.push(BalanceChange { address: tx.from, token, before, after: from_acct.balance(asset) });
         Ok(())
     }
 }
 
 /* ---- helpers ---- */
 
-fn now_ts() -> u64 {
+pub(crate) fn now_ts() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
 }
 
-fn h_txs(txs: &[Tx]) -> H256 {
-    // very simple tx root: hash concatenation
-    use blake3::Hasher;
-    let mut hasher = Hasher::new();
-    for tx in txs {
-        let s = serde_json::to_vec(tx).unwrap();
-        hasher.update(&s);
-    }
-    *hasher.finalize().as_bytes()
-}
-
-fn h_block_header(header: &BlockHeader) -> H256 {
+pub fn h_block_header(header: &BlockHeader) -> H256 {
     // Hash the block header
     use blake3::Hasher;
     let mut hasher = Hasher::new();
@@ -434,7 +1270,11 @@ fn h_block_header(header: &BlockHeader) -> H256 {
     *hasher.finalize().as_bytes()
 }
 
-fn dehex32(s: &str) -> Option<H256> {
+pub(crate) fn is_zero_hash(h: &H256) -> bool {
+    *h == [0u8; 32]
+}
+
+pub(crate) fn dehex32(s: &str) -> Option<H256> {
     let v = hex::decode(s).ok()?;
     if v.len() != 32 { return None; }
     let mut out = [0u8; 32];
@@ -442,8 +1282,281 @@ fn dehex32(s: &str) -> Option<H256> {
     Some(out)
 }
 
-fn u128_to_h256(x: u128) -> H256 {
-    let mut out = [0u8; 32];
-    out[16..].copy_from_slice(&x.to_be_bytes());
-    out
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dxid_crypto::{SecretKey, StarkSignEngine, ENGINE as STARK};
+
+    /// Fresh chain in a throwaway directory with one funded account.
+    fn test_chain(tag: &str) -> (Arc<Chain>, SecretKey, H256) {
+        let base = std::env::temp_dir().join(format!("dxid-test-{}-{}-{}", tag, std::process::id(), now_ts()));
+        let _ = fs::remove_dir_all(&base);
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let state = State::new_with_genesis(vec![(pk, 1_000_000)]);
+        (Arc::new(Chain::new(state, base, 2000).unwrap()), sk, pk)
+    }
+
+    fn submit(chain: &Chain, tx: &Tx) {
+        let path = chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash())));
+        fs::write(path, serde_json::to_string(tx).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_block_receipts_and_tx_proof() {
+        let (chain, sk, pk) = test_chain("receipts");
+        let to = [9u8; 32];
+        let ok = Tx::new_signed(&sk, pk, 0, 10, CHAIN_ID, None, TxPayload::Transfer { to, amount: 500, token_type: TokenType::Native }).unwrap();
+        let broke = Tx::new_signed(&sk, pk, 1, 0, CHAIN_ID, None, TxPayload::Transfer { to, amount: u128::MAX, token_type: TokenType::Native }).unwrap();
+        submit(&chain, &ok);
+        submit(&chain, &broke);
+
+        let (block, receipts) = chain.make_block_once().unwrap().unwrap();
+        assert_eq!(block.txs.len(), 1);
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].status, TxStatus::Success);
+        assert_eq!(receipts[0].nonce_consumed, Some(0));
+        assert_eq!(receipts[1].error_code.as_deref(), Some("INSUFFICIENT_BALANCE"));
+        assert_eq!(block.header.receipts_root, receipt::receipts_root(&receipts[..1]));
+        assert_eq!(receipts[1].block_height, 1);
+        assert_eq!(fs::read_dir(&chain.mempool_dir).unwrap().count(), 0);
+
+        let (header, tx, proof) = chain.prove_tx(&ok.hash()).unwrap().unwrap();
+        assert_eq!(tx.hash(), ok.hash());
+        assert!(proof.verify(&header.tx_root, &ok.hash()));
+        assert_eq!(chain.find_receipt(&broke.hash()).unwrap().unwrap().status, TxStatus::Failed);
+        assert_eq!(chain.find_receipt(&ok.hash()).unwrap().unwrap().index, 0);
+
+        // Receipts are found through the index, so a reverted block's are gone
+        chain.revert_to(0).unwrap();
+        assert!(chain.find_receipt(&broke.hash()).unwrap().is_none());
+        assert!(chain.find_receipt(&ok.hash()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_block_rewards_credit_coinbase_within_supply() {
+        let (chain, sk, pk) = test_chain("rewards");
+        let coinbase = [5u8; 32];
+        let chain = Arc::new((*chain).clone().with_coinbase(coinbase));
+        let tx = Tx::new_signed(&sk, pk, 0, 10, CHAIN_ID, None, TxPayload::Transfer { to: [9u8; 32], amount: 500, token_type: TokenType::Layer0 }).unwrap();
+        submit(&chain, &tx);
+
+        let (block, _) = chain.make_block_once().unwrap().unwrap();
+        let st = chain.state.lock();
+        let acct = st.accounts.get(&hex::encode(coinbase)).unwrap();
+        assert_eq!(block.header.coinbase, coinbase);
+        assert!(block.header.layer0_reward > 0);
+        assert_eq!(acct.balance(assets::LAYER0_ASSET), block.header.layer0_reward);
+        assert_eq!(acct.balance(assets::LONGYIELD_ASSET), block.header.longyield_reward);
+        assert!(st.audit_supply().iter().all(|a| a.consistent), "{:?}", st.audit_supply());
+    }
+
+    #[test]
+    fn test_fees_split_between_burn_and_proposer() {
+        let (chain, sk, pk) = test_chain("fees");
+        let coinbase = [5u8; 32];
+        let chain = Arc::new((*chain).clone().with_coinbase(coinbase));
+        let supply_before = chain.state.lock().circulating(TokenType::Native);
+        let tx = Tx::new_signed(&sk, pk, 0, 100, CHAIN_ID, None, TxPayload::Transfer { to: [9u8; 32], amount: 500, token_type: TokenType::Native }).unwrap();
+        submit(&chain, &tx);
+
+        let (_, receipts) = chain.make_block_once().unwrap().unwrap();
+        assert_eq!(receipts[0].fee, Some(fees::FeePaid { token: TokenType::Native, amount: 100 }));
+        let mut st = chain.state.lock();
+        assert_eq!(st.accounts[&hex::encode(coinbase)].balance(NATIVE_ASSET), 50);
+        assert_eq!(st.circulating(TokenType::Native), supply_before - 50);
+        assert!(st.audit_supply().iter().all(|a| a.consistent), "{:?}", st.audit_supply());
+
+        st.min_fee = 1_000;
+        let cheap = Tx::new_signed(&sk, pk, 1, 999, CHAIN_ID, None, TxPayload::Transfer { to: [9u8; 32], amount: 1, token_type: TokenType::Native }).unwrap();
+        assert_eq!(Chain::apply_tx(&mut st, &cheap).unwrap_err(), TxError::FeeTooLow { minimum: 1_000, got: 999 });
+
+        // The genesis floor holds even where the dynamic minimum is lower
+        st.min_fee = 0;
+        st.monetary.fees.min_fee_floor = 1_000;
+        assert_eq!(Chain::apply_tx(&mut st, &cheap).unwrap_err(), TxError::FeeTooLow { minimum: 1_000, got: 999 });
+    }
+
+    #[test]
+    fn test_unknown_version_and_foreign_chain_are_rejected() {
+        let (chain, sk, pk) = test_chain("envelope");
+        let mut st = chain.state.lock();
+        let send = TxPayload::Transfer { to: [9u8; 32], amount: 1, token_type: TokenType::Native };
+        let foreign = Tx::new_signed(&sk, pk, 0, 10, CHAIN_ID + 1, None, send.clone()).unwrap();
+        assert_eq!(Chain::apply_tx(&mut st, &foreign).unwrap_err(), TxError::WrongChain { expected: CHAIN_ID, got: CHAIN_ID + 1 });
+
+        let mut future = Tx::new_signed(&sk, pk, 0, 10, CHAIN_ID, None, send).unwrap();
+        future.version = TX_VERSION + 1;
+        assert_eq!(Chain::apply_tx(&mut st, &future).unwrap_err(), TxError::UnsupportedVersion { max: TX_VERSION, got: TX_VERSION + 1 });
+        assert_eq!(st.accounts[&hex::encode(pk)].nonce, 0);
+    }
+
+    #[test]
+    fn test_asset_issue_mint_burn() {
+        let (chain, sk, pk) = test_chain("assets");
+        let (other_sk, other_pk) = STARK.generate_keys().unwrap();
+        let issue = TxPayload::IssueAsset { symbol: "ACME".into(), decimals: 6, supply_cap: Some(1_000), initial_supply: 400 };
+        submit(&chain, &Tx::new_signed(&sk, pk, 0, 0, CHAIN_ID, None, issue).unwrap());
+        let (_, receipts) = chain.make_block_once().unwrap().unwrap();
+        let id = receipts[0].created_asset.unwrap();
+        assert_eq!(id, assets::FIRST_CUSTOM_ASSET);
+
+        let mut st = chain.state.lock();
+        let dup = TxPayload::IssueAsset { symbol: "ACME".into(), decimals: 0, supply_cap: None, initial_supply: 0 };
+        assert!(Chain::apply_tx(&mut st, &Tx::new_signed(&sk, pk, 1, 0, CHAIN_ID, None, dup).unwrap()).is_err());
+
+        let mint = Tx::new_signed(&sk, pk, 1, 0, CHAIN_ID, None, TxPayload::Mint { asset: id, to: other_pk, amount: 500 }).unwrap();
+        Chain::apply_tx(&mut st, &mint).unwrap();
+        let over = Tx::new_signed(&sk, pk, 2, 0, CHAIN_ID, None, TxPayload::Mint { asset: id, to: other_pk, amount: 101 }).unwrap();
+        assert_eq!(Chain::apply_tx(&mut st, &over).unwrap_err(), TxError::SupplyCapExceeded);
+        let stranger = Tx::new_signed(&other_sk, other_pk, 0, 0, CHAIN_ID, None, TxPayload::Mint { asset: id, to: other_pk, amount: 1 }).unwrap();
+        assert_eq!(Chain::apply_tx(&mut st, &stranger).unwrap_err(), TxError::NotIssuer);
+
+        let burn = Tx::new_signed(&other_sk, other_pk, 0, 0, CHAIN_ID, None, TxPayload::Burn { asset: id, amount: 200 }).unwrap();
+        Chain::apply_tx(&mut st, &burn).unwrap();
+        let send = TxPayload::Transfer { to: [9u8; 32], amount: 50, token_type: TokenType::Asset(id) };
+        Chain::apply_tx(&mut st, &Tx::new_signed(&other_sk, other_pk, 1, 0, CHAIN_ID, None, send).unwrap()).unwrap();
+        assert_eq!(st.accounts[&hex::encode(other_pk)].balance(id), 250);
+        assert_eq!(st.asset(id).unwrap().supply, 700);
+        let unknown = TxPayload::Burn { asset: id + 1, amount: 1 };
+        assert_eq!(
+            Chain::apply_tx(&mut st, &Tx::new_signed(&other_sk, other_pk, 2, 0, CHAIN_ID, None, unknown).unwrap()).unwrap_err(),
+            TxError::UnknownAsset(id + 1)
+        );
+        assert!(st.audit_supply().iter().all(|a| a.consistent), "{:?}", st.audit_supply());
+
+        let root = st.commit_root();
+        let (rec, proof) = st.prove_asset(id);
+        assert!(assets::verify_asset(&root, id, rec.as_ref(), &proof));
+    }
+
+    #[test]
+    fn test_legacy_state_migrates_to_registry() {
+        let legacy = serde_json::json!({
+            "balance": 7, "nonce": 3, "layer0_balance": 11, "longyield_balance": 0
+        });
+        let acct: Account = serde_json::from_value(legacy).unwrap();
+        assert_eq!((acct.nonce, acct.balance(NATIVE_ASSET), acct.balance(assets::LAYER0_ASSET)), (3, 7, 11));
+        assert!(!acct.balances.contains_key(&assets::LONGYIELD_ASSET));
+
+        let mut st = State::empty();
+        st.set_account([1u8; 32], &acct);
+        // A state saved before the registry: no asset records, counters on the state
+        st.assets.clear();
+        let saved = serde_json::to_string(&st).unwrap().replace(r#""assets":{}"#, r#""layer0_circulating":11"#);
+        let mut st: State = serde_json::from_str(&saved).unwrap();
+        st.migrate_legacy_supply();
+        assert_eq!(st.circulating(TokenType::Layer0), 11);
+        assert_eq!(st.circulating(TokenType::Native), 7);
+        assert!(st.audit_supply().iter().all(|a| a.consistent), "{:?}", st.audit_supply());
+    }
+
+    #[test]
+    fn test_revert_to_restores_state_and_requeues_txs() {
+        let (chain, sk, pk) = test_chain("revert");
+        let chain = Arc::new((*chain).clone().with_coinbase([5u8; 32]));
+        let (first, _) = chain.make_block_once().unwrap().unwrap();
+        let accounts = chain.state.lock().accounts.clone();
+        let asset = TxPayload::IssueAsset { symbol: "ACME".into(), decimals: 0, supply_cap: None, initial_supply: 10 };
+        submit(&chain, &Tx::new_signed(&sk, pk, 0, 0, CHAIN_ID, None, asset).unwrap());
+        chain.make_block_once().unwrap().unwrap();
+        let send = TxPayload::Transfer { to: [9u8; 32], amount: 500, token_type: TokenType::Native };
+        submit(&chain, &Tx::new_signed(&sk, pk, 1, 10, CHAIN_ID, None, send).unwrap());
+        chain.make_block_once().unwrap().unwrap();
+
+        assert!(chain.revert_to(4).is_err());
+        let reverted = chain.revert_to(1).unwrap();
+        assert_eq!(reverted.iter().map(|b| b.header.height).collect::<Vec<_>>(), vec![3, 2]);
+        {
+            let st = chain.state.lock();
+            assert_eq!(st.height, 1);
+            assert_eq!(st.state_root, first.header.state_root);
+            assert_eq!(st.last_block_hash, h_block_header(&first.header));
+            assert_eq!(st.accounts, accounts);
+            assert!(st.asset_by_symbol("ACME").is_none());
+        }
+        assert!(chain.load_block(2).unwrap().is_none());
+        assert_eq!(fs::read_dir(&chain.mempool_dir).unwrap().count(), 2);
+
+        // The requeued transactions apply again on top of the reverted tip
+        let (block, receipts) = chain.make_block_once().unwrap().unwrap();
+        assert_eq!((block.header.height, block.txs.len()), (2, 2));
+        assert!(receipts.iter().all(|r| r.status == TxStatus::Success));
+    }
+
+    #[test]
+    fn test_snapshot_of_past_height_matches_its_header() {
+        let (chain, sk, pk) = test_chain("snapshot");
+        let chain = Arc::new((*chain).clone().with_coinbase([5u8; 32]));
+        let send = TxPayload::Transfer { to: [9u8; 32], amount: 500, token_type: TokenType::Native };
+        submit(&chain, &Tx::new_signed(&sk, pk, 0, 10, CHAIN_ID, None, send).unwrap());
+        let (first, _) = chain.make_block_once().unwrap().unwrap();
+        chain.make_block_once().unwrap().unwrap();
+
+        let dir = chain.data_dir.join("snapshot-1");
+        let manifest = chain.export_snapshot(1, &dir).unwrap();
+        assert_eq!(manifest.header.as_ref(), Some(&first.header));
+        let (_, st) = snapshot::import_snapshot(&dir).unwrap();
+        assert_eq!((st.height, st.state_root), (1, first.header.state_root));
+        assert_eq!(chain.state.lock().height, 2);
+    }
+
+    #[test]
+    fn test_poa_validators_take_turns_and_seal() {
+        let (chain, sk, pk) = test_chain("poa");
+        let (other_sk, other_pk) = STARK.generate_keys().unwrap();
+        chain.state.lock().validators = vec![pk, other_pk];
+        let ours = Arc::new((*chain).clone().with_consensus(Arc::new(RoundRobinPoa::validator(sk).unwrap())));
+        let theirs = Arc::new((*chain).clone().with_consensus(Arc::new(RoundRobinPoa::validator(other_sk).unwrap())));
+
+        // Block 1 belongs to validators[1]
+        assert!(ours.make_block_once().unwrap().is_none());
+        let parent = chain.state.lock().clone();
+        let (block, _) = theirs.make_block_once().unwrap().unwrap();
+        assert_eq!(block.seal.as_ref().map(|s| s.proposer), Some(other_pk));
+        assert_eq!(block.header.coinbase, other_pk);
+        chain.verify_seal(&parent, &block).unwrap();
+        let mut unsealed = block.clone();
+        unsealed.seal = None;
+        assert!(chain.verify_seal(&parent, &unsealed).is_err());
+
+        assert!(theirs.make_block_once().unwrap().is_none());
+        let (block, _) = ours.make_block_once().unwrap().unwrap();
+        assert_eq!((block.header.height, block.seal.map(|s| s.proposer)), (2, Some(pk)));
+    }
+
+    #[test]
+    fn test_commit_certificate_finalizes_block() {
+        use finality::{CommitCertificate, Vote, VoteKind};
+
+        let (chain, _, _) = test_chain("finality");
+        let (block, _) = chain.make_block_once().unwrap().unwrap();
+        let keys: Vec<(SecretKey, H256)> = (0..4).map(|_| STARK.generate_keys().unwrap()).collect();
+        let chain_id = {
+            let mut st = chain.state.lock();
+            st.validators = keys.iter().map(|(_, pk)| *pk).collect();
+            st.chain_id
+        };
+        let cert = |signers: usize, block_hash: H256| CommitCertificate {
+            height: 1,
+            round: 0,
+            block_hash,
+            precommits: keys[..signers]
+                .iter()
+                .map(|(sk, _)| Vote::new_signed(chain_id, sk, VoteKind::Precommit, 1, 0, Some(block_hash)).unwrap())
+                .collect(),
+        };
+        let hash = h_block_header(&block.header);
+        assert!(chain.record_commit(cert(2, hash)).is_err());
+        assert!(chain.record_commit(cert(3, [1u8; 32])).is_err());
+        assert!(chain.record_commit(cert(3, hash)).unwrap());
+        assert!(!chain.record_commit(cert(4, hash)).unwrap());
+        assert_eq!(chain.finality().unwrap().0, 1);
+
+        // The next block carries the certificate; the finalized block stays
+        chain.state.lock().validators.clear();
+        let (next, _) = chain.make_block_once().unwrap().unwrap();
+        assert_eq!(next.header.last_commit, Some(cert(3, hash)));
+        assert!(chain.revert_to(0).is_err());
+        assert_eq!(chain.revert_to(1).unwrap().len(), 1);
+    }
 }
@@ -0,0 +1,160 @@
+//! Binary Merkle tree over canonical transaction hashes.
+//! - Leaf hash  = H(0x00 || tx_hash)
+//! - Inner hash = H(0x01 || left || right)
+//! - An odd node at the end of a level is carried up unchanged (no duplication),
+//!   so a tree over N leaves cannot collide with a tree over N+1 leaves.
+//! - The root of an empty tree is all zeroes.
+//!
+//! Proofs carry the leaf index and leaf count, which is enough for a verifier to
+//! replay the exact path without any other block data.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Tx, H256};
+
+fn hash_leaf(leaf: &H256) -> H256 {
+    let mut buf = [0u8; 1 + 32];
+    buf[0] = 0x00;
+    buf[1..].copy_from_slice(leaf);
+    *blake3::hash(&buf).as_bytes()
+}
+
+fn hash_node(left: &H256, right: &H256) -> H256 {
+    let mut buf = [0u8; 1 + 32 + 32];
+    buf[0] = 0x01;
+    buf[1..33].copy_from_slice(left);
+    buf[33..].copy_from_slice(right);
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// Inclusion proof for a single leaf.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MerkleProof {
+    /// Position of the leaf in the block
+    pub index: u64,
+    /// Number of leaves in the tree
+    pub leaf_count: u64,
+    /// Sibling hashes from the leaf level upwards (carried levels have no entry)
+    pub siblings: Vec<H256>,
+}
+
+impl MerkleProof {
+    /// Check that `leaf` sits at `self.index` in the tree committed to by `root`.
+    pub fn verify(&self, root: &H256, leaf: &H256) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut cur = hash_leaf(leaf);
+        let mut idx = self.index;
+        let mut width = self.leaf_count;
+        let mut sibs = self.siblings.iter();
+        while width > 1 {
+            if idx & 1 == 0 && idx + 1 == width {
+                // last odd node, carried up
+            } else {
+                let Some(sib) = sibs.next() else { return false };
+                cur = if idx & 1 == 0 { hash_node(&cur, sib) } else { hash_node(sib, &cur) };
+            }
+            idx /= 2;
+            width = width.div_ceil(2);
+        }
+        sibs.next().is_none() && &cur == root
+    }
+}
+
+/// Compute the Merkle root over a list of leaf hashes.
+pub fn merkle_root(leaves: &[H256]) -> H256 {
+    if leaves.is_empty() {
+        return [0u8; 32];
+    }
+    let mut level: Vec<H256> = leaves.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// Build an inclusion proof for the leaf at `index`.
+pub fn merkle_prove(leaves: &[H256], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut siblings = Vec::new();
+    let mut level: Vec<H256> = leaves.iter().map(hash_leaf).collect();
+    let mut idx = index;
+    while level.len() > 1 {
+        let sib = idx ^ 1;
+        if sib < level.len() {
+            siblings.push(level[sib]);
+        }
+        level = next_level(&level);
+        idx /= 2;
+    }
+    Some(MerkleProof { index: index as u64, leaf_count: leaves.len() as u64, siblings })
+}
+
+fn next_level(level: &[H256]) -> Vec<H256> {
+    level
+        .chunks(2)
+        .map(|pair| if pair.len() == 2 { hash_node(&pair[0], &pair[1]) } else { pair[0] })
+        .collect()
+}
+
+/// Transaction root committed in `BlockHeader::tx_root`.
+pub fn tx_root(txs: &[Tx]) -> H256 {
+    let leaves: Vec<H256> = txs.iter().map(Tx::hash).collect();
+    merkle_root(&leaves)
+}
+
+/// Inclusion proof for the transaction with hash `tx_hash` among `txs`.
+pub fn prove_tx(txs: &[Tx], tx_hash: &H256) -> Option<MerkleProof> {
+    let leaves: Vec<H256> = txs.iter().map(Tx::hash).collect();
+    let index = leaves.iter().position(|h| h == tx_hash)?;
+    merkle_prove(&leaves, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<H256> {
+        (0..n).map(|i| *blake3::hash(&(i as u64).to_le_bytes()).as_bytes()).collect()
+    }
+
+    #[test]
+    fn test_every_leaf_proves_for_all_sizes() {
+        for n in 1..=17 {
+            let ls = leaves(n);
+            let root = merkle_root(&ls);
+            for (i, leaf) in ls.iter().enumerate() {
+                let proof = merkle_prove(&ls, i).unwrap();
+                assert!(proof.verify(&root, leaf), "n={} i={}", n, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_proof_rejects_wrong_leaf_and_position() {
+        let ls = leaves(7);
+        let root = merkle_root(&ls);
+        let proof = merkle_prove(&ls, 3).unwrap();
+        assert!(!proof.verify(&root, &ls[4]));
+
+        let mut moved = proof.clone();
+        moved.index = 2;
+        assert!(!moved.verify(&root, &ls[3]));
+
+        let mut truncated = proof;
+        truncated.siblings.pop();
+        assert!(!truncated.verify(&root, &ls[3]));
+    }
+
+    #[test]
+    fn test_odd_tail_does_not_collide_with_duplicate() {
+        let ls = leaves(3);
+        let mut dup = ls.clone();
+        dup.push(ls[2]);
+        assert_ne!(merkle_root(&ls), merkle_root(&dup));
+        assert_eq!(merkle_root(&[]), [0u8; 32]);
+    }
+}
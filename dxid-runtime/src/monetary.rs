@@ -0,0 +1,270 @@
+//! Monetary policy.
+//!
+//! A `MonetaryPolicy` owns the reward schedule and any transfer-time issuance.
+//! The policy is selected by `MonetaryParams::policy` in the genesis spec; the
+//! runtime only ever asks the policy how much to mint and then issues it through
+//! `State::issue`, which enforces the supply caps.
+//!
+//! `simulate` replays a policy over an arbitrary number of blocks against the
+//! same issuance path and checks the supply invariants at every step, so a
+//! schedule change can be reviewed by its simulated outcome.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fees::{FeeParams, FeeShares},
+    State, TokenType, LAYER0_APPRECIATION_RATE, LAYER0_BLOCK_REWARD, LAYER0_HALVING_BLOCKS,
+    LAYER0_TOTAL_SUPPLY, LONGYIELD_TOTAL_SUPPLY,
+};
+
+/// Which `MonetaryPolicy` implementation a network runs.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyKind {
+    /// Halving plus early-miner and difficulty bonuses, Layer0 appreciation on transfers
+    #[default]
+    Legacy,
+    /// Plain halving schedule, no transfer-time issuance
+    Halving,
+}
+
+/// Monetary parameters fixed at genesis
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MonetaryParams {
+    #[serde(default)]
+    pub policy: PolicyKind,
+    pub layer0_total_supply: u128,
+    pub layer0_block_reward: u128,
+    pub layer0_halving_blocks: u64,
+    /// Parts per million minted to the recipient of a Layer0 transfer
+    pub layer0_appreciation_rate: u64,
+    pub longyield_total_supply: u128,
+    pub longyield_block_reward: u128,
+    #[serde(default)]
+    pub fees: FeeParams,
+}
+
+impl Default for MonetaryParams {
+    fn default() -> Self {
+        Self {
+            policy: PolicyKind::default(),
+            layer0_total_supply: LAYER0_TOTAL_SUPPLY,
+            layer0_block_reward: LAYER0_BLOCK_REWARD,
+            layer0_halving_blocks: LAYER0_HALVING_BLOCKS,
+            layer0_appreciation_rate: LAYER0_APPRECIATION_RATE,
+            longyield_total_supply: LONGYIELD_TOTAL_SUPPLY,
+            longyield_block_reward: 1_000_000_000_000_000_000, // 1 L1 token per block
+            fees: FeeParams::default(),
+        }
+    }
+}
+
+impl MonetaryParams {
+    /// Instantiate the configured policy.
+    pub fn policy(&self) -> Box<dyn MonetaryPolicy> {
+        match self.policy {
+            PolicyKind::Legacy => Box::new(LegacyPolicy(self.clone())),
+            PolicyKind::Halving => Box::new(HalvingPolicy(self.clone())),
+        }
+    }
+}
+
+/// Reward schedule and transfer-time issuance. Amounts returned here are requests;
+/// the caller clamps them to the remaining supply.
+pub trait MonetaryPolicy: Send + Sync {
+    fn kind(&self) -> PolicyKind;
+
+    /// Layer0 reward for the block at `height`
+    fn layer0_reward(&self, height: u64) -> u128;
+
+    /// LongYield reward for the block at `height`
+    fn longyield_reward(&self, height: u64) -> u128;
+
+    /// Tokens minted to the recipient of a transfer of `amount`
+    fn transfer_issuance(&self, token: TokenType, amount: u128) -> u128;
+
+    /// How a block's fee total is divided between burn, proposer and treasury
+    fn fee_split(&self, fee: u128) -> FeeShares;
+}
+
+fn halved(reward: u128, height: u64, halving_blocks: u64) -> u128 {
+    let halvings = height / halving_blocks.max(1);
+    reward.checked_shr(u32::try_from(halvings).unwrap_or(u32::MAX)).unwrap_or(0)
+}
+
+/// The original devnet economics.
+pub struct LegacyPolicy(pub MonetaryParams);
+
+impl MonetaryPolicy for LegacyPolicy {
+    fn kind(&self) -> PolicyKind {
+        PolicyKind::Legacy
+    }
+
+    fn layer0_reward(&self, height: u64) -> u128 {
+        let block_reward = self.0.layer0_block_reward;
+        let base_reward = halved(block_reward, height, self.0.layer0_halving_blocks);
+
+        // Early block bonuses
+        let early_miner_bonus = if height < 1000 {
+            block_reward / 2 // 50% bonus for first 1000 blocks
+        } else if height < 10000 {
+            block_reward / 4 // 25% bonus for first 10,000 blocks
+        } else {
+            0
+        };
+
+        // Difficulty bonuses
+        let difficulty_bonus = if height > 1000 {
+            ((height / 1000) as u128) * 10_000_000_000u128 // Bonus increases with height
+        } else {
+            0u128
+        };
+
+        base_reward + early_miner_bonus + difficulty_bonus
+    }
+
+    fn longyield_reward(&self, _height: u64) -> u128 {
+        self.0.longyield_block_reward
+    }
+
+    fn transfer_issuance(&self, token: TokenType, amount: u128) -> u128 {
+        match token {
+            TokenType::Layer0 => amount.saturating_mul(self.0.layer0_appreciation_rate as u128) / 1_000_000,
+            _ => 0,
+        }
+    }
+
+    fn fee_split(&self, fee: u128) -> FeeShares {
+        self.0.fees.split(fee)
+    }
+}
+
+/// Geometric schedule: total issuance converges to 2 * reward * halving_blocks.
+pub struct HalvingPolicy(pub MonetaryParams);
+
+impl MonetaryPolicy for HalvingPolicy {
+    fn kind(&self) -> PolicyKind {
+        PolicyKind::Halving
+    }
+
+    fn layer0_reward(&self, height: u64) -> u128 {
+        halved(self.0.layer0_block_reward, height, self.0.layer0_halving_blocks)
+    }
+
+    fn longyield_reward(&self, height: u64) -> u128 {
+        halved(self.0.longyield_block_reward, height, self.0.layer0_halving_blocks)
+    }
+
+    fn transfer_issuance(&self, _token: TokenType, _amount: u128) -> u128 {
+        0
+    }
+
+    fn fee_split(&self, fee: u128) -> FeeShares {
+        self.0.fees.split(fee)
+    }
+}
+
+/// Outcome of a policy simulation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimReport {
+    pub blocks: u64,
+    pub layer0_issued: u128,
+    pub longyield_issued: u128,
+    /// First height at which Layer0 issuance was clamped by the cap
+    pub layer0_capped_at: Option<u64>,
+}
+
+/// Run `blocks` blocks of `params`' policy through `State::issue`, with
+/// `transfer_volume(height)` worth of Layer0 transfers per block. Panics on the
+/// first violated invariant:
+/// - circulating supply never exceeds the cap and never decreases,
+/// - circulating supply equals genesis supply plus everything issued,
+/// - once the cap has been hit, nothing more is issued.
+pub fn simulate(params: &MonetaryParams, blocks: u64, transfer_volume: impl Fn(u64) -> u128) -> SimReport {
+    let policy = params.policy();
+    let mut st = State::with_monetary(params.clone());
+    let mut report = SimReport { blocks, ..Default::default() };
+
+    for height in 1..=blocks {
+        st.height = height;
+        let before = (st.circulating(TokenType::Layer0), st.circulating(TokenType::LongYield));
+
+        let requested = policy.layer0_reward(height);
+        let issued = st.issue(TokenType::Layer0, requested);
+        let appreciation = st.issue(TokenType::Layer0, policy.transfer_issuance(TokenType::Layer0, transfer_volume(height)));
+        if issued < requested && report.layer0_capped_at.is_none() {
+            report.layer0_capped_at = Some(height);
+        }
+        report.layer0_issued += issued + appreciation;
+        report.longyield_issued += st.issue(TokenType::LongYield, policy.longyield_reward(height));
+
+        let now = (st.circulating(TokenType::Layer0), st.circulating(TokenType::LongYield));
+        assert!(now.0 <= params.layer0_total_supply, "layer0 over cap at {}", height);
+        assert!(now.1 <= params.longyield_total_supply, "longyield over cap at {}", height);
+        assert!(now.0 >= before.0 && now.1 >= before.1, "supply decreased at {}", height);
+        assert_eq!(now.0, report.layer0_issued, "layer0 counter drift at {}", height);
+        assert_eq!(now.1, report.longyield_issued, "longyield counter drift at {}", height);
+        if report.layer0_capped_at.is_some_and(|h| h < height) {
+            assert_eq!(now.0, before.0, "layer0 issued past the cap at {}", height);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MILLION: u64 = 1_000_000;
+
+    fn params(policy: PolicyKind) -> impl Strategy<Value = MonetaryParams> {
+        (1u128..=1_000_000_000_000, 1u64..=500_000, 0u64..=10_000, 1u128..=u64::MAX as u128).prop_map(
+            move |(layer0_block_reward, layer0_halving_blocks, layer0_appreciation_rate, longyield_block_reward)| {
+                MonetaryParams {
+                    policy,
+                    layer0_block_reward,
+                    layer0_halving_blocks,
+                    layer0_appreciation_rate,
+                    longyield_block_reward,
+                    ..Default::default()
+                }
+            },
+        )
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(4))]
+
+        #[test]
+        fn test_legacy_supply_invariants(p in params(PolicyKind::Legacy), volume in 0u128..=1_000_000_000_000) {
+            simulate(&p, 2 * MILLION, |_| volume);
+        }
+
+        #[test]
+        fn test_halving_supply_invariants(p in params(PolicyKind::Halving)) {
+            let report = simulate(&p, 2 * MILLION, |_| u128::MAX);
+            let bound = 2 * p.layer0_block_reward * p.layer0_halving_blocks as u128;
+            prop_assert!(report.layer0_issued <= bound);
+        }
+    }
+
+    #[test]
+    fn test_default_legacy_schedule_hits_cap() {
+        let report = simulate(&MonetaryParams::default(), 5 * MILLION, |_| 0);
+        assert_eq!(report.layer0_issued, LAYER0_TOTAL_SUPPLY);
+        assert!(report.layer0_capped_at.is_some());
+    }
+
+    #[test]
+    fn test_policy_selected_from_params() {
+        let mut p = MonetaryParams::default();
+        assert_eq!(p.policy().kind(), PolicyKind::Legacy);
+        p.policy = PolicyKind::Halving;
+        assert_eq!(p.policy().kind(), PolicyKind::Halving);
+        assert_eq!(p.policy().transfer_issuance(TokenType::Layer0, 1_000_000), 0);
+
+        let spec: MonetaryParams = serde_json::from_str(&serde_json::to_string(&p).unwrap()).unwrap();
+        assert_eq!(spec.policy, PolicyKind::Halving);
+    }
+}
@@ -0,0 +1,208 @@
+//! Transaction receipts.
+//!
+//! Every transaction the block builder picks up produces a receipt, whether it was
+//! included or rejected. Receipts are stored per block and committed to by
+//! `BlockHeader::receipts_root` (a binary Merkle root over receipt hashes).
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    assets::AssetId, did::DidId, fees::FeePaid, htlc::HtlcId, merkle, vesting::VestingId, wasm::ModuleId, TokenType, H256,
+};
+
+/// Why a transaction could not be applied.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum TxError {
+    #[error("signature verification failed")]
+    BadSignature,
+    #[error("bad nonce: expected {expected}, got {got}")]
+    BadNonce { expected: u64, got: u64 },
+    #[error("insufficient {token:?} balance")]
+    InsufficientBalance { token: TokenType },
+    #[error("wrong chain id: expected {expected}, got {got}")]
+    WrongChain { expected: u32, got: u32 },
+    #[error("transaction version {got} is newer than the supported {max}")]
+    UnsupportedVersion { max: u8, got: u8 },
+    #[error("expired at height {expiry}, now {height}")]
+    Expired { expiry: u64, height: u64 },
+    #[error("invalid payload: {0}")]
+    InvalidPayload(String),
+    #[error("fee {got} below minimum {minimum}")]
+    FeeTooLow { minimum: u128, got: u128 },
+    #[error("unknown asset {0}")]
+    UnknownAsset(AssetId),
+    #[error("sender is not the asset issuer")]
+    NotIssuer,
+    #[error("asset supply cap exceeded")]
+    SupplyCapExceeded,
+    #[error("unknown module {}", hex::encode(.0))]
+    UnknownModule(ModuleId),
+    #[error("out of gas (limit {limit})")]
+    OutOfGas { limit: u64 },
+    #[error("module trapped: {0}")]
+    ModuleTrap(String),
+    #[error("module reverted with code {0}")]
+    ModuleReverted(i32),
+    #[error("insufficient stake: {staked} bonded")]
+    InsufficientStake { staked: u128 },
+    #[error("invalid slashing evidence: {0}")]
+    InvalidEvidence(String),
+    #[error("validator {} is tombstoned", hex::encode(.0))]
+    Tombstoned(H256),
+    #[error("unknown vesting schedule {}", hex::encode(.0))]
+    UnknownVesting(VestingId),
+    #[error("sender is not the vesting beneficiary")]
+    NotBeneficiary,
+    #[error("nothing vested to claim (cliff at height {cliff_height})")]
+    NothingVested { cliff_height: u64 },
+    #[error("unknown escrow {}", hex::encode(.0))]
+    UnknownHtlc(HtlcId),
+    #[error("escrow already claimed or refunded")]
+    HtlcSettled,
+    #[error("preimage does not match the hash lock")]
+    WrongPreimage,
+    #[error("escrow expired at height {expiry}")]
+    HtlcExpired { expiry: u64 },
+    #[error("escrow cannot be refunded before height {expiry}")]
+    HtlcNotExpired { expiry: u64 },
+    #[error("unknown DID {}", crate::did::did_string(.0))]
+    UnknownDid(DidId),
+    #[error("sender is not the DID controller")]
+    NotController,
+    #[error("DID is deactivated")]
+    DidDeactivated,
+    #[error("credential {} is already revoked", hex::encode(.0))]
+    AlreadyRevoked(H256),
+}
+
+impl TxError {
+    /// Stable machine-readable error code carried in receipts.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TxError::BadSignature => "BAD_SIGNATURE",
+            TxError::BadNonce { .. } => "BAD_NONCE",
+            TxError::InsufficientBalance { .. } => "INSUFFICIENT_BALANCE",
+            TxError::WrongChain { .. } => "WRONG_CHAIN",
+            TxError::UnsupportedVersion { .. } => "UNSUPPORTED_VERSION",
+            TxError::Expired { .. } => "EXPIRED",
+            TxError::InvalidPayload(_) => "INVALID_PAYLOAD",
+            TxError::FeeTooLow { .. } => "FEE_TOO_LOW",
+            TxError::UnknownAsset(_) => "UNKNOWN_ASSET",
+            TxError::NotIssuer => "NOT_ISSUER",
+            TxError::SupplyCapExceeded => "SUPPLY_CAP_EXCEEDED",
+            TxError::UnknownModule(_) => "UNKNOWN_MODULE",
+            TxError::OutOfGas { .. } => "OUT_OF_GAS",
+            TxError::ModuleTrap(_) => "MODULE_TRAP",
+            TxError::ModuleReverted(_) => "MODULE_REVERTED",
+            TxError::InsufficientStake { .. } => "INSUFFICIENT_STAKE",
+            TxError::InvalidEvidence(_) => "INVALID_EVIDENCE",
+            TxError::Tombstoned(_) => "TOMBSTONED",
+            TxError::UnknownVesting(_) => "UNKNOWN_VESTING",
+            TxError::NotBeneficiary => "NOT_BENEFICIARY",
+            TxError::NothingVested { .. } => "NOTHING_VESTED",
+            TxError::UnknownHtlc(_) => "UNKNOWN_HTLC",
+            TxError::HtlcSettled => "HTLC_SETTLED",
+            TxError::WrongPreimage => "WRONG_PREIMAGE",
+            TxError::HtlcExpired { .. } => "HTLC_EXPIRED",
+            TxError::HtlcNotExpired { .. } => "HTLC_NOT_EXPIRED",
+            TxError::UnknownDid(_) => "UNKNOWN_DID",
+            TxError::NotController => "NOT_CONTROLLER",
+            TxError::DidDeactivated => "DID_DEACTIVATED",
+            TxError::AlreadyRevoked(_) => "ALREADY_REVOKED",
+        }
+    }
+
+    /// A nonce ahead of the account may become valid once earlier transactions land,
+    /// so such transactions stay queued instead of being rejected.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, TxError::BadNonce { expected, got } if got > expected)
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum TxStatus {
+    Success,
+    Failed,
+}
+
+/// A single balance movement caused by a transaction.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct BalanceChange {
+    pub address: H256,
+    pub token: TokenType,
+    pub before: u128,
+    pub after: u128,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Receipt {
+    pub tx_hash: H256,
+    pub block_height: u64,
+    /// Position among the block's receipts
+    pub index: u32,
+    pub status: TxStatus,
+    pub error_code: Option<String>,
+    pub balance_changes: Vec<BalanceChange>,
+    /// Sender nonce consumed by the transaction (None when rejected)
+    pub nonce_consumed: Option<u64>,
+    /// Layer0 appreciation minted to the recipient
+    pub appreciation_minted: u128,
+    /// Fee charged to the sender (None for Layer0 and rejected transactions)
+    #[serde(default)]
+    pub fee: Option<FeePaid>,
+    /// Id assigned by an `IssueAsset` transaction
+    #[serde(default)]
+    pub created_asset: Option<AssetId>,
+    /// Id assigned by a `DeployModule` transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_module: Option<ModuleId>,
+    /// Id assigned by a `CreateVesting` transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_vesting: Option<VestingId>,
+    /// Id assigned by a `LockHtlc` transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_htlc: Option<HtlcId>,
+    /// Id assigned by a `CreateDid` transaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_did: Option<DidId>,
+    /// Gas consumed by module deployment or execution
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gas_used: Option<u64>,
+    /// Data returned by a module call, hex-encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+impl Receipt {
+    pub fn failed(tx_hash: H256, err: &TxError) -> Self {
+        Self {
+            tx_hash,
+            block_height: 0,
+            index: 0,
+            status: TxStatus::Failed,
+            error_code: Some(err.code().to_string()),
+            balance_changes: Vec::new(),
+            nonce_consumed: None,
+            appreciation_minted: 0,
+            fee: None,
+            created_asset: None,
+            created_module: None,
+            created_vesting: None,
+            created_htlc: None,
+            created_did: None,
+            gas_used: None,
+            output: None,
+        }
+    }
+
+    pub fn hash(&self) -> H256 {
+        *blake3::hash(&serde_json::to_vec(self).unwrap_or_default()).as_bytes()
+    }
+}
+
+/// Receipts root committed in `BlockHeader::receipts_root`.
+pub fn receipts_root(receipts: &[Receipt]) -> H256 {
+    let leaves: Vec<H256> = receipts.iter().map(Receipt::hash).collect();
+    merkle::merkle_root(&leaves)
+}
@@ -0,0 +1,317 @@
+//! State snapshots.
+//!
+//! A snapshot is a directory with a `manifest.json` and a `chunks/` directory.
+//! Accounts are written in address order, `CHUNK_ACCOUNTS` per chunk, and every
+//! chunk is stored under the BLAKE3 hash of its bytes, so chunks can be shared
+//! between snapshots and fetched from untrusted sources. The manifest carries the
+//! block header at the snapshot height, the state root and the remaining state
+//! (asset registry, modules and their storage, fee market, validator registry and
+//! active set, vesting schedules, HTLC escrows, DID documents, credential revocations).
+//!
+//! `import_snapshot` checks every chunk against its hash, rebuilds the SMT and
+//! only returns a state whose root matches both the manifest and the header.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::{AssetId, AssetInfo},
+    dehex32,
+    did::DidDocument,
+    h_block_header,
+    htlc::Htlc,
+    monetary::MonetaryParams,
+    staking::{StakingParams, ValidatorRecord},
+    vesting::VestingSchedule,
+    wasm::ModuleInfo,
+    Account, BlockHeader, State, H256,
+};
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Accounts per chunk
+pub const CHUNK_ACCOUNTS: usize = 4_096;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const CHUNKS_DIR: &str = "chunks";
+
+/// One chunk of accounts, addressed by its content hash.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChunkRef {
+    /// hex(BLAKE3) of the chunk file
+    pub hash: String,
+    pub accounts: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    pub chain_id: u32,
+    pub genesis_hash: H256,
+    pub height: u64,
+    /// Header of the block at `height` (None for a genesis snapshot)
+    pub header: Option<BlockHeader>,
+    pub state_root: H256,
+    pub last_block_hash: H256,
+    pub monetary: MonetaryParams,
+    pub validators: Vec<H256>,
+    #[serde(default)]
+    pub previous_validators: Vec<H256>,
+    #[serde(default)]
+    pub validators_since: u64,
+    #[serde(default)]
+    pub staking: StakingParams,
+    #[serde(default)]
+    pub validator_registry: BTreeMap<String, ValidatorRecord>,
+    #[serde(default)]
+    pub vesting: BTreeMap<String, VestingSchedule>,
+    #[serde(default)]
+    pub htlcs: BTreeMap<String, Htlc>,
+    #[serde(default)]
+    pub dids: BTreeMap<String, DidDocument>,
+    #[serde(default)]
+    pub revocations: BTreeMap<String, BTreeMap<String, u64>>,
+    pub min_fee: u128,
+    pub recent_block_txs: Vec<u32>,
+    pub assets: BTreeMap<AssetId, AssetInfo>,
+    /// Deployed modules and their storage
+    #[serde(default)]
+    pub modules: BTreeMap<String, ModuleInfo>,
+    #[serde(default)]
+    pub module_storage: BTreeMap<String, BTreeMap<String, String>>,
+    pub chunks: Vec<ChunkRef>,
+}
+
+impl SnapshotManifest {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        let txt = fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&txt).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn account_count(&self) -> usize {
+        self.chunks.iter().map(|c| c.accounts).sum()
+    }
+}
+
+fn chunk_path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join(CHUNKS_DIR).join(format!("{}.json", hash))
+}
+
+/// Write `state` as a snapshot into `dir`. `header` must be the header of the block
+/// at `state.height` (None at genesis).
+pub fn export_snapshot(state: &State, header: Option<&BlockHeader>, dir: &Path) -> Result<SnapshotManifest> {
+    match header {
+        Some(h) if h.height != state.height || h.state_root != state.state_root => {
+            bail!("header {} does not match the state at height {}", h.height, state.height)
+        }
+        None if state.height != 0 => bail!("a header is required above genesis"),
+        _ => {}
+    }
+    fs::create_dir_all(dir.join(CHUNKS_DIR))?;
+
+    let mut accounts: Vec<(&String, &Account)> = state.accounts.iter().collect();
+    accounts.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut chunks = Vec::with_capacity(accounts.len().div_ceil(CHUNK_ACCOUNTS));
+    for chunk in accounts.chunks(CHUNK_ACCOUNTS) {
+        let bytes = serde_json::to_vec(chunk)?;
+        let hash = blake3::hash(&bytes).to_hex().to_string();
+        let path = chunk_path(dir, &hash);
+        if !path.exists() {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, &bytes)?;
+            fs::rename(&tmp, &path)?;
+        }
+        chunks.push(ChunkRef { hash, accounts: chunk.len() });
+    }
+
+    let manifest = SnapshotManifest {
+        version: SNAPSHOT_VERSION,
+        chain_id: state.chain_id,
+        genesis_hash: state.genesis_hash,
+        height: state.height,
+        header: header.cloned(),
+        state_root: state.state_root,
+        last_block_hash: state.last_block_hash,
+        monetary: state.monetary.clone(),
+        validators: state.validators.clone(),
+        previous_validators: state.previous_validators.clone(),
+        validators_since: state.validators_since,
+        staking: state.staking.clone(),
+        validator_registry: state.validator_registry.clone(),
+        vesting: state.vesting.clone(),
+        htlcs: state.htlcs.clone(),
+        dids: state.dids.clone(),
+        revocations: state.revocations.clone(),
+        min_fee: state.min_fee,
+        recent_block_txs: state.recent_block_txs.clone(),
+        assets: state.assets.clone(),
+        modules: state.modules.clone(),
+        module_storage: state.module_storage.clone(),
+        chunks,
+    };
+    fs::write(dir.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
+/// Read and verify the snapshot in `dir`. Fails unless every chunk matches its hash
+/// and the rebuilt state root matches the manifest and its header.
+pub fn import_snapshot(dir: &Path) -> Result<(SnapshotManifest, State)> {
+    let manifest = SnapshotManifest::load(dir)?;
+    if manifest.version != SNAPSHOT_VERSION {
+        bail!("unsupported snapshot version {}", manifest.version);
+    }
+    match &manifest.header {
+        Some(h) => {
+            if h.height != manifest.height || h.state_root != manifest.state_root {
+                bail!("manifest header does not match its height and state root");
+            }
+            if h_block_header(h) != manifest.last_block_hash {
+                bail!("manifest header does not hash to last_block_hash");
+            }
+        }
+        None if manifest.height != 0 || manifest.last_block_hash != manifest.genesis_hash => {
+            bail!("manifest without a header must be a genesis snapshot")
+        }
+        None => {}
+    }
+
+    let mut state = State::with_monetary(manifest.monetary.clone());
+    state.assets.clear();
+    state.dirty_assets.clear();
+    for (id, info) in &manifest.assets {
+        state.set_asset(*id, info.clone());
+    }
+
+    let mut leaves = Vec::with_capacity(manifest.account_count());
+    let mut last: Option<String> = None;
+    for (i, chunk) in manifest.chunks.iter().enumerate() {
+        let path = chunk_path(dir, &chunk.hash);
+        let bytes = fs::read(&path).with_context(|| format!("reading chunk {}", chunk.hash))?;
+        if blake3::hash(&bytes).to_hex().as_str() != chunk.hash {
+            bail!("chunk {} ({}) does not match its hash", i, chunk.hash);
+        }
+        let accounts: Vec<(String, Account)> =
+            serde_json::from_slice(&bytes).with_context(|| format!("parsing chunk {}", chunk.hash))?;
+        if accounts.len() != chunk.accounts {
+            bail!("chunk {} holds {} accounts, manifest says {}", chunk.hash, accounts.len(), chunk.accounts);
+        }
+        for (addr_hex, acct) in accounts {
+            // Strict address order rules out duplicates across chunks
+            if last.as_ref().is_some_and(|prev| *prev >= addr_hex) {
+                bail!("accounts out of order at {}", addr_hex);
+            }
+            let addr = dehex32(&addr_hex).with_context(|| format!("bad address {}", addr_hex))?;
+            leaves.push((addr, acct.leaf_hash()));
+            state.accounts.insert(addr_hex.clone(), acct);
+            last = Some(addr_hex);
+        }
+    }
+    state.smt.extend(leaves);
+    state.modules = manifest.modules.clone();
+    state.module_storage = manifest.module_storage.clone();
+    state.rebuild_module_trees();
+    // The active set is committed too, so it has to be in place before the root is checked
+    state.validators = manifest.validators.clone();
+    state.previous_validators = manifest.previous_validators.clone();
+    state.validators_since = manifest.validators_since;
+    state.staking = manifest.staking.clone();
+    state.validator_registry = manifest.validator_registry.clone();
+    state.dirty_validators = state.validator_registry.keys().filter_map(|addr| dehex32(addr)).collect();
+    state.vesting = manifest.vesting.clone();
+    state.dirty_vesting = state.vesting.keys().filter_map(|id| dehex32(id)).collect();
+    state.htlcs = manifest.htlcs.clone();
+    state.dirty_htlcs = state.htlcs.keys().filter_map(|id| dehex32(id)).collect();
+    state.dids = manifest.dids.clone();
+    state.dirty_dids = state.dids.keys().filter_map(|id| dehex32(id)).collect();
+    state.revocations = manifest.revocations.clone();
+    state.rebuild_revocation_trees();
+
+    let root = state.commit_root();
+    if root != manifest.state_root {
+        bail!(
+            "rebuilt state root {} does not match manifest root {}",
+            hex::encode(root),
+            hex::encode(manifest.state_root)
+        );
+    }
+    state.chain_id = manifest.chain_id;
+    state.genesis_hash = manifest.genesis_hash;
+    state.height = manifest.height;
+    state.last_block_hash = manifest.last_block_hash;
+    state.min_fee = manifest.min_fee;
+    state.recent_block_txs = manifest.recent_block_txs.clone();
+    Ok((manifest, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assets::NATIVE_ASSET, genesis::GenesisSpec, TokenType};
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dxid-snapshot-{}-{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn sample_state(accounts: u8) -> State {
+        let mut st = GenesisSpec::dev([1u8; 32], 0).build_state().unwrap();
+        for i in 2..accounts {
+            let mut acct = Account::default();
+            acct.credit(NATIVE_ASSET, st.issue(TokenType::Native, i as u128));
+            st.set_account([i; 32], &acct);
+        }
+        st.commit_root();
+        st.last_block_hash = st.genesis_hash;
+        st
+    }
+
+    #[test]
+    fn test_round_trip_rebuilds_root() {
+        let st = sample_state(20);
+        let dir = temp_dir("roundtrip");
+        let manifest = export_snapshot(&st, None, &dir).unwrap();
+        assert_eq!(manifest.account_count(), st.accounts.len());
+
+        let (imported_manifest, imported) = import_snapshot(&dir).unwrap();
+        assert_eq!(imported_manifest, manifest);
+        assert_eq!(imported.state_root, st.state_root);
+        assert_eq!(imported.accounts, st.accounts);
+        assert_eq!(imported.assets, st.assets);
+        assert_eq!(imported.genesis_hash, st.genesis_hash);
+
+        // Same content, same chunk names
+        let again = export_snapshot(&imported, None, &dir).unwrap();
+        assert_eq!(again.chunks, manifest.chunks);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_import_rejects_tampering() {
+        let st = sample_state(5);
+        let dir = temp_dir("tamper");
+        let manifest = export_snapshot(&st, None, &dir).unwrap();
+
+        // A modified chunk no longer matches its name
+        let path = chunk_path(&dir, &manifest.chunks[0].hash);
+        let original = fs::read_to_string(&path).unwrap();
+        fs::write(&path, original.replacen(":2}", ":3}", 1)).unwrap();
+        assert!(import_snapshot(&dir).unwrap_err().to_string().contains("does not match its hash"));
+        fs::write(&path, &original).unwrap();
+
+        // A manifest claiming a different root
+        let mut forged = manifest.clone();
+        forged.assets.get_mut(&NATIVE_ASSET).unwrap().supply += 1;
+        fs::write(dir.join(MANIFEST_FILE), serde_json::to_string(&forged).unwrap()).unwrap();
+        assert!(import_snapshot(&dir).unwrap_err().to_string().contains("state root"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,521 @@
+//! Staking and the validator registry.
+//!
+//! Operators join block production by bonding native tokens with a `Stake`
+//! transaction and leave with `Unstake`, which moves the amount into an unbonding
+//! entry that is paid back `unbonding_blocks` later. Bonded and unbonding stake can
+//! be slashed by anyone holding `Equivocation` evidence: two conflicting finality
+//! votes signed by the validator for the same height and round. A slash burns
+//! `slash_bps` of the offender's stake and tombstones it; a tombstoned validator
+//! never rejoins the active set and can only unstake what is left.
+//!
+//! Registry records are committed to the state SMT under `validator_key(addr)` and
+//! the active set under `active_set_key()`. At the end of every block matured
+//! unbonding entries are paid out, and at every epoch boundary (`epoch_length`
+//! blocks) the active set is recomputed from the registry: the `max_validators`
+//! largest stakes of at least `min_stake`, largest first (ties by address). The new
+//! set proposes and finalizes from the next block on; the previous one is kept to
+//! check certificates of the blocks it sealed. While nobody qualifies the current
+//! set stays, so a chain started with genesis validators keeps producing blocks
+//! until operators have staked.
+
+use anyhow::{bail, Result};
+use dxid_smt::{SmtProof, SparseMerkleTree};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::NATIVE_ASSET,
+    finality::Vote,
+    receipt::{BalanceChange, Receipt, TxError},
+    tx::Tx,
+    Account, Chain, State, TokenType, H256,
+};
+
+/// Staking parameters fixed at genesis
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct StakingParams {
+    /// Blocks per epoch; the active set is recomputed at every epoch boundary
+    pub epoch_length: u64,
+    /// Blocks unstaked tokens stay locked (and slashable) before they are paid back
+    pub unbonding_blocks: u64,
+    /// Smallest bonded stake that qualifies for the active set
+    pub min_stake: u128,
+    /// Size cap of the active set
+    pub max_validators: u32,
+    /// Share of bonded and unbonding stake burned for equivocation, in basis points
+    pub slash_bps: u32,
+}
+
+impl Default for StakingParams {
+    fn default() -> Self {
+        Self {
+            epoch_length: 100,
+            unbonding_blocks: 1_000,
+            min_stake: 1_000_000,
+            max_validators: 100,
+            slash_bps: 500,
+        }
+    }
+}
+
+impl StakingParams {
+    pub fn validate(&self) -> Result<()> {
+        if self.epoch_length == 0 {
+            bail!("epoch_length must be non-zero");
+        }
+        if self.max_validators == 0 {
+            bail!("max_validators must be non-zero");
+        }
+        if self.slash_bps > 10_000 {
+            bail!("slash_bps must not exceed 10000");
+        }
+        Ok(())
+    }
+
+    /// Epoch the block at `height` belongs to
+    pub fn epoch(&self, height: u64) -> u64 {
+        height / self.epoch_length
+    }
+}
+
+/// Unstaked tokens waiting out the unbonding period.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Unbonding {
+    pub amount: u128,
+    /// Block at the end of which the amount is paid back
+    pub release_height: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ValidatorRecord {
+    /// Bonded native tokens
+    pub stake: u128,
+    /// Pending unbonding entries, oldest first
+    #[serde(default)]
+    pub unbonding: Vec<Unbonding>,
+    /// Total burned by slashing
+    #[serde(default)]
+    pub slashed: u128,
+    /// Set by a slash; the validator never rejoins the active set
+    #[serde(default)]
+    pub tombstoned: bool,
+}
+
+impl ValidatorRecord {
+    fn leaf_hash(&self) -> H256 {
+        *blake3::hash(&serde_json::to_vec(self).unwrap_or_default()).as_bytes()
+    }
+
+    pub fn unbonding_total(&self) -> u128 {
+        self.unbonding.iter().map(|u| u.amount).sum()
+    }
+
+    /// Nothing bonded, nothing pending and nothing to remember
+    fn is_empty(&self) -> bool {
+        self.stake == 0 && self.unbonding.is_empty() && !self.tombstoned
+    }
+}
+
+/// Two conflicting finality votes signed by the same validator.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Equivocation {
+    pub first: Vote,
+    pub second: Vote,
+}
+
+impl Equivocation {
+    /// Check that the votes conflict and are both validly signed; returns the offender.
+    pub fn verify(&self, chain_id: u32) -> Result<H256> {
+        let (a, b) = (&self.first, &self.second);
+        if a.voter != b.voter {
+            bail!("votes are from different validators");
+        }
+        if (a.kind, a.height, a.round) != (b.kind, b.height, b.round) {
+            bail!("votes are for different steps");
+        }
+        if a.block_hash == b.block_hash {
+            bail!("votes do not conflict");
+        }
+        a.verify(chain_id)?;
+        b.verify(chain_id)?;
+        Ok(a.voter)
+    }
+}
+
+/// SMT key of a registry record (domain-separated from account addresses)
+pub fn validator_key(addr: &H256) -> H256 {
+    let mut buf = b"dxid-validator".to_vec();
+    buf.extend_from_slice(addr);
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// SMT key of the active set
+pub fn active_set_key() -> H256 {
+    *blake3::hash(b"dxid-active-set").as_bytes()
+}
+
+/// Leaf committing to the active set and the first block it proposes (None when empty).
+pub fn active_set_leaf(validators: &[H256], since: u64) -> Option<H256> {
+    if validators.is_empty() {
+        return None;
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&since.to_le_bytes());
+    for v in validators {
+        hasher.update(v);
+    }
+    Some(*hasher.finalize().as_bytes())
+}
+
+/// Check a registry record (or its absence) against a state root.
+pub fn verify_validator(root: &H256, addr: &H256, record: Option<&ValidatorRecord>, proof: &SmtProof) -> bool {
+    let leaf = record.map(ValidatorRecord::leaf_hash);
+    SparseMerkleTree::verify(root, &validator_key(addr), leaf.as_ref(), proof)
+}
+
+/// Check the active set against a state root.
+pub fn verify_active_set(root: &H256, validators: &[H256], since: u64, proof: &SmtProof) -> bool {
+    SparseMerkleTree::verify(root, &active_set_key(), active_set_leaf(validators, since).as_ref(), proof)
+}
+
+impl State {
+    pub fn validator_record(&self, addr: &H256) -> Option<&ValidatorRecord> {
+        self.validator_registry.get(&hex::encode(addr))
+    }
+
+    /// Write (or drop, when empty) a registry record. Its leaf is updated by the next `commit_root`.
+    pub fn set_validator_record(&mut self, addr: H256, record: ValidatorRecord) {
+        self.journal_validator(&addr);
+        if record.is_empty() {
+            self.validator_registry.remove(&hex::encode(addr));
+        } else {
+            self.validator_registry.insert(hex::encode(addr), record);
+        }
+        self.dirty_validators.insert(addr);
+    }
+
+    /// Validators that sealed and finalized the block at `height`: the current set, or
+    /// the previous one for blocks before the last rotation.
+    pub fn validators_at(&self, height: u64) -> &[H256] {
+        if height < self.validators_since {
+            &self.previous_validators
+        } else {
+            &self.validators
+        }
+    }
+
+    /// Active set the registry selects right now.
+    pub fn select_active_set(&self) -> Vec<H256> {
+        let mut candidates: Vec<(u128, H256)> = self
+            .validator_registry
+            .iter()
+            .filter(|(_, r)| !r.tombstoned && r.stake >= self.staking.min_stake && r.stake > 0)
+            .filter_map(|(addr, r)| Some((r.stake, crate::dehex32(addr)?)))
+            .collect();
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        candidates.into_iter().take(self.staking.max_validators as usize).map(|(_, addr)| addr).collect()
+    }
+
+    /// Staking work at the end of the block at `self.height`: pay out matured
+    /// unbonding entries and rotate the active set at an epoch boundary.
+    pub(crate) fn end_block_staking(&mut self) {
+        let height = self.height;
+        let matured: Vec<(H256, ValidatorRecord)> = self
+            .validator_registry
+            .iter()
+            .filter(|(_, r)| r.unbonding.first().is_some_and(|u| u.release_height <= height))
+            .filter_map(|(addr, r)| Some((crate::dehex32(addr)?, r.clone())))
+            .collect();
+        for (addr, mut record) in matured {
+            let released: u128 = record.unbonding.iter().filter(|u| u.release_height <= height).map(|u| u.amount).sum();
+            record.unbonding.retain(|u| u.release_height > height);
+            let mut acct = self.accounts.get(&hex::encode(addr)).cloned().unwrap_or_default();
+            acct.credit(NATIVE_ASSET, released);
+            self.set_account(addr, &acct);
+            self.set_validator_record(addr, record);
+        }
+
+        if !height.is_multiple_of(self.staking.epoch_length) {
+            return;
+        }
+        let next = self.select_active_set();
+        if !next.is_empty() && next != self.validators {
+            println!("Epoch {}: active set of {} validator(s) from block {}", self.staking.epoch(height + 1), next.len(), height + 1);
+            self.previous_validators = std::mem::replace(&mut self.validators, next);
+            self.validators_since = height + 1;
+        }
+    }
+
+    pub(crate) fn flush_validators(&mut self) {
+        let mut updates: Vec<(H256, Option<H256>)> = std::mem::take(&mut self.dirty_validators)
+            .into_iter()
+            .map(|addr| (validator_key(&addr), self.validator_record(&addr).map(ValidatorRecord::leaf_hash)))
+            .collect();
+        let active = active_set_leaf(&self.validators, self.validators_since);
+        if self.smt.get(&active_set_key()) != active {
+            updates.push((active_set_key(), active));
+        }
+        if !updates.is_empty() {
+            self.smt.update_many(updates);
+        }
+    }
+
+    /// Registry record with its SMT proof against the state root.
+    pub fn prove_validator(&self, addr: &H256) -> (Option<ValidatorRecord>, SmtProof) {
+        let (_, proof) = self.smt.prove(&validator_key(addr));
+        (self.validator_record(addr).cloned(), proof)
+    }
+
+    /// SMT proof of the active set against the state root.
+    pub fn prove_active_set(&self) -> SmtProof {
+        self.smt.prove(&active_set_key()).1
+    }
+}
+
+impl Chain {
+    pub(crate) fn apply_stake(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        amount: u128,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        if amount == 0 {
+            return Err(TxError::InvalidPayload("stake amount must be non-zero".into()));
+        }
+        let mut record = st.validator_record(&tx.from).cloned().unwrap_or_default();
+        if record.tombstoned {
+            return Err(TxError::Tombstoned(tx.from));
+        }
+        record.stake = record.stake.checked_add(amount).ok_or_else(|| TxError::InvalidPayload("stake overflows".into()))?;
+
+        let before = from_acct.balance(NATIVE_ASSET);
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, false, receipt)?;
+        if !from_acct.debit(NATIVE_ASSET, amount) {
+            return Err(TxError::InsufficientBalance { token: TokenType::Native });
+        }
+        st.set_validator_record(tx.from, record);
+        receipt.balance_changes.push(BalanceChange {
+            address: tx.from,
+            token: TokenType::Native,
+            before,
+            after: from_acct.balance(NATIVE_ASSET),
+        });
+        Ok(())
+    }
+
+    pub(crate) fn apply_unstake(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        amount: u128,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        if amount == 0 {
+            return Err(TxError::InvalidPayload("unstake amount must be non-zero".into()));
+        }
+        let mut record = st.validator_record(&tx.from).cloned().unwrap_or_default();
+        if record.stake < amount {
+            return Err(TxError::InsufficientStake { staked: record.stake });
+        }
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        record.stake -= amount;
+        let release_height = st.height + 1 + st.staking.unbonding_blocks;
+        record.unbonding.push(Unbonding { amount, release_height });
+        st.set_validator_record(tx.from, record);
+        Ok(())
+    }
+
+    pub(crate) fn apply_slash(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        evidence: &Equivocation,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let offender = evidence.verify(st.chain_id).map_err(|e| TxError::InvalidEvidence(e.to_string()))?;
+        // Stake stays slashable while it unbonds; older offenses go unpunished
+        let height = st.height + 1;
+        if evidence.first.height.saturating_add(st.staking.unbonding_blocks) < height {
+            return Err(TxError::InvalidEvidence(format!("offense at height {} is too old", evidence.first.height)));
+        }
+        let Some(mut record) = st.validator_record(&offender).cloned() else {
+            return Err(TxError::InvalidEvidence(format!("{} has no stake", hex::encode(offender))));
+        };
+        if record.tombstoned {
+            return Err(TxError::Tombstoned(offender));
+        }
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        let bps = st.staking.slash_bps as u128;
+        let cut = |amount: &mut u128| {
+            let slashed = *amount / 10_000 * bps + *amount % 10_000 * bps / 10_000;
+            *amount -= slashed;
+            slashed
+        };
+        let mut slashed = cut(&mut record.stake);
+        for entry in &mut record.unbonding {
+            slashed += cut(&mut entry.amount);
+        }
+        record.unbonding.retain(|u| u.amount > 0);
+        record.slashed += slashed;
+        record.tombstoned = true;
+        st.burn(TokenType::Native, slashed);
+        st.set_validator_record(offender, record);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{finality::VoteKind, tx::TxPayload};
+    use dxid_crypto::{SecretKey, StarkSignEngine, ENGINE as STARK};
+
+    struct Env {
+        st: State,
+        keys: Vec<(SecretKey, H256)>,
+        /// Fees of the current block, settled by `end_block`
+        fees: u128,
+    }
+
+    impl Env {
+        fn new(n: usize) -> Self {
+            let keys: Vec<(SecretKey, H256)> = (0..n).map(|_| STARK.generate_keys().unwrap()).collect();
+            let mut st = State::new_with_genesis(keys.iter().map(|(_, pk)| (*pk, 100_000_000)).collect()).lock().clone();
+            st.staking = StakingParams { epoch_length: 4, unbonding_blocks: 6, min_stake: 1_000, max_validators: 2, slash_bps: 1_000 };
+            st.validators = vec![keys[0].1];
+            st.commit_root();
+            Env { st, keys, fees: 0 }
+        }
+
+        fn send(&mut self, who: usize, payload: TxPayload) -> std::result::Result<Receipt, TxError> {
+            let (sk, pk) = &self.keys[who];
+            let nonce = self.st.accounts.get(&hex::encode(pk)).map_or(0, |a| a.nonce);
+            let tx = Tx::new_signed(sk, *pk, nonce, 10, crate::CHAIN_ID, None, payload).unwrap();
+            let result = Chain::apply_tx(&mut self.st, &tx);
+            if let Ok(receipt) = &result {
+                self.fees += receipt.fee.map_or(0, |f| f.amount);
+            }
+            result
+        }
+
+        /// Close the current block the way `Chain::finish_block` does
+        fn end_block(&mut self) {
+            self.st.distribute_fees(TokenType::Native, std::mem::take(&mut self.fees), None);
+            self.st.height += 1;
+            self.st.end_block_staking();
+            self.st.commit_root();
+        }
+
+        fn balance(&self, who: usize) -> u128 {
+            self.st.accounts.get(&hex::encode(self.keys[who].1)).map_or(0, |a| a.balance(NATIVE_ASSET))
+        }
+    }
+
+    #[test]
+    fn test_stake_rotates_active_set_at_epoch_boundary() {
+        let mut env = Env::new(4);
+        for (who, amount) in [(1, 5_000), (2, 9_000), (3, 500)] {
+            env.send(who, TxPayload::Stake { amount }).unwrap();
+        }
+        assert_eq!(env.balance(1), 100_000_000 - 5_000 - 10);
+        assert!(env.send(0, TxPayload::Unstake { amount: 1 }).is_err());
+
+        // The genesis validator keeps proposing until the epoch ends
+        env.end_block();
+        assert_eq!(env.st.validators, vec![env.keys[0].1]);
+        for _ in 0..3 {
+            env.end_block();
+        }
+        // Largest stakes first, below-minimum stake left out
+        let expected = vec![env.keys[2].1, env.keys[1].1];
+        assert_eq!(env.st.validators, expected);
+        let native = env.st.audit_supply().into_iter().find(|a| a.token == TokenType::Native).unwrap();
+        assert_eq!(native.held_total, 14_500);
+        assert!(native.consistent, "{:?}", native);
+        assert_eq!(env.st.validators_since, 5);
+        assert_eq!(env.st.validators_at(4), &[env.keys[0].1]);
+        assert_eq!(env.st.validators_at(5), &expected[..]);
+
+        let root = env.st.state_root;
+        assert!(verify_active_set(&root, &expected, 5, &env.st.prove_active_set()));
+        let (record, proof) = env.st.prove_validator(&env.keys[2].1);
+        assert_eq!(record.as_ref().map(|r| r.stake), Some(9_000));
+        assert!(verify_validator(&root, &env.keys[2].1, record.as_ref(), &proof));
+        let (none, proof) = env.st.prove_validator(&env.keys[0].1);
+        assert!(none.is_none() && verify_validator(&root, &env.keys[0].1, None, &proof));
+
+        // Registry and active set survive a reload
+        let mut reloaded: State = serde_json::from_str(&serde_json::to_string(&env.st).unwrap()).unwrap();
+        reloaded.reconstruct_smt();
+        assert_eq!(reloaded.state_root, root);
+    }
+
+    #[test]
+    fn test_unstake_releases_after_unbonding_and_undoes() {
+        let mut env = Env::new(2);
+        env.send(1, TxPayload::Stake { amount: 5_000 }).unwrap();
+        env.end_block();
+        let staked = env.balance(1);
+
+        env.st.begin_journal();
+        let root = env.st.state_root;
+        assert_eq!(env.send(1, TxPayload::Unstake { amount: 6_000 }), Err(TxError::InsufficientStake { staked: 5_000 }));
+        env.send(1, TxPayload::Unstake { amount: 5_000 }).unwrap();
+        env.end_block();
+        let journal = env.st.take_journal().unwrap();
+        let record = env.st.validator_record(&env.keys[1].1).unwrap();
+        assert_eq!(record.unbonding, vec![Unbonding { amount: 5_000, release_height: 8 }]);
+        assert_eq!(env.balance(1), staked - 10);
+
+        // Undo restores the record and the root
+        let mut undone = env.st.clone();
+        undone.apply_undo(&journal).unwrap();
+        assert_eq!(undone.state_root, root);
+        assert_eq!(undone.validator_record(&env.keys[1].1).map(|r| r.stake), Some(5_000));
+
+        while env.st.height < 7 {
+            env.end_block();
+        }
+        assert_eq!(env.balance(1), staked - 10);
+        env.end_block();
+        assert_eq!(env.balance(1), staked - 10 + 5_000);
+        assert!(env.st.validator_record(&env.keys[1].1).is_none());
+        assert!(env.st.audit_supply().iter().all(|a| a.consistent), "{:?}", env.st.audit_supply());
+    }
+
+    #[test]
+    fn test_equivocation_slashes_and_tombstones() {
+        let mut env = Env::new(3);
+        env.send(1, TxPayload::Stake { amount: 10_000 }).unwrap();
+        env.send(1, TxPayload::Unstake { amount: 2_000 }).unwrap();
+        let supply = env.st.asset(NATIVE_ASSET).unwrap().supply;
+
+        let sk = &env.keys[1].0;
+        let vote = |hash| Vote::new_signed(env.st.chain_id, sk, VoteKind::Precommit, 1, 0, Some(hash)).unwrap();
+        let evidence = Equivocation { first: vote([1u8; 32]), second: vote([2u8; 32]) };
+        let same = Equivocation { first: vote([1u8; 32]), second: vote([1u8; 32]) };
+        let slash = |evidence: &Equivocation| TxPayload::Slash { evidence: Box::new(evidence.clone()) };
+
+        assert!(matches!(env.send(2, slash(&same)), Err(TxError::InvalidEvidence(_))));
+        env.send(2, slash(&evidence)).unwrap();
+        let record = env.st.validator_record(&env.keys[1].1).unwrap().clone();
+        assert_eq!((record.stake, record.unbonding[0].amount, record.slashed), (7_200, 1_800, 1_000));
+        assert!(record.tombstoned);
+        // The slashed stake is burned
+        assert_eq!(env.st.asset(NATIVE_ASSET).unwrap().supply, supply - 1_000);
+
+        assert_eq!(env.send(2, slash(&evidence)), Err(TxError::Tombstoned(env.keys[1].1)));
+        assert_eq!(env.send(1, TxPayload::Stake { amount: 1 }), Err(TxError::Tombstoned(env.keys[1].1)));
+        env.send(1, TxPayload::Unstake { amount: 7_200 }).unwrap();
+
+        // A tombstoned validator never enters the active set
+        while env.st.height < 4 {
+            env.end_block();
+        }
+        assert_eq!(env.st.validators, vec![env.keys[0].1]);
+        // Slashed stake left the supply, the rest is still held for the validator
+        assert!(env.st.audit_supply().iter().all(|a| a.consistent), "{:?}", env.st.audit_supply());
+    }
+}
@@ -1,33 +1,105 @@
+//! Persistent storage.
+//!
+//! Blocks are kept in the compressed segment files of the block archive
+//! (see `archive`). Everything else lives in one redb database, `chain.redb`, with a
+//! table per kind of record: receipts, undo journals and commit certificates by
+//! height, accounts by address, the leaves of the state SMT, the transaction index,
+//! one table per registry (assets, validators, vesting, HTLCs, DIDs, modules, module
+//! storage, revocations), and the remaining parameters as one record in `meta`.
+//!
+//! Every block is written by a single write transaction holding its receipts, undo
+//! journal and index entries, the accounts and registry records it touched, the SMT
+//! leaves it changed and the parameters. The block itself is appended to the archive just before; a
+//! crash leaves either all of it or none, since archived blocks above the committed
+//! tip are dropped on open. The work per block is proportional to what the block
+//! changed rather than to the size of the state.
+//!
+//! Only the leaves of the state SMT are stored, not its interior nodes: `dxid-smt`
+//! keeps none in memory either, recomputing the root from the leaves on every update.
+//! Opening the database therefore costs O(state): every account, registry record and
+//! leaf is read and the root recomputed once. `load_state` refuses a state whose
+//! rebuilt root differs from the stored one (`dxid-node rebuild-smt` recomputes the
+//! leaves from the accounts and registries instead). Storing interior nodes only pays
+//! off once the tree itself updates paths incrementally.
+//!
+//! Data directories from before the database (`state.json`, `blocks/*.json`, ...)
+//! are imported the first time they are opened. JSON is otherwise only written by
+//! `export_json`, in that same layout.
+
 use anyhow::{Context, Result};
 use parking_lot::RwLock;
-use serde::{Deserialize, Serialize};
+use redb::{Database, ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle, WriteTransaction};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    fs::{self, File, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Write},
+    collections::BTreeMap,
+    fs::{self, File},
+    io::BufReader,
     path::{Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::{Block, BlockHeader, State, H256};
+pub use crate::txindex::{AddressTx, TransactionIndex};
 
-/// Backup manifest for tracking backup metadata
-#[derive(Debug, Serialize, Deserialize)]
-struct BackupManifest {
-    timestamp: u64,
-    backup_name: String,
-    files_backed_up: usize,
-    blockchain_height: u64,
-}
+use crate::{
+    archive::BlockArchive,
+    backup::{self, BACKUP_DIR, BACKUP_ITEMS},
+    assets::AssetId, dehex32, finality::CommitCertificate, monetary::MonetaryParams, receipt::Receipt,
+    staking::StakingParams,
+    txindex::{self, ADDRESS_TXS, REJECTED_TXS, TX_INDEX},
+    undo::UndoJournal, Block, State, H256,
+};
+
+/// Database file inside the data directory
+pub const DB_FILE: &str = "chain.redb";
+
+/// Where `migrate_legacy_json` moves the files it imported
+const LEGACY_DIR: &str = "legacy-json";
+
+type HeightTable = TableDefinition<'static, u64, &'static [u8]>;
+type BytesTable = TableDefinition<'static, &'static [u8], &'static [u8]>;
+/// SMT key and leaf value
+type SmtLeaf = (H256, H256);
+
+/// State parameters (`STATE_KEY`, see `StateMeta`) and the tip height (`HEIGHT_KEY`)
+const META: TableDefinition<&str, &[u8]> = TableDefinition::new("meta");
+/// address -> account
+const ACCOUNTS: BytesTable = TableDefinition::new("accounts");
+/// SMT key -> leaf value
+const SMT_LEAVES: BytesTable = TableDefinition::new("smt_leaves");
+/// asset id -> registry record
+const ASSETS: TableDefinition<AssetId, &[u8]> = TableDefinition::new("assets");
+/// id -> record, for the registries keyed by hex(id) in `State`
+const VALIDATORS: BytesTable = TableDefinition::new("validators");
+const VESTING: BytesTable = TableDefinition::new("vesting");
+const HTLCS: BytesTable = TableDefinition::new("htlcs");
+const DIDS: BytesTable = TableDefinition::new("dids");
+const MODULES: BytesTable = TableDefinition::new("modules");
+/// module id ++ slot key -> slot value
+const MODULE_STORAGE: BytesTable = TableDefinition::new("module_storage");
+/// issuer ++ credential id -> revocation height
+const REVOCATIONS: BytesTable = TableDefinition::new("revocations");
+const REGISTRIES: [BytesTable; 7] = [VALIDATORS, VESTING, HTLCS, DIDS, MODULES, MODULE_STORAGE, REVOCATIONS];
+/// Blocks of databases written before the archive, moved to it on open
+const BLOCKS: HeightTable = TableDefinition::new("blocks");
+const RECEIPTS: HeightTable = TableDefinition::new("receipts");
+const UNDO: HeightTable = TableDefinition::new("undo");
+const COMMITS: HeightTable = TableDefinition::new("commits");
+
+const STATE_KEY: &str = "state";
+const HEIGHT_KEY: &str = "height";
+/// Layout version of the transaction index (`TX_INDEX_VERSION`); rebuilt when it differs
+const TX_INDEX_KEY: &str = "tx_index";
+const TX_INDEX_VERSION: u32 = 2;
+/// Layout version of the stored state (`STATE_LAYOUT_VERSION`); databases from before
+/// the registry tables kept the registries in the `meta` record and are split on open
+const STATE_LAYOUT_KEY: &str = "state_layout";
+const STATE_LAYOUT_VERSION: u32 = 1;
 
 /// Storage configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageConfig {
     /// Base directory for all storage
     pub base_dir: PathBuf,
-    /// How often to create checkpoints (in blocks)
-    pub checkpoint_interval: u64,
-    /// Maximum number of checkpoints to keep
-    pub max_checkpoints: usize,
     /// Whether to enable transaction indexing
     pub enable_indexing: bool,
     /// Whether to enable data compression
@@ -40,8 +112,6 @@ impl Default for StorageConfig {
     fn default() -> Self {
         Self {
             base_dir: PathBuf::from("./dxid-data"),
-            checkpoint_interval: 100, // More frequent checkpoints for better persistence
-            max_checkpoints: 50, // Keep more checkpoints
             enable_indexing: true,
             enable_compression: true,
             backup_interval_secs: 1800, // Every 30 minutes for better persistence
@@ -52,344 +122,402 @@ impl Default for StorageConfig {
 /// Persistent storage manager
 pub struct Storage {
     config: StorageConfig,
-    state_file: PathBuf,
-    checkpoints_dir: PathBuf,
-    index_dir: PathBuf,
+    db: Database,
+    blocks: BlockArchive,
     backup_dir: PathBuf,
-    last_checkpoint: RwLock<u64>,
     last_backup: RwLock<u64>,
 }
 
 impl Storage {
     pub fn new(config: StorageConfig) -> Result<Self> {
-        let state_file = config.base_dir.join("state.json");
-        let checkpoints_dir = config.base_dir.join("checkpoints");
-        let index_dir = config.base_dir.join("index");
-        let backup_dir = config.base_dir.join("backups");
-
-        // Create directories
+        let backup_dir = config.base_dir.join(BACKUP_DIR);
         fs::create_dir_all(&config.base_dir)?;
-        fs::create_dir_all(&checkpoints_dir)?;
-        fs::create_dir_all(&index_dir)?;
         fs::create_dir_all(&backup_dir)?;
 
-        Ok(Self {
-            config,
-            state_file,
-            checkpoints_dir,
-            index_dir,
-            backup_dir,
-            last_checkpoint: RwLock::new(0),
-            last_backup: RwLock::new(0),
-        })
+        let db_file = config.base_dir.join(DB_FILE);
+        let db = Database::create(&db_file).with_context(|| format!("Failed to open database {:?}", db_file))?;
+        // Create every table up front so readers never find one missing
+        let txn = db.begin_write()?;
+        {
+            txn.open_table(META)?;
+            txn.open_table(ASSETS)?;
+            txn.open_table(REJECTED_TXS)?;
+            for table in [ACCOUNTS, SMT_LEAVES, TX_INDEX, ADDRESS_TXS].into_iter().chain(REGISTRIES) {
+                txn.open_table(table)?;
+            }
+            for table in [BLOCKS, RECEIPTS, UNDO, COMMITS] {
+                txn.open_table(table)?;
+            }
+        }
+        txn.commit()?;
+
+        let blocks = BlockArchive::open(&config.base_dir.join("blocks"), config.enable_compression)?;
+        let storage = Self { config, db, blocks, backup_dir, last_backup: RwLock::new(0) };
+        if !storage.has_state()? {
+            storage.migrate_legacy_json()?;
+        } else if storage.meta_version(STATE_LAYOUT_KEY)? != Some(STATE_LAYOUT_VERSION) {
+            storage.migrate_state_layout()?;
+        }
+        storage.migrate_block_table()?;
+        // Blocks archived by a commit that never completed
+        storage.blocks.truncate_after(storage.stored_height()?)?;
+        if storage.config.enable_indexing && storage.meta_version(TX_INDEX_KEY)? != Some(TX_INDEX_VERSION) {
+            let indexed = storage.rebuild_tx_index()?;
+            println!("Rebuilt the transaction index ({} transactions)", indexed);
+        }
+        Ok(storage)
     }
 
-    /// Save the current state to disk with multiple backup copies
-    pub fn save_state(&self, state: &State) -> Result<()> {
-        let temp_file = self.state_file.with_extension("tmp");
-        let backup_file = self.state_file.with_extension("backup");
-        
-        // Write to temporary file first
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&temp_file)
-            .context("Failed to create temporary state file")?;
-        
-        let mut writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(&mut writer, state)
-            .context("Failed to serialize state")?;
-        writer.flush().context("Failed to flush state file")?;
-        drop(writer);
-
-        // Create backup of current state if it exists
-        if self.state_file.exists() {
-            fs::copy(&self.state_file, &backup_file)
-                .context("Failed to create state backup")?;
-        }
-
-        // Atomic rename
-        fs::rename(&temp_file, &self.state_file)
-            .context("Failed to atomically rename state file")?;
-
-        // Also save to a height-specific file for extra safety
-        let height_file = self.config.base_dir.join(format!("state_height_{}.json", state.height));
-        fs::copy(&self.state_file, &height_file)
-            .context("Failed to create height-specific state backup")?;
-
-        println!("State saved successfully at height {} (with backups)", state.height);
-        Ok(())
+    /// Whether a state has been stored
+    pub fn has_state(&self) -> Result<bool> {
+        let txn = self.db.begin_read()?;
+        Ok(txn.open_table(META)?.get(STATE_KEY)?.is_some())
     }
 
-    /// Load state from disk with fallback to backups
+    /// Load the stored state, with its Merkle trees rebuilt.
     pub fn load_state(&self) -> Result<Option<State>> {
-        // Try loading from main state file first
-        if self.state_file.exists() {
-            match self.try_load_state_file(&self.state_file) {
-                Ok(state) => {
-                    println!("State loaded successfully from main file at height {}", state.height);
-                    return Ok(Some(state));
-                }
-                Err(e) => {
-                    println!("Failed to load main state file: {}, trying backups...", e);
-                }
-            }
+        let Some((mut state, leaves)) = self.read_state()? else {
+            return Ok(None);
+        };
+        if !state.restore_smt(leaves) {
+            anyhow::bail!(
+                "stored state tree at height {} does not add up to state root {}; check the data directory or run `dxid-node rebuild-smt`",
+                state.height,
+                hex::encode(state.state_root)
+            );
         }
+        Ok(Some(state))
+    }
 
-        // Try loading from backup file
-        let backup_file = self.state_file.with_extension("backup");
-        if backup_file.exists() {
-            match self.try_load_state_file(&backup_file) {
-                Ok(state) => {
-                    println!("State loaded successfully from backup file at height {}", state.height);
-                    return Ok(Some(state));
-                }
-                Err(e) => {
-                    println!("Failed to load backup state file: {}, trying checkpoints...", e);
-                }
-            }
+    /// The stored state and SMT leaves, with the trees not yet built
+    fn read_state(&self) -> Result<Option<(State, Vec<SmtLeaf>)>> {
+        let txn = self.db.begin_read()?;
+        let Some(raw) = txn.open_table(META)?.get(STATE_KEY)? else {
+            return Ok(None);
+        };
+        let mut state: State = serde_json::from_slice(raw.value()).context("Failed to deserialize state")?;
+        for entry in txn.open_table(ACCOUNTS)?.iter()? {
+            let (addr, acct) = entry?;
+            let acct = serde_json::from_slice(acct.value()).context("Failed to deserialize account")?;
+            state.accounts.insert(hex::encode(addr.value()), acct);
         }
-
-        // Try loading from latest checkpoint
-        if let Ok(Some(state)) = self.load_from_checkpoint() {
-            println!("State loaded successfully from checkpoint at height {}", state.height);
-            return Ok(Some(state));
+        for entry in txn.open_table(ASSETS)?.iter()? {
+            let (id, info) = entry?;
+            state.assets.insert(id.value(), serde_json::from_slice(info.value()).context("Failed to deserialize asset")?);
         }
-
-        // Try loading from height-specific files (find the latest)
-        if let Ok(Some(state)) = self.load_from_latest_height_file() {
-            println!("State loaded successfully from height file at height {}", state.height);
-            return Ok(Some(state));
+        read_records(&txn, VALIDATORS, &mut state.validator_registry)?;
+        read_records(&txn, VESTING, &mut state.vesting)?;
+        read_records(&txn, HTLCS, &mut state.htlcs)?;
+        read_records(&txn, DIDS, &mut state.dids)?;
+        read_records(&txn, MODULES, &mut state.modules)?;
+        read_slots(&txn, MODULE_STORAGE, &mut state.module_storage)?;
+        read_slots(&txn, REVOCATIONS, &mut state.revocations)?;
+        let mut leaves = Vec::new();
+        for entry in txn.open_table(SMT_LEAVES)?.iter()? {
+            let (key, leaf) = entry?;
+            let (Ok(key), Ok(leaf)) = (H256::try_from(key.value()), H256::try_from(leaf.value())) else {
+                anyhow::bail!("malformed SMT leaf in {}", DB_FILE);
+            };
+            leaves.push((key, leaf));
         }
+        Ok(Some((state, leaves)))
+    }
 
-        println!("No valid state found, starting fresh");
-        Ok(None)
+    /// Rebuild the stored SMT leaves from the stored accounts and records. Only written
+    /// if the rebuilt root is the one the tip block header commits to. Returns the height.
+    pub fn rebuild_smt(&self) -> Result<u64> {
+        let (mut state, _) = self.read_state()?.context("no state stored")?;
+        state.reconstruct_smt();
+        if state.height > 0 {
+            let header = self.load_block(state.height)?.with_context(|| format!("block {} is missing", state.height))?.header;
+            if header.state_root != state.state_root {
+                anyhow::bail!(
+                    "rebuilt state root {} does not match block {} ({}); the stored records themselves are damaged",
+                    hex::encode(state.state_root),
+                    state.height,
+                    hex::encode(header.state_root)
+                );
+            }
+        }
+        state.take_smt_changes();
+        self.save_state(&state)?;
+        Ok(state.height)
     }
 
-    /// Try to load state from a specific file
-    fn try_load_state_file(&self, path: &Path) -> Result<State> {
-        let file = File::open(path)
-            .context("Failed to open state file")?;
-        
-        let reader = BufReader::new(file);
-        let state: State = serde_json::from_reader(reader)
-            .context("Failed to deserialize state")?;
+    /// Replace the stored state (all accounts and SMT leaves) in one transaction.
+    pub fn save_state(&self, state: &State) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        write_full_state(&txn, state)?;
+        txn.commit()?;
+        Ok(())
+    }
 
-        Ok(state)
+    /// Install a state that does not descend from the stored blocks, such as an imported
+    /// snapshot. The undo journals are dropped, since they cannot apply to it.
+    pub fn replace_state(&self, state: &State) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        txn.delete_table(UNDO)?;
+        txn.open_table(UNDO)?;
+        write_full_state(&txn, state)?;
+        txn.commit()?;
+        self.blocks.truncate_after(state.height)
     }
 
-    /// Load state from the latest height-specific file
-    fn load_from_latest_height_file(&self) -> Result<Option<State>> {
-        let mut height_files = Vec::new();
-        
-        for entry in fs::read_dir(&self.config.base_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if let Some(name) = path.file_name() {
-                if let Some(name_str) = name.to_str() {
-                    if name_str.starts_with("state_height_") && name_str.ends_with(".json") {
-                        height_files.push(path);
+    /// Write a block that `state` now includes, together with everything it changed, in one
+    /// transaction. `smt_changes` are the SMT leaves written since the previous block; the
+    /// accounts and registry records written are those in the undo journal. Without a journal, or before any
+    /// state was stored, the whole state is written instead.
+    pub fn commit_block(
+        &self,
+        state: &State,
+        smt_changes: &[(H256, Option<H256>)],
+        block: &Block,
+        receipts: &[Receipt],
+        journal: Option<&UndoJournal>,
+    ) -> Result<()> {
+        let height = block.header.height;
+        self.blocks.append(block).with_context(|| format!("Failed to archive block {}", height))?;
+        let txn = self.db.begin_write()?;
+        {
+            txn.open_table(RECEIPTS)?.insert(height, serde_json::to_vec(receipts)?.as_slice())?;
+            if let Some(journal) = journal {
+                txn.open_table(UNDO)?.insert(height, serde_json::to_vec(journal)?.as_slice())?;
+            }
+            if self.config.enable_indexing {
+                txindex::index_block(&txn, block)?;
+                txindex::index_rejected(&txn, block, receipts)?;
+            }
+        }
+        let stored = txn.open_table(META)?.get(STATE_KEY)?.is_some();
+        match journal {
+            Some(journal) if stored => {
+                {
+                    let mut accounts = txn.open_table(ACCOUNTS)?;
+                    for key in journal.accounts.keys() {
+                        let Some(addr) = dehex32(key) else { continue };
+                        match state.accounts.get(key) {
+                            Some(acct) => accounts.insert(addr.as_slice(), serde_json::to_vec(acct)?.as_slice())?,
+                            None => accounts.remove(addr.as_slice())?,
+                        };
+                    }
+                    let mut leaves = txn.open_table(SMT_LEAVES)?;
+                    for (key, leaf) in smt_changes {
+                        match leaf {
+                            Some(leaf) => leaves.insert(key.as_slice(), leaf.as_slice())?,
+                            None => leaves.remove(key.as_slice())?,
+                        };
                     }
                 }
+                write_registries(&txn, state, Some(journal))?;
+                write_meta(&txn, state)?;
             }
+            _ => write_full_state(&txn, state)?,
         }
+        txn.commit().with_context(|| format!("Failed to commit block {}", height))?;
+        Ok(())
+    }
 
-        if height_files.is_empty() {
-            return Ok(None);
+    /// Store the state after rolling back `heights`, and delete those blocks with their
+    /// receipts, undo journals and index entries, in one transaction.
+    pub fn revert(&self, state: &State, heights: &[u64]) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut receipts = txn.open_table(RECEIPTS)?;
+            let mut undo = txn.open_table(UNDO)?;
+            for &height in heights {
+                if let Some(block) = self.blocks.load(height)? {
+                    txindex::unindex_block(&txn, &block)?;
+                    if let Some(stored) = self.load_receipts(height)? {
+                        txindex::unindex_rejected(&txn, &block, &stored)?;
+                    }
+                }
+                receipts.remove(height)?;
+                undo.remove(height)?;
+            }
         }
+        write_full_state(&txn, state)?;
+        txn.commit()?;
+        self.blocks.truncate_after(state.height)
+    }
 
-        // Sort by height (extract height from filename)
-        height_files.sort_by(|a, b| {
-            let a_name = a.file_name().unwrap().to_str().unwrap();
-            let b_name = b.file_name().unwrap().to_str().unwrap();
-            let a_height = a_name.trim_start_matches("state_height_").trim_end_matches(".json");
-            let b_height = b_name.trim_start_matches("state_height_").trim_end_matches(".json");
-            a_height.parse::<u64>().unwrap_or(0).cmp(&b_height.parse::<u64>().unwrap_or(0))
-        });
-
-        // Load the latest height file
-        let latest_file = height_files.last().unwrap();
-        match self.try_load_state_file(latest_file) {
-            Ok(state) => Ok(Some(state)),
-            Err(e) => {
-                println!("Failed to load height file {:?}: {}", latest_file, e);
-                Ok(None)
+    /// Import a data directory written before the database: the newest loadable state
+    /// file and every block, receipt list, undo journal and commit certificate, in one
+    /// transaction. The JSON files are then moved to `legacy-json/`.
+    fn migrate_legacy_json(&self) -> Result<()> {
+        let base = &self.config.base_dir;
+        let Some(mut state) = load_legacy_state(base) else {
+            return Ok(());
+        };
+        state.migrate_legacy_supply();
+        state.reconstruct_smt();
+
+        let txn = self.db.begin_write()?;
+        let block_files = legacy_height_files(&base.join("blocks"))?;
+        for (_, path) in &block_files {
+            let block: Block = serde_json::from_slice(&fs::read(path)?).with_context(|| format!("Failed to parse {:?}", path))?;
+            if self.config.enable_indexing {
+                txindex::index_block(&txn, &block)?;
+            }
+            self.blocks.append(&block)?;
+        }
+        for (table, dir) in [(RECEIPTS, "receipts"), (UNDO, "undo"), (COMMITS, "commits")] {
+            let mut t = txn.open_table(table)?;
+            for (height, path) in legacy_height_files(&base.join(dir))? {
+                let value: serde_json::Value = serde_json::from_slice(&fs::read(&path)?)
+                    .with_context(|| format!("Failed to parse {:?}", path))?;
+                t.insert(height, serde_json::to_vec(&value)?.as_slice())?;
+            }
+        }
+        write_full_state(&txn, &state)?;
+        txn.commit()?;
+
+        let legacy = base.join(LEGACY_DIR);
+        fs::create_dir_all(legacy.join("blocks"))?;
+        for entry in fs::read_dir(base)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+            let is_legacy = name.starts_with("state.json")
+                || (name.starts_with("state_height_") && name.ends_with(".json"))
+                || ["receipts", "undo", "commits", "index", "checkpoints"].contains(&name);
+            if is_legacy {
+                fs::rename(&path, legacy.join(name)).with_context(|| format!("Failed to move {:?}", path))?;
+            }
+        }
+        // The archive shares `blocks/`; move out the per-height files and their copies
+        for entry in fs::read_dir(base.join("blocks"))? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+            if name.ends_with(".json") || name.ends_with(".json.gz") || name.ends_with(".backup") || name.ends_with(".tmp") {
+                fs::rename(&path, legacy.join("blocks").join(name)).with_context(|| format!("Failed to move {:?}", path))?;
             }
         }
+        println!(
+            "Migrated JSON data at height {} ({} blocks) into {}; the old files are in {:?}",
+            state.height,
+            block_files.len(),
+            DB_FILE,
+            legacy
+        );
+        Ok(())
     }
 
-    /// Create a checkpoint of the current state
-    pub fn create_checkpoint(&self, state: &State, block: &Block) -> Result<()> {
-        let current_height = state.height;
-        let last_checkpoint = *self.last_checkpoint.read();
-        
-        // Check if we need to create a checkpoint
-        if current_height < last_checkpoint + self.config.checkpoint_interval {
+    /// Move the blocks of a database written before the archive into it, then drop the table.
+    fn migrate_block_table(&self) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        let mut moved = 0;
+        for entry in txn.open_table(BLOCKS)?.iter()? {
+            let (_, raw) = entry?;
+            let block: Block = serde_json::from_slice(raw.value()).context("Failed to deserialize block")?;
+            self.blocks.append(&block)?;
+            moved += 1;
+        }
+        if moved == 0 {
             return Ok(());
         }
-
-        let checkpoint_file = self.checkpoints_dir.join(format!("checkpoint_{:016x}.json", current_height));
-        let temp_file = checkpoint_file.with_extension("tmp");
-
-        // Create checkpoint data
-        let checkpoint = Checkpoint {
-            height: current_height,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-            state: state.clone(),
-            block_header: block.header.clone(),
-        };
-
-        // Write checkpoint to temporary file
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&temp_file)
-            .context("Failed to create checkpoint file")?;
-        
-        let mut writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(&mut writer, &checkpoint)
-            .context("Failed to serialize checkpoint")?;
-        writer.flush().context("Failed to flush checkpoint file")?;
-        drop(writer);
-
-        // Atomic rename
-        fs::rename(&temp_file, &checkpoint_file)
-            .context("Failed to atomically rename checkpoint file")?;
-
-        // Update last checkpoint
-        *self.last_checkpoint.write() = current_height;
-
-        // Clean up old checkpoints
-        self.cleanup_old_checkpoints()?;
-
-        println!("Checkpoint created at height {}", current_height);
+        txn.delete_table(BLOCKS)?;
+        txn.commit()?;
+        println!("Moved {} blocks from {} into the block archive", moved, DB_FILE);
         Ok(())
     }
 
-    /// Load state from the latest checkpoint
-    pub fn load_from_checkpoint(&self) -> Result<Option<State>> {
-        let mut checkpoints = Vec::new();
-        
-        // Find all checkpoint files
-        for entry in fs::read_dir(&self.checkpoints_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if let Some(ext) = path.extension() {
-                if ext == "json" {
-                    if let Some(name) = path.file_name() {
-                        if let Some(name_str) = name.to_str() {
-                            if name_str.starts_with("checkpoint_") {
-                                checkpoints.push(path);
-                            }
-                        }
-                    }
-                }
+    /// Write the state and all blocks, receipts, undo journals and certificates as JSON
+    /// files under `dir`, in the layout of pre-database data directories. Returns the
+    /// height of the exported state.
+    pub fn export_json(&self, dir: &Path) -> Result<u64> {
+        let state = self.load_state()?.context("no state stored")?;
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("state.json"), serde_json::to_vec_pretty(&state)?)?;
+
+        let blocks = dir.join("blocks");
+        fs::create_dir_all(&blocks)?;
+        for height in self.blocks.heights()? {
+            if let Some(block) = self.blocks.load(height)? {
+                fs::write(blocks.join(format!("{:016x}.json", height)), serde_json::to_vec_pretty(&block)?)?;
             }
         }
-
-        if checkpoints.is_empty() {
-            return Ok(None);
+        let txn = self.db.begin_read()?;
+        for (table, name) in [(RECEIPTS, "receipts"), (UNDO, "undo"), (COMMITS, "commits")] {
+            let out = dir.join(name);
+            fs::create_dir_all(&out)?;
+            for entry in txn.open_table(table)?.iter()? {
+                let (height, raw) = entry?;
+                let value: serde_json::Value = serde_json::from_slice(raw.value())?;
+                fs::write(out.join(format!("{:016x}.json", height.value())), serde_json::to_vec_pretty(&value)?)?;
+            }
         }
+        Ok(state.height)
+    }
 
-        // Sort by height (filename contains hex height)
-        checkpoints.sort_by(|a, b| {
-            let a_name = a.file_name().unwrap().to_str().unwrap();
-            let b_name = b.file_name().unwrap().to_str().unwrap();
-            a_name.cmp(b_name)
-        });
-
-        // Load the latest checkpoint
-        let latest_checkpoint = checkpoints.last().unwrap();
-        let file = File::open(latest_checkpoint)
-            .context("Failed to open checkpoint file")?;
-        
-        let reader = BufReader::new(file);
-        let checkpoint: Checkpoint = serde_json::from_reader(reader)
-            .context("Failed to deserialize checkpoint")?;
-
-        println!("Loaded checkpoint from height {}", checkpoint.height);
-        Ok(Some(checkpoint.state))
+    /// Find transaction by hash
+    pub fn find_transaction(&self, tx_hash: &str) -> Result<Option<TransactionIndex>> {
+        let Some(hash) = dehex32(tx_hash) else { return Ok(None) };
+        let txn = self.db.begin_read()?;
+        txindex::lookup(&txn.open_table(TX_INDEX)?, &hash)
     }
 
-    /// Index a transaction for efficient querying
-    pub fn index_transaction(&self, tx_hash: H256, block_height: u64, tx_index: usize) -> Result<()> {
-        if !self.config.enable_indexing {
-            return Ok(());
-        }
+    /// Height of the block whose receipts record `tx_hash` as rejected
+    pub fn find_rejected(&self, tx_hash: &H256) -> Result<Option<u64>> {
+        let txn = self.db.begin_read()?;
+        Ok(txn.open_table(REJECTED_TXS)?.get(tx_hash.as_slice())?.map(|h| h.value()))
+    }
 
-        let index_file = self.index_dir.join("transactions.jsonl");
-        let temp_file = index_file.with_extension("tmp");
+    /// Whether the transaction index is kept
+    pub fn indexing_enabled(&self) -> bool {
+        self.config.enable_indexing
+    }
 
-        let index_entry = TransactionIndex {
-            tx_hash: hex::encode(tx_hash),
-            block_height,
-            tx_index,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs(),
-        };
+    /// Transactions sent or received by `addr` in (height, position) order, after the
+    /// `cursor` position (before it when `descending`), at most `limit`.
+    pub fn address_transactions(&self, addr: &H256, cursor: Option<(u64, u32)>, descending: bool, limit: usize) -> Result<Vec<AddressTx>> {
+        let txn = self.db.begin_read()?;
+        txindex::address_range(&txn.open_table(ADDRESS_TXS)?, addr, cursor, descending, limit)
+    }
 
-        // Append to index file
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(&temp_file)
-            .context("Failed to open transaction index file")?;
-        
-        let mut writer = BufWriter::new(file);
-        writeln!(writer, "{}", serde_json::to_string(&index_entry)?)
-            .context("Failed to write transaction index")?;
-        writer.flush().context("Failed to flush index file")?;
-        drop(writer);
-
-        // Atomic rename
-        fs::rename(&temp_file, &index_file)
-            .context("Failed to atomically rename index file")?;
+    /// Layout version stored under `key` in `meta`
+    fn meta_version(&self, key: &str) -> Result<Option<u32>> {
+        let txn = self.db.begin_read()?;
+        let raw = txn.open_table(META)?.get(key)?;
+        Ok(raw.and_then(|raw| Some(u32::from_le_bytes(raw.value().try_into().ok()?))))
+    }
 
+    /// Move the registries of a state stored as one `meta` record into their tables.
+    fn migrate_state_layout(&self) -> Result<()> {
+        let (state, _) = self.read_state()?.context("no state stored")?;
+        let txn = self.db.begin_write()?;
+        write_registries(&txn, &state, None)?;
+        write_meta(&txn, &state)?;
+        txn.commit()?;
+        println!("Moved the state registries into their own tables");
         Ok(())
     }
 
-    /// Find transaction by hash
-    pub fn find_transaction(&self, tx_hash: &str) -> Result<Option<TransactionIndex>> {
-        if !self.config.enable_indexing {
-            return Ok(None);
-        }
-
-        let index_file = self.index_dir.join("transactions.jsonl");
-        if !index_file.exists() {
-            return Ok(None);
-        }
-
-        let file = File::open(&index_file)
-            .context("Failed to open transaction index file")?;
-        
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            let line = line.context("Failed to read index line")?;
-            if let Ok(entry) = serde_json::from_str::<TransactionIndex>(&line) {
-                if entry.tx_hash == tx_hash {
-                    return Ok(Some(entry));
-                }
+    /// Rebuild the transaction index from the archived blocks, in one transaction.
+    /// Returns the number of transactions indexed.
+    pub fn rebuild_tx_index(&self) -> Result<usize> {
+        let txn = self.db.begin_write()?;
+        txindex::clear(&txn)?;
+        let mut indexed = 0;
+        for height in self.blocks.heights()? {
+            let block = self.blocks.load(height)?.with_context(|| format!("Block {} vanished from the archive", height))?;
+            txindex::index_block(&txn, &block)?;
+            if let Some(receipts) = self.load_receipts(height)? {
+                txindex::index_rejected(&txn, &block, &receipts)?;
             }
+            indexed += block.txs.len();
         }
-
-        Ok(None)
+        txn.open_table(META)?.insert(TX_INDEX_KEY, TX_INDEX_VERSION.to_le_bytes().as_slice())?;
+        txn.commit()?;
+        Ok(indexed)
     }
 
-    /// Create a backup of all data with enhanced persistence
-    pub fn create_backup(&self) -> Result<()> {
+    /// Back up the database, block archive and genesis spec holding `state`, at most
+    /// once per `backup_interval_secs`, with a manifest of file hashes (see `backup`).
+    pub fn create_backup(&self, state: &State) -> Result<()> {
         let current_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs();
-        
+
         let last_backup = *self.last_backup.read();
         if current_time < last_backup + self.config.backup_interval_secs {
             return Ok(());
@@ -397,57 +525,19 @@ impl Storage {
 
         let backup_name = format!("backup_{}", current_time);
         let backup_path = self.backup_dir.join(&backup_name);
-        
+
         // Create backup directory
         fs::create_dir_all(&backup_path)?;
 
-        // Copy all important files with enhanced error handling
-        let files_to_backup = [
-            "state.json",
-            "state.json.backup",
-            "blocks",
-            "checkpoints",
-            "index",
-        ];
-
-        for file_name in &files_to_backup {
-            let source = self.config.base_dir.join(file_name);
-            let dest = backup_path.join(file_name);
-            
+        // Blocks are written under the chain lock, so the database and the archive are
+        // not changing while they are copied
+        for item in BACKUP_ITEMS {
+            let source = self.config.base_dir.join(item);
             if source.exists() {
-                if source.is_dir() {
-                    copy_dir_recursive(&source, &dest)?;
-                } else {
-                    fs::copy(&source, &dest)?;
-                }
-            }
-        }
-
-        // Also backup height-specific state files
-        for entry in fs::read_dir(&self.config.base_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if let Some(name) = path.file_name() {
-                if let Some(name_str) = name.to_str() {
-                    if name_str.starts_with("state_height_") && name_str.ends_with(".json") {
-                        let dest = backup_path.join(name_str);
-                        fs::copy(&path, &dest)?;
-                    }
-                }
+                copy_dir_recursive(&source, &backup_path.join(item))?;
             }
         }
-
-        // Create a manifest file for this backup
-        let manifest = BackupManifest {
-            timestamp: current_time,
-            backup_name: backup_name.clone(),
-            files_backed_up: files_to_backup.len(),
-            blockchain_height: self.get_current_height_from_state()?,
-        };
-
-        let manifest_file = backup_path.join("manifest.json");
-        let manifest_content = serde_json::to_string_pretty(&manifest)?;
-        fs::write(manifest_file, manifest_content)?;
+        let manifest = backup::write_manifest(&backup_path, current_time, state.height, state.state_root)?;
 
         // Update last backup time
         *self.last_backup.write() = current_time;
@@ -459,107 +549,61 @@ impl Storage {
         Ok(())
     }
 
-    /// Get current height from state file
-    fn get_current_height_from_state(&self) -> Result<u64> {
-        if let Ok(Some(state)) = self.load_state() {
-            Ok(state.height)
-        } else {
-            Ok(0)
-        }
-    }
-
-    /// Save a block with enhanced persistence
-    pub fn save_block(&self, block: &Block) -> Result<()> {
-        let fname = format!("{:016x}.json", block.header.height);
-        let block_file = self.config.base_dir.join("blocks").join(&fname);
-        let temp_file = block_file.with_extension("tmp");
-        let backup_file = block_file.with_extension("backup");
-
-        // Create blocks directory if it doesn't exist
-        fs::create_dir_all(self.config.base_dir.join("blocks"))?;
-
-        // Write to temporary file first
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&temp_file)
-            .context("Failed to create temporary block file")?;
-        
-        let mut writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(&mut writer, block)
-            .context("Failed to serialize block")?;
-        writer.flush().context("Failed to flush block file")?;
-        drop(writer);
-
-        // Create backup of existing block if it exists
-        if block_file.exists() {
-            fs::copy(&block_file, &backup_file)
-                .context("Failed to create block backup")?;
-        }
-
-        // Atomic rename
-        fs::rename(&temp_file, &block_file)
-            .context("Failed to atomically rename block file")?;
-
-        // Also save to a compressed backup
-        let compressed_file = self.config.base_dir.join("blocks").join(format!("{:016x}.json.gz", block.header.height));
-        if self.config.enable_compression {
-            // Note: In a real implementation, you'd use a compression library like flate2
-            // For now, we'll just copy the file
-            fs::copy(&block_file, &compressed_file)
-                .context("Failed to create compressed block backup")?;
-        }
-
-        println!("Block {} saved with enhanced persistence", block.header.height);
-        Ok(())
+    /// Height of the stored state (0 if none)
+    fn stored_height(&self) -> Result<u64> {
+        let txn = self.db.begin_read()?;
+        let height = txn.open_table(META)?.get(HEIGHT_KEY)?.and_then(|raw| <[u8; 8]>::try_from(raw.value()).ok());
+        Ok(height.map_or(0, u64::from_le_bytes))
     }
 
-    /// Clean up old checkpoints
-    fn cleanup_old_checkpoints(&self) -> Result<()> {
-        let mut checkpoints = Vec::new();
-        
-        for entry in fs::read_dir(&self.checkpoints_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if let Some(ext) = path.extension() {
-                if ext == "json" {
-                    if let Some(name) = path.file_name() {
-                        if let Some(name_str) = name.to_str() {
-                            if name_str.starts_with("checkpoint_") {
-                                checkpoints.push(path);
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    /// Load a block by height
+    pub fn load_block(&self, height: u64) -> Result<Option<Block>> {
+        self.blocks.load(height).context("Failed to load block")
+    }
 
-        if checkpoints.len() <= self.config.max_checkpoints {
-            return Ok(());
-        }
+    /// Load the receipts of a block, if any were recorded
+    pub fn load_receipts(&self, height: u64) -> Result<Option<Vec<Receipt>>> {
+        self.load_at(RECEIPTS, height).context("Failed to load receipts")
+    }
 
-        // Sort by height and remove oldest
-        checkpoints.sort_by(|a, b| {
-            let a_name = a.file_name().unwrap().to_str().unwrap();
-            let b_name = b.file_name().unwrap().to_str().unwrap();
-            a_name.cmp(b_name)
-        });
+    /// Load the undo journal of a block, if one was recorded
+    pub fn load_undo(&self, height: u64) -> Result<Option<UndoJournal>> {
+        self.load_at(UNDO, height).context("Failed to load undo journal")
+    }
 
-        let to_remove = checkpoints.len() - self.config.max_checkpoints;
-        for checkpoint in checkpoints.iter().take(to_remove) {
-            fs::remove_file(checkpoint)?;
-        }
+    /// Save the commit certificate finalizing a block
+    pub fn save_commit(&self, cert: &CommitCertificate) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(COMMITS)?.insert(cert.height, serde_json::to_vec(cert)?.as_slice())?;
+        txn.commit()?;
+        Ok(())
+    }
 
-        println!("Cleaned up {} old checkpoints", to_remove);
+    /// Save a commit certificate and the state that records it as final, in one transaction.
+    pub fn save_finality(&self, cert: &CommitCertificate, state: &State) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(COMMITS)?.insert(cert.height, serde_json::to_vec(cert)?.as_slice())?;
+        write_meta(&txn, state)?;
+        txn.commit()?;
         Ok(())
     }
 
+    /// Load the commit certificate of a block, if it was finalized
+    pub fn load_commit(&self, height: u64) -> Result<Option<CommitCertificate>> {
+        self.load_at(COMMITS, height).context("Failed to load commit certificate")
+    }
+
+    fn load_at<T: DeserializeOwned>(&self, table: HeightTable, height: u64) -> Result<Option<T>> {
+        let txn = self.db.begin_read()?;
+        let raw = txn.open_table(table)?.get(height)?;
+        Ok(raw.map(|raw| serde_json::from_slice(raw.value())).transpose()?)
+    }
+
     /// Clean up old backups
     fn cleanup_old_backups(&self) -> Result<()> {
         let max_backups = 5; // Keep last 5 backups
         let mut backups = Vec::new();
-        
+
         for entry in fs::read_dir(&self.backup_dir)? {
             let entry = entry?;
             let path = entry.path();
@@ -597,16 +641,10 @@ impl Storage {
     /// Get storage statistics
     pub fn get_stats(&self) -> Result<StorageStats> {
         let mut stats = StorageStats::default();
-        
-        // Count blocks
-        let blocks_dir = self.config.base_dir.join("blocks");
-        if blocks_dir.exists() {
-            stats.block_count = fs::read_dir(&blocks_dir)?.count();
-        }
-
-        // Count checkpoints
-        if self.checkpoints_dir.exists() {
-            stats.checkpoint_count = fs::read_dir(&self.checkpoints_dir)?.count();
+        (stats.block_count, stats.block_archive_size) = self.blocks.stats()?;
+        {
+            let txn = self.db.begin_read()?;
+            stats.total_transactions = txn.open_table(TX_INDEX)?.len()? as usize;
         }
 
         // Count backups
@@ -614,26 +652,15 @@ impl Storage {
             stats.backup_count = fs::read_dir(&self.backup_dir)?.count();
         }
 
-        // Count transactions in index
-        let index_file = self.index_dir.join("transactions.jsonl");
-        if index_file.exists() {
-            let file = File::open(&index_file)?;
-            let reader = BufReader::new(file);
-            stats.total_transactions = reader.lines().count();
-        }
-
         // Calculate total size
         stats.total_size = self.calculate_directory_size(&self.config.base_dir)?;
 
         // Set timing information
         stats.last_backup_time = *self.last_backup.read();
-        stats.last_checkpoint_height = *self.last_checkpoint.read();
 
         // Determine storage health
-        stats.storage_health = if stats.block_count > 0 && stats.checkpoint_count > 0 {
+        stats.storage_health = if stats.block_count > 0 {
             "Healthy".to_string()
-        } else if stats.block_count > 0 {
-            "Warning: No checkpoints".to_string()
         } else {
             "New: No data yet".to_string()
         };
@@ -644,7 +671,7 @@ impl Storage {
     /// Calculate directory size recursively
     fn calculate_directory_size(&self, path: &Path) -> Result<u64> {
         let mut size = 0;
-        
+
         if path.is_file() {
             size += fs::metadata(path)?.len();
         } else if path.is_dir() {
@@ -653,54 +680,416 @@ impl Storage {
                 size += self.calculate_directory_size(&entry.path())?;
             }
         }
-        
+
         Ok(size)
     }
 }
 
-/// Checkpoint data structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Checkpoint {
+/// What `meta` keeps of the state: everything but the accounts, the SMT leaves and the
+/// registries, which have tables of their own. Field names are those of `State`, which
+/// reads the record back.
+#[derive(Serialize)]
+struct StateMeta<'a> {
     height: u64,
-    timestamp: u64,
-    state: State,
-    block_header: BlockHeader,
+    last_block_hash: &'a H256,
+    state_root: &'a H256,
+    genesis_hash: &'a H256,
+    chain_id: u32,
+    monetary: &'a MonetaryParams,
+    validators: &'a [H256],
+    previous_validators: &'a [H256],
+    validators_since: u64,
+    staking: &'a StakingParams,
+    min_fee: u128,
+    recent_block_txs: &'a [u32],
+    finalized_height: u64,
 }
 
-/// Transaction index entry
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TransactionIndex {
-    pub tx_hash: String,
-    pub block_height: u64,
-    pub tx_index: usize,
-    pub timestamp: u64,
+impl<'a> From<&'a State> for StateMeta<'a> {
+    fn from(state: &'a State) -> Self {
+        Self {
+            height: state.height,
+            last_block_hash: &state.last_block_hash,
+            state_root: &state.state_root,
+            genesis_hash: &state.genesis_hash,
+            chain_id: state.chain_id,
+            monetary: &state.monetary,
+            validators: &state.validators,
+            previous_validators: &state.previous_validators,
+            validators_since: state.validators_since,
+            staking: &state.staking,
+            min_fee: state.min_fee,
+            recent_block_txs: &state.recent_block_txs,
+            finalized_height: state.finalized_height,
+        }
+    }
+}
+
+/// Write the state parameters, the tip height and the layout version.
+fn write_meta(txn: &WriteTransaction, state: &State) -> Result<()> {
+    let mut meta = txn.open_table(META)?;
+    meta.insert(STATE_KEY, serde_json::to_vec(&StateMeta::from(state))?.as_slice())?;
+    meta.insert(HEIGHT_KEY, state.height.to_le_bytes().as_slice())?;
+    meta.insert(STATE_LAYOUT_KEY, STATE_LAYOUT_VERSION.to_le_bytes().as_slice())?;
+    Ok(())
+}
+
+/// Replace the accounts, SMT leaves and registries with those of `state`, and write the
+/// rest of it.
+fn write_full_state(txn: &WriteTransaction, state: &State) -> Result<()> {
+    txn.delete_table(ACCOUNTS)?;
+    txn.delete_table(SMT_LEAVES)?;
+    {
+        let mut accounts = txn.open_table(ACCOUNTS)?;
+        for (key, acct) in &state.accounts {
+            let Some(addr) = dehex32(key) else { continue };
+            accounts.insert(addr.as_slice(), serde_json::to_vec(acct)?.as_slice())?;
+        }
+        let mut leaves = txn.open_table(SMT_LEAVES)?;
+        for (key, leaf) in state.smt.leaves() {
+            leaves.insert(key.as_slice(), leaf.as_slice())?;
+        }
+    }
+    txn.delete_table(ASSETS)?;
+    for table in REGISTRIES {
+        txn.delete_table(table)?;
+    }
+    write_registries(txn, state, None)?;
+    write_meta(txn, state)
+}
+
+/// Write the registry records `journal` touched (every record without one), removing
+/// those `state` no longer has.
+fn write_registries(txn: &WriteTransaction, state: &State, journal: Option<&UndoJournal>) -> Result<()> {
+    {
+        let mut assets = txn.open_table(ASSETS)?;
+        let ids: Vec<AssetId> = match journal {
+            Some(journal) => journal.assets.keys().copied().collect(),
+            None => state.assets.keys().copied().collect(),
+        };
+        for id in ids {
+            match state.assets.get(&id) {
+                Some(info) => assets.insert(id, serde_json::to_vec(info)?.as_slice())?,
+                None => assets.remove(id)?,
+            };
+        }
+    }
+    write_records(txn, VALIDATORS, &state.validator_registry, journal.map(|j| j.validator_records.keys().collect()))?;
+    write_records(txn, VESTING, &state.vesting, journal.map(|j| j.vesting.keys().collect()))?;
+    write_records(txn, HTLCS, &state.htlcs, journal.map(|j| j.htlcs.keys().collect()))?;
+    write_records(txn, DIDS, &state.dids, journal.map(|j| j.dids.keys().collect()))?;
+    write_records(txn, MODULES, &state.modules, journal.map(|j| j.modules.keys().collect()))?;
+    write_slots(txn, MODULE_STORAGE, &state.module_storage, journal.map(|j| slot_ids(&j.module_slots)))?;
+    write_slots(txn, REVOCATIONS, &state.revocations, journal.map(|j| slot_ids(&j.revocations)))
+}
+
+/// Write the records of `records` named by `ids` (all of them if None); ids it no longer
+/// holds are removed.
+fn write_records<T: Serialize>(txn: &WriteTransaction, table: BytesTable, records: &BTreeMap<String, T>, ids: Option<Vec<&String>>) -> Result<()> {
+    let mut out = txn.open_table(table)?;
+    for id in ids.unwrap_or_else(|| records.keys().collect()) {
+        let Some(key) = dehex32(id) else { continue };
+        match records.get(id) {
+            Some(record) => out.insert(key.as_slice(), serde_json::to_vec(record)?.as_slice())?,
+            None => out.remove(key.as_slice())?,
+        };
+    }
+    Ok(())
+}
+
+/// `write_records` for two-level maps (hex(id) -> hex(key) -> value), keyed by id ++ key
+fn write_slots<T: Serialize>(
+    txn: &WriteTransaction,
+    table: BytesTable,
+    records: &BTreeMap<String, BTreeMap<String, T>>,
+    ids: Option<Vec<(&String, &String)>>,
+) -> Result<()> {
+    let mut out = txn.open_table(table)?;
+    for (id, slot) in ids.unwrap_or_else(|| slot_ids(records)) {
+        let (Some(id_bytes), Ok(slot_bytes)) = (dehex32(id), hex::decode(slot)) else { continue };
+        let key = [id_bytes.as_slice(), &slot_bytes].concat();
+        match records.get(id).and_then(|slots| slots.get(slot)) {
+            Some(value) => out.insert(key.as_slice(), serde_json::to_vec(value)?.as_slice())?,
+            None => out.remove(key.as_slice())?,
+        };
+    }
+    Ok(())
+}
+
+fn slot_ids<T>(records: &BTreeMap<String, BTreeMap<String, T>>) -> Vec<(&String, &String)> {
+    records.iter().flat_map(|(id, slots)| slots.keys().map(move |slot| (id, slot))).collect()
+}
+
+fn read_records<T: DeserializeOwned>(txn: &ReadTransaction, table: BytesTable, records: &mut BTreeMap<String, T>) -> Result<()> {
+    for entry in txn.open_table(table)?.iter()? {
+        let (id, record) = entry?;
+        let record = serde_json::from_slice(record.value()).with_context(|| format!("Failed to deserialize {} record", table.name()))?;
+        records.insert(hex::encode(id.value()), record);
+    }
+    Ok(())
+}
+
+fn read_slots<T: DeserializeOwned>(txn: &ReadTransaction, table: BytesTable, records: &mut BTreeMap<String, BTreeMap<String, T>>) -> Result<()> {
+    for entry in txn.open_table(table)?.iter()? {
+        let (key, value) = entry?;
+        if key.value().len() < 32 {
+            anyhow::bail!("malformed {} key in {}", table.name(), DB_FILE);
+        }
+        let (id, slot) = key.value().split_at(32);
+        let value = serde_json::from_slice(value.value()).with_context(|| format!("Failed to deserialize {} entry", table.name()))?;
+        records.entry(hex::encode(id)).or_default().insert(hex::encode(slot), value);
+    }
+    Ok(())
+}
+
+/// Whether `base` already holds a chain: the database, or the state files of a
+/// pre-database data directory.
+pub fn holds_chain_data(base: &Path) -> bool {
+    base.join(DB_FILE).exists() || base.join(LEGACY_DIR).exists() || load_legacy_state(base).is_some()
+}
+
+/// Newest loadable state of a pre-database data directory: `state.json`, its
+/// `.backup` copy, then the highest `state_height_*.json`.
+fn load_legacy_state(base: &Path) -> Option<State> {
+    let load = |path: &Path| -> Option<State> {
+        let file = File::open(path).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    };
+    if let Some(state) = load(&base.join("state.json")).or_else(|| load(&base.join("state.json.backup"))) {
+        return Some(state);
+    }
+    let mut height_files: Vec<(u64, PathBuf)> = fs::read_dir(base)
+        .ok()?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?;
+            let height = name.strip_prefix("state_height_")?.strip_suffix(".json")?.parse().ok()?;
+            Some((height, path))
+        })
+        .collect();
+    height_files.sort();
+    height_files.iter().rev().find_map(|(_, path)| load(path))
+}
+
+/// `{:016x}.json` files of a pre-database directory, by height
+fn legacy_height_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(stem) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".json")) else { continue };
+        if let Ok(height) = u64::from_str_radix(stem, 16) {
+            files.push((height, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Copy a file, or a directory recursively
+fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<()> {
+    if src.is_file() {
+        fs::copy(src, dst)?;
+    } else if src.is_dir() {
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_dir_recursive(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
 }
 
 /// Storage statistics
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct StorageStats {
     pub block_count: usize,
-    pub checkpoint_count: usize,
+    /// Bytes of block segment data
+    pub block_archive_size: u64,
     pub backup_count: usize,
     pub total_size: u64,
     pub total_transactions: usize,
     pub last_backup_time: u64,
-    pub last_checkpoint_height: u64,
     pub storage_health: String,
 }
 
-/// Copy directory recursively
-fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<()> {
-    if src.is_file() {
-        fs::copy(src, dst)?;
-    } else if src.is_dir() {
-        fs::create_dir_all(dst)?;
-        for entry in fs::read_dir(src)? {
-            let entry = entry?;
-            let src_path = entry.path();
-            let dst_path = dst.join(entry.file_name());
-            copy_dir_recursive(&src_path, &dst_path)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tx::{Tx, TxPayload}, Chain, TokenType};
+    use dxid_crypto::{StarkSignEngine, ENGINE as STARK};
+    use std::sync::Arc;
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dxid-storage-{}-{}-{}", tag, std::process::id(), crate::now_ts()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_blocks_survive_reopen_without_per_height_files() {
+        let base = temp_dir("reopen");
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap());
+        for nonce in 0..3 {
+            let send = TxPayload::Transfer { to: [nonce as u8 + 1; 32], amount: 100, token_type: TokenType::Native };
+            let tx = Tx::new_signed(&sk, pk, nonce, 10, crate::CHAIN_ID, None, send).unwrap();
+            fs::write(chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash()))), serde_json::to_string(&tx).unwrap()).unwrap();
+            chain.make_block_once().unwrap().unwrap();
         }
+        let (height, root) = { let st = chain.state.lock(); (st.height, st.state_root) };
+        drop(chain);
+
+        let names: Vec<String> = fs::read_dir(&base).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        assert!(names.iter().all(|n| !n.starts_with("state")), "no JSON state files: {:?}", names);
+
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap());
+        let st = chain.state.lock().clone();
+        assert_eq!((st.height, st.state_root), (height, root));
+        assert_eq!(st.accounts.get(&hex::encode([3u8; 32])).map(|a| a.balance(crate::assets::NATIVE_ASSET)), Some(100));
+        let block = chain.load_block(2).unwrap().unwrap();
+        let (found, pos) = chain.find_tx(&block.txs[0].hash()).unwrap().unwrap();
+        assert_eq!((found.header.height, pos), (2, 0));
+    }
+
+    #[test]
+    fn test_failed_commit_does_not_advance_the_tip() {
+        let base = temp_dir("failed-commit");
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap());
+        let before = chain.state.lock().clone();
+        let send = TxPayload::Transfer { to: [7u8; 32], amount: 100, token_type: TokenType::Native };
+        let tx = Tx::new_signed(&sk, pk, 0, 10, crate::CHAIN_ID, None, send).unwrap();
+        let queued = chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash())));
+        fs::write(&queued, serde_json::to_string(&tx).unwrap()).unwrap();
+
+        // The archive cannot be written while its directory is a file
+        fs::remove_dir_all(base.join("blocks")).unwrap();
+        fs::write(base.join("blocks"), b"").unwrap();
+        assert!(chain.make_block_once().is_err());
+        let st = chain.state.lock().clone();
+        assert_eq!((st.height, st.state_root, st.last_block_hash), (before.height, before.state_root, before.last_block_hash));
+        assert!(queued.exists(), "the transaction goes back to the mempool");
+
+        fs::remove_file(base.join("blocks")).unwrap();
+        fs::create_dir_all(base.join("blocks")).unwrap();
+        let (block, _) = chain.make_block_once().unwrap().unwrap();
+        assert_eq!((block.header.height, block.txs.len()), (1, 1));
+        drop(chain);
+        let chain = Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap();
+        assert_eq!(chain.state.lock().state_root, block.header.state_root);
+    }
+
+    #[test]
+    fn test_unreadable_parent_commit_abandons_the_block() {
+        let base = temp_dir("bad-commit");
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap());
+        chain.make_block_once().unwrap().unwrap();
+        let txn = chain.storage.db.begin_write().unwrap();
+        txn.open_table(COMMITS).unwrap().insert(1, b"not json".as_slice()).unwrap();
+        txn.commit().unwrap();
+
+        let before = chain.state.lock().clone();
+        let send = TxPayload::Transfer { to: [7u8; 32], amount: 100, token_type: TokenType::Native };
+        let tx = Tx::new_signed(&sk, pk, 0, 10, crate::CHAIN_ID, None, send).unwrap();
+        let queued = chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash())));
+        fs::write(&queued, serde_json::to_string(&tx).unwrap()).unwrap();
+        assert!(chain.make_block_once().is_err());
+        let st = chain.state.lock().clone();
+        assert_eq!((st.height, st.state_root, st.last_block_hash), (before.height, before.state_root, before.last_block_hash));
+        assert!(queued.exists(), "the transaction goes back to the mempool");
+    }
+
+    #[test]
+    fn test_damaged_state_tree_is_an_error_until_rebuilt() {
+        let base = temp_dir("damaged-smt");
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap());
+        let send = TxPayload::Transfer { to: [7u8; 32], amount: 100, token_type: TokenType::Native };
+        let tx = Tx::new_signed(&sk, pk, 0, 10, crate::CHAIN_ID, None, send).unwrap();
+        fs::write(chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash()))), serde_json::to_string(&tx).unwrap()).unwrap();
+        let (block, _) = chain.make_block_once().unwrap().unwrap();
+        drop(chain);
+
+        let storage = Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() }).unwrap();
+        let txn = storage.db.begin_write().unwrap();
+        txn.open_table(SMT_LEAVES).unwrap().remove([7u8; 32].as_slice()).unwrap();
+        txn.commit().unwrap();
+        let err = storage.load_state().unwrap_err().to_string();
+        assert!(err.contains("height 1"), "{}", err);
+
+        assert_eq!(storage.rebuild_smt().unwrap(), 1);
+        assert_eq!(storage.load_state().unwrap().unwrap().state_root, block.header.state_root);
+    }
+
+    #[test]
+    fn test_registries_are_stored_per_record() {
+        let base = temp_dir("registries");
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap());
+        let create = TxPayload::CreateVesting { beneficiary: [7u8; 32], token_type: TokenType::Native, amount: 1_000, cliff_height: 5, end_height: 10 };
+        let tx = Tx::new_signed(&sk, pk, 0, 10, crate::CHAIN_ID, None, create).unwrap();
+        fs::write(chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash()))), serde_json::to_string(&tx).unwrap()).unwrap();
+        chain.make_block_once().unwrap().unwrap();
+        let expected = chain.state.lock().clone();
+        assert_eq!(expected.vesting.len(), 1);
+        drop(chain);
+
+        let storage = Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() }).unwrap();
+        let st = storage.load_state().unwrap().unwrap();
+        assert_eq!((st.state_root, &st.vesting, &st.assets), (expected.state_root, &expected.vesting, &expected.assets));
+        let txn = storage.db.begin_read().unwrap();
+        let meta = txn.open_table(META).unwrap().get(STATE_KEY).unwrap().unwrap().value().to_vec();
+        assert!(!String::from_utf8(meta).unwrap().contains("vesting"));
+        drop(txn);
+
+        // A database from before the registry tables keeps everything in `meta`
+        let txn = storage.db.begin_write().unwrap();
+        txn.open_table(META).unwrap().insert(STATE_KEY, serde_json::to_vec(&st).unwrap().as_slice()).unwrap();
+        txn.open_table(META).unwrap().remove(STATE_LAYOUT_KEY).unwrap();
+        txn.delete_table(VESTING).unwrap();
+        txn.delete_table(ASSETS).unwrap();
+        txn.commit().unwrap();
+        drop(storage);
+        let storage = Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() }).unwrap();
+        assert_eq!(storage.meta_version(STATE_LAYOUT_KEY).unwrap(), Some(STATE_LAYOUT_VERSION));
+        let st = storage.load_state().unwrap().unwrap();
+        assert_eq!((st.state_root, &st.vesting, &st.assets), (expected.state_root, &expected.vesting, &expected.assets));
+        assert_eq!(storage.db.begin_read().unwrap().open_table(VESTING).unwrap().len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_legacy_json_data_dir_is_migrated() {
+        let base = temp_dir("legacy");
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(pk, 1_000_000)]), base.clone(), 2000).unwrap());
+        let send = TxPayload::Transfer { to: [7u8; 32], amount: 100, token_type: TokenType::Native };
+        let tx = Tx::new_signed(&sk, pk, 0, 10, crate::CHAIN_ID, None, send).unwrap();
+        fs::write(chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash()))), serde_json::to_string(&tx).unwrap()).unwrap();
+        chain.make_block_once().unwrap().unwrap();
+        let root = chain.state.lock().state_root;
+        drop(chain);
+
+        // Rewrite the data dir in the pre-database layout
+        let legacy = temp_dir("legacy-export");
+        Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() }).unwrap().export_json(&legacy).unwrap();
+        fs::remove_file(base.join(DB_FILE)).unwrap();
+        fs::remove_dir_all(base.join("blocks")).unwrap();
+        for entry in fs::read_dir(&legacy).unwrap() {
+            let path = entry.unwrap().path();
+            fs::rename(&path, base.join(path.file_name().unwrap())).unwrap();
+        }
+        assert!(holds_chain_data(&base) && !holds_chain_data(&legacy));
+
+        let storage = Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() }).unwrap();
+        let st = storage.load_state().unwrap().unwrap();
+        assert_eq!((st.height, st.state_root), (1, root));
+        assert_eq!(storage.load_block(1).unwrap().unwrap().txs[0].hash(), tx.hash());
+        assert!(storage.find_transaction(&hex::encode(tx.hash())).unwrap().is_some());
+        assert!(!base.join("state.json").exists() && base.join(LEGACY_DIR).join("blocks").join(format!("{:016x}.json", 1)).is_file());
+        assert!(fs::read_dir(base.join("blocks")).unwrap().all(|e| e.unwrap().file_name().to_string_lossy().starts_with("seg-")));
     }
-    Ok(())
 }
@@ -0,0 +1,173 @@
+//! Token issuance and supply accounting.
+//!
+//! Every asset's circulating supply lives in its registry record (see `assets`).
+//! Anything that creates tokens (block rewards, Layer0 appreciation, mints) goes
+//! through `State::issue`, which enforces the asset's cap; anything that destroys
+//! tokens (burned fees, burns) goes through `State::burn`. `State::audit_supply`
+//! recomputes the totals from account balances, plus the tokens the protocol holds
+//! for accounts (bonded and unbonding stake, locked vesting, HTLC escrow), and
+//! compares them with the registry.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::{AssetId, LAYER0_ASSET, LONGYIELD_ASSET, NATIVE_ASSET},
+    htlc::HtlcStatus,
+    State, TokenType, H256,
+};
+
+/// Result of checking one token's counter against the sum of balances.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SupplyAudit {
+    pub token: TokenType,
+    pub symbol: String,
+    /// Circulating supply according to the asset registry
+    pub circulating: u128,
+    /// Sum of all account balances
+    pub balances_total: u128,
+    /// Tokens held outside accounts: bonded and unbonding stake, unclaimed vesting,
+    /// locked HTLCs
+    #[serde(default)]
+    pub held_total: u128,
+    /// Maximum supply (None = uncapped)
+    pub cap: Option<u128>,
+    /// Counter matches balances plus held tokens and stays within the cap
+    pub consistent: bool,
+}
+
+impl State {
+    /// Circulating supply of a token, from its registry record.
+    pub fn circulating(&self, token: TokenType) -> u128 {
+        self.asset(token.asset_id()).map_or(0, |a| a.supply)
+    }
+
+    /// Supply cap of a token (None = uncapped, e.g. the native token).
+    pub fn supply_cap(&self, token: TokenType) -> Option<u128> {
+        self.asset(token.asset_id()).and_then(|a| a.supply_cap)
+    }
+
+    /// Tokens that can still be issued before the cap is hit.
+    pub fn remaining_supply(&self, token: TokenType) -> u128 {
+        match self.asset(token.asset_id()) {
+            Some(asset) => asset.supply_cap.unwrap_or(u128::MAX).saturating_sub(asset.supply),
+            None => 0,
+        }
+    }
+
+    /// Account for up to `amount` new tokens, clamped to the remaining supply.
+    /// Returns the amount actually issued; the caller credits it to an account.
+    pub fn issue(&mut self, token: TokenType, amount: u128) -> u128 {
+        let issued = amount.min(self.remaining_supply(token));
+        if issued > 0 {
+            if let Some(asset) = self.asset_mut(token.asset_id()) {
+                asset.supply += issued;
+            }
+        }
+        issued
+    }
+
+    /// Account for tokens removed from circulation (e.g. burned fees).
+    pub fn burn(&mut self, token: TokenType, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        if let Some(asset) = self.asset_mut(token.asset_id()) {
+            asset.supply = asset.supply.saturating_sub(amount);
+        }
+    }
+
+    /// Credit this block's Layer0 and LongYield rewards to `coinbase`, within the caps.
+    /// Returns the issued `(layer0, longyield)` amounts.
+    pub fn credit_block_rewards(&mut self, coinbase: H256) -> (u128, u128) {
+        let layer0 = self.issue(TokenType::Layer0, self.calculate_layer0_reward());
+        let longyield = self.issue(TokenType::LongYield, self.calculate_longyield_reward());
+        if layer0 > 0 || longyield > 0 {
+            let mut acct = self.accounts.get(&hex::encode(coinbase)).cloned().unwrap_or_default();
+            acct.credit(LAYER0_ASSET, layer0);
+            acct.credit(LONGYIELD_ASSET, longyield);
+            self.set_account(coinbase, &acct);
+        }
+        (layer0, longyield)
+    }
+
+    /// Tokens of `asset` that belong to no account but are still part of its supply.
+    fn held_outside_accounts(&self, asset: AssetId) -> u128 {
+        let mut held = 0u128;
+        if asset == NATIVE_ASSET {
+            for record in self.validator_registry.values() {
+                held = held.saturating_add(record.stake).saturating_add(record.unbonding_total());
+            }
+        }
+        for schedule in self.vesting.values().filter(|s| s.asset == asset) {
+            held = held.saturating_add(schedule.total - schedule.claimed);
+        }
+        for htlc in self.htlcs.values().filter(|h| h.asset == asset && h.status == HtlcStatus::Locked) {
+            held = held.saturating_add(htlc.amount);
+        }
+        held
+    }
+
+    /// Sum balances and held tokens per registered asset and check them against the
+    /// recorded supply and cap.
+    pub fn audit_supply(&self) -> Vec<SupplyAudit> {
+        self.assets
+            .iter()
+            .map(|(&id, asset)| {
+                let balances_total = self
+                    .accounts
+                    .values()
+                    .fold(0u128, |sum, a| sum.saturating_add(a.balance(id)));
+                let held_total = self.held_outside_accounts(id);
+                let circulating = asset.supply;
+                let cap = asset.supply_cap;
+                let consistent = balances_total.saturating_add(held_total) == circulating && cap.is_none_or(|cap| circulating <= cap);
+                SupplyAudit {
+                    token: TokenType::from_asset_id(id),
+                    symbol: asset.symbol.clone(),
+                    circulating,
+                    balances_total,
+                    held_total,
+                    cap,
+                    consistent,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{monetary::MonetaryParams, Account};
+
+    #[test]
+    fn test_issuance_stops_at_cap() {
+        let mut st = State::with_monetary(MonetaryParams {
+            layer0_total_supply: 250,
+            layer0_block_reward: 100,
+            longyield_block_reward: 0,
+            ..Default::default()
+        });
+        let coinbase = [9u8; 32];
+
+        let issued: Vec<u128> = (0..4).map(|_| st.credit_block_rewards(coinbase).0).collect();
+        assert!(issued.iter().sum::<u128>() <= 250);
+        assert_eq!(st.circulating(TokenType::Layer0), 250);
+        assert_eq!(st.credit_block_rewards(coinbase), (0, 0));
+        assert!(st.audit_supply().iter().all(|a| a.consistent));
+    }
+
+    #[test]
+    fn test_audit_detects_counter_drift() {
+        let mut st = State::empty();
+        let mut acct = Account::default();
+        acct.credit(NATIVE_ASSET, 10);
+        st.set_account([1u8; 32], &acct);
+        let native = |st: &State| st.audit_supply().into_iter().find(|a| a.token == TokenType::Native).unwrap();
+        assert!(!native(&st).consistent);
+
+        st.issue(TokenType::Native, 10);
+        assert!(native(&st).consistent);
+        assert_eq!(native(&st).symbol, "DXID");
+    }
+}
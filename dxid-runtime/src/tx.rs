@@ -0,0 +1,487 @@
+//! Versioned transaction envelope.
+//!
+//! A transaction is a set of common header fields (sender, nonce, fee, chain id,
+//! expiry) plus a typed payload. New transaction kinds add a `TxPayload` variant
+//! and a handler in `Chain::apply_tx`'s dispatch table.
+//!
+//! Wire compatibility: the original flat `Tx` JSON (`to`, `amount`, `token_type`,
+//! `cross_chain`, `target_chain_id`) still decodes, as a version 0 envelope, and a
+//! version 0 envelope re-encodes to exactly that layout so existing transaction
+//! hashes and block `tx_root`s stay valid.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use dxid_crypto::{SecretKey, StarkSignEngine, StarkSignature, ENGINE as STARK};
+
+use crate::{
+    assets::AssetId,
+    did::{DidId, ServiceEndpoint, VerificationMethod},
+    htlc::HtlcId,
+    staking::Equivocation, vesting::VestingId, wasm::ModuleId, TokenType, CHAIN_ID, H256,
+};
+
+/// Envelope version produced by `Tx::new_signed`.
+pub const TX_VERSION: u8 = 1;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum TxPayload {
+    /// Move tokens between two accounts on L0
+    Transfer { to: H256, amount: u128, token_type: TokenType },
+    /// Transfer addressed to another chain; settled on L0 and picked up by relayers
+    CrossChainTransfer { to: H256, amount: u128, token_type: TokenType, target_chain_id: u32 },
+    /// Register a partner asset; the sender becomes its issuer and receives `initial_supply`
+    IssueAsset { symbol: String, decimals: u8, supply_cap: Option<u128>, initial_supply: u128 },
+    /// Create new units of an asset (issuer only, within the cap)
+    Mint { asset: AssetId, to: H256, amount: u128 },
+    /// Destroy units from the sender's balance
+    Burn { asset: AssetId, amount: u128 },
+    /// Store a WASM module; its id is derived from the sender and nonce
+    DeployModule {
+        #[serde(with = "hex_bytes")]
+        code: Vec<u8>,
+    },
+    /// Run an exported function of a module, optionally sending it native tokens
+    CallModule {
+        module: ModuleId,
+        function: String,
+        #[serde(with = "hex_bytes")]
+        input: Vec<u8>,
+        value: u128,
+        gas_limit: u64,
+    },
+    /// Bond native tokens to the sender's validator record
+    Stake { amount: u128 },
+    /// Start unbonding part of the sender's stake
+    Unstake { amount: u128 },
+    /// Burn part of an equivocating validator's stake and tombstone it
+    Slash { evidence: Box<Equivocation> },
+    /// Lock tokens for `beneficiary`, vesting linearly until `end_height` with nothing before the cliff
+    CreateVesting { beneficiary: H256, token_type: TokenType, amount: u128, cliff_height: u64, end_height: u64 },
+    /// Release the vested, unclaimed part of a schedule to its beneficiary
+    ClaimVesting { schedule: VestingId },
+    /// Escrow tokens for `recipient` under a SHA-256 hash lock until `expiry_height`
+    LockHtlc { recipient: H256, token_type: TokenType, amount: u128, hash_lock: H256, expiry_height: u64 },
+    /// Pay an escrow to its recipient by revealing the preimage (before expiry)
+    ClaimHtlc {
+        htlc: HtlcId,
+        #[serde(with = "hex_bytes")]
+        preimage: Vec<u8>,
+    },
+    /// Return an expired escrow to its sender
+    RefundHtlc { htlc: HtlcId },
+    /// Register a DID controlled by the sender
+    CreateDid { keys: Vec<VerificationMethod>, services: Vec<ServiceEndpoint> },
+    /// Replace the keys and services of a DID document (controller only)
+    UpdateDid { did: DidId, keys: Vec<VerificationMethod>, services: Vec<ServiceEndpoint> },
+    /// Hand control of a DID to another account (controller only)
+    RotateDidController { did: DidId, new_controller: H256 },
+    /// Permanently retire a DID (controller only)
+    DeactivateDid { did: DidId },
+    /// Add credential ids to the sender's revocation registry
+    RevokeCredentials { credentials: Vec<H256> },
+}
+
+impl TxPayload {
+    /// Short name used in logs, receipts and indexes
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TxPayload::Transfer { .. } => "transfer",
+            TxPayload::CrossChainTransfer { .. } => "cross_chain_transfer",
+            TxPayload::IssueAsset { .. } => "issue_asset",
+            TxPayload::Mint { .. } => "mint",
+            TxPayload::Burn { .. } => "burn",
+            TxPayload::DeployModule { .. } => "deploy_module",
+            TxPayload::CallModule { .. } => "call_module",
+            TxPayload::Stake { .. } => "stake",
+            TxPayload::Unstake { .. } => "unstake",
+            TxPayload::Slash { .. } => "slash",
+            TxPayload::CreateVesting { .. } => "create_vesting",
+            TxPayload::ClaimVesting { .. } => "claim_vesting",
+            TxPayload::LockHtlc { .. } => "lock_htlc",
+            TxPayload::ClaimHtlc { .. } => "claim_htlc",
+            TxPayload::RefundHtlc { .. } => "refund_htlc",
+            TxPayload::CreateDid { .. } => "create_did",
+            TxPayload::UpdateDid { .. } => "update_did",
+            TxPayload::RotateDidController { .. } => "rotate_did_controller",
+            TxPayload::DeactivateDid { .. } => "deactivate_did",
+            TxPayload::RevokeCredentials { .. } => "revoke_credentials",
+        }
+    }
+
+    /// Account credited by this payload, if any
+    pub fn recipient(&self) -> Option<H256> {
+        match self {
+            TxPayload::Transfer { to, .. } | TxPayload::CrossChainTransfer { to, .. } | TxPayload::Mint { to, .. } => Some(*to),
+            TxPayload::CallModule { module, .. } => Some(*module),
+            TxPayload::CreateVesting { beneficiary, .. } => Some(*beneficiary),
+            TxPayload::LockHtlc { recipient, .. } => Some(*recipient),
+            TxPayload::IssueAsset { .. }
+            | TxPayload::Burn { .. }
+            | TxPayload::DeployModule { .. }
+            | TxPayload::Stake { .. }
+            | TxPayload::Unstake { .. }
+            | TxPayload::Slash { .. }
+            | TxPayload::ClaimVesting { .. }
+            | TxPayload::ClaimHtlc { .. }
+            | TxPayload::RefundHtlc { .. }
+            | TxPayload::CreateDid { .. }
+            | TxPayload::UpdateDid { .. }
+            | TxPayload::RotateDidController { .. }
+            | TxPayload::DeactivateDid { .. }
+            | TxPayload::RevokeCredentials { .. } => None,
+        }
+    }
+
+    /// Token amount moved by this payload
+    pub fn amount(&self) -> u128 {
+        match self {
+            TxPayload::Transfer { amount, .. }
+            | TxPayload::CrossChainTransfer { amount, .. }
+            | TxPayload::Mint { amount, .. }
+            | TxPayload::Burn { amount, .. }
+            | TxPayload::Stake { amount }
+            | TxPayload::Unstake { amount }
+            | TxPayload::CreateVesting { amount, .. }
+            | TxPayload::LockHtlc { amount, .. } => *amount,
+            TxPayload::IssueAsset { initial_supply, .. } => *initial_supply,
+            TxPayload::CallModule { value, .. } => *value,
+            TxPayload::DeployModule { .. }
+            | TxPayload::Slash { .. }
+            | TxPayload::ClaimVesting { .. }
+            | TxPayload::ClaimHtlc { .. }
+            | TxPayload::RefundHtlc { .. }
+            | TxPayload::CreateDid { .. }
+            | TxPayload::UpdateDid { .. }
+            | TxPayload::RotateDidController { .. }
+            | TxPayload::DeactivateDid { .. }
+            | TxPayload::RevokeCredentials { .. } => 0,
+        }
+    }
+
+    /// Token moved by this payload
+    pub fn token_type(&self) -> Option<TokenType> {
+        match self {
+            TxPayload::Transfer { token_type, .. }
+            | TxPayload::CrossChainTransfer { token_type, .. }
+            | TxPayload::CreateVesting { token_type, .. }
+            | TxPayload::LockHtlc { token_type, .. } => Some(*token_type),
+            TxPayload::Mint { asset, .. } | TxPayload::Burn { asset, .. } => Some(TokenType::from_asset_id(*asset)),
+            TxPayload::CallModule { .. } | TxPayload::Stake { .. } | TxPayload::Unstake { .. } => Some(TokenType::Native),
+            TxPayload::IssueAsset { .. }
+            | TxPayload::DeployModule { .. }
+            | TxPayload::Slash { .. }
+            | TxPayload::ClaimVesting { .. }
+            | TxPayload::ClaimHtlc { .. }
+            | TxPayload::RefundHtlc { .. }
+            | TxPayload::CreateDid { .. }
+            | TxPayload::UpdateDid { .. }
+            | TxPayload::RotateDidController { .. }
+            | TxPayload::DeactivateDid { .. }
+            | TxPayload::RevokeCredentials { .. } => None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(try_from = "TxRaw", into = "TxWire")]
+pub struct Tx {
+    /// 0 = legacy flat layout, 1 = envelope
+    pub version: u8,
+    pub from: H256,
+    pub nonce: u64,
+    pub fee: u128,
+    pub chain_id: u32,
+    /// Last block height at which the transaction may be included
+    pub expiry: Option<u64>,
+    pub payload: TxPayload,
+    pub signature: StarkSignature,
+}
+
+impl Tx {
+    /// Build and sign a current-version envelope.
+    pub fn new_signed(
+        secret: &SecretKey,
+        from: H256,
+        nonce: u64,
+        fee: u128,
+        chain_id: u32,
+        expiry: Option<u64>,
+        payload: TxPayload,
+    ) -> Result<Self> {
+        let mut tx = Tx {
+            version: TX_VERSION,
+            from,
+            nonce,
+            fee,
+            chain_id,
+            expiry,
+            payload,
+            signature: placeholder_signature(),
+        };
+        tx.signature = STARK.sign(secret, &tx.signing_bytes()?, nonce)?;
+        Ok(tx)
+    }
+
+    /// Wrap a legacy-signed transfer (signature over `(from, to, amount, fee, nonce, CHAIN_ID)`).
+    pub fn legacy_transfer(from: H256, to: H256, amount: u128, fee: u128, token_type: TokenType, signature: StarkSignature) -> Self {
+        Tx {
+            version: 0,
+            from,
+            nonce: signature.nonce,
+            fee,
+            chain_id: CHAIN_ID,
+            expiry: None,
+            payload: TxPayload::Transfer { to, amount, token_type },
+            signature,
+        }
+    }
+
+    /// Canonical transaction hash (BLAKE3 over the JSON encoding).
+    pub fn hash(&self) -> H256 {
+        *blake3::hash(&serde_json::to_vec(self).unwrap_or_default()).as_bytes()
+    }
+
+    /// Bytes covered by the sender's signature.
+    pub fn signing_bytes(&self) -> Result<Vec<u8>> {
+        if self.version == 0 {
+            // Legacy domain separation; payload kind and token were never signed. Current
+            // envelopes sign the whole payload, token and cross-chain target included.
+            let to = self.payload.recipient().unwrap_or_default();
+            return Ok(serde_json::to_vec(&(self.from, to, self.payload.amount(), self.fee, self.signature.nonce, self.chain_id))?);
+        }
+        Ok(serde_json::to_vec(&(self.version, self.from, self.nonce, self.fee, self.chain_id, self.expiry, &self.payload))?)
+    }
+
+    /// Check the signature, its binding to the sender and to the envelope nonce.
+    pub fn verify_signature(&self) -> Result<()> {
+        if self.signature.pubkey_hash != self.from {
+            anyhow::bail!("signature key does not match sender");
+        }
+        if self.signature.nonce != self.nonce {
+            anyhow::bail!("signature nonce does not match envelope nonce");
+        }
+        STARK.verify(&self.signature, &self.signing_bytes()?)
+    }
+}
+
+fn placeholder_signature() -> StarkSignature {
+    StarkSignature {
+        msg_hash: [0u8; 32],
+        sig: [0u8; 32],
+        proof: dxid_crypto::StarkProof { bytes: vec![] },
+        pubkey_hash: [0u8; 32],
+        nonce: 0,
+    }
+}
+
+/* ---- wire formats ---- */
+
+/// Byte strings (module code, call input) as hex rather than JSON number arrays.
+pub(crate) mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        hex::decode(String::deserialize(d)?).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+enum TxWire {
+    Envelope(EnvelopeWire),
+    Legacy(LegacyWire),
+}
+
+#[derive(Clone, Serialize)]
+struct EnvelopeWire {
+    version: u8,
+    from: H256,
+    nonce: u64,
+    fee: u128,
+    chain_id: u32,
+    expiry: Option<u64>,
+    payload: TxPayload,
+    signature: StarkSignature,
+}
+
+/// The original flat layout, field order preserved so hashes are stable.
+#[derive(Clone, Serialize)]
+struct LegacyWire {
+    from: H256,
+    to: H256,
+    amount: u128,
+    fee: u128,
+    signature: StarkSignature,
+    token_type: TokenType,
+    cross_chain: bool,
+    target_chain_id: Option<u32>,
+}
+
+/// Union of both layouts. Decoded field-by-field rather than through an untagged
+/// enum, which would buffer numbers and lose `u128` amounts above `u64::MAX`.
+#[derive(Deserialize)]
+struct TxRaw {
+    version: Option<u8>,
+    from: H256,
+    nonce: Option<u64>,
+    fee: u128,
+    chain_id: Option<u32>,
+    expiry: Option<u64>,
+    payload: Option<TxPayload>,
+    signature: StarkSignature,
+    // legacy fields
+    to: Option<H256>,
+    amount: Option<u128>,
+    token_type: Option<TokenType>,
+    #[serde(default)]
+    cross_chain: bool,
+    target_chain_id: Option<u32>,
+}
+
+impl TryFrom<TxRaw> for Tx {
+    type Error = String;
+
+    fn try_from(raw: TxRaw) -> std::result::Result<Self, Self::Error> {
+        if let Some(version) = raw.version {
+            return Ok(Tx {
+                version,
+                from: raw.from,
+                nonce: raw.nonce.ok_or("missing field `nonce`")?,
+                fee: raw.fee,
+                chain_id: raw.chain_id.ok_or("missing field `chain_id`")?,
+                expiry: raw.expiry,
+                payload: raw.payload.ok_or("missing field `payload`")?,
+                signature: raw.signature,
+            });
+        }
+
+        let to = raw.to.ok_or("missing field `to`")?;
+        let amount = raw.amount.ok_or("missing field `amount`")?;
+        let token_type = raw.token_type.ok_or("missing field `token_type`")?;
+        let payload = match (raw.cross_chain, raw.target_chain_id) {
+            (true, Some(target_chain_id)) => TxPayload::CrossChainTransfer { to, amount, token_type, target_chain_id },
+            _ => TxPayload::Transfer { to, amount, token_type },
+        };
+        Ok(Tx {
+            version: 0,
+            from: raw.from,
+            nonce: raw.signature.nonce,
+            fee: raw.fee,
+            chain_id: CHAIN_ID,
+            expiry: None,
+            payload,
+            signature: raw.signature,
+        })
+    }
+}
+
+impl From<Tx> for TxWire {
+    fn from(tx: Tx) -> Self {
+        let legacy = match (tx.version, &tx.payload) {
+            (0, TxPayload::Transfer { to, amount, token_type }) => Some((*to, *amount, *token_type, false, None)),
+            (0, TxPayload::CrossChainTransfer { to, amount, token_type, target_chain_id }) => {
+                Some((*to, *amount, *token_type, true, Some(*target_chain_id)))
+            }
+            // the legacy layout only has transfers
+            _ => None,
+        };
+        if let Some((to, amount, token_type, cross_chain, target_chain_id)) = legacy {
+            return TxWire::Legacy(LegacyWire {
+                from: tx.from,
+                to,
+                amount,
+                fee: tx.fee,
+                signature: tx.signature,
+                token_type,
+                cross_chain,
+                target_chain_id,
+            });
+        }
+        TxWire::Envelope(EnvelopeWire {
+            version: tx.version,
+            from: tx.from,
+            nonce: tx.nonce,
+            fee: tx.fee,
+            chain_id: tx.chain_id,
+            expiry: tx.expiry,
+            payload: tx.payload,
+            signature: tx.signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_json_round_trips_byte_identical() {
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let to = [7u8; 32];
+        let msg = serde_json::to_vec(&(pk, to, 5u128, 1u128, 0u64, CHAIN_ID)).unwrap();
+        let signature = STARK.sign(&sk, &msg, 0).unwrap();
+        // Field order of the original flat struct
+        let raw = format!(
+            r#"{{"from":{},"to":{},"amount":5,"fee":1,"signature":{},"token_type":"Layer0","cross_chain":false,"target_chain_id":null}}"#,
+            serde_json::to_string(&pk).unwrap(),
+            serde_json::to_string(&to).unwrap(),
+            serde_json::to_string(&signature).unwrap(),
+        )
+        .into_bytes();
+
+        let tx: Tx = serde_json::from_slice(&raw).unwrap();
+        assert_eq!(tx.version, 0);
+        assert_eq!(tx.payload, TxPayload::Transfer { to, amount: 5, token_type: TokenType::Layer0 });
+        assert!(tx.verify_signature().is_ok());
+        assert_eq!(serde_json::to_vec(&tx).unwrap(), raw);
+    }
+
+    #[test]
+    fn test_legacy_message_verifies_for_every_token() {
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let to = [7u8; 32];
+        // What existing wallets sign, whatever the token
+        let msg = serde_json::to_vec(&(pk, to, 5u128, 1u128, 0u64, CHAIN_ID)).unwrap();
+        let signature = STARK.sign(&sk, &msg, 0).unwrap();
+        for token_type in [TokenType::Native, TokenType::Layer0, TokenType::LongYield, TokenType::Asset(crate::assets::FIRST_CUSTOM_ASSET)] {
+            assert!(Tx::legacy_transfer(pk, to, 5, 1, token_type, signature.clone()).verify_signature().is_ok());
+        }
+
+        // A current envelope binds the token, so its signature cannot be moved to another one
+        let payload = TxPayload::Transfer { to, amount: 5, token_type: TokenType::LongYield };
+        let mut tx = Tx::new_signed(&sk, pk, 0, 1, CHAIN_ID, None, payload).unwrap();
+        assert!(tx.verify_signature().is_ok());
+        tx.payload = TxPayload::Transfer { to, amount: 5, token_type: TokenType::Native };
+        assert!(tx.verify_signature().is_err());
+    }
+
+    #[test]
+    fn test_amounts_above_u64_decode() {
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let amount = u64::MAX as u128 * 1000;
+        let payload = TxPayload::Transfer { to: [2u8; 32], amount, token_type: TokenType::LongYield };
+        let tx = Tx::new_signed(&sk, pk, 0, 0, CHAIN_ID, None, payload).unwrap();
+        let decoded: Tx = serde_json::from_str(&serde_json::to_string(&tx).unwrap()).unwrap();
+        assert_eq!(decoded.payload.amount(), amount);
+    }
+
+    #[test]
+    fn test_envelope_signature_covers_payload() {
+        let (sk, pk) = STARK.generate_keys().unwrap();
+        let payload = TxPayload::Transfer { to: [1u8; 32], amount: 10, token_type: TokenType::Native };
+        let tx = Tx::new_signed(&sk, pk, 0, 2, CHAIN_ID, Some(100), payload).unwrap();
+        assert!(tx.verify_signature().is_ok());
+
+        let decoded: Tx = serde_json::from_str(&serde_json::to_string(&tx).unwrap()).unwrap();
+        assert_eq!(decoded.hash(), tx.hash());
+
+        let mut tampered = tx;
+        tampered.payload = TxPayload::Transfer { to: [1u8; 32], amount: 11, token_type: TokenType::Native };
+        assert!(tampered.verify_signature().is_err());
+    }
+}
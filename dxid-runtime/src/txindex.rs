@@ -0,0 +1,278 @@
+//! Transaction index.
+//!
+//! Three redb tables written in the same transaction as the block they describe:
+//! `tx_index` maps a transaction hash to its block height and position,
+//! `rejected_txs` maps a rejected transaction to the height whose receipts record it,
+//! and `address_txs` lists every transaction an address sent or received. Keys of the
+//! latter are `address ‖ height ‖ position` (big-endian), so an address's history
+//! is one ordered range scan in either direction, and a `(height, position)` pair
+//! is a cursor that later blocks never move.
+//!
+//! `Chain::address_history` (in the crate root) joins index entries with their
+//! blocks and receipts for the account history API.
+
+use std::ops::Bound;
+
+use anyhow::{Context, Result};
+use redb::{ReadableTable, TableDefinition, WriteTransaction};
+use serde::{Deserialize, Serialize};
+
+use crate::{assets::AssetId, receipt::Receipt, Block, Tx, H256};
+
+/// tx hash -> `TransactionIndex`
+pub(crate) const TX_INDEX: TableDefinition<&[u8], &[u8]> = TableDefinition::new("tx_index");
+/// tx hash -> height of the block that rejected it (its receipt follows the included ones)
+pub(crate) const REJECTED_TXS: TableDefinition<&[u8], u64> = TableDefinition::new("rejected_txs");
+/// address ‖ height ‖ position -> tx hash ‖ role
+pub(crate) const ADDRESS_TXS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("address_txs");
+
+/// Most index entries examined for one history page. A token filter can skip many;
+/// a page that reaches this is returned early with `HistoryPage::scan_limit_reached`.
+pub const MAX_HISTORY_SCAN: usize = 10_000;
+
+const ROLE_SENDER: u8 = 1;
+const ROLE_RECIPIENT: u8 = 2;
+
+/// Where an included transaction is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionIndex {
+    pub tx_hash: String,
+    pub block_height: u64,
+    pub tx_index: usize,
+    /// Timestamp of the block
+    pub timestamp: u64,
+}
+
+/// One entry of an address's history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressTx {
+    pub tx_hash: H256,
+    pub height: u64,
+    pub position: u32,
+    /// The address signed the transaction
+    pub sent: bool,
+    /// The address is the payload's recipient
+    pub received: bool,
+}
+
+/// A transaction of an address's history with its block time and receipt
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub tx: Tx,
+    pub height: u64,
+    pub position: u32,
+    /// Timestamp of the block
+    pub timestamp: u64,
+    pub sent: bool,
+    pub received: bool,
+    /// The other side: the recipient of a sent transaction, the sender of a received one
+    pub counterparty: Option<H256>,
+    pub receipt: Option<Receipt>,
+}
+
+/// One page of an address's history
+#[derive(Debug, Clone)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// Cursor for the next page (None = no more entries)
+    pub next: Option<(u64, u32)>,
+    /// The scan stopped after `MAX_HISTORY_SCAN` index entries with fewer than `limit`
+    /// matches; more may follow from `next`
+    pub scan_limit_reached: bool,
+}
+
+fn address_key(addr: &H256, height: u64, position: u32) -> [u8; 44] {
+    let mut key = [0u8; 44];
+    key[..32].copy_from_slice(addr);
+    key[32..40].copy_from_slice(&height.to_be_bytes());
+    key[40..].copy_from_slice(&position.to_be_bytes());
+    key
+}
+
+/// Add the transactions of a block to both tables.
+pub(crate) fn index_block(txn: &WriteTransaction, block: &Block) -> Result<()> {
+    let height = block.header.height;
+    let mut index = txn.open_table(TX_INDEX)?;
+    let mut by_address = txn.open_table(ADDRESS_TXS)?;
+    for (position, tx) in block.txs.iter().enumerate() {
+        let hash = tx.hash();
+        let entry = TransactionIndex { tx_hash: hex::encode(hash), block_height: height, tx_index: position, timestamp: block.header.timestamp };
+        index.insert(hash.as_slice(), serde_json::to_vec(&entry)?.as_slice())?;
+
+        let recipient = tx.payload.recipient();
+        let mut roles = vec![(tx.from, ROLE_SENDER)];
+        match recipient {
+            Some(to) if to == tx.from => roles[0].1 |= ROLE_RECIPIENT,
+            Some(to) => roles.push((to, ROLE_RECIPIENT)),
+            None => {}
+        }
+        for (addr, role) in roles {
+            let value = [&hash[..], &[role]].concat();
+            by_address.insert(address_key(&addr, height, position as u32).as_slice(), value.as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the transactions of a reverted block from both tables.
+pub(crate) fn unindex_block(txn: &WriteTransaction, block: &Block) -> Result<()> {
+    let height = block.header.height;
+    let mut index = txn.open_table(TX_INDEX)?;
+    let mut by_address = txn.open_table(ADDRESS_TXS)?;
+    for (position, tx) in block.txs.iter().enumerate() {
+        index.remove(tx.hash().as_slice())?;
+        for addr in [Some(tx.from), tx.payload.recipient()].into_iter().flatten() {
+            by_address.remove(address_key(&addr, height, position as u32).as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+/// Record the transactions a block rejected: its receipts after the included ones.
+pub(crate) fn index_rejected(txn: &WriteTransaction, block: &Block, receipts: &[Receipt]) -> Result<()> {
+    let mut rejected = txn.open_table(REJECTED_TXS)?;
+    for receipt in receipts.iter().skip(block.txs.len()) {
+        rejected.insert(receipt.tx_hash.as_slice(), block.header.height)?;
+    }
+    Ok(())
+}
+
+/// Drop the rejections recorded for a reverted block.
+pub(crate) fn unindex_rejected(txn: &WriteTransaction, block: &Block, receipts: &[Receipt]) -> Result<()> {
+    let mut rejected = txn.open_table(REJECTED_TXS)?;
+    for receipt in receipts.iter().skip(block.txs.len()) {
+        let height = rejected.get(receipt.tx_hash.as_slice())?.map(|h| h.value());
+        if height == Some(block.header.height) {
+            rejected.remove(receipt.tx_hash.as_slice())?;
+        }
+    }
+    Ok(())
+}
+
+/// Empty all tables (before a rebuild).
+pub(crate) fn clear(txn: &WriteTransaction) -> Result<()> {
+    txn.delete_table(TX_INDEX)?;
+    txn.delete_table(REJECTED_TXS)?;
+    txn.delete_table(ADDRESS_TXS)?;
+    txn.open_table(TX_INDEX)?;
+    txn.open_table(REJECTED_TXS)?;
+    txn.open_table(ADDRESS_TXS)?;
+    Ok(())
+}
+
+pub(crate) fn lookup(table: &impl ReadableTable<&'static [u8], &'static [u8]>, hash: &H256) -> Result<Option<TransactionIndex>> {
+    let entry = table.get(hash.as_slice())?;
+    entry.map(|raw| serde_json::from_slice(raw.value()).context("Failed to deserialize index entry")).transpose()
+}
+
+/// Up to `limit` transactions of `addr` strictly after (or, descending, before) the
+/// `(height, position)` cursor, or from the start (end) of its history without one.
+pub(crate) fn address_range(
+    table: &impl ReadableTable<&'static [u8], &'static [u8]>,
+    addr: &H256,
+    cursor: Option<(u64, u32)>,
+    descending: bool,
+    limit: usize,
+) -> Result<Vec<AddressTx>> {
+    let first = address_key(addr, 0, 0);
+    let last = address_key(addr, u64::MAX, u32::MAX);
+    let at = cursor.map(|(height, position)| address_key(addr, height, position));
+    let (lower, upper) = match (&at, descending) {
+        (Some(at), false) => (Bound::Excluded(at.as_slice()), Bound::Included(last.as_slice())),
+        (Some(at), true) => (Bound::Included(first.as_slice()), Bound::Excluded(at.as_slice())),
+        (None, _) => (Bound::Included(first.as_slice()), Bound::Included(last.as_slice())),
+    };
+    let range = table.range::<&[u8]>((lower, upper))?;
+    let entries: Box<dyn Iterator<Item = _>> = if descending { Box::new(range.rev()) } else { Box::new(range) };
+
+    let mut out = Vec::new();
+    for entry in entries.take(limit) {
+        let (key, value) = entry?;
+        let (key, value) = (key.value(), value.value());
+        if key.len() != 44 || value.len() != 33 {
+            anyhow::bail!("malformed address index entry");
+        }
+        out.push(AddressTx {
+            tx_hash: value[..32].try_into()?,
+            height: u64::from_be_bytes(key[32..40].try_into()?),
+            position: u32::from_be_bytes(key[40..].try_into()?),
+            sent: value[32] & ROLE_SENDER != 0,
+            received: value[32] & ROLE_RECIPIENT != 0,
+        });
+    }
+    Ok(out)
+}
+
+impl HistoryEntry {
+    /// Whether the transaction moved `asset`: its payload's token, or for payloads
+    /// without one (claims, refunds) a balance change of the address in the receipt.
+    pub(crate) fn moves(&self, addr: &H256, asset: AssetId) -> bool {
+        match self.tx.payload.token_type() {
+            Some(token) => token.asset_id() == asset,
+            None => self.receipt.as_ref().is_some_and(|r| {
+                r.balance_changes.iter().any(|c| &c.address == addr && c.token.asset_id() == asset)
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{storage::{Storage, StorageConfig}, tx::{Tx, TxPayload}, Chain, State, TokenType};
+    use dxid_crypto::{StarkSignEngine, ENGINE as STARK};
+    use std::{fs, sync::Arc};
+
+    #[test]
+    fn test_address_history_pages_reverts_and_rebuilds() {
+        let base = std::env::temp_dir().join(format!("dxid-txindex-{}-{}", std::process::id(), crate::now_ts()));
+        let (sk, alice) = STARK.generate_keys().unwrap();
+        let bob = [9u8; 32];
+        let chain = Arc::new(Chain::new(State::new_with_genesis(vec![(alice, 1_000_000)]), base.clone(), 2000).unwrap());
+        let mut hashes = Vec::new();
+        for nonce in 0..4 {
+            let to = if nonce % 2 == 0 { bob } else { [nonce as u8; 32] };
+            let send = TxPayload::Transfer { to, amount: 100, token_type: TokenType::Native };
+            let tx = Tx::new_signed(&sk, alice, nonce, 10, crate::CHAIN_ID, None, send).unwrap();
+            hashes.push(tx.hash());
+            fs::write(chain.mempool_dir.join(format!("{}.json", hex::encode(tx.hash()))), serde_json::to_string(&tx).unwrap()).unwrap();
+            chain.make_block_once().unwrap().unwrap();
+        }
+
+        let all = chain.address_txs(&alice, None, false, 10).unwrap();
+        assert_eq!(all.iter().map(|t| t.tx_hash).collect::<Vec<_>>(), hashes);
+        assert!(all.iter().all(|t| t.sent && !t.received));
+        let received = chain.address_txs(&bob, None, true, 10).unwrap();
+        assert_eq!(received.iter().map(|t| t.tx_hash).collect::<Vec<_>>(), vec![hashes[2], hashes[0]]);
+        assert!(received[0].received && !received[0].sent);
+
+        // Pages continue from the last entry in either direction
+        let page = chain.address_txs(&alice, None, true, 2).unwrap();
+        let last = page.last().unwrap();
+        let next = chain.address_txs(&alice, Some((last.height, last.position)), true, 2).unwrap();
+        assert_eq!(next.iter().map(|t| t.tx_hash).collect::<Vec<_>>(), vec![hashes[1], hashes[0]]);
+        let first = &all[0];
+        assert_eq!(chain.address_txs(&alice, Some((first.height, first.position)), false, 10).unwrap().len(), 3);
+
+        // History pages join blocks and receipts, and can filter by token
+        let page = chain.address_history(&bob, None, false, 1, None).unwrap();
+        let entry = &page.entries[0];
+        assert_eq!((entry.height, entry.counterparty, entry.received), (1, Some(alice), true));
+        assert_eq!(entry.receipt.as_ref().map(|r| r.status), Some(crate::receipt::TxStatus::Success));
+        let rest = chain.address_history(&bob, page.next, false, 10, None).unwrap();
+        assert_eq!((rest.entries.len(), rest.next, rest.scan_limit_reached), (1, None, false));
+        let native = crate::assets::NATIVE_ASSET;
+        assert_eq!(chain.address_history(&alice, None, true, 10, Some(native)).unwrap().entries.len(), 4);
+        assert!(chain.address_history(&alice, None, true, 10, Some(crate::assets::LAYER0_ASSET)).unwrap().entries.is_empty());
+
+        // Reverted blocks leave the index, and a rebuild from blocks gives the same result
+        chain.revert_to(2).unwrap();
+        assert_eq!(chain.address_txs(&alice, None, false, 10).unwrap(), all[..2].to_vec());
+        assert!(chain.find_tx(&hashes[3]).unwrap().is_none());
+        drop(chain);
+        let storage = Storage::new(StorageConfig { base_dir: base.clone(), ..Default::default() }).unwrap();
+        assert_eq!(storage.rebuild_tx_index().unwrap(), 2);
+        assert_eq!(storage.address_transactions(&alice, None, false, 10).unwrap(), all[..2].to_vec());
+        assert_eq!(storage.find_transaction(&hex::encode(hashes[1])).unwrap().unwrap().block_height, 2);
+        let _ = fs::remove_dir_all(&base);
+    }
+}
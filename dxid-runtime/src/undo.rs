@@ -0,0 +1,331 @@
+//! Undo journals.
+//!
+//! While a block is being built, `State` records the value every account,
+//! registry record, module, module storage slot, validator record, vesting
+//! schedule, escrow entry, DID document and credential revocation had before
+//! the block first touched it, together with the SMT
+//! leaf it was committed under and the block-level scalars (height, last block
+//! hash, state root, fee market, active validator set). Applying the journal restores the pre-block
+//! state exactly; `Chain::revert_to` walks journals back from the tip.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::{asset_key, AssetId, AssetInfo},
+    dehex32,
+    did::{DidDocument, DidId},
+    htlc::{Htlc, HtlcId},
+    staking::ValidatorRecord,
+    vesting::{VestingId, VestingSchedule},
+    wasm::{ModuleId, ModuleInfo},
+    Account, State, H256,
+};
+
+/// Pre-block value of one account.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AccountUndo {
+    /// None if the block created the account
+    pub account: Option<Account>,
+    /// SMT leaf before the block
+    pub leaf: Option<H256>,
+}
+
+/// Everything needed to undo one block.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UndoJournal {
+    /// Height of the block this journal undoes
+    pub height: u64,
+    pub prev_height: u64,
+    pub prev_last_block_hash: H256,
+    pub prev_state_root: H256,
+    pub prev_min_fee: u128,
+    pub prev_recent_block_txs: Vec<u32>,
+    /// Touched accounts, keyed by hex(addr)
+    pub accounts: BTreeMap<String, AccountUndo>,
+    /// Touched registry records (supply counters live here); None if the block registered it
+    pub assets: BTreeMap<AssetId, Option<AssetInfo>>,
+    /// Touched modules, keyed by hex(id); None if the block deployed it
+    #[serde(default)]
+    pub modules: BTreeMap<String, Option<ModuleInfo>>,
+    /// Touched module storage: hex(id) -> hex(key) -> previous hex(value)
+    #[serde(default)]
+    pub module_slots: BTreeMap<String, BTreeMap<String, Option<String>>>,
+    /// Touched validator records, keyed by hex(addr); None if the block created it
+    #[serde(default)]
+    pub validator_records: BTreeMap<String, Option<ValidatorRecord>>,
+    /// Touched vesting schedules, keyed by hex(id); None if the block created it
+    #[serde(default)]
+    pub vesting: BTreeMap<String, Option<VestingSchedule>>,
+    /// Touched escrow entries, keyed by hex(id); None if the block created it
+    #[serde(default)]
+    pub htlcs: BTreeMap<String, Option<Htlc>>,
+    /// Touched DID documents, keyed by hex(id); None if the block created it
+    #[serde(default)]
+    pub dids: BTreeMap<String, Option<DidDocument>>,
+    /// Touched revocations: hex(issuer) -> hex(credential) -> prior revocation height
+    #[serde(default)]
+    pub revocations: BTreeMap<String, BTreeMap<String, Option<u64>>>,
+    /// Active set before the block (None in journals written before staking)
+    #[serde(default)]
+    pub prev_active_set: Option<ActiveSetUndo>,
+}
+
+/// Active validator set before a block, which may rotate it at an epoch boundary.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ActiveSetUndo {
+    pub validators: Vec<H256>,
+    pub previous_validators: Vec<H256>,
+    pub validators_since: u64,
+}
+
+impl State {
+    /// Start recording an undo journal for the block about to be built.
+    pub fn begin_journal(&mut self) {
+        self.journal = Some(UndoJournal {
+            height: self.height + 1,
+            prev_height: self.height,
+            prev_last_block_hash: self.last_block_hash,
+            prev_state_root: self.state_root,
+            prev_min_fee: self.min_fee,
+            prev_recent_block_txs: self.recent_block_txs.clone(),
+            accounts: BTreeMap::new(),
+            assets: BTreeMap::new(),
+            modules: BTreeMap::new(),
+            module_slots: BTreeMap::new(),
+            validator_records: BTreeMap::new(),
+            vesting: BTreeMap::new(),
+            htlcs: BTreeMap::new(),
+            dids: BTreeMap::new(),
+            revocations: BTreeMap::new(),
+            prev_active_set: Some(ActiveSetUndo {
+                validators: self.validators.clone(),
+                previous_validators: self.previous_validators.clone(),
+                validators_since: self.validators_since,
+            }),
+        });
+    }
+
+    /// Stop recording and hand out the journal.
+    pub fn take_journal(&mut self) -> Option<UndoJournal> {
+        self.journal.take()
+    }
+
+    pub(crate) fn journal_account(&mut self, addr: &H256) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        if let Entry::Vacant(slot) = journal.accounts.entry(hex::encode(addr)) {
+            let undo = AccountUndo { account: self.accounts.get(slot.key()).cloned(), leaf: self.smt.get(addr) };
+            slot.insert(undo);
+        }
+    }
+
+    pub(crate) fn journal_asset(&mut self, id: AssetId) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        journal.assets.entry(id).or_insert_with(|| self.assets.get(&id).cloned());
+    }
+
+    pub(crate) fn journal_module(&mut self, id: &ModuleId) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        let id_hex = hex::encode(id);
+        let before = self.modules.get(&id_hex).cloned();
+        journal.modules.entry(id_hex).or_insert(before);
+    }
+
+    pub(crate) fn journal_module_slot(&mut self, id_hex: &str, key_hex: &str) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        let slots = journal.module_slots.entry(id_hex.to_string()).or_default();
+        if let Entry::Vacant(slot) = slots.entry(key_hex.to_string()) {
+            slot.insert(self.module_storage.get(id_hex).and_then(|s| s.get(key_hex)).cloned());
+        }
+    }
+
+    pub(crate) fn journal_validator(&mut self, addr: &H256) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        let key = hex::encode(addr);
+        let before = self.validator_registry.get(&key).cloned();
+        journal.validator_records.entry(key).or_insert(before);
+    }
+
+    pub(crate) fn journal_vesting(&mut self, id: &VestingId) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        let key = hex::encode(id);
+        let before = self.vesting.get(&key).cloned();
+        journal.vesting.entry(key).or_insert(before);
+    }
+
+    pub(crate) fn journal_htlc(&mut self, id: &HtlcId) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        let key = hex::encode(id);
+        let before = self.htlcs.get(&key).cloned();
+        journal.htlcs.entry(key).or_insert(before);
+    }
+
+    pub(crate) fn journal_did(&mut self, id: &DidId) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        let key = hex::encode(id);
+        let before = self.dids.get(&key).cloned();
+        journal.dids.entry(key).or_insert(before);
+    }
+
+    pub(crate) fn journal_revocation(&mut self, issuer_hex: &str, credential_hex: &str) {
+        let Some(journal) = self.journal.as_mut() else { return };
+        let revoked = journal.revocations.entry(issuer_hex.to_string()).or_default();
+        if let Entry::Vacant(slot) = revoked.entry(credential_hex.to_string()) {
+            slot.insert(self.revocations.get(issuer_hex).and_then(|r| r.get(credential_hex)).copied());
+        }
+    }
+
+    /// Roll back one block. The restored root must match the one recorded before the block.
+    pub fn apply_undo(&mut self, journal: &UndoJournal) -> Result<()> {
+        if journal.height != self.height {
+            bail!("undo journal is for block {}, state is at {}", journal.height, self.height);
+        }
+        for (key, undo) in &journal.accounts {
+            let Some(addr) = dehex32(key) else { bail!("bad address {} in undo journal", key) };
+            match &undo.account {
+                Some(acct) => self.accounts.insert(key.clone(), acct.clone()),
+                None => self.accounts.remove(key),
+            };
+            self.smt.update(addr, undo.leaf);
+        }
+        for (id, asset) in &journal.assets {
+            match asset {
+                Some(info) => self.set_asset(*id, info.clone()),
+                None => {
+                    self.assets.remove(id);
+                    self.dirty_assets.remove(id);
+                    self.smt.update(asset_key(*id), None);
+                }
+            }
+        }
+        for (id_hex, slots) in &journal.module_slots {
+            let Some(id) = dehex32(id_hex) else { bail!("bad module id {} in undo journal", id_hex) };
+            let storage = self.module_storage.entry(id_hex.clone()).or_default();
+            for (key_hex, value) in slots {
+                match value {
+                    Some(v) => storage.insert(key_hex.clone(), v.clone()),
+                    None => storage.remove(key_hex),
+                };
+            }
+            if storage.is_empty() {
+                self.module_storage.remove(id_hex);
+            }
+            self.dirty_modules.entry(id).or_default().extend(slots.keys().cloned());
+        }
+        for (id_hex, info) in &journal.modules {
+            let Some(id) = dehex32(id_hex) else { bail!("bad module id {} in undo journal", id_hex) };
+            match info {
+                Some(info) => self.modules.insert(id_hex.clone(), info.clone()),
+                None => self.modules.remove(id_hex),
+            };
+            self.dirty_modules.entry(id).or_default();
+        }
+        for (key, record) in &journal.validator_records {
+            let Some(addr) = dehex32(key) else { bail!("bad validator {} in undo journal", key) };
+            match record {
+                Some(record) => self.validator_registry.insert(key.clone(), record.clone()),
+                None => self.validator_registry.remove(key),
+            };
+            self.dirty_validators.insert(addr);
+        }
+        for (key, schedule) in &journal.vesting {
+            let Some(id) = dehex32(key) else { bail!("bad vesting id {} in undo journal", key) };
+            match schedule {
+                Some(schedule) => self.vesting.insert(key.clone(), schedule.clone()),
+                None => self.vesting.remove(key),
+            };
+            self.dirty_vesting.insert(id);
+        }
+        for (key, htlc) in &journal.htlcs {
+            let Some(id) = dehex32(key) else { bail!("bad escrow id {} in undo journal", key) };
+            match htlc {
+                Some(htlc) => self.htlcs.insert(key.clone(), htlc.clone()),
+                None => self.htlcs.remove(key),
+            };
+            self.dirty_htlcs.insert(id);
+        }
+        for (key, doc) in &journal.dids {
+            let Some(id) = dehex32(key) else { bail!("bad DID {} in undo journal", key) };
+            match doc {
+                Some(doc) => self.dids.insert(key.clone(), doc.clone()),
+                None => self.dids.remove(key),
+            };
+            self.dirty_dids.insert(id);
+        }
+        for (issuer_hex, credentials) in &journal.revocations {
+            let Some(issuer) = dehex32(issuer_hex) else { bail!("bad issuer {} in undo journal", issuer_hex) };
+            let revoked = self.revocations.entry(issuer_hex.clone()).or_default();
+            let dirty = self.dirty_revocations.entry(issuer).or_default();
+            for (credential_hex, height) in credentials {
+                let Some(credential) = dehex32(credential_hex) else { bail!("bad credential {} in undo journal", credential_hex) };
+                match height {
+                    Some(h) => revoked.insert(credential_hex.clone(), *h),
+                    None => revoked.remove(credential_hex),
+                };
+                dirty.insert(credential);
+            }
+            if revoked.is_empty() {
+                self.revocations.remove(issuer_hex);
+            }
+        }
+        if let Some(set) = &journal.prev_active_set {
+            self.validators = set.validators.clone();
+            self.previous_validators = set.previous_validators.clone();
+            self.validators_since = set.validators_since;
+        }
+        self.height = journal.prev_height;
+        self.last_block_hash = journal.prev_last_block_hash;
+        self.min_fee = journal.prev_min_fee;
+        self.recent_block_txs = journal.prev_recent_block_txs.clone();
+
+        let root = self.commit_root();
+        if root != journal.prev_state_root {
+            bail!(
+                "state root after undoing block {} is {}, expected {}",
+                journal.height,
+                hex::encode(root),
+                hex::encode(journal.prev_state_root)
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assets::NATIVE_ASSET, TokenType};
+
+    #[test]
+    fn test_undo_restores_accounts_assets_and_root() {
+        let mut st = State::empty();
+        let mut acct = Account::default();
+        acct.credit(NATIVE_ASSET, st.issue(TokenType::Native, 100));
+        st.set_account([1u8; 32], &acct);
+        st.commit_root();
+        let before = st.clone();
+        let root = st.state_root;
+
+        st.begin_journal();
+        st.height += 1;
+        acct.debit(NATIVE_ASSET, 40);
+        st.set_account([1u8; 32], &acct);
+        let mut fresh = Account::default();
+        fresh.credit(NATIVE_ASSET, 40);
+        st.set_account([2u8; 32], &fresh);
+        st.burn(TokenType::Native, 1);
+        st.register_asset(AssetInfo { symbol: "NEW".into(), decimals: 0, supply_cap: None, issuer: None, supply: 0 });
+        st.commit_root();
+        let journal = st.take_journal().unwrap();
+        assert_ne!(st.state_root, root);
+
+        st.apply_undo(&journal).unwrap();
+        assert_eq!(st.state_root, root);
+        assert_eq!(st.accounts, before.accounts);
+        assert_eq!(st.assets, before.assets);
+        assert_eq!((st.height, st.last_block_hash, st.min_fee), (before.height, before.last_block_hash, before.min_fee));
+        assert!(st.apply_undo(&journal).is_err());
+    }
+}
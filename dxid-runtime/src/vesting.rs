@@ -0,0 +1,290 @@
+//! Vesting schedules.
+//!
+//! A `CreateVesting` transaction moves tokens from the funder into a schedule for a
+//! beneficiary. Nothing is released before the cliff; from then on the vested amount
+//! grows linearly from the creation block to `end_height`, where everything has
+//! vested. The beneficiary pulls what has vested so far with `ClaimVesting`; a
+//! schedule is dropped once fully claimed.
+//!
+//! Locked tokens are still part of the asset's supply but belong to no account
+//! until claimed. Schedules are committed to the state SMT under `vesting_key(id)`,
+//! so a locked balance is provable like an account.
+
+use dxid_smt::{SmtProof, SparseMerkleTree};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::AssetId,
+    receipt::{BalanceChange, Receipt, TxError},
+    tx::Tx,
+    Account, Chain, State, TokenType, H256,
+};
+
+pub type VestingId = H256;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct VestingSchedule {
+    /// Account that locked the tokens
+    pub funder: H256,
+    pub beneficiary: H256,
+    pub asset: AssetId,
+    pub total: u128,
+    /// Released to the beneficiary so far
+    pub claimed: u128,
+    /// Block that created the schedule; vesting is linear from here
+    pub start_height: u64,
+    /// Nothing vests before this block
+    pub cliff_height: u64,
+    /// Everything has vested at this block
+    pub end_height: u64,
+}
+
+impl VestingSchedule {
+    fn leaf_hash(&self) -> H256 {
+        *blake3::hash(&serde_json::to_vec(self).unwrap_or_default()).as_bytes()
+    }
+
+    /// Amount vested by the block at `height`, claimed or not
+    pub fn vested_at(&self, height: u64) -> u128 {
+        if height < self.cliff_height {
+            return 0;
+        }
+        if height >= self.end_height {
+            return self.total;
+        }
+        let (elapsed, span) = ((height - self.start_height) as u128, (self.end_height - self.start_height) as u128);
+        // total * elapsed / span without overflowing
+        self.total / span * elapsed + self.total % span * elapsed / span
+    }
+
+    /// Not yet vested at `height`
+    pub fn locked_at(&self, height: u64) -> u128 {
+        self.total - self.vested_at(height)
+    }
+
+    /// Vested at `height` but not claimed yet
+    pub fn claimable_at(&self, height: u64) -> u128 {
+        self.vested_at(height).saturating_sub(self.claimed)
+    }
+}
+
+/// Id of the schedule created by `funder`'s transaction with `nonce`
+pub fn vesting_id(funder: &H256, nonce: u64) -> VestingId {
+    let mut buf = b"dxid-vesting-id".to_vec();
+    buf.extend_from_slice(funder);
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// SMT key of a schedule (domain-separated from accounts and other records)
+pub fn vesting_key(id: &VestingId) -> H256 {
+    let mut buf = b"dxid-vesting".to_vec();
+    buf.extend_from_slice(id);
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// Check a schedule (or its absence) against a state root.
+pub fn verify_vesting(root: &H256, id: &VestingId, schedule: Option<&VestingSchedule>, proof: &SmtProof) -> bool {
+    let leaf = schedule.map(VestingSchedule::leaf_hash);
+    SparseMerkleTree::verify(root, &vesting_key(id), leaf.as_ref(), proof)
+}
+
+impl State {
+    pub fn vesting_schedule(&self, id: &VestingId) -> Option<&VestingSchedule> {
+        self.vesting.get(&hex::encode(id))
+    }
+
+    /// Schedules paying out to `beneficiary`
+    pub fn vesting_of(&self, beneficiary: &H256) -> Vec<(VestingId, &VestingSchedule)> {
+        self.vesting
+            .iter()
+            .filter(|(_, s)| s.beneficiary == *beneficiary)
+            .filter_map(|(id, s)| Some((crate::dehex32(id)?, s)))
+            .collect()
+    }
+
+    /// Write (or with None, drop) a schedule. Its leaf is updated by the next `commit_root`.
+    pub fn set_vesting_schedule(&mut self, id: VestingId, schedule: Option<VestingSchedule>) {
+        self.journal_vesting(&id);
+        match schedule {
+            Some(schedule) => self.vesting.insert(hex::encode(id), schedule),
+            None => self.vesting.remove(&hex::encode(id)),
+        };
+        self.dirty_vesting.insert(id);
+    }
+
+    pub(crate) fn flush_vesting(&mut self) {
+        if self.dirty_vesting.is_empty() {
+            return;
+        }
+        let updates: Vec<(H256, Option<H256>)> = std::mem::take(&mut self.dirty_vesting)
+            .into_iter()
+            .map(|id| (vesting_key(&id), self.vesting_schedule(&id).map(VestingSchedule::leaf_hash)))
+            .collect();
+        self.smt.update_many(updates);
+    }
+
+    /// Schedule with its SMT proof against the state root.
+    pub fn prove_vesting(&self, id: &VestingId) -> (Option<VestingSchedule>, SmtProof) {
+        let (_, proof) = self.smt.prove(&vesting_key(id));
+        (self.vesting_schedule(id).cloned(), proof)
+    }
+}
+
+impl Chain {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn apply_create_vesting(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        beneficiary: H256,
+        token: TokenType,
+        amount: u128,
+        cliff_height: u64,
+        end_height: u64,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let token = TokenType::from_asset_id(token.asset_id());
+        let asset = token.asset_id();
+        if st.asset(asset).is_none() {
+            return Err(TxError::UnknownAsset(asset));
+        }
+        if amount == 0 {
+            return Err(TxError::InvalidPayload("vesting amount must be non-zero".into()));
+        }
+        let start_height = st.height + 1;
+        if end_height <= start_height || !(start_height..=end_height).contains(&cliff_height) {
+            return Err(TxError::InvalidPayload(format!(
+                "schedule must satisfy {} <= cliff <= end and {} < end",
+                start_height, start_height
+            )));
+        }
+
+        let before = from_acct.balance(asset);
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, token != TokenType::Native, receipt)?;
+        if !from_acct.debit(asset, amount) {
+            return Err(TxError::InsufficientBalance { token });
+        }
+        let schedule = VestingSchedule {
+            funder: tx.from,
+            beneficiary,
+            asset,
+            total: amount,
+            claimed: 0,
+            start_height,
+            cliff_height,
+            end_height,
+        };
+        let id = vesting_id(&tx.from, tx.nonce);
+        st.set_vesting_schedule(id, Some(schedule));
+        receipt.created_vesting = Some(id);
+        receipt.balance_changes.push(BalanceChange { address: tx.from, token, before, after: from_acct.balance(asset) });
+        Ok(())
+    }
+
+    pub(crate) fn apply_claim_vesting(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        id: VestingId,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        let Some(mut schedule) = st.vesting_schedule(&id).cloned() else { return Err(TxError::UnknownVesting(id)) };
+        if schedule.beneficiary != tx.from {
+            return Err(TxError::NotBeneficiary);
+        }
+        let height = st.height + 1;
+        let amount = schedule.claimable_at(height);
+        if amount == 0 {
+            return Err(TxError::NothingVested { cliff_height: schedule.cliff_height });
+        }
+        let token = TokenType::from_asset_id(schedule.asset);
+        let before = from_acct.balance(schedule.asset);
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, token != TokenType::Native, receipt)?;
+
+        from_acct.credit(schedule.asset, amount);
+        receipt.balance_changes.push(BalanceChange { address: tx.from, token, before, after: from_acct.balance(schedule.asset) });
+        schedule.claimed += amount;
+        let done = schedule.claimed == schedule.total;
+        st.set_vesting_schedule(id, (!done).then_some(schedule));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assets::NATIVE_ASSET, tx::TxPayload};
+    use dxid_crypto::{SecretKey, StarkSignEngine, ENGINE as STARK};
+
+    fn send(st: &mut State, sk: &SecretKey, pk: H256, payload: TxPayload) -> std::result::Result<Receipt, TxError> {
+        let nonce = st.accounts.get(&hex::encode(pk)).map_or(0, |a| a.nonce);
+        let tx = Tx::new_signed(sk, pk, nonce, 10, crate::CHAIN_ID, None, payload).unwrap();
+        let receipt = Chain::apply_tx(st, &tx)?;
+        // Settle the fee right away so the supply audit holds between txs
+        st.distribute_fees(TokenType::Native, receipt.fee.map_or(0, |f| f.amount), None);
+        Ok(receipt)
+    }
+
+    fn assert_supply_consistent(st: &State) {
+        assert!(st.audit_supply().iter().all(|a| a.consistent), "{:?}", st.audit_supply());
+    }
+
+    fn native(st: &State, addr: &H256) -> u128 {
+        st.accounts.get(&hex::encode(addr)).map_or(0, |a| a.balance(NATIVE_ASSET))
+    }
+
+    #[test]
+    fn test_linear_release_after_cliff() {
+        let s = VestingSchedule {
+            funder: [0u8; 32],
+            beneficiary: [1u8; 32],
+            asset: NATIVE_ASSET,
+            total: 1_000,
+            claimed: 0,
+            start_height: 10,
+            cliff_height: 30,
+            end_height: 110,
+        };
+        assert_eq!((s.vested_at(29), s.vested_at(30), s.vested_at(60), s.vested_at(110), s.vested_at(500)), (0, 200, 500, 1_000, 1_000));
+        assert_eq!(s.locked_at(60), 500);
+        let huge = VestingSchedule { total: u128::MAX, ..s };
+        assert_eq!(huge.vested_at(60), u128::MAX / 2);
+    }
+
+    #[test]
+    fn test_claims_release_only_the_vested_amount() {
+        let (funder_sk, funder) = STARK.generate_keys().unwrap();
+        let (ben_sk, ben) = STARK.generate_keys().unwrap();
+        let mut st = State::new_with_genesis(vec![(funder, 1_000_000), (ben, 100)]).lock().clone();
+        let create = TxPayload::CreateVesting { beneficiary: ben, token_type: TokenType::Native, amount: 10_000, cliff_height: 5, end_height: 11 };
+        let bad = TxPayload::CreateVesting { beneficiary: ben, token_type: TokenType::Native, amount: 10_000, cliff_height: 12, end_height: 11 };
+        assert!(matches!(send(&mut st, &funder_sk, funder, bad), Err(TxError::InvalidPayload(_))));
+        let id = send(&mut st, &funder_sk, funder, create).unwrap().created_vesting.unwrap();
+        assert_eq!(native(&st, &funder), 1_000_000 - 10_000 - 10);
+        assert_supply_consistent(&st);
+        st.commit_root();
+
+        // Before the cliff nothing can be claimed, and only the beneficiary may claim
+        st.height = 3;
+        assert_eq!(send(&mut st, &ben_sk, ben, TxPayload::ClaimVesting { schedule: id }), Err(TxError::NothingVested { cliff_height: 5 }));
+        st.height = 5;
+        assert_eq!(send(&mut st, &funder_sk, funder, TxPayload::ClaimVesting { schedule: id }), Err(TxError::NotBeneficiary));
+
+        // Block 6 is halfway from the creation block (1) to the end (11)
+        send(&mut st, &ben_sk, ben, TxPayload::ClaimVesting { schedule: id }).unwrap();
+        assert_eq!(native(&st, &ben), 100 - 10 + 5_000);
+        assert_supply_consistent(&st);
+        let root = st.commit_root();
+        let (schedule, proof) = st.prove_vesting(&id);
+        assert_eq!(schedule.as_ref().map(|s| (s.claimed, s.locked_at(6))), Some((5_000, 5_000)));
+        assert!(verify_vesting(&root, &id, schedule.as_ref(), &proof));
+
+        st.height = 20;
+        send(&mut st, &ben_sk, ben, TxPayload::ClaimVesting { schedule: id }).unwrap();
+        assert_eq!(native(&st, &ben), 100 - 20 + 10_000);
+        assert!(st.vesting_schedule(&id).is_none());
+        assert_supply_consistent(&st);
+        assert_eq!(send(&mut st, &ben_sk, ben, TxPayload::ClaimVesting { schedule: id }), Err(TxError::UnknownVesting(id)));
+    }
+}
@@ -0,0 +1,838 @@
+//! WASM modules.
+//!
+//! Partner logic runs on L0 as WebAssembly modules, executed by the wasmi
+//! interpreter. `DeployModule` stores a module under an id derived from the
+//! deployer and nonce; `CallModule` runs one of its exported `() -> ()`
+//! functions. A module is also an account: it can receive native tokens with a
+//! call, hold balances and send them on through the host API.
+//!
+//! Execution is metered. Every instruction costs fuel, loading the code and
+//! host functions charge on top, and a call stops once `gas_limit` is used up.
+//! The fee must cover the minimum fee plus `gas_fee` of the gas limit (of the
+//! code size for a deployment). As with any rejected transaction, a call that
+//! traps, reverts or runs out of gas leaves the state untouched and pays
+//! nothing: host writes are buffered and applied only once the function
+//! returns. Floating point is disabled so execution is deterministic.
+//!
+//! Each module has its own storage namespace: a sparse Merkle tree over
+//! `blake3(key)`. Its root is folded into the module's leaf in the state SMT
+//! (`module_key(id)`), so a storage slot is provable against the state root in
+//! two steps. Like account leaves, module leaves are written by
+//! `State::commit_root`.
+//!
+//! Host functions are imported from `env`; pointers and lengths are offsets
+//! into the module's exported `memory`:
+//!
+//! - `input_len() -> i32`, `input_read(ptr)`: the call input
+//! - `output_write(ptr, len)`: data returned in the receipt
+//! - `caller(ptr)`, `self_address(ptr)`: 32-byte sender and module addresses
+//! - `block_height() -> i64`: height of the block being built
+//! - `storage_read(key_ptr, key_len, out_ptr, out_cap) -> i32`: value length
+//!   (copying at most `out_cap` bytes), or -1 if the slot is empty
+//! - `storage_write(key_ptr, key_len, value_ptr, value_len)`, `storage_remove(key_ptr, key_len)`
+//! - `balance(addr_ptr, asset, out_ptr)`: a 16-byte little-endian balance
+//! - `transfer(to_ptr, asset, amount_ptr) -> i32`: send from the module's own
+//!   account; 0 on success, 1 if the balance is too low, 2 for an unknown asset
+//! - `verify_signature(signer_ptr, msg_ptr, msg_len, sig_ptr, sig_len) -> i32`:
+//!   1 if the JSON-encoded signature over `msg` is valid and from `signer`
+//! - `revert(code)`: abort the call, reported as `ModuleReverted(code)`
+
+use std::{
+    collections::BTreeMap,
+    sync::OnceLock,
+};
+
+use dxid_crypto::{StarkSignEngine, StarkSignature, ENGINE as STARK};
+use dxid_smt::{SmtProof, SparseMerkleTree};
+use serde::{Deserialize, Serialize};
+use wasmi::{
+    core::TrapCode, Caller, Config, EnforcedLimits, Engine, Error, Extern, ExternType, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder,
+};
+
+use crate::{
+    assets::{AssetId, NATIVE_ASSET},
+    receipt::{BalanceChange, Receipt, TxError},
+    tx::Tx,
+    Account, Chain, State, TokenType, H256,
+};
+
+pub type ModuleId = H256;
+
+/// Largest accepted module binary
+pub const MAX_CODE_SIZE: usize = 256 * 1024;
+/// Highest gas limit a call may request
+pub const MAX_GAS_PER_TX: u64 = 50_000_000;
+/// Gas bought by one unit of fee
+pub const GAS_PER_FEE_UNIT: u64 = 10_000;
+/// Longest storage key
+pub const MAX_KEY_LEN: usize = 64;
+/// Longest storage value
+pub const MAX_VALUE_LEN: usize = 16 * 1024;
+/// Longest call input or output
+pub const MAX_IO_LEN: usize = 64 * 1024;
+/// Linear memory available to a module (16 pages)
+const MAX_MEMORY_BYTES: usize = 16 * 64 * 1024;
+
+/// Charged per byte of code, at deployment and again to load it for a call
+const GAS_PER_CODE_BYTE: u64 = 10;
+const GAS_HOST_CALL: u64 = 500;
+/// Per byte copied between the module's memory and the host
+const GAS_PER_BYTE: u64 = 10;
+const GAS_STORAGE_READ: u64 = 5_000;
+const GAS_STORAGE_WRITE: u64 = 20_000;
+const GAS_TRANSFER: u64 = 10_000;
+const GAS_VERIFY_SIGNATURE: u64 = 100_000;
+
+/// Names a module may import from `env`
+const HOST_FUNCTIONS: &[&str] = &[
+    "input_len",
+    "input_read",
+    "output_write",
+    "caller",
+    "self_address",
+    "block_height",
+    "storage_read",
+    "storage_write",
+    "storage_remove",
+    "balance",
+    "transfer",
+    "verify_signature",
+    "revert",
+];
+
+/// A deployed module.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ModuleInfo {
+    /// Account that deployed the module
+    pub owner: H256,
+    pub code_hash: H256,
+    #[serde(with = "crate::tx::hex_bytes")]
+    pub code: Vec<u8>,
+    /// Height of the block that deployed it
+    pub deployed_at: u64,
+}
+
+/// Id of the module deployed by `deployer`'s transaction with `nonce`.
+pub fn module_id(deployer: &H256, nonce: u64) -> ModuleId {
+    let mut buf = b"dxid-module-id".to_vec();
+    buf.extend_from_slice(deployer);
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// SMT key of a module record (domain-separated from accounts and assets)
+pub fn module_key(id: &ModuleId) -> H256 {
+    let mut buf = b"dxid-module".to_vec();
+    buf.extend_from_slice(id);
+    *blake3::hash(&buf).as_bytes()
+}
+
+/// Key of a storage slot in its module's tree
+pub fn slot_key(key: &[u8]) -> H256 {
+    *blake3::hash(key).as_bytes()
+}
+
+fn slot_leaf(value: &[u8]) -> H256 {
+    *blake3::hash(value).as_bytes()
+}
+
+/// State SMT leaf of a module: its record and the root of its storage tree.
+pub fn module_leaf(info: &ModuleInfo, storage_root: &H256) -> H256 {
+    let mut h = blake3::Hasher::new();
+    h.update(b"dxid-module-leaf");
+    h.update(&info.owner);
+    h.update(&info.code_hash);
+    h.update(&info.deployed_at.to_le_bytes());
+    h.update(storage_root);
+    *h.finalize().as_bytes()
+}
+
+/// Fee units needed to pay for `gas`.
+pub fn gas_fee(gas: u64) -> u128 {
+    gas.div_ceil(GAS_PER_FEE_UNIT) as u128
+}
+
+/// Verify a storage slot against a state root: the slot against the module's
+/// storage root, then the module record and that root against the state root.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_module_slot(
+    state_root: &H256,
+    id: &ModuleId,
+    info: &ModuleInfo,
+    storage_root: &H256,
+    module_proof: &SmtProof,
+    key: &[u8],
+    value: Option<&[u8]>,
+    slot_proof: &SmtProof,
+) -> bool {
+    let slot = value.map(slot_leaf);
+    SparseMerkleTree::verify(storage_root, &slot_key(key), slot.as_ref(), slot_proof)
+        && SparseMerkleTree::verify(state_root, &module_key(id), Some(&module_leaf(info, storage_root)), module_proof)
+}
+
+impl State {
+    pub fn module(&self, id: &ModuleId) -> Option<&ModuleInfo> {
+        self.modules.get(&hex::encode(id))
+    }
+
+    /// Value stored by module `id` under `key`.
+    pub fn module_slot(&self, id: &ModuleId, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.module_storage.get(&hex::encode(id))?.get(&hex::encode(key))?;
+        hex::decode(value).ok()
+    }
+
+    pub(crate) fn insert_module(&mut self, id: ModuleId, info: ModuleInfo) {
+        self.journal_module(&id);
+        self.modules.insert(hex::encode(id), info);
+        self.dirty_modules.entry(id).or_default();
+    }
+
+    /// Write (or with None, clear) a storage slot of module `id`.
+    pub(crate) fn set_module_slot(&mut self, id: &ModuleId, key: &[u8], value: Option<&[u8]>) {
+        let (id_hex, key_hex) = (hex::encode(id), hex::encode(key));
+        self.journal_module_slot(&id_hex, &key_hex);
+        match value {
+            Some(v) => {
+                self.module_storage.entry(id_hex).or_default().insert(key_hex.clone(), hex::encode(v));
+            }
+            None => {
+                if let Some(slots) = self.module_storage.get_mut(&id_hex) {
+                    slots.remove(&key_hex);
+                    if slots.is_empty() {
+                        self.module_storage.remove(&id_hex);
+                    }
+                }
+            }
+        }
+        self.dirty_modules.entry(*id).or_default().insert(key_hex);
+    }
+
+    /// Root of module `id`'s storage tree as of the last `commit_root`.
+    pub fn module_storage_root(&self, id: &ModuleId) -> H256 {
+        match self.module_smts.get(id) {
+            Some(tree) => tree.root(),
+            None => SparseMerkleTree::new().root(),
+        }
+    }
+
+    /// Module record and storage root with their state SMT proof.
+    pub fn prove_module(&self, id: &ModuleId) -> (Option<ModuleInfo>, H256, SmtProof) {
+        let (_, proof) = self.smt.prove(&module_key(id));
+        (self.module(id).cloned(), self.module_storage_root(id), proof)
+    }
+
+    /// Storage slot with its proof against the module's storage root.
+    pub fn prove_module_slot(&self, id: &ModuleId, key: &[u8]) -> (Option<Vec<u8>>, SmtProof) {
+        let proof = match self.module_smts.get(id) {
+            Some(tree) => tree.prove(&slot_key(key)).1,
+            None => SparseMerkleTree::new().prove(&slot_key(key)).1,
+        };
+        (self.module_slot(id, key), proof)
+    }
+
+    /// Rebuild every module's storage tree; the leaves are written by the next `commit_root`.
+    pub(crate) fn rebuild_module_trees(&mut self) {
+        self.module_smts.clear();
+        self.dirty_modules = self
+            .modules
+            .keys()
+            .filter_map(|id_hex| {
+                let slots = self.module_storage.get(id_hex).map(|s| s.keys().cloned().collect()).unwrap_or_default();
+                Some((crate::dehex32(id_hex)?, slots))
+            })
+            .collect();
+    }
+
+    /// Write pending storage and module changes to the module trees and the state SMT.
+    pub(crate) fn flush_modules(&mut self) {
+        if self.dirty_modules.is_empty() {
+            return;
+        }
+        let mut leaves = Vec::with_capacity(self.dirty_modules.len());
+        for (id, slots) in std::mem::take(&mut self.dirty_modules) {
+            let id_hex = hex::encode(id);
+            let Some(info) = self.modules.get(&id_hex) else {
+                self.module_smts.remove(&id);
+                leaves.push((module_key(&id), None));
+                continue;
+            };
+            let storage = self.module_storage.get(&id_hex);
+            let tree = self.module_smts.entry(id).or_default();
+            if !slots.is_empty() {
+                tree.update_many(slots.iter().filter_map(|key_hex| {
+                    let key = hex::decode(key_hex).ok()?;
+                    let value = storage.and_then(|s| s.get(key_hex)).and_then(|v| hex::decode(v).ok());
+                    Some((slot_key(&key), value.as_deref().map(slot_leaf)))
+                }));
+            }
+            leaves.push((module_key(&id), Some(module_leaf(info, &tree.root()))));
+        }
+        self.smt.update_many(leaves);
+    }
+}
+
+/* ---- transaction handlers ---- */
+
+impl Chain {
+    pub(crate) fn apply_deploy_module(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        code: &[u8],
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        if code.len() > MAX_CODE_SIZE {
+            return Err(TxError::InvalidPayload(format!("module is {} bytes, at most {}", code.len(), MAX_CODE_SIZE)));
+        }
+        let gas = GAS_PER_CODE_BYTE * code.len() as u64;
+        check_gas_fee(st, tx, gas)?;
+        validate_module(code)?;
+        let id = module_id(&tx.from, tx.nonce);
+        if st.module(&id).is_some() {
+            return Err(TxError::InvalidPayload("module already deployed".into()));
+        }
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        let info = ModuleInfo { owner: tx.from, code_hash: *blake3::hash(code).as_bytes(), code: code.to_vec(), deployed_at: st.height + 1 };
+        st.insert_module(id, info);
+        receipt.created_module = Some(id);
+        receipt.gas_used = Some(gas);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn apply_call_module(
+        st: &mut State,
+        tx: &Tx,
+        from_acct: &mut Account,
+        module: ModuleId,
+        function: &str,
+        input: &[u8],
+        value: u128,
+        gas_limit: u64,
+        receipt: &mut Receipt,
+    ) -> std::result::Result<(), TxError> {
+        if gas_limit > MAX_GAS_PER_TX {
+            return Err(TxError::InvalidPayload(format!("gas limit {} above {}", gas_limit, MAX_GAS_PER_TX)));
+        }
+        if input.len() > MAX_IO_LEN {
+            return Err(TxError::InvalidPayload(format!("input is {} bytes, at most {}", input.len(), MAX_IO_LEN)));
+        }
+        if module == tx.from {
+            return Err(TxError::InvalidPayload("module cannot call itself".into()));
+        }
+        let code = st.module(&module).ok_or(TxError::UnknownModule(module))?.code.clone();
+        check_gas_fee(st, tx, gas_limit)?;
+        Self::charge_fee(st, tx, from_acct, TokenType::Native, true, receipt)?;
+
+        // The attached value reaches the module before its code runs
+        let mut module_acct = st.accounts.get(&hex::encode(module)).cloned().unwrap_or_default();
+        let mut changes = Vec::new();
+        if value > 0 {
+            let (from_before, module_before) = (from_acct.balance(NATIVE_ASSET), module_acct.balance(NATIVE_ASSET));
+            if !from_acct.debit(NATIVE_ASSET, value) {
+                return Err(TxError::InsufficientBalance { token: TokenType::Native });
+            }
+            module_acct.credit(NATIVE_ASSET, value);
+            changes.push(BalanceChange { address: tx.from, token: TokenType::Native, before: from_before, after: from_acct.balance(NATIVE_ASSET) });
+            changes.push(BalanceChange { address: module, token: TokenType::Native, before: module_before, after: module_acct.balance(NATIVE_ASSET) });
+        }
+
+        // The host owns the state while the module runs and buffers every write
+        let height = st.height + 1;
+        let host = Host {
+            st: std::mem::replace(st, State::empty()),
+            module,
+            caller: tx.from,
+            height,
+            input: input.to_vec(),
+            output: Vec::new(),
+            storage: BTreeMap::new(),
+            accounts: BTreeMap::from([(tx.from, from_acct.clone()), (module, module_acct)]),
+            changes,
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).memories(1).tables(1).instances(1).build(),
+        };
+        let (mut host, result) = run(host, &code, function, gas_limit);
+        *st = std::mem::replace(&mut host.st, State::empty());
+        let gas_used = result?;
+
+        if let Some(acct) = host.accounts.remove(&tx.from) {
+            *from_acct = acct;
+        }
+        for (addr, acct) in &host.accounts {
+            st.set_account(*addr, acct);
+        }
+        for (key, value) in &host.storage {
+            st.set_module_slot(&module, key, value.as_deref());
+        }
+        receipt.balance_changes.extend(host.changes);
+        receipt.gas_used = Some(gas_used);
+        receipt.output = (!host.output.is_empty()).then(|| hex::encode(&host.output));
+        Ok(())
+    }
+}
+
+fn check_gas_fee(st: &State, tx: &Tx, gas: u64) -> std::result::Result<(), TxError> {
+    let minimum = st.required_min_fee().saturating_add(gas_fee(gas));
+    if tx.fee < minimum {
+        return Err(TxError::FeeTooLow { minimum, got: tx.fee });
+    }
+    Ok(())
+}
+
+/* ---- execution ---- */
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::default();
+        config.consume_fuel(true).floats(false).enforced_limits(EnforcedLimits::strict());
+        Engine::new(&config)
+    })
+}
+
+/// Everything a running module can see, plus its buffered writes.
+struct Host {
+    st: State,
+    module: ModuleId,
+    caller: H256,
+    height: u64,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// Pending storage writes (None clears the slot)
+    storage: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Pending account writes, including the sender's and the module's
+    accounts: BTreeMap<H256, Account>,
+    changes: Vec<BalanceChange>,
+    limits: StoreLimits,
+}
+
+impl Host {
+    fn account(&self, addr: &H256) -> Account {
+        match self.accounts.get(addr) {
+            Some(acct) => acct.clone(),
+            None => self.st.accounts.get(&hex::encode(addr)).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn slot(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.storage.get(key) {
+            Some(value) => value.clone(),
+            None => self.st.module_slot(&self.module, key),
+        }
+    }
+}
+
+/// Check that `code` is a module this runtime can run: valid WASM, only host
+/// imports with the right signatures, an exported memory within limits and no
+/// start function.
+fn validate_module(code: &[u8]) -> std::result::Result<(), TxError> {
+    let invalid = |e: &dyn std::fmt::Display| TxError::InvalidPayload(format!("invalid module: {}", e));
+    let module = Module::new(engine(), code).map_err(|e| invalid(&e))?;
+    if let Some(import) = module.imports().find(|i| i.module() != "env" || !HOST_FUNCTIONS.contains(&i.name())) {
+        return Err(invalid(&format!("unknown import {}::{}", import.module(), import.name())));
+    }
+    if !module.exports().any(|e| e.name() == "memory" && matches!(e.ty(), ExternType::Memory(_))) {
+        return Err(invalid(&"module must export `memory`"));
+    }
+    let host = Host {
+        st: State::empty(),
+        module: [0u8; 32],
+        caller: [0u8; 32],
+        height: 0,
+        input: Vec::new(),
+        output: Vec::new(),
+        storage: BTreeMap::new(),
+        accounts: BTreeMap::new(),
+        changes: Vec::new(),
+        limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).memories(1).tables(1).instances(1).build(),
+    };
+    let mut store = Store::new(engine(), host);
+    store.limiter(|h| &mut h.limits);
+    let pre = linker().instantiate(&mut store, &module).map_err(|e| invalid(&e))?;
+    pre.ensure_no_start(&mut store).map_err(|e| invalid(&e))?;
+    Ok(())
+}
+
+/// Run `function` of `code` with `gas_limit`, returning the host and the gas used.
+fn run(host: Host, code: &[u8], function: &str, gas_limit: u64) -> (Host, std::result::Result<u64, TxError>) {
+    let mut store = Store::new(engine(), host);
+    store.limiter(|h| &mut h.limits);
+    let mut call = || {
+        let load = GAS_PER_CODE_BYTE * code.len() as u64;
+        let fuel = gas_limit.checked_sub(load).ok_or(TxError::OutOfGas { limit: gas_limit })?;
+        store.set_fuel(fuel).map_err(|e| TxError::ModuleTrap(e.to_string()))?;
+        let module = Module::new(engine(), code).map_err(|e| TxError::ModuleTrap(e.to_string()))?;
+        let instance = linker()
+            .instantiate(&mut store, &module)
+            .and_then(|pre| Ok(pre.ensure_no_start(&mut store)?))
+            .map_err(|e| TxError::ModuleTrap(e.to_string()))?;
+        let func = instance.get_typed_func::<(), ()>(&store, function).map_err(|_| {
+            TxError::InvalidPayload(format!("module exports no function `{}` without parameters and results", function))
+        })?;
+        func.call(&mut store, ()).map_err(|e| trap_error(e, gas_limit))?;
+        Ok(gas_limit - store.get_fuel().unwrap_or(0))
+    };
+    let result = call();
+    (store.into_data(), result)
+}
+
+fn trap_error(e: Error, gas_limit: u64) -> TxError {
+    if let Some(code) = e.i32_exit_status() {
+        TxError::ModuleReverted(code)
+    } else if e.as_trap_code() == Some(TrapCode::OutOfFuel) {
+        TxError::OutOfGas { limit: gas_limit }
+    } else {
+        TxError::ModuleTrap(e.to_string())
+    }
+}
+
+/* ---- host functions ---- */
+
+fn charge(caller: &mut Caller<'_, Host>, gas: u64) -> Result<(), Error> {
+    let fuel = caller.get_fuel()?;
+    match fuel.checked_sub(gas) {
+        Some(left) => Ok(caller.set_fuel(left)?),
+        None => {
+            caller.set_fuel(0)?;
+            Err(TrapCode::OutOfFuel.into())
+        }
+    }
+}
+
+fn memory(caller: &Caller<'_, Host>) -> Result<Memory, Error> {
+    caller.get_export("memory").and_then(Extern::into_memory).ok_or_else(|| Error::new("module exports no memory"))
+}
+
+fn read_bytes(caller: &mut Caller<'_, Host>, ptr: i32, len: i32, max: usize) -> Result<Vec<u8>, Error> {
+    let len = len as u32 as usize;
+    if len > max {
+        return Err(Error::new(format!("{} bytes exceed the limit of {}", len, max)));
+    }
+    charge(caller, GAS_PER_BYTE * len as u64)?;
+    let mut buf = vec![0u8; len];
+    memory(caller)?.read(&*caller, ptr as u32 as usize, &mut buf).map_err(|_| TrapCode::MemoryOutOfBounds)?;
+    Ok(buf)
+}
+
+fn read_array<const N: usize>(caller: &mut Caller<'_, Host>, ptr: i32) -> Result<[u8; N], Error> {
+    let bytes = read_bytes(caller, ptr, N as i32, N)?;
+    Ok(bytes.try_into().expect("read exactly N bytes"))
+}
+
+fn write_bytes(caller: &mut Caller<'_, Host>, ptr: i32, bytes: &[u8]) -> Result<(), Error> {
+    charge(caller, GAS_PER_BYTE * bytes.len() as u64)?;
+    memory(caller)?.write(&mut *caller, ptr as u32 as usize, bytes).map_err(|_| TrapCode::MemoryOutOfBounds)?;
+    Ok(())
+}
+
+fn linker() -> Linker<Host> {
+    let mut linker = Linker::new(engine());
+    define_host_functions(&mut linker).expect("host function names are unique");
+    linker
+}
+
+fn define_host_functions(linker: &mut Linker<Host>) -> Result<(), Error> {
+    linker.func_wrap("env", "input_len", |mut caller: Caller<'_, Host>| -> Result<i32, Error> {
+        charge(&mut caller, GAS_HOST_CALL)?;
+        Ok(caller.data().input.len() as i32)
+    })?;
+    linker.func_wrap("env", "input_read", |mut caller: Caller<'_, Host>, ptr: i32| -> Result<(), Error> {
+        charge(&mut caller, GAS_HOST_CALL)?;
+        let input = caller.data().input.clone();
+        write_bytes(&mut caller, ptr, &input)
+    })?;
+    linker.func_wrap("env", "output_write", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<(), Error> {
+        charge(&mut caller, GAS_HOST_CALL)?;
+        let output = read_bytes(&mut caller, ptr, len, MAX_IO_LEN)?;
+        caller.data_mut().output = output;
+        Ok(())
+    })?;
+    linker.func_wrap("env", "caller", |mut caller: Caller<'_, Host>, ptr: i32| -> Result<(), Error> {
+        charge(&mut caller, GAS_HOST_CALL)?;
+        let addr = caller.data().caller;
+        write_bytes(&mut caller, ptr, &addr)
+    })?;
+    linker.func_wrap("env", "self_address", |mut caller: Caller<'_, Host>, ptr: i32| -> Result<(), Error> {
+        charge(&mut caller, GAS_HOST_CALL)?;
+        let addr = caller.data().module;
+        write_bytes(&mut caller, ptr, &addr)
+    })?;
+    linker.func_wrap("env", "block_height", |mut caller: Caller<'_, Host>| -> Result<i64, Error> {
+        charge(&mut caller, GAS_HOST_CALL)?;
+        Ok(caller.data().height as i64)
+    })?;
+    linker.func_wrap(
+        "env",
+        "storage_read",
+        |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, out_ptr: i32, out_cap: i32| -> Result<i32, Error> {
+            charge(&mut caller, GAS_STORAGE_READ)?;
+            let key = read_bytes(&mut caller, key_ptr, key_len, MAX_KEY_LEN)?;
+            let Some(value) = caller.data().slot(&key) else { return Ok(-1) };
+            let n = value.len().min(out_cap.max(0) as usize);
+            write_bytes(&mut caller, out_ptr, &value[..n])?;
+            Ok(value.len() as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "storage_write",
+        |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32| -> Result<(), Error> {
+            charge(&mut caller, GAS_STORAGE_WRITE)?;
+            let key = read_bytes(&mut caller, key_ptr, key_len, MAX_KEY_LEN)?;
+            let value = read_bytes(&mut caller, value_ptr, value_len, MAX_VALUE_LEN)?;
+            caller.data_mut().storage.insert(key, Some(value));
+            Ok(())
+        },
+    )?;
+    linker.func_wrap("env", "storage_remove", |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32| -> Result<(), Error> {
+        charge(&mut caller, GAS_STORAGE_WRITE)?;
+        let key = read_bytes(&mut caller, key_ptr, key_len, MAX_KEY_LEN)?;
+        caller.data_mut().storage.insert(key, None);
+        Ok(())
+    })?;
+    linker.func_wrap(
+        "env",
+        "balance",
+        |mut caller: Caller<'_, Host>, addr_ptr: i32, asset: i32, out_ptr: i32| -> Result<(), Error> {
+            charge(&mut caller, GAS_STORAGE_READ)?;
+            let addr = read_array::<32>(&mut caller, addr_ptr)?;
+            let balance = caller.data().account(&addr).balance(asset as AssetId);
+            write_bytes(&mut caller, out_ptr, &balance.to_le_bytes())
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "transfer",
+        |mut caller: Caller<'_, Host>, to_ptr: i32, asset: i32, amount_ptr: i32| -> Result<i32, Error> {
+            charge(&mut caller, GAS_TRANSFER)?;
+            let to = read_array::<32>(&mut caller, to_ptr)?;
+            let amount = u128::from_le_bytes(read_array::<16>(&mut caller, amount_ptr)?);
+            let asset = asset as AssetId;
+            let host = caller.data_mut();
+            if host.st.asset(asset).is_none() {
+                return Ok(2);
+            }
+            let (from, token) = (host.module, TokenType::from_asset_id(asset));
+            let mut from_acct = host.account(&from);
+            let from_before = from_acct.balance(asset);
+            if !from_acct.debit(asset, amount) {
+                return Ok(1);
+            }
+            if to == from {
+                return Ok(0);
+            }
+            let mut to_acct = host.account(&to);
+            let to_before = to_acct.balance(asset);
+            to_acct.credit(asset, amount);
+            host.changes.push(BalanceChange { address: from, token, before: from_before, after: from_acct.balance(asset) });
+            host.changes.push(BalanceChange { address: to, token, before: to_before, after: to_acct.balance(asset) });
+            host.accounts.insert(from, from_acct);
+            host.accounts.insert(to, to_acct);
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "verify_signature",
+        |mut caller: Caller<'_, Host>, signer_ptr: i32, msg_ptr: i32, msg_len: i32, sig_ptr: i32, sig_len: i32| -> Result<i32, Error> {
+            charge(&mut caller, GAS_VERIFY_SIGNATURE)?;
+            let signer = read_array::<32>(&mut caller, signer_ptr)?;
+            let msg = read_bytes(&mut caller, msg_ptr, msg_len, MAX_IO_LEN)?;
+            let sig = read_bytes(&mut caller, sig_ptr, sig_len, MAX_IO_LEN)?;
+            let valid = serde_json::from_slice::<StarkSignature>(&sig)
+                .is_ok_and(|sig| sig.pubkey_hash == signer && STARK.verify(&sig, &msg).is_ok());
+            Ok(valid as i32)
+        },
+    )?;
+    linker.func_wrap("env", "revert", |_: Caller<'_, Host>, code: i32| -> Result<(), Error> { Err(Error::i32_exit(code)) })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{snapshot, tx::TxPayload};
+    use dxid_crypto::SecretKey;
+
+    const MODULE: &str = r#"
+        (module
+          (import "env" "input_len" (func $input_len (result i32)))
+          (import "env" "input_read" (func $input_read (param i32)))
+          (import "env" "output_write" (func $output_write (param i32 i32)))
+          (import "env" "storage_read" (func $storage_read (param i32 i32 i32 i32) (result i32)))
+          (import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+          (import "env" "self_address" (func $self_address (param i32)))
+          (import "env" "balance" (func $balance (param i32 i32 i32)))
+          (import "env" "transfer" (func $transfer (param i32 i32 i32) (result i32)))
+          (import "env" "verify_signature" (func $verify (param i32 i32 i32 i32 i32) (result i32)))
+          (import "env" "revert" (func $revert (param i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "count")
+          (data (i32.const 16) "hello")
+          ;; bump the counter stored under "count" and return it
+          (func (export "inc")
+            (drop (call $storage_read (i32.const 0) (i32.const 5) (i32.const 64) (i32.const 8)))
+            (i64.store (i32.const 64) (i64.add (i64.load (i32.const 64)) (i64.const 1)))
+            (call $storage_write (i32.const 0) (i32.const 5) (i32.const 64) (i32.const 8))
+            (call $output_write (i32.const 64) (i32.const 8)))
+          ;; input: recipient (32 bytes) ++ native amount (16 bytes LE)
+          (func (export "pay")
+            (call $input_read (i32.const 128))
+            (if (call $transfer (i32.const 128) (i32.const 0) (i32.const 160))
+              (then (call $revert (i32.const 7)))))
+          (func (export "own_balance")
+            (call $self_address (i32.const 256))
+            (call $balance (i32.const 256) (i32.const 0) (i32.const 288))
+            (call $output_write (i32.const 288) (i32.const 16)))
+          ;; input: signer (32 bytes) ++ JSON signature over "hello"
+          (func (export "check") (local $len i32)
+            (local.set $len (call $input_len))
+            (call $input_read (i32.const 1024))
+            (i32.store8 (i32.const 512)
+              (call $verify (i32.const 1024) (i32.const 16) (i32.const 5) (i32.const 1056)
+                (i32.sub (local.get $len) (i32.const 32))))
+            (call $output_write (i32.const 512) (i32.const 1)))
+          (func (export "spin") (loop $l (br $l))))
+    "#;
+
+    struct Env {
+        st: State,
+        sk: SecretKey,
+        pk: H256,
+    }
+
+    impl Env {
+        fn new() -> Self {
+            let (sk, pk) = STARK.generate_keys().unwrap();
+            let st = State::new_with_genesis(vec![(pk, 1_000_000)]).lock().clone();
+            Env { st, sk, pk }
+        }
+
+        fn send(&mut self, fee: u128, payload: TxPayload) -> std::result::Result<Receipt, TxError> {
+            let nonce = self.st.accounts.get(&hex::encode(self.pk)).map_or(0, |a| a.nonce);
+            let tx = Tx::new_signed(&self.sk, self.pk, nonce, fee, crate::CHAIN_ID, None, payload).unwrap();
+            Chain::apply_tx(&mut self.st, &tx)
+        }
+
+        fn deploy(&mut self) -> ModuleId {
+            let code = wat::parse_str(MODULE).unwrap();
+            self.send(1_000, TxPayload::DeployModule { code }).unwrap().created_module.unwrap()
+        }
+
+        fn call(&mut self, module: ModuleId, function: &str, input: Vec<u8>, value: u128, gas_limit: u64) -> std::result::Result<Receipt, TxError> {
+            let fee = 10 + gas_fee(gas_limit);
+            self.send(fee, TxPayload::CallModule { module, function: function.into(), input, value, gas_limit })
+        }
+
+        fn native(&self, addr: &H256) -> u128 {
+            self.st.accounts.get(&hex::encode(addr)).map_or(0, |a| a.balance(NATIVE_ASSET))
+        }
+    }
+
+    #[test]
+    fn test_module_storage_is_provable_and_undoable() {
+        let mut env = Env::new();
+        let genesis_root = env.st.commit_root();
+        env.st.begin_journal();
+        let id = env.deploy();
+        assert_eq!(id, module_id(&env.pk, 0));
+        env.call(id, "inc", vec![], 0, 1_000_000).unwrap();
+        let receipt = env.call(id, "inc", vec![], 0, 1_000_000).unwrap();
+        assert_eq!(receipt.output, Some(hex::encode(2u64.to_le_bytes())));
+        assert!(receipt.gas_used.unwrap() > GAS_STORAGE_WRITE);
+
+        let root = env.st.commit_root();
+        assert_ne!(root, genesis_root);
+        let (info, storage_root, module_proof) = env.st.prove_module(&id);
+        let (value, slot_proof) = env.st.prove_module_slot(&id, b"count");
+        assert_eq!(value, Some(2u64.to_le_bytes().to_vec()));
+        let info = info.unwrap();
+        assert!(verify_module_slot(&root, &id, &info, &storage_root, &module_proof, b"count", value.as_deref(), &slot_proof));
+        let forged = 3u64.to_le_bytes();
+        assert!(!verify_module_slot(&root, &id, &info, &storage_root, &module_proof, b"count", Some(&forged), &slot_proof));
+
+        // Rebuilding the trees from the serialized state and a snapshot gives the same root
+        let mut reloaded: State = serde_json::from_str(&serde_json::to_string(&env.st).unwrap()).unwrap();
+        reloaded.reconstruct_smt();
+        assert_eq!(reloaded.state_root, root);
+        let dir = std::env::temp_dir().join(format!("dxid-wasm-snapshot-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        snapshot::export_snapshot(&env.st, None, &dir).unwrap();
+        let (_, imported) = snapshot::import_snapshot(&dir).unwrap();
+        assert_eq!(imported.module_slot(&id, b"count"), value);
+
+        let journal = env.st.take_journal().unwrap();
+        env.st.height += 1;
+        env.st.apply_undo(&journal).unwrap();
+        assert_eq!(env.st.state_root, genesis_root);
+        assert!(env.st.module(&id).is_none() && env.st.module_storage.is_empty());
+    }
+
+    #[test]
+    fn test_failed_calls_change_nothing() {
+        let mut env = Env::new();
+        let id = env.deploy();
+        env.call(id, "inc", vec![], 0, 1_000_000).unwrap();
+        let before = env.st.clone();
+        let root = env.st.commit_root();
+
+        assert_eq!(env.call(id, "spin", vec![], 0, 200_000), Err(TxError::OutOfGas { limit: 200_000 }));
+        // The code alone costs more than this to load
+        assert_eq!(env.call(id, "inc", vec![], 0, 100), Err(TxError::OutOfGas { limit: 100 }));
+        let mut pay = [9u8; 48].to_vec();
+        pay[32..].copy_from_slice(&1u128.to_le_bytes());
+        assert_eq!(env.call(id, "pay", pay, 0, 1_000_000), Err(TxError::ModuleReverted(7)));
+        assert!(matches!(env.call(id, "missing", vec![], 0, 1_000_000), Err(TxError::InvalidPayload(_))));
+        assert_eq!(env.call([5u8; 32], "inc", vec![], 0, 1_000_000), Err(TxError::UnknownModule([5u8; 32])));
+        let low = TxPayload::CallModule { module: id, function: "inc".into(), input: vec![], value: 0, gas_limit: 1_000_000 };
+        assert!(matches!(env.send(10, low), Err(TxError::FeeTooLow { .. })));
+
+        assert_eq!(env.st.accounts, before.accounts);
+        assert_eq!(env.st.module_storage, before.module_storage);
+        assert_eq!(env.st.commit_root(), root);
+    }
+
+    #[test]
+    fn test_host_balances_transfers_and_signatures() {
+        let mut env = Env::new();
+        let id = env.deploy();
+        let receipt = env.call(id, "own_balance", vec![], 1_000, 1_000_000).unwrap();
+        assert_eq!(receipt.output, Some(hex::encode(1_000u128.to_le_bytes())));
+
+        let to = [9u8; 32];
+        let mut pay = to.to_vec();
+        pay.extend_from_slice(&400u128.to_le_bytes());
+        let sender_before = env.native(&env.pk);
+        let receipt = env.call(id, "pay", pay, 0, 1_000_000).unwrap();
+        assert_eq!((env.native(&id), env.native(&to)), (600, 400));
+        assert_eq!(env.native(&env.pk), sender_before - receipt.fee.unwrap().amount);
+        assert!(receipt.balance_changes.iter().any(|c| c.address == to && c.after == 400));
+
+        let (other_sk, other) = STARK.generate_keys().unwrap();
+        let sig = serde_json::to_vec(&STARK.sign(&other_sk, b"hello", 0).unwrap()).unwrap();
+        let check = |signer: H256| [signer.to_vec(), sig.clone()].concat();
+        assert_eq!(env.call(id, "check", check(other), 0, 1_000_000).unwrap().output, Some("01".into()));
+        assert_eq!(env.call(id, "check", check(env.pk), 0, 1_000_000).unwrap().output, Some("00".into()));
+    }
+
+    #[test]
+    fn test_deploy_rejects_unsupported_modules() {
+        let mut env = Env::new();
+        let bad = [
+            r#"(module (import "env" "exit" (func (param i32))) (memory (export "memory") 1))"#,
+            r#"(module (import "env" "caller" (func (param i64))) (memory (export "memory") 1))"#,
+            r#"(module (memory (export "memory") 1) (func $f) (start $f))"#,
+            r#"(module (memory (export "memory") 64))"#,
+            r#"(module (func (export "f") (result f32) (f32.const 1)) (memory (export "memory") 1))"#,
+            r#"(module (func (export "f")))"#,
+        ];
+        for wat_src in bad {
+            let code = wat::parse_str(wat_src).unwrap();
+            let err = env.send(1_000, TxPayload::DeployModule { code }).unwrap_err();
+            assert!(matches!(err, TxError::InvalidPayload(_)), "{}: {:?}", wat_src, err);
+        }
+        assert!(env.st.modules.is_empty());
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use blake3::Hasher;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 /// 32-byte array helper
 pub type H256 = [u8; 32];
@@ -64,6 +64,8 @@ pub struct SparseMerkleTree {
     store: HashMap<H256, H256>,
     /// current root
     root: H256,
+    /// keys written since the last `take_changes`
+    changed: HashSet<H256>,
 }
 
 impl Default for SparseMerkleTree {
@@ -71,6 +73,7 @@ impl Default for SparseMerkleTree {
         Self {
             store: HashMap::new(),
             root: zero_hashes()[256],
+            changed: HashSet::new(),
         }
     }
 }
@@ -86,6 +89,7 @@ impl SparseMerkleTree {
 
     /// Update a leaf (insert or set). If `value` is None, delete leaf.
     pub fn update(&mut self, key: H256, value: Option<H256>) {
+        self.changed.insert(key);
         if let Some(v) = value {
             self.store.insert(key, v);
         } else {
@@ -94,13 +98,46 @@ impl SparseMerkleTree {
         self.recompute_root();
     }
 
+    /// Insert many leaves at once, recomputing the root a single time.
+    pub fn extend(&mut self, leaves: impl IntoIterator<Item = (H256, H256)>) {
+        for (key, value) in leaves {
+            self.changed.insert(key);
+            self.store.insert(key, value);
+        }
+        self.recompute_root();
+    }
+
+    /// Apply many updates (None deletes), recomputing the root a single time.
+    pub fn update_many(&mut self, updates: impl IntoIterator<Item = (H256, Option<H256>)>) {
+        for (key, value) in updates {
+            self.changed.insert(key);
+            match value {
+                Some(v) => self.store.insert(key, v),
+                None => self.store.remove(&key),
+            };
+        }
+        self.recompute_root();
+    }
+
+    /// All leaves, in no particular order.
+    pub fn leaves(&self) -> impl Iterator<Item = (&H256, &H256)> {
+        self.store.iter()
+    }
+
+    /// Leaves written since the last call (None = deleted), so a persistent store
+    /// can apply just the difference.
+    pub fn take_changes(&mut self) -> Vec<(H256, Option<H256>)> {
+        let changed = std::mem::take(&mut self.changed);
+        changed.into_iter().map(|key| (key, self.store.get(&key).copied())).collect()
+    }
+
     fn recompute_root(&mut self) {
         // Note: This naive recomputation is O(n log N) but fine for devnet.
         // For production, switch to a persistent node store.
         let zeros = zero_hashes();
-        let mut nodes: HashMap<usize, HashMap<u64, H256>> = HashMap::new();
+        let mut nodes: HashMap<usize, HashMap<H256, H256>> = HashMap::new();
         // Level 0: leaves
-        let mut leaves: HashMap<u64, H256> = HashMap::new();
+        let mut leaves: HashMap<H256, H256> = HashMap::new();
         for (k, v) in self.store.iter() {
             let idx = Self::key_index(k);
             // leaf hash = H(0x00 || key || value)
@@ -114,22 +151,22 @@ impl SparseMerkleTree {
 
         for level in 0..256 {
             let cur = nodes.get(&level).cloned().unwrap_or_default();
-            let mut next: HashMap<u64, H256> = HashMap::new();
+            let mut next: HashMap<H256, H256> = HashMap::new();
             if cur.is_empty() {
                 // all-zero subtree
                 break;
             }
             for (idx, val) in cur.iter() {
-                let sib_idx = idx ^ 1;
-                let left_is_me = idx & 1 == 0;
+                let sib_idx = sibling_index(idx);
+                let left_is_me = is_left(idx);
                 let left = if left_is_me { *val } else { cur.get(&sib_idx).cloned().unwrap_or(zeros[level]) };
                 let right = if left_is_me { cur.get(&sib_idx).cloned().unwrap_or(zeros[level]) } else { *val };
                 let parent = h2(&left, &right);
-                next.insert(idx >> 1, parent);
+                next.insert(parent_index(idx), parent);
             }
             nodes.insert(level + 1, next);
         }
-        self.root = nodes.get(&256).and_then(|m| m.get(&0).cloned()).unwrap_or(zeros[256]);
+        self.root = nodes.get(&256).and_then(|m| m.get(&[0u8; 32]).cloned()).unwrap_or(zeros[256]);
     }
 
     /// Build a Merkle proof for `key` with respect to current tree.
@@ -139,7 +176,7 @@ impl SparseMerkleTree {
         let mut idx = Self::key_index(key);
         // For proof construction we need the sibling hash at each level.
         // We recompute per-level nodes on the fly using the set of present leaves.
-        let mut level_nodes: HashMap<u64, H256> = HashMap::new();
+        let mut level_nodes: HashMap<H256, H256> = HashMap::new();
         // leaf hash
         let leaf_val = self.get(key);
         if let Some(v) = leaf_val {
@@ -150,7 +187,7 @@ impl SparseMerkleTree {
             level_nodes.insert(Self::key_index(key), h(&buf));
         }
         // Build sparse presence map for all leaves
-        let mut present: HashMap<u64, H256> = HashMap::new();
+        let mut present: HashMap<H256, H256> = HashMap::new();
         for (k, v) in self.store.iter() {
             let kidx = Self::key_index(k);
             let mut buf = [0u8; 1 + 32 + 32];
@@ -161,26 +198,26 @@ impl SparseMerkleTree {
         }
 
         for level in 0..256 {
-            let sib_idx = idx ^ 1;
+            let sib_idx = sibling_index(&idx);
             // sibling at this level
             let sib = present.get(&sib_idx).cloned().unwrap_or(zeros[level]);
             siblings.push(sib);
 
             // propagate present map up a level
-            let mut next: HashMap<u64, H256> = HashMap::new();
+            let mut next: HashMap<H256, H256> = HashMap::new();
             if present.is_empty() {
                 // nothing to propagate
             } else {
                 for (i, val) in present.iter() {
-                    let si = i ^ 1;
-                    let left_is_me = i & 1 == 0;
+                    let si = sibling_index(i);
+                    let left_is_me = is_left(i);
                     let left = if left_is_me { *val } else { present.get(&si).cloned().unwrap_or(zeros[level]) };
                     let right = if left_is_me { present.get(&si).cloned().unwrap_or(zeros[level]) } else { *val };
-                    next.insert(i >> 1, h2(&left, &right));
+                    next.insert(parent_index(i), h2(&left, &right));
                 }
             }
             present = next;
-            idx >>= 1;
+            idx = parent_index(&idx);
         }
 
         (leaf_val, SmtProof { siblings })
@@ -203,22 +240,85 @@ impl SparseMerkleTree {
         };
 
         let mut idx = Self::key_index(key);
-        for (level, sib) in proof.siblings.iter().enumerate() {
-            let left_is_me = idx & 1 == 0;
+        for sib in proof.siblings.iter() {
+            let left_is_me = is_left(&idx);
             let left = if left_is_me { cur } else { *sib };
             let right = if left_is_me { *sib } else { cur };
             cur = h2(&left, &right);
-            idx >>= 1;
+            idx = parent_index(&idx);
         }
         &cur == root
     }
 
     #[inline]
-    fn key_index(key: &H256) -> u64 {
-        // use the lowest 64 bits of the 256-bit key as binary path index
-        // (devnet-friendly; can be extended to full 256-bit if desired)
-        let mut arr = [0u8; 8];
-        arr.copy_from_slice(&key[24..32]);
-        u64::from_be_bytes(arr)
+    fn key_index(key: &H256) -> H256 {
+        // The whole 256-bit key (big-endian) is the path, so distinct keys never share a leaf
+        *key
+    }
+}
+
+/// Whether the node at `idx` is the left child of its parent
+#[inline]
+fn is_left(idx: &H256) -> bool {
+    idx[31] & 1 == 0
+}
+
+/// Index of the other child of the same parent (`idx ^ 1`)
+#[inline]
+fn sibling_index(idx: &H256) -> H256 {
+    let mut sib = *idx;
+    sib[31] ^= 1;
+    sib
+}
+
+/// Index of the parent node (`idx >> 1` on the big-endian 256-bit index)
+#[inline]
+fn parent_index(idx: &H256) -> H256 {
+    let mut out = [0u8; 32];
+    out[0] = idx[0] >> 1;
+    for i in 1..32 {
+        out[i] = (idx[i] >> 1) | (idx[i - 1] << 7);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_sharing_low_bits_get_their_own_leaves() {
+        let mut a = [0u8; 32];
+        a[31] = 5;
+        let mut b = a;
+        b[0] = 0x80;
+        let (va, vb) = ([1u8; 32], [2u8; 32]);
+
+        let mut tree = SparseMerkleTree::new();
+        tree.update(a, Some(va));
+        let only_a = tree.root();
+        tree.update(b, Some(vb));
+        assert_ne!(tree.root(), only_a);
+
+        // Both leaves prove against the same root, and neither proof works for the other key
+        let root = tree.root();
+        for (key, value, other) in [(a, va, b), (b, vb, a)] {
+            let (leaf, proof) = tree.prove(&key);
+            assert_eq!(leaf, Some(value));
+            assert!(SparseMerkleTree::verify(&root, &key, Some(&value), &proof));
+            assert!(!SparseMerkleTree::verify(&root, &other, Some(&value), &proof));
+        }
+
+        // Insertion order does not matter
+        let mut reversed = SparseMerkleTree::new();
+        reversed.update(b, Some(vb));
+        reversed.update(a, Some(va));
+        assert_eq!(reversed.root(), root);
+
+        let mut absent = [3u8; 32];
+        absent[24..].copy_from_slice(&a[24..]);
+        let (leaf, proof) = tree.prove(&absent);
+        assert_eq!(leaf, None);
+        assert!(SparseMerkleTree::verify(&root, &absent, None, &proof));
     }
 }